@@ -0,0 +1,160 @@
+//! Results in the JSON an Ansible module returns (`changed`, `failed`, `msg` and `diff`), for
+//! `apply --report ansible` and `plan --format ansible`, so the binary can be run from a playbook
+//! without a wrapper script.
+
+use color_eyre::eyre::Result;
+use plist::{Dictionary, Value};
+use serde::Serialize;
+
+use crate::defaults::replace_data_in_plist;
+
+/// What Ansible reads from a module's stdout.
+#[derive(Debug, Serialize)]
+pub struct AnsibleResult {
+    pub changed: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub failed: bool,
+    pub msg: String,
+    /// One entry per domain, which `--diff` shows as the change between the `before` and `after`
+    /// dictionaries of its changed keys.
+    pub diff: Vec<AnsibleDiff>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AnsibleDiff {
+    pub before_header: String,
+    pub after_header: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Changed keys and errors, collected per domain in the order they're seen.
+#[derive(Debug, Default)]
+pub struct AnsibleReport {
+    domains: Vec<(String, Dictionary, Dictionary)>,
+    errors: Vec<String>,
+}
+
+impl AnsibleReport {
+    /// A key in a domain was (or would be) changed. `old_value` is `None` if the key didn't exist.
+    pub fn add_change(&mut self, domain: &str, key: &str, old_value: Option<&Value>, new_value: &Value) {
+        if !self.domains.iter().any(|(name, ..)| name == domain) {
+            self.domains.push((domain.to_owned(), Dictionary::new(), Dictionary::new()));
+        }
+
+        let Some((_, before, after)) = self.domains.iter_mut().find(|(name, ..)| name == domain) else {
+            return;
+        };
+
+        if let Some(old_value) = old_value {
+            before.insert(key.to_owned(), old_value.clone());
+        }
+
+        after.insert(key.to_owned(), new_value.clone());
+    }
+
+    /// Applying (or planning) a domain, or a whole document of a file, failed.
+    pub fn add_error(&mut self, domain: &str, error: &str) {
+        self.errors.push(format!("{domain}: {error}"));
+    }
+
+    /// Applying (or planning) stopped on an error that isn't about one domain or document.
+    pub fn add_failure(&mut self, error: &str) {
+        self.errors.push(error.to_owned());
+    }
+
+    /// The module result of a run that failed before anything could be reported.
+    pub fn failure(error: &str) -> Result<AnsibleResult> {
+        let mut report = Self::default();
+        report.add_failure(error);
+        report.result()
+    }
+
+    /// The module result, with binary data hex-encoded as in `plan`.
+    pub fn result(&self) -> Result<AnsibleResult> {
+        let keys: usize = self.domains.iter().map(|(_, _, after)| after.len()).sum();
+
+        let msg = match (keys, self.domains.len()) {
+            _ if !self.errors.is_empty() => self.errors.join("\n"),
+            (0, _) => "All defaults are already set.".to_owned(),
+            (1, _) => "Changed 1 key in 1 domain.".to_owned(),
+            (keys, 1) => format!("Changed {keys} keys in 1 domain."),
+            (keys, domains) => format!("Changed {keys} keys in {domains} domains."),
+        };
+
+        let diff = self
+            .domains
+            .iter()
+            .map(|(domain, before, after)| {
+                let mut before = Value::Dictionary(before.clone());
+                let mut after = Value::Dictionary(after.clone());
+                replace_data_in_plist(&mut before)?;
+                replace_data_in_plist(&mut after)?;
+
+                Ok(AnsibleDiff {
+                    before_header: format!("{domain} (before)"),
+                    after_header: format!("{domain} (after)"),
+                    before,
+                    after,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(AnsibleResult {
+            changed: keys > 0,
+            failed: !self.errors.is_empty(),
+            msg,
+            diff,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::AnsibleReport;
+
+    #[test]
+    fn test_ansible_result() -> TestResult {
+        let mut report = AnsibleReport::default();
+
+        let result = report.result()?;
+        assert!(!result.changed);
+        assert_eq!(result.msg, "All defaults are already set.");
+
+        report.add_change("com.apple.dock", "autohide", Some(&Value::Boolean(false)), &Value::Boolean(true));
+        report.add_change("com.apple.dock", "tilesize", None, &Value::from(36));
+        report.add_change("NSGlobalDomain", "AppleShowAllExtensions", None, &Value::Boolean(true));
+
+        let result = report.result()?;
+        assert!(result.changed);
+        assert_eq!(result.msg, "Changed 3 keys in 2 domains.");
+
+        assert_eq!(
+            serde_json::to_value(&result.diff[0])?,
+            serde_json::json!({
+                "before_header": "com.apple.dock (before)",
+                "after_header": "com.apple.dock (after)",
+                "before": {"autohide": false},
+                "after": {"autohide": true, "tilesize": 36},
+            })
+        );
+
+        // `failed` is only there when something failed.
+        assert!(serde_json::to_value(&result)?.get("failed").is_none());
+
+        report.add_error("com.example.app", "Permission denied");
+        let result = report.result()?;
+        assert!(result.failed);
+        assert_eq!(result.msg, "com.example.app: Permission denied");
+
+        // Errors not about a domain fail the result too.
+        let result = AnsibleReport::failure("No YAML files found")?;
+        assert!(result.failed && !result.changed);
+        assert_eq!(serde_json::to_value(&result)?["msg"], "No YAML files found");
+
+        Ok(())
+    }
+}
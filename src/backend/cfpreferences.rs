@@ -0,0 +1,282 @@
+//! `apply --backend cfpreferences`: changed keys are set with `CFPreferencesSetMultiple` and
+//! flushed with `CFPreferencesSynchronize`, so cfprefsd makes every change itself and its cache
+//! can't go stale, as it can when plist files are rewritten behind its back. Plists are read with
+//! `CFPreferencesCopyMultiple`, so planning sees the values cfprefsd has too.
+
+use camino::Utf8Path;
+use color_eyre::eyre::Result;
+use plist::{Dictionary, Value};
+
+use super::{key_updates, PrefsBackend};
+use crate::defaults::{domain_for_path, home_dir, write_plist_file, PlistWrite, WriteOptions};
+use crate::domain::Domain;
+use crate::errors::DefaultsError as E;
+
+/// Sets changed keys through `CFPreferences`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CfPreferencesBackend;
+
+impl PrefsBackend for CfPreferencesBackend {
+    fn read(&self, plist_path: &Utf8Path) -> Result<Option<Value>, E> {
+        match ffi::copy_multiple(&scope(plist_path)) {
+            // Keys cfprefsd hasn't written yet count, even without a file.
+            Some(dict) if dict.is_empty() && !plist_path.exists() => Ok(None),
+            Some(dict) => Ok(Some(Value::Dictionary(dict))),
+            None => Err(E::CfPreferencesRead { path: plist_path.to_owned() }),
+        }
+    }
+
+    fn write(&self, write: &PlistWrite, options: &WriteOptions) -> Result<bool> {
+        write_plist_file(write, options, |plist_path_exists| {
+            let (set, removed) = key_updates(self, write, plist_path_exists)?;
+            set_multiple(&write.path, &set, &removed)
+        })
+    }
+}
+
+/// Where `CFPreferences` keeps a plist's keys.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Scope {
+    /// The application ID: a domain like `com.apple.dock`, or a path without `.plist` for plists
+    /// outside the current user's preferences. `None` for `NSGlobalDomain`.
+    pub application: Option<String>,
+    /// Whether it's the current host's (`ByHost`) preferences, rather than any host's.
+    pub current_host: bool,
+}
+
+impl Scope {
+    /// The scope of a plist, given the current user's home directory.
+    pub fn for_path(plist_path: &Utf8Path, home: &Utf8Path) -> Self {
+        let preferences = home.join("Library/Preferences");
+        let parent = plist_path.parent().unwrap_or(plist_path);
+        let current_host = parent == preferences.join("ByHost");
+
+        match domain_for_path(plist_path) {
+            Some(domain) if current_host || parent == preferences => Self {
+                application: match domain {
+                    Domain::Global => None,
+                    domain => Some(domain.to_string()),
+                },
+                current_host,
+            },
+            _ => Self {
+                application: Some(plist_path.as_str().strip_suffix(".plist").unwrap_or(plist_path.as_str()).to_owned()),
+                current_host: false,
+            },
+        }
+    }
+}
+
+/// The scope of a plist, relative to the home directory preferences are resolved in.
+fn scope(plist_path: &Utf8Path) -> Scope {
+    Scope::for_path(plist_path, &home_dir().unwrap_or_default())
+}
+
+/// Set and remove keys of a plist through `CFPreferences`, for the current user.
+fn set_multiple(plist_path: &Utf8Path, set: &Dictionary, remove: &[String]) -> Result<(), E> {
+    if ffi::set_multiple(&scope(plist_path), set, remove) {
+        Ok(())
+    } else {
+        Err(E::CfPreferencesWrite { path: plist_path.to_owned() })
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod ffi {
+    use core_foundation_sys::base::{kCFAllocatorDefault, CFIndex, CFRelease, CFTypeRef};
+    use core_foundation_sys::data::{CFDataCreate, CFDataGetBytePtr, CFDataGetLength};
+    use core_foundation_sys::preferences::{
+        kCFPreferencesAnyApplication, kCFPreferencesAnyHost, kCFPreferencesCurrentHost, kCFPreferencesCurrentUser, CFPreferencesCopyKeyList,
+        CFPreferencesCopyMultiple, CFPreferencesSetMultiple, CFPreferencesSynchronize,
+    };
+    use core_foundation_sys::propertylist::{
+        kCFPropertyListBinaryFormat_v1_0, kCFPropertyListImmutable, CFPropertyListCreateData, CFPropertyListCreateWithData,
+    };
+    use core_foundation_sys::string::{kCFStringEncodingUTF8, CFStringCreateWithBytes, CFStringRef};
+    use plist::{Dictionary, Value};
+
+    use super::Scope;
+
+    /// A Core Foundation object this owns, released when it's dropped.
+    struct Owned(CFTypeRef);
+
+    impl Drop for Owned {
+        fn drop(&mut self) {
+            // SAFETY: Only non-null objects returned by `Create` functions are owned.
+            unsafe { CFRelease(self.0) }
+        }
+    }
+
+    fn owned(cf: CFTypeRef) -> Option<Owned> {
+        (!cf.is_null()).then_some(Owned(cf))
+    }
+
+    fn string(s: &str) -> Option<Owned> {
+        let len = CFIndex::try_from(s.len()).ok()?;
+
+        // SAFETY: The bytes are valid UTF-8, and are copied before the call returns.
+        owned(unsafe { CFStringCreateWithBytes(kCFAllocatorDefault, s.as_ptr(), len, kCFStringEncodingUTF8, 0) }.cast())
+    }
+
+    /// A Core Foundation property list of a value, by way of its binary plist encoding.
+    fn property_list(value: &Value) -> Option<Owned> {
+        let mut bytes = Vec::new();
+        plist::to_writer_binary(&mut bytes, value).ok()?;
+        let len = CFIndex::try_from(bytes.len()).ok()?;
+
+        // SAFETY: The bytes are copied before the call returns.
+        let data = owned(unsafe { CFDataCreate(kCFAllocatorDefault, bytes.as_ptr(), len) }.cast())?;
+
+        // SAFETY: `data` is a valid `CFData`, and the format and error out parameters are optional.
+        owned(unsafe {
+            CFPropertyListCreateWithData(
+                kCFAllocatorDefault,
+                data.0.cast(),
+                kCFPropertyListImmutable,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        })
+    }
+
+    /// A value of a Core Foundation property list, by way of its binary plist encoding.
+    fn value(property_list: &Owned) -> Option<Value> {
+        // SAFETY: `property_list` is a valid property list, and the error out parameter is optional.
+        let data =
+            owned(unsafe { CFPropertyListCreateData(kCFAllocatorDefault, property_list.0, kCFPropertyListBinaryFormat_v1_0, 0, std::ptr::null_mut()) }.cast())?;
+
+        // SAFETY: `data` is a valid `CFData`, whose bytes live as long as it does.
+        let bytes = unsafe {
+            let len = usize::try_from(CFDataGetLength(data.0.cast())).ok()?;
+            std::slice::from_raw_parts(CFDataGetBytePtr(data.0.cast()), len)
+        };
+
+        plist::from_bytes(bytes).ok()
+    }
+
+    /// The host a scope's preferences are for.
+    fn host(scope: &Scope) -> CFStringRef {
+        // SAFETY: The constants are provided by Core Foundation.
+        unsafe {
+            if scope.current_host {
+                kCFPreferencesCurrentHost
+            } else {
+                kCFPreferencesAnyHost
+            }
+        }
+    }
+
+    /// Every key of a scope with its value, or `None` if they couldn't be read.
+    pub(super) fn copy_multiple(scope: &Scope) -> Option<Dictionary> {
+        let application = match &scope.application {
+            Some(application) => Some(string(application)?),
+            None => None,
+        };
+
+        // SAFETY: Every object is valid for the duration of the calls, and the constants are
+        // provided by Core Foundation.
+        let values = unsafe {
+            let application = application.as_ref().map_or(kCFPreferencesAnyApplication, |application| application.0.cast());
+
+            // No key list means there are no keys.
+            let Some(keys) = owned(CFPreferencesCopyKeyList(application, kCFPreferencesCurrentUser, host(scope)).cast()) else {
+                return Some(Dictionary::new());
+            };
+
+            owned(CFPreferencesCopyMultiple(keys.0.cast(), application, kCFPreferencesCurrentUser, host(scope)).cast())?
+        };
+
+        value(&values)?.into_dictionary()
+    }
+
+    /// Whether the keys were set and removed, and synchronized to disk.
+    pub(super) fn set_multiple(scope: &Scope, set: &Dictionary, remove: &[String]) -> bool {
+        let Some(set) = property_list(&Value::Dictionary(set.clone())) else {
+            return false;
+        };
+        let Some(remove) = property_list(&Value::Array(remove.iter().cloned().map(Value::String).collect())) else {
+            return false;
+        };
+        let application = match &scope.application {
+            Some(application) => match string(application) {
+                Some(application) => Some(application),
+                None => return false,
+            },
+            None => None,
+        };
+
+        // SAFETY: Every object is valid for the duration of the calls, and the constants are
+        // provided by Core Foundation.
+        unsafe {
+            let application = application.as_ref().map_or(kCFPreferencesAnyApplication, |application| application.0.cast());
+
+            CFPreferencesSetMultiple(set.0.cast(), remove.0.cast(), application, kCFPreferencesCurrentUser, host(scope));
+            CFPreferencesSynchronize(application, kCFPreferencesCurrentUser, host(scope)) != 0
+        }
+    }
+}
+
+#[cfg(not(target_os = "macos"))]
+mod ffi {
+    use plist::Dictionary;
+
+    use super::Scope;
+
+    /// There's no `CFPreferences` to read from.
+    pub(super) const fn copy_multiple(_scope: &Scope) -> Option<Dictionary> {
+        None
+    }
+
+    /// There's no `CFPreferences` to write to.
+    pub(super) const fn set_multiple(_scope: &Scope, _set: &Dictionary, _remove: &[String]) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+
+    use super::Scope;
+
+    #[test]
+    fn test_scope() {
+        let home = Utf8Path::new("/Users/me");
+        let scope = |path: &str| Scope::for_path(Utf8Path::new(path), home);
+
+        assert_eq!(
+            scope("/Users/me/Library/Preferences/com.apple.dock.plist"),
+            Scope {
+                application: Some("com.apple.dock".to_owned()),
+                current_host: false,
+            }
+        );
+        assert_eq!(
+            scope("/Users/me/Library/Preferences/.GlobalPreferences.plist"),
+            Scope {
+                application: None,
+                current_host: false,
+            }
+        );
+        assert_eq!(
+            scope("/Users/me/Library/Preferences/ByHost/com.apple.screensaver.01234567-89AB-CDEF-0123-456789ABCDEF.plist"),
+            Scope {
+                application: Some("com.apple.screensaver".to_owned()),
+                current_host: true,
+            }
+        );
+
+        // Containers, other users and root-owned preferences are set by path.
+        assert_eq!(
+            scope("/Users/me/Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.Safari.plist"),
+            Scope {
+                application: Some("/Users/me/Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.Safari".to_owned()),
+                current_host: false,
+            }
+        );
+        assert_eq!(
+            scope("/Library/Preferences/com.apple.loginwindow.plist").application.as_deref(),
+            Some("/Library/Preferences/com.apple.loginwindow")
+        );
+    }
+}
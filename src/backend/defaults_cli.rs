@@ -0,0 +1,116 @@
+//! `apply --backend defaults-cli`: each changed key is written with `defaults write`, and each
+//! removed key deleted with `defaults delete`, so cfprefsd makes the changes itself.
+
+use camino::Utf8Path;
+use color_eyre::eyre::Result;
+use duct::cmd;
+use plist::Value;
+use tracing::trace;
+
+use super::{key_updates, PrefsBackend};
+use crate::defaults::{write_plist_file, PlistWrite, WriteOptions};
+use crate::errors::DefaultsError as E;
+
+/// Runs the `defaults` command for each changed key.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefaultsCliBackend;
+
+impl PrefsBackend for DefaultsCliBackend {
+    fn write(&self, write: &PlistWrite, options: &WriteOptions) -> Result<bool> {
+        write_plist_file(write, options, |plist_path_exists| write_with_defaults_cli(self, write, plist_path_exists))
+    }
+}
+
+/// Write a plist's changed keys one at a time with `defaults write`, and delete the keys it no
+/// longer has with `defaults delete`, using `sudo` if the file isn't writable.
+fn write_with_defaults_cli(backend: &dyn PrefsBackend, write: &PlistWrite, plist_path_exists: bool) -> Result<(), E> {
+    let plist_path = &write.path;
+    let (set, removed) = key_updates(backend, write, plist_path_exists)?;
+
+    for (key, value) in &set {
+        let mut args = vec!["write".to_owned(), plist_path.to_string(), key.clone()];
+        args.extend(defaults_write_args(value, plist_path)?);
+
+        run_defaults(&args, write.needs_sudo, plist_path, key)?;
+    }
+
+    for key in &removed {
+        run_defaults(&["delete".to_owned(), plist_path.to_string(), key.clone()], write.needs_sudo, plist_path, key)?;
+    }
+
+    Ok(())
+}
+
+fn run_defaults(args: &[String], sudo: bool, plist_path: &Utf8Path, key: &str) -> Result<(), E> {
+    trace!("Running defaults {}", args.join(" "));
+
+    let expression = if sudo {
+        cmd("sudo", ["defaults"].into_iter().map(str::to_owned).chain(args.iter().cloned()))
+    } else {
+        cmd("defaults", args)
+    };
+
+    expression.stdout_null().stderr_capture().run().map(|_| ()).map_err(|e| E::DefaultsWrite {
+        path: plist_path.to_owned(),
+        key: key.to_owned(),
+        source: e,
+    })
+}
+
+/// The type flag and value arguments of `defaults write` for a value, e.g. `-bool true`. Values
+/// without a flag of their own (dates, arrays, dictionaries, UIDs) are given as a plist fragment,
+/// e.g. `<array><integer>1</integer></array>`, which `defaults` also accepts.
+fn defaults_write_args(value: &Value, plist_path: &Utf8Path) -> Result<Vec<String>, E> {
+    Ok(match value {
+        Value::Boolean(b) => vec!["-bool".to_owned(), b.to_string()],
+        Value::Integer(i) => vec!["-int".to_owned(), i.to_string()],
+        Value::Real(f) => vec!["-float".to_owned(), f.to_string()],
+        Value::String(s) => vec!["-string".to_owned(), s.clone()],
+        Value::Data(data) => vec!["-data".to_owned(), hex::encode(data)],
+        value => {
+            let mut xml = Vec::new();
+            value
+                .to_writer_xml_with_options(&mut xml, &plist::XmlWriteOptions::default().indent(b' ', 0))
+                .map_err(|e| E::PlistWrite {
+                    path: plist_path.to_owned(),
+                    source: e,
+                })?;
+            let xml = String::from_utf8_lossy(&xml);
+
+            let fragment = xml
+                .split_once("<plist version=\"1.0\">")
+                .and_then(|(_, rest)| rest.rsplit_once("</plist>"))
+                .map_or(xml.as_ref(), |(fragment, _)| fragment);
+
+            vec![fragment.trim().to_owned()]
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::defaults_write_args;
+
+    #[test]
+    fn test_defaults_write_args() -> TestResult {
+        let path = Utf8Path::new("com.example.cli.plist");
+
+        assert_eq!(defaults_write_args(&Value::Boolean(true), path)?, ["-bool", "true"]);
+        assert_eq!(defaults_write_args(&Value::from(36), path)?, ["-int", "36"]);
+        assert_eq!(defaults_write_args(&Value::Real(0.5), path)?, ["-float", "0.5"]);
+        assert_eq!(defaults_write_args(&Value::from("-bool"), path)?, ["-string", "-bool"]);
+        assert_eq!(defaults_write_args(&Value::Data(vec![0xca, 0xfe]), path)?, ["-data", "cafe"]);
+
+        let nested = Value::Dictionary(Dictionary::from_iter([("list", Value::Array(vec![Value::from(1)]))]));
+        assert_eq!(
+            defaults_write_args(&nested, path)?,
+            ["<dict><key>list</key><array><integer>1</integer></array></dict>"]
+        );
+
+        Ok(())
+    }
+}
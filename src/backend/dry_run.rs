@@ -0,0 +1,52 @@
+//! `apply --dry-run`: plans are made and reported as usual, but nothing is written.
+
+use std::cell::RefCell;
+
+use camino::Utf8PathBuf;
+use color_eyre::eyre::Result;
+use tracing::info;
+
+use super::PrefsBackend;
+use crate::defaults::{PlistWrite, WriteOptions};
+use crate::tree::render_change;
+
+/// Records the writes it's given rather than making them.
+#[derive(Debug, Default)]
+pub struct DryRunBackend {
+    written: RefCell<Vec<Utf8PathBuf>>,
+}
+
+impl DryRunBackend {
+    /// The plists that would have been written, in the order they would have been.
+    pub fn written(&self) -> Vec<Utf8PathBuf> {
+        self.written.borrow().clone()
+    }
+}
+
+impl PrefsBackend for DryRunBackend {
+    fn write(&self, write: &PlistWrite, _options: &WriteOptions) -> Result<bool> {
+        let PlistWrite {
+            domain,
+            path,
+            changes,
+            removed,
+            ..
+        } = write;
+
+        for change in changes {
+            let (old_value, new_value) = change.shown_values();
+            info!(
+                "Would change default {domain} {}",
+                render_change(&change.key, old_value.as_deref(), &new_value, 0).trim_end()
+            );
+        }
+
+        for key in removed {
+            info!("Would remove default {domain} {key}");
+        }
+
+        self.written.borrow_mut().push(path.clone());
+
+        Ok(false)
+    }
+}
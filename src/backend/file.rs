@@ -0,0 +1,25 @@
+//! The default backend, rewriting each changed plist file as a whole.
+
+use color_eyre::eyre::Result;
+
+use super::PrefsBackend;
+use crate::defaults::{write_plist, write_plist_file, PlistWrite, WriteOptions};
+
+/// Rewrites plist files, in the format they're already in unless `--convert-format` is given.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FileBackend;
+
+impl PrefsBackend for FileBackend {
+    fn write(&self, write: &PlistWrite, options: &WriteOptions) -> Result<bool> {
+        write_plist_file(write, options, |plist_path_exists| {
+            write_plist(
+                plist_path_exists,
+                &write.path,
+                &write.value,
+                &options.xml_format,
+                options.write_format,
+                options.convert_format,
+            )
+        })
+    }
+}
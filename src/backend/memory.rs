@@ -0,0 +1,112 @@
+//! An in-memory plist store, so whole applies can be tested without touching any files, and the
+//! golden-file tests running the YAML fixtures in `testdata/` through it.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use plist::Value;
+
+use super::PrefsBackend;
+use crate::defaults::{PlistWrite, WriteOptions};
+use crate::errors::DefaultsError as E;
+
+/// Plists kept in memory, by path.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    plists: RefCell<BTreeMap<Utf8PathBuf, Value>>,
+}
+
+impl MemoryBackend {
+    /// Set a plist's contents, as if the file already existed.
+    pub fn insert(&self, plist_path: impl Into<Utf8PathBuf>, value: Value) {
+        self.plists.borrow_mut().insert(plist_path.into(), value);
+    }
+
+    /// A plist's contents, or `None` if it hasn't been written.
+    pub fn get(&self, plist_path: &Utf8Path) -> Option<Value> {
+        self.plists.borrow().get(plist_path).cloned()
+    }
+}
+
+impl PrefsBackend for MemoryBackend {
+    fn read(&self, plist_path: &Utf8Path) -> Result<Option<Value>, E> {
+        Ok(self.get(plist_path))
+    }
+
+    fn write(&self, write: &PlistWrite, _options: &WriteOptions) -> Result<bool> {
+        self.insert(write.path.clone(), write.value.clone());
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use camino::{Utf8Path, Utf8PathBuf};
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::MemoryBackend;
+    use crate::cmd::{apply_files, ApplyOptions, ApplySummary};
+    use crate::defaults::WriteOptions;
+    use crate::observer::ConsoleObserver;
+
+    /// The plist every fixture applies to.
+    const GOLDEN_PLIST: &str = "/memory/com.example.golden.plist";
+
+    /// Apply each `testdata/<name>.yaml` to `<name>.before.plist` (or to nothing, if there isn't
+    /// one), and compare the result with `<name>.golden.plist`. Set `UPDATE_GOLDEN=1` to rewrite
+    /// the golden files instead.
+    #[test]
+    fn test_golden_files() -> TestResult {
+        let testdata = Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("src/backend/testdata");
+        let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+
+        let mut fixtures: Vec<Utf8PathBuf> = testdata
+            .read_dir_utf8()?
+            .map(|entry| entry.map(camino::Utf8DirEntry::into_path))
+            .filter(|path| path.as_ref().map_or(true, |path| path.extension() == Some("yaml")))
+            .collect::<Result<_, _>>()?;
+        fixtures.sort();
+        assert!(!fixtures.is_empty());
+
+        for fixture in fixtures {
+            let before = fixture.with_extension("before.plist");
+            let golden = fixture.with_extension("golden.plist");
+
+            let backend = Rc::new(MemoryBackend::default());
+            if before.exists() {
+                backend.insert(GOLDEN_PLIST, Value::from_file(&before)?);
+            }
+
+            let options = ApplyOptions {
+                write: WriteOptions {
+                    backend: backend.clone(),
+                    ..WriteOptions::default()
+                },
+                ..ApplyOptions::default()
+            };
+            apply_files(
+                std::slice::from_ref(&fixture),
+                &options,
+                &ConsoleObserver::default(),
+                &mut ApplySummary::default(),
+            )?;
+
+            let applied = backend
+                .get(Utf8Path::new(GOLDEN_PLIST))
+                .ok_or(format!("{fixture} didn't write {GOLDEN_PLIST}"))?;
+
+            if update {
+                applied.to_file_xml(&golden)?;
+            } else {
+                assert_eq!(applied, Value::from_file(&golden)?, "{fixture} doesn't match {golden}");
+            }
+        }
+
+        Ok(())
+    }
+}
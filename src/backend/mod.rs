@@ -0,0 +1,91 @@
+//! How plists are read and written. Planning and merging only read through a [`PrefsBackend`],
+//! and executing a plan only writes through one, so every way of writing changes shares the
+//! same merge logic.
+
+mod cfpreferences;
+mod defaults_cli;
+mod dry_run;
+mod file;
+#[cfg(test)]
+mod memory;
+
+use std::fmt::Debug;
+use std::rc::Rc;
+
+use camino::Utf8Path;
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use plist::{Dictionary, Value};
+
+pub use self::cfpreferences::CfPreferencesBackend;
+pub use self::defaults_cli::DefaultsCliBackend;
+pub use self::dry_run::DryRunBackend;
+pub use self::file::FileBackend;
+use crate::defaults::{get_plist_value_type, read_plist, PlistWrite, WriteOptions};
+use crate::errors::DefaultsError as E;
+
+/// Reads plists for planning, and writes planned changes to them.
+pub trait PrefsBackend: Debug {
+    /// The current contents of a plist, or `None` if it doesn't exist.
+    fn read(&self, plist_path: &Utf8Path) -> Result<Option<Value>, E> {
+        plist_path.exists().then(|| read_plist(plist_path)).transpose()
+    }
+
+    /// Write a planned change to a plist. Returns whether anything was written, e.g. `false` for a
+    /// symlink left untouched.
+    fn write(&self, write: &PlistWrite, options: &WriteOptions) -> Result<bool>;
+}
+
+/// How `apply` writes changed keys.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Rewrite the plist file.
+    #[default]
+    File,
+    /// Run `defaults write` (and `defaults delete`) for each changed key, so cfprefsd makes the
+    /// changes and its cache stays consistent.
+    DefaultsCli,
+    /// Set the changed keys through `CFPreferences`, which makes the changes with cfprefsd too,
+    /// without a process per key.
+    #[value(name = "cfpreferences")]
+    CfPreferences,
+}
+
+impl Backend {
+    /// The backend writing changes this way.
+    pub fn prefs_backend(self) -> Rc<dyn PrefsBackend> {
+        match self {
+            Self::File => Rc::new(FileBackend),
+            Self::DefaultsCli => Rc::new(DefaultsCliBackend),
+            Self::CfPreferences => Rc::new(CfPreferencesBackend),
+        }
+    }
+}
+
+/// For the backends that set keys rather than rewriting the file: the changed keys with their new
+/// values, and the keys the plist no longer has, as `backend` reads it.
+fn key_updates(backend: &dyn PrefsBackend, write: &PlistWrite, plist_path_exists: bool) -> Result<(Dictionary, Vec<String>), E> {
+    let PlistWrite { path: plist_path, value, .. } = write;
+
+    let new_dict = value.as_dictionary().ok_or_else(|| E::NotADictionary {
+        domain: write.domain.to_string(),
+        key: "Unknown".to_owned(),
+        plist_type: get_plist_value_type(value),
+    })?;
+
+    let set = write
+        .changes
+        .iter()
+        .filter_map(|change| new_dict.get(&change.key).map(|value| (change.key.clone(), value.clone())))
+        .collect();
+
+    // Not just `removed`, as wiping a domain with `"!"` also removes keys.
+    let old_value = if plist_path_exists { backend.read(plist_path)? } else { None };
+
+    let removed = match old_value {
+        Some(Value::Dictionary(old_dict)) => old_dict.keys().filter(|key| !new_dict.contains_key(key)).cloned().collect(),
+        _ => Vec::new(),
+    };
+
+    Ok((set, removed))
+}
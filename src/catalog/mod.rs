@@ -0,0 +1,59 @@
+//! Built-in catalog of well known defaults, with a description of what each one does.
+
+use std::collections::BTreeMap;
+use std::sync::OnceLock;
+
+use serde::Deserialize;
+
+/// The catalog source, compiled into the binary.
+const CATALOG_YAML: &str = include_str!("catalog.yaml");
+
+/// Domain -> key -> entry.
+pub type Catalog = BTreeMap<String, BTreeMap<String, CatalogEntry>>;
+
+/// What is known about a single default.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CatalogEntry {
+    /// What the setting does.
+    pub description: String,
+
+    /// The plist type of the value.
+    #[serde(rename = "type")]
+    pub value_type: String,
+
+    /// Valid values and what they mean.
+    #[serde(default)]
+    pub values: Vec<String>,
+
+    /// Process to restart for a change to take effect.
+    pub kill: Option<String>,
+}
+
+/// The built-in catalog, parsed on first use.
+pub fn catalog() -> &'static Catalog {
+    static CATALOG: OnceLock<Catalog> = OnceLock::new();
+
+    CATALOG.get_or_init(|| serde_yaml::from_str(CATALOG_YAML).expect("The built-in catalog should be valid YAML."))
+}
+
+/// Look up a single default.
+pub fn lookup(domain: &str, key: &str) -> Option<&'static CatalogEntry> {
+    catalog().get(domain)?.get(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{catalog, lookup};
+
+    #[test]
+    fn test_catalog_parses() {
+        assert!(!catalog().is_empty());
+
+        let entry = lookup("com.apple.dock", "autohide").expect("dock autohide is in the catalog");
+        assert_eq!(entry.value_type, "boolean");
+        assert_eq!(entry.kill.as_deref(), Some("Dock"));
+
+        assert!(lookup("com.apple.dock", "not-a-real-key").is_none());
+    }
+}
@@ -0,0 +1,139 @@
+//! Versioned metadata about behavior changes, and which YAML files each one affects.
+
+use std::sync::OnceLock;
+
+use serde::{Deserialize, Serialize};
+
+use crate::defaults::NS_GLOBAL_DOMAIN;
+use crate::domain::Domain;
+use crate::errors::DefaultsError as E;
+use crate::volatile::is_volatile_key;
+
+/// The changelog source, compiled into the binary.
+const CHANGES_YAML: &str = include_str!("changes.yaml");
+
+/// A behavior change between versions.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Change {
+    /// Stable identifier, for scripts to match on.
+    pub id: String,
+
+    /// First version with the change.
+    pub version: String,
+
+    /// What changed, and what to check.
+    pub summary: String,
+
+    /// Which configs the change is relevant to.
+    #[serde(skip_serializing)]
+    pub affects: Affects,
+}
+
+/// Conditions under which a change is relevant to a config. Any matching condition is enough.
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Affects {
+    #[serde(default)]
+    pub always: bool,
+
+    pub tag: Option<String>,
+
+    #[serde(default)]
+    pub key_paths: bool,
+
+    #[serde(default)]
+    pub domain_names: bool,
+
+    #[serde(default)]
+    pub invalid_domains: bool,
+
+    #[serde(default)]
+    pub volatile_keys: bool,
+}
+
+impl Affects {
+    /// Whether a domain name in a YAML document is affected.
+    pub fn domain(&self, domain: &str) -> bool {
+        (self.domain_names && domain != NS_GLOBAL_DOMAIN && !domain.starts_with('/') && !domain.contains('.'))
+            || (self.invalid_domains && domain.split_whitespace().collect::<Vec<_>>().join(" ").parse::<Domain>().is_err())
+    }
+
+    /// Whether a top level key of a domain is affected.
+    pub fn key(&self, key: &str) -> bool {
+        (self.key_paths && key.starts_with('.')) || (self.volatile_keys && is_volatile_key(key))
+    }
+
+    /// Whether a tagged value is affected.
+    pub fn tag(&self, tag: &str) -> bool {
+        self.tag.as_deref().is_some_and(|expected| {
+            tag.strip_prefix(expected).is_some_and(|offset| {
+                offset.is_empty() || offset.starts_with('+') || (offset.starts_with('-') && offset[1..].starts_with(|c: char| c.is_ascii_digit()))
+            })
+        })
+    }
+}
+
+/// All known behavior changes, oldest first.
+pub fn changes() -> &'static [Change] {
+    static CHANGES: OnceLock<Vec<Change>> = OnceLock::new();
+
+    CHANGES.get_or_init(|| serde_yaml::from_str(CHANGES_YAML).expect("The built-in changelog should be valid YAML."))
+}
+
+/// Parse a `major.minor.patch` version, with an optional leading `v`, into comparable numbers.
+pub fn parse_version(version: &str) -> Result<Vec<u64>, E> {
+    version
+        .trim_start_matches('v')
+        .split('.')
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u64>>>()
+        .filter(|parts| !parts.is_empty())
+        .ok_or_else(|| E::InvalidVersion { version: version.to_owned() })
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::{changes, parse_version, Affects};
+
+    #[test]
+    fn test_changelog() -> TestResult {
+        // Changes are listed under the version that ships them, not a later one.
+        let current = parse_version(env!("CARGO_PKG_VERSION"))?;
+        for change in changes() {
+            assert!(parse_version(&change.version)? <= current, "{} is newer than this version", change.id);
+        }
+
+        assert!(parse_version("v0.10.1")? > parse_version("0.9.0")?);
+        assert!(parse_version("latest").is_err());
+
+        let affects = Affects {
+            tag: Some("!now".to_owned()),
+            domain_names: true,
+            ..Affects::default()
+        };
+        assert!(affects.tag("!now") && affects.tag("!now+7d") && affects.tag("!now-1h"));
+        assert!(!affects.tag("!nowhere") && !affects.tag("!uuid"));
+
+        let uuid = Affects {
+            tag: Some("!uuid".to_owned()),
+            ..Affects::default()
+        };
+        assert!(!uuid.tag("!uuid-stable"));
+
+        assert!(affects.domain("Dock") && affects.domain("-g"));
+        assert!(!affects.domain("com.apple.dock") && !affects.domain("NSGlobalDomain"));
+
+        let invalid = Affects {
+            invalid_domains: true,
+            ..Affects::default()
+        };
+        assert!(invalid.domain("com..apple.dock") && invalid.domain("Library/com.apple.dock"));
+        assert!(!invalid.domain(" Google  Chrome ") && !invalid.domain("com.apple.dock"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use duct::cmd;
+use tracing::debug;
+
+use crate::defaults::home_dir;
+use crate::errors::DefaultsError as E;
+
+/// `launchd` label of the launch agent, also its file name.
+pub const AGENT_LABEL: &str = "com.github.dsully.macos-defaults";
+
+/// `install-agent` command.
+///
+/// Writes a launch agent that runs `apply` at login (and every `interval` seconds, if given),
+/// posting a notification when it finishes if `notify` is set, and loads it, replacing a
+/// previously installed agent.
+#[allow(clippy::print_stdout)]
+pub fn install_agent(path: Option<&Utf8Path>, config: Option<&Utf8Path>, interval: Option<u64>, notify: bool) -> Result<()> {
+    //
+    let exe = Utf8PathBuf::try_from(env::current_exe()?)?;
+    let path = path.map(Utf8Path::canonicalize_utf8).transpose().map_err(|e| E::FileRead {
+        path: path.map(Utf8Path::to_path_buf).unwrap_or_default(),
+        source: e,
+    })?;
+    let config = config.map(Utf8Path::canonicalize_utf8).transpose().map_err(|e| E::FileRead {
+        path: config.map(Utf8Path::to_path_buf).unwrap_or_default(),
+        source: e,
+    })?;
+
+    let home_dir = home_dir()?;
+    let agent_path = agent_path(&home_dir);
+    let log_path = home_dir.join("Library/Logs/macos-defaults.log");
+
+    let agent = agent_plist(&exe, path.as_deref(), config.as_deref(), interval, notify, &log_path);
+
+    if let Some(dir) = agent_path.parent() {
+        fs::create_dir_all(dir).map_err(|e| E::DirCreation {
+            path: dir.to_path_buf(),
+            source: e,
+        })?;
+    }
+
+    if agent_path.exists() {
+        unload_agent(&home_dir)?;
+    }
+
+    agent.to_file_xml(&agent_path).map_err(|e| E::PlistWrite {
+        path: agent_path.clone(),
+        source: e,
+    })?;
+
+    cmd!("launchctl", "bootstrap", gui_domain(&home_dir)?, &agent_path)
+        .stdout_null()
+        .run()
+        .map_err(|e| E::AgentLoad {
+            path: agent_path.clone(),
+            source: e,
+        })?;
+
+    println!("  {} {}", "✔".green(), agent_path.as_str().white());
+    println!("\n`apply` will run at login, logging to {log_path}");
+
+    Ok(())
+}
+
+/// `uninstall-agent` command.
+#[allow(clippy::print_stdout)]
+pub fn uninstall_agent() -> Result<()> {
+    //
+    let home_dir = home_dir()?;
+    let agent_path = agent_path(&home_dir);
+
+    if !agent_path.exists() {
+        println!("No launch agent installed at {agent_path}");
+        return Ok(());
+    }
+
+    unload_agent(&home_dir)?;
+
+    fs::remove_file(&agent_path).map_err(|e| E::FileRemove {
+        path: agent_path.clone(),
+        source: e,
+    })?;
+
+    println!("  {} Removed {}", "✔".green(), agent_path.as_str().white());
+
+    Ok(())
+}
+
+/// Path of the launch agent plist, e.g. `~/Library/LaunchAgents/com.github.dsully.macos-defaults.plist`.
+fn agent_path(home_dir: &Utf8Path) -> Utf8PathBuf {
+    home_dir.join("Library/LaunchAgents").join(format!("{AGENT_LABEL}.plist"))
+}
+
+/// The `launchd` domain of the home directory owner's GUI session, e.g. `gui/501`.
+pub(crate) fn gui_domain(home_dir: &Utf8Path) -> Result<String> {
+    let metadata = fs::metadata(home_dir).map_err(|e| E::FileRead {
+        path: home_dir.to_path_buf(),
+        source: e,
+    })?;
+
+    Ok(format!("gui/{}", metadata.uid()))
+}
+
+/// Stop the agent if it's loaded. Not being loaded isn't an error.
+fn unload_agent(home_dir: &Utf8Path) -> Result<()> {
+    let target = format!("{}/{AGENT_LABEL}", gui_domain(home_dir)?);
+
+    if let Err(e) = cmd!("launchctl", "bootout", &target).stdout_null().stderr_null().run() {
+        debug!("Unable to unload {target}, assuming it isn't loaded: {e}");
+    }
+
+    Ok(())
+}
+
+/// The launch agent plist running `apply` with the given YAML path and config file.
+fn agent_plist(exe: &Utf8Path, path: Option<&Utf8Path>, config: Option<&Utf8Path>, interval: Option<u64>, notify: bool, log_path: &Utf8Path) -> plist::Value {
+    //
+    let mut arguments = vec![exe.to_string()];
+
+    if let Some(config) = config {
+        arguments.extend(["--config".to_owned(), config.to_string()]);
+    }
+
+    arguments.extend(["apply".to_owned(), "--no-progress".to_owned()]);
+
+    if notify {
+        arguments.push("--notify".to_owned());
+    }
+
+    arguments.extend(path.map(ToString::to_string));
+
+    let mut agent = plist::Dictionary::new();
+    agent.insert("Label".into(), AGENT_LABEL.into());
+    agent.insert(
+        "ProgramArguments".into(),
+        plist::Value::Array(arguments.into_iter().map(plist::Value::String).collect()),
+    );
+    agent.insert("RunAtLoad".into(), true.into());
+
+    if let Some(interval) = interval {
+        agent.insert("StartInterval".into(), interval.into());
+    }
+
+    agent.insert("StandardOutPath".into(), log_path.as_str().into());
+    agent.insert("StandardErrorPath".into(), log_path.as_str().into());
+    agent.insert("ProcessType".into(), "Background".into());
+
+    plist::Value::Dictionary(agent)
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+    use testresult::TestResult;
+
+    use super::{agent_plist, AGENT_LABEL};
+
+    #[test]
+    fn test_agent_plist() -> TestResult {
+        let log_path = Utf8Path::new("/Users/me/Library/Logs/macos-defaults.log");
+
+        let agent = agent_plist(
+            Utf8Path::new("/usr/local/bin/macos-defaults"),
+            Some(Utf8Path::new("/Users/me/defaults")),
+            None,
+            Some(3600),
+            false,
+            log_path,
+        );
+        let agent = agent.as_dictionary().ok_or("is a dictionary")?;
+
+        assert_eq!(agent["Label"].as_string(), Some(AGENT_LABEL));
+        assert_eq!(agent["RunAtLoad"].as_boolean(), Some(true));
+        assert_eq!(agent["StartInterval"].as_unsigned_integer(), Some(3600));
+
+        let arguments: Vec<_> = agent["ProgramArguments"]
+            .as_array()
+            .ok_or("is an array")?
+            .iter()
+            .filter_map(plist::Value::as_string)
+            .collect();
+        assert_eq!(arguments, ["/usr/local/bin/macos-defaults", "apply", "--no-progress", "/Users/me/defaults"]);
+
+        let agent = agent_plist(Utf8Path::new("macos-defaults"), None, Some(Utf8Path::new("/c.yaml")), None, true, log_path);
+        let agent = agent.as_dictionary().ok_or("is a dictionary")?;
+
+        assert!(!agent.contains_key("StartInterval"));
+        assert_eq!(agent["ProgramArguments"].as_array().map(Vec::len), Some(6));
+
+        Ok(())
+    }
+}
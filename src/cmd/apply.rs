@@ -1,19 +1,36 @@
-use std::collections::HashMap;
-use std::io::{BufReader, BufRead};
-use std::ffi::OsStr;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::fs::File;
-use std::os::unix::ffi::OsStrExt;
-
-use camino::Utf8PathBuf;
-use color_eyre::eyre::{eyre, Result, WrapErr};
-use colored::Colorize;
-use log::{debug, error, trace};
+use std::io::{self, BufReader, Read};
+use std::rc::Rc;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use color_eyre::Report;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use sysinfo::{Signal, System};
+use tracing::{debug, error, info_span, trace, warn};
+use uuid::Uuid;
 use yaml_split::DocumentIterator;
 
-use crate::defaults::{write_defaults_values, MacOSDefaults};
-use crate::errors::DefaultsError as E;
+use crate::cmd::restart::{is_running, kill_processes, quit_app, relaunch_app};
+use crate::defaults::{domains as installed_domains, execute_plist_write, if_unset, MacOSDefaults, WriteOptions, BANG};
+use crate::domain::Domain;
+use crate::errors::{remedy, DefaultsError as E};
+use crate::glob::glob_matches;
+use crate::manifest::Manifest;
+use crate::observer::ApplyObserver;
+use crate::planner::{DocumentPlan, DomainPlan, Planner};
+use crate::presets::BundledPreset;
+use crate::schedule::{load_scheduled, Document, SourceDocument};
+use crate::state_cache::StateCache;
+use crate::tags::{yaml_to_plist, TagContext};
+use crate::validate::{self, split_constraints};
+use crate::volatile::DriftIgnore;
 
 /*
 // NB: Some of this code originated from: https://github.com/gibfahn/up-rs, MIT & Apache 2.0 licensed.
@@ -79,112 +96,571 @@ data:
 
 // Dummy struct before YAML deserialization attempt.
 #[derive(Debug, Default, Serialize, Deserialize)]
-struct DefaultsConfig(HashMap<String, HashMap<String, plist::Value>>);
+pub(crate) struct DefaultsConfig(pub(crate) HashMap<String, HashMap<String, plist::Value>>);
+
+impl DefaultsConfig {
+    /// Convert the `data` key of a document, resolving any tagged values.
+    pub(crate) fn from_yaml(data: serde_yaml::Value, context: &TagContext) -> Result<Self, E> {
+        let domains: HashMap<String, HashMap<String, serde_yaml::Value>> = serde_yaml::from_value(data).map_err(|e| E::DeserializationFailed { source: e })?;
+
+        let domains = domains
+            .into_iter()
+            .map(|(domain, prefs)| {
+                let prefs = prefs
+                    .into_iter()
+                    .map(|(key, value)| {
+                        let (value, valid) = split_constraints(value)?;
+                        let value = yaml_to_plist(value, context)?;
+
+                        if let Some(valid) = valid {
+                            validate::check(&domain, &key, &value, valid, context)?;
+                        }
+
+                        Ok((key, value))
+                    })
+                    .collect::<Result<_, E>>()?;
+                Ok((domain, prefs))
+            })
+            .collect::<Result<_, E>>()?;
+
+        Ok(Self(domains))
+    }
+
+    /// Convert the `data` key of a document, also applying document wide options like `once`.
+    pub(crate) fn for_document(data: serde_yaml::Value, once: bool, context: &TagContext) -> Result<Self, E> {
+        let mut defaults = Self::from_yaml(data, context)?;
+
+        if once {
+            for prefs in defaults.0.values_mut() {
+                *prefs = std::mem::take(prefs)
+                    .into_iter()
+                    .map(|(key, value)| if key == BANG { (key, value) } else { (key, if_unset(value)) })
+                    .collect();
+            }
+        }
+
+        Ok(defaults)
+    }
+
+    /// Replace glob domains like `com.jetbrains.*` with the installed domains they match.
+    pub(crate) fn expand_domain_globs(self, current_host: bool) -> Self {
+        if !self.0.keys().any(|domain| is_domain_glob(domain)) {
+            return self;
+        }
+
+        let installed = installed_domains(current_host).unwrap_or_default();
+        self.expand_domain_globs_in(&installed.iter().map(Domain::as_str).collect::<Vec<_>>())
+    }
+
+    /// Keys a domain is given by name take precedence over the same keys from globs, and globs
+    /// that sort first over later ones.
+    fn expand_domain_globs_in(self, installed: &[&str]) -> Self {
+        let (globs, named): (Vec<_>, Vec<_>) = self.0.into_iter().partition(|(domain, _)| is_domain_glob(domain));
+        let mut domains: HashMap<_, _> = named.into_iter().collect();
+
+        for (glob, prefs) in globs.into_iter().sorted_by(|(a, _), (b, _)| a.cmp(b)) {
+            let matches: Vec<&str> = installed.iter().copied().filter(|domain| glob_matches(&glob, domain, false)).collect();
+
+            if matches.is_empty() {
+                warn!("{glob} doesn't match any installed domain, skipping it.");
+            } else {
+                debug!("{glob} matches {}", matches.join(", "));
+            }
+
+            for domain in matches {
+                let merged = domains.entry(domain.to_owned()).or_default();
+
+                for (key, value) in &prefs {
+                    merged.entry(key.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        Self(domains)
+    }
+}
+
+/// Whether a domain of a document is a glob, like `com.jetbrains.*`, applied to every installed
+/// domain it matches. Plist paths are never globs.
+pub(crate) fn is_domain_glob(domain: &str) -> bool {
+    domain.contains(['*', '?']) && !domain.starts_with(['/', '~'])
+}
+
+/// A document's per domain setting (`ignore_keys`, `drift_ignore`) for a domain, given by name, or
+/// else by the first glob that matches it.
+pub(crate) fn domain_setting<'a, T>(settings: &'a HashMap<String, T>, domain: &str) -> Option<&'a T> {
+    settings.get(domain).or_else(|| {
+        settings
+            .iter()
+            .filter(|(pattern, _)| is_domain_glob(pattern) && glob_matches(pattern, domain, false))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(_, setting)| setting)
+    })
+}
+
+/// Options controlling how defaults are applied.
+#[derive(Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct ApplyOptions {
+    /// How plist files are written.
+    pub write: WriteOptions,
+
+    /// Processes to restart, in addition to a document's `kill` list, when it changes anything.
+    pub kill: Vec<String>,
+
+    /// Keys `--assert-idempotent` doesn't compare.
+    pub drift_ignore: DriftIgnore,
+
+    /// Per domain, keys that are never written, from the config file. Documents can add their own.
+    pub ignore_keys: HashMap<String, Vec<String>>,
+
+    /// Fail on domains without a plist, unless their document has `create: true`.
+    pub no_create: bool,
+
+    /// Leave out writes to keys managed by configuration profiles, rather than only warning.
+    pub skip_managed: bool,
+
+    /// The managed-keys manifest to record changed and removed keys in, with their original
+    /// values, for `teardown`. Saved once the apply finishes.
+    pub manifest: Option<RefCell<Manifest>>,
+
+    /// Carry on with the other domains and documents when one fails, collecting the failures in
+    /// [`ApplySummary::failed`] rather than stopping.
+    pub keep_going: bool,
+
+    /// Quit no apps before writing, as `--dry-run` writes nothing for them to pick up.
+    pub dry_run: bool,
+
+    /// With `--lazy-read`, skip domains whose YAML and plists haven't changed since they were last
+    /// applied, and record the ones applied.
+    pub state_cache: Option<Rc<StateCache>>,
+}
+
+/// What happened over the course of one or more `apply_defaults` calls.
+#[derive(Debug, Default)]
+pub struct ApplySummary {
+    /// Unique to this run of the tool.
+    pub run_id: Uuid,
+    /// Whether any defaults were changed.
+    pub changed: bool,
+    /// Number of domains that were successfully processed, whether or not they changed.
+    pub domains_applied: usize,
+    /// Number of keys changed or removed.
+    pub keys_changed: usize,
+    /// Processes to restart once everything has been written, from the `kill` lists of the
+    /// documents that changed anything. Each is listed once however many documents name it.
+    pub kill: Vec<String>,
+    /// Apps that were quit before writing, to relaunch after the processes are restarted.
+    pub relaunch: Vec<String>,
+    /// Domains (or whole documents) that failed with `keep_going`, in the order they failed.
+    pub failed: Vec<Failure>,
+    /// How long each domain took to read, plan and write, over every document setting it.
+    pub domain_times: HashMap<String, Duration>,
+    /// Whether the observer was told the apply finished, so it has reported any error.
+    pub reported: bool,
+}
+
+/// A domain that failed to apply, or a document that couldn't be planned at all.
+#[derive(Debug)]
+pub struct Failure {
+    pub source: Utf8PathBuf,
+    /// `None` if the whole document failed.
+    pub domain: Option<String>,
+    pub error: Report,
+}
+
+impl ApplySummary {
+    /// The `count` domains that took longest to apply, slowest first.
+    pub fn slowest_domains(&self, count: usize) -> Vec<(&str, Duration)> {
+        self.domain_times
+            .iter()
+            .map(|(domain, time)| (domain.as_str(), *time))
+            .sorted_by(|(a_domain, a_time), (b_domain, b_time)| b_time.cmp(a_time).then(a_domain.cmp(b_domain)))
+            .take(count)
+            .collect()
+    }
+
+    /// With `keep_going`, the error to exit with once everything has been applied: each failed
+    /// domain with its error, and what to do about it where that's known.
+    pub fn failures(&self) -> Option<E> {
+        if self.failed.is_empty() {
+            return None;
+        }
+
+        let failures = self
+            .failed
+            .iter()
+            .map(|Failure { source, domain, error }| {
+                let name = domain.as_ref().map_or_else(|| source.to_string(), |domain| format!("{domain} ({source})"));
+
+                match remedy(error) {
+                    Some(remedy) => format!("  {name}: {}\n    {remedy}", error.chain().join(": ")),
+                    None => format!("  {name}: {}", error.chain().join(": ")),
+                }
+            })
+            .join("\n");
+
+        Some(E::DomainsFailed {
+            count: self.failed.len(),
+            failures,
+        })
+    }
+}
+
+/// Machine-readable result of `apply --report`, printed instead of the console output.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ReportFormat {
+    /// The JSON an Ansible module returns: `changed`, `msg`, and a `diff` of each domain's keys.
+    Ansible,
+    /// A JSON summary: `changed`, the number of domains applied and keys changed, the failures
+    /// with `--keep-going`, and the slowest domains.
+    Json,
+}
+
+/// A path of `-` means read the YAML document stream from stdin.
+pub const STDIN_PATH: &str = "-";
+
+/// Stdin, read in full the first time documents are loaded from it, so they can be loaded again,
+/// e.g. for `--assert-idempotent` and then the apply.
+static STDIN: OnceLock<Vec<u8>> = OnceLock::new();
+
+fn read_stdin() -> Result<&'static [u8]> {
+    if let Some(stdin) = STDIN.get() {
+        return Ok(stdin);
+    }
+
+    let mut stdin = Vec::new();
+
+    io::stdin().lock().read_to_end(&mut stdin).map_err(|e| E::FileRead {
+        path: STDIN_PATH.into(),
+        source: e,
+    })?;
+
+    Ok(STDIN.get_or_init(|| stdin))
+}
+
+/// Apply the documents of YAML and raw `.plist` files, in the order [`schedule`](crate::schedule)
+/// puts them. Every file is read before anything is applied.
+pub fn apply_files(files: &[Utf8PathBuf], options: &ApplyOptions, observer: &dyn ApplyObserver, summary: &mut ApplySummary) -> Result<()> {
+    //
+    for path in files {
+        if path != STDIN_PATH {
+            fs::metadata(path).map_err(|e| E::FileRead { path: path.clone(), source: e })?;
+        }
+    }
+
+    let mut started = HashSet::new();
+
+    for SourceDocument { path, index, document } in load_scheduled(files)? {
+        let _span = info_span!("file", %path).entered();
+
+        if started.insert(path.clone()) {
+            trace!("Processing documents from file: {}", path);
+            observer.on_file_start(&path);
+        }
+
+        let _span = info_span!("document", index).entered();
+
+        let plan = match document {
+            Document::Plist => {
+                observer.on_document_start(None);
+                Planner::default().plan_plist_file(&path, options).map(|plan| (plan, Vec::new()))
+            }
+            Document::Yaml(config) => plan_quitting_apps(*config, &path, options, observer),
+        };
+
+        keep_going(
+            plan.and_then(|(plan, quit)| execute_document(plan, &quit, options, observer, summary)),
+            &path,
+            options,
+            summary,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Apply a bundled preset's documents, as if they were read from a `preset:<name>` file.
+pub fn apply_preset(preset: &BundledPreset, options: &ApplyOptions, observer: &dyn ApplyObserver, summary: &mut ApplySummary) -> Result<()> {
+    //
+    let path = preset_path(preset);
+    let _span = info_span!("file", %path, version = preset.version).entered();
+    observer.on_file_start(&path);
+
+    apply_documents(load_documents_from_reader(preset.yaml.as_bytes(), &path)?, &path, options, observer, summary)
+}
+
+/// The `preset:<name>` path a bundled preset's documents are reported as coming from.
+pub(crate) fn preset_path(preset: &BundledPreset) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("preset:{}", preset.name))
+}
+
+fn apply_documents(
+    documents: Vec<MacOSDefaults>,
+    path: &Utf8PathBuf,
+    options: &ApplyOptions,
+    observer: &dyn ApplyObserver,
+    summary: &mut ApplySummary,
+) -> Result<()> {
+    for (index, config) in documents.into_iter().enumerate() {
+        let _span = info_span!("document", index).entered();
+        let result = process_yaml_document(config, path, options, observer, summary);
+        keep_going(result, path, options, summary)?;
+    }
+
+    Ok(())
+}
 
-pub fn apply_defaults(path: &Utf8PathBuf) -> Result<bool> {
+/// With `keep_going`, note a document that failed as a whole and carry on, otherwise stop.
+fn keep_going(result: Result<()>, path: &Utf8Path, options: &ApplyOptions, summary: &mut ApplySummary) -> Result<()> {
+    match result {
+        Err(error) if options.keep_going => {
+            error!("{path}: {error:?}");
+            summary.failed.push(Failure {
+                source: path.to_owned(),
+                domain: None,
+                error,
+            });
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+/// Split a YAML file (or stdin if the path is `-`) into documents, and parse each of them.
+pub(crate) fn load_documents(path: &Utf8PathBuf) -> Result<Vec<MacOSDefaults>> {
     //
+    if path == STDIN_PATH {
+        return load_documents_from_reader(read_stdin()?, path);
+    }
+
     let file = File::open(path).map_err(|e| E::FileRead {
         path: path.to_owned(),
         source: e,
     })?;
 
-    let reader = BufReader::new(file);
-
-    trace!("Processing YAML documents from file: {}", path);
+    load_documents_from_reader(BufReader::new(file), path)
+}
 
-    let mut any_changed = false;
+/// Split a stream of YAML documents, and parse each of them. `path` is only used for errors.
+pub(crate) fn load_documents_from_reader(reader: impl Read, path: &Utf8PathBuf) -> Result<Vec<MacOSDefaults>> {
+    //
+    let mut documents = Vec::new();
 
     for doc in DocumentIterator::new(reader) {
         let doc = doc.map_err(|e| E::YamlSplitError {
             path: path.to_owned(),
             source: e,
         })?;
-        any_changed |= process_yaml_document(doc.as_bytes(), path)?;
+
+        let config: MacOSDefaults = serde_yaml::from_str(&doc).map_err(|e| E::InvalidYaml {
+            path: path.to_owned(),
+            source: e,
+        })?;
+
+        documents.push(config);
     }
 
-    Ok(any_changed)
+    Ok(documents)
 }
 
-fn process_yaml_document(doc: impl BufRead, path: &Utf8PathBuf) -> Result<bool> {
-    let config: MacOSDefaults = serde_yaml::from_reader(doc).map_err(|e| E::InvalidYaml {
-        path: path.to_owned(),
-        source: e,
-    })?;
+fn process_yaml_document(
+    config: MacOSDefaults,
+    path: &Utf8PathBuf,
+    options: &ApplyOptions,
+    observer: &dyn ApplyObserver,
+    summary: &mut ApplySummary,
+) -> Result<()> {
+    // Each document is planned right before it's executed, so it sees what earlier ones wrote.
+    let (plan, quit) = plan_quitting_apps(config, path, options, observer)?;
+
+    execute_document(plan, &quit, options, observer, summary)
+}
+
+/// Plan a YAML document, and if it changes anything, quit the running apps in its `quit` list
+/// and plan it again: apps save their preferences as they quit, which would otherwise be
+/// overwritten with the values the first plan read. Returns the plan and the apps quit.
+fn plan_quitting_apps(config: MacOSDefaults, path: &Utf8PathBuf, options: &ApplyOptions, observer: &dyn ApplyObserver) -> Result<(DocumentPlan, Vec<String>)> {
+    //
+    observer.on_document_start(config.description.as_deref());
 
-    let maybe_data = config.data.ok_or_else(|| eyre!("Couldn't parse YAML data key in: {path}"))?;
+    let replan = (!config.quit.is_empty() && !options.dry_run).then(|| config.clone());
+    let plan = Planner::default().plan_document(config, path, options)?;
 
-    let defaults: DefaultsConfig = serde_yaml::from_value(maybe_data).map_err(|e| E::DeserializationFailed { source: e })?;
+    // Only the apps that were running are relaunched.
+    let quit: Vec<String> = plan.quit.iter().filter(|app| !options.dry_run && is_running(app)).cloned().collect();
 
+    let Some(config) = replan.filter(|_| !quit.is_empty()) else {
+        return Ok((plan, quit));
+    };
+
+    for app in &quit {
+        observer.on_quit(app);
+        quit_app(app);
+    }
+
+    debug!("Planning {path} again, now that {} have quit", quit.join(", "));
+
+    Ok((Planner::default().plan_document(config, path, options)?, quit))
+}
+
+/// Restart the processes of the documents that changed anything, once each, after every document
+/// has been applied (or applying stopped on an error), then relaunch the apps quit before writing.
+pub fn restart_processes(observer: &dyn ApplyObserver, summary: &mut ApplySummary) {
+    //
+    for process in summary.kill.drain(..) {
+        observer.on_kill(&process);
+        kill_processes(&process);
+    }
+
+    for app in summary.relaunch.drain(..) {
+        if let Err(e) = relaunch_app(&app) {
+            warn!("{e}");
+        }
+    }
+}
+
+fn add_unique<'a>(list: &mut Vec<String>, items: impl IntoIterator<Item = &'a String>) {
+    for item in items {
+        if !list.contains(item) {
+            list.push(item.clone());
+        }
+    }
+}
+
+/// Execute a document's plan: write its plists, and queue its processes for
+/// [`restart_processes`] if anything changed, and the apps in `quit` for relaunching.
+pub fn execute_document(plan: DocumentPlan, quit: &[String], options: &ApplyOptions, observer: &dyn ApplyObserver, summary: &mut ApplySummary) -> Result<()> {
+    //
     debug!("Setting defaults");
 
-    // TODO: Get global CLI verbosity values.
-    if let Some(description) = config.description {
-        println!("  {} {}", "▶".green(), description.bold().white());
+    let mut keys_changed = 0;
+
+    let mut state_cache = HashMap::new();
+
+    for domain in &plan.domains {
+        *summary.domain_times.entry(domain.domain.clone()).or_default() += domain.planning_time;
+
+        if let Some(entry) = &domain.state_cache {
+            state_cache.insert(domain.domain.clone(), entry.clone());
+        }
     }
 
-    let results: Vec<_> = defaults.0
+    let results: Vec<_> = plan
+        .domains
         .into_iter()
-        .map(|(domain, prefs)| write_defaults_values(&domain, prefs, config.current_host))
-        .collect();
+        .map(|DomainPlan { domain, writes, error, .. }| {
+            observer.on_domain_start(&domain);
+            let started = Instant::now();
+
+            let result = match error {
+                Some(error) => Err(error),
+                None => writes.iter().try_fold(false, |changed, write| {
+                    // The plist before the write, for the original values of the keys it removes.
+                    let before = match &options.manifest {
+                        Some(_) => options.write.backend.read(&write.path)?,
+                        None => None,
+                    };
+
+                    let written = execute_plist_write(write, &options.write, observer)?;
+
+                    if written {
+                        keys_changed += write.changes.len() + write.removed.len();
+
+                        if let Some(manifest) = &options.manifest {
+                            manifest.borrow_mut().record(&plan.source, write, before.as_ref());
+                        }
+                    }
+
+                    Ok(written || changed)
+                }),
+            };
+
+            if let Err(error) = &result {
+                observer.on_domain_error(&domain, error);
+            }
 
-    let (passed, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(Result::is_ok);
+            *summary.domain_times.entry(domain.clone()).or_default() += started.elapsed();
 
-    let changed = passed.iter().any(|r| *r.as_ref().unwrap());
+            (domain, result)
+        })
+        .collect();
 
-    if changed {
-        if let Some(kill) = config.kill {
-            for process in kill {
-                println!("    {} Restarting: {}", "✖".blue(), process.white());
-                kill_process_by_name(&process);
+    let (passed, errors): (Vec<_>, Vec<_>) = results.into_iter().partition(|(_, result)| result.is_ok());
+
+    if let Some(cache) = &options.state_cache {
+        for (domain, _) in &passed {
+            if let Some((key, plist_paths)) = state_cache.get(domain) {
+                cache.record(&plan.source, key, plist_paths);
             }
         }
     }
 
-    if errors.is_empty() {
-        return Ok(changed);
+    let changed = passed.iter().any(|(_, result)| matches!(result, Ok(true)));
+
+    summary.changed |= changed;
+    summary.domains_applied += passed.len();
+    summary.keys_changed += keys_changed;
+
+    if changed {
+        add_unique(&mut summary.kill, &plan.kill);
     }
 
-    for error in &errors {
-        error!("{error:?}");
+    if plan.relaunch {
+        add_unique(&mut summary.relaunch, quit);
     }
 
-    let mut errors_iter = errors.into_iter();
+    if errors.is_empty() {
+        return Ok(());
+    }
 
-    let first_error = errors_iter.next().ok_or(E::UnexpectedNone)??;
+    if options.keep_going {
+        summary.failed.extend(errors.into_iter().filter_map(|(domain, result)| {
+            result.err().map(|error| Failure {
+                source: plan.source.clone(),
+                domain: Some(domain),
+                error,
+            })
+        }));
 
-    Err(eyre!("{:?}", errors_iter.collect::<Vec<_>>())).wrap_err(first_error)
-}
+        return Ok(());
+    }
 
-fn kill_process_by_name(name: &str) {
-    let mut sys = System::new();
-    sys.refresh_processes(sysinfo::ProcessesToUpdate::All);
+    let failed = errors.len();
 
-    for process in sys.processes_by_exact_name(OsStr::from_bytes(name.as_bytes())) {
-        debug!("Process running: {} {}", process.pid(), process.name().to_string_lossy());
+    let first_error = errors.into_iter().find_map(|(_, result)| result.err()).ok_or(E::UnexpectedNone)?;
 
-        process.kill_with(Signal::Term);
+    if failed == 1 {
+        return Err(first_error);
     }
+
+    Err(first_error.wrap_err(format!("{failed} domains failed to apply in: {}", plan.source)))
 }
 
 fn is_yaml(path: &Utf8PathBuf) -> bool {
     path.extension().map(str::to_ascii_lowercase).is_some_and(|ext| ext == "yml" || ext == "yaml")
 }
 
+/// Raw `.plist` files replace the whole plist of the domain they're named after.
+pub(crate) fn is_plist(path: &Utf8Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("plist"))
+}
+
 pub fn process_path(path: Utf8PathBuf) -> Result<Vec<Utf8PathBuf>> {
     match path {
-        path if path.is_file() => Ok(vec![path]),
+        path if path == STDIN_PATH || path.is_file() => Ok(vec![path]),
         path if path.is_dir() => {
             let mut files = path
                 .read_dir_utf8()?
                 .filter_map(Result::ok)
                 .map(camino::Utf8DirEntry::into_path)
-                .filter(is_yaml)
+                .filter(|path| is_yaml(path) || is_plist(path))
                 .collect::<Vec<Utf8PathBuf>>();
 
             files.sort();
 
             if files.is_empty() {
-                Err(eyre!("No YAML files were found in path {path}."))
+                Err(eyre!("No YAML or plist files were found in path {path}."))
             } else {
                 Ok(files)
             }
@@ -192,3 +668,140 @@ pub fn process_path(path: Utf8PathBuf) -> Result<Vec<Utf8PathBuf>> {
         _ => Err(eyre!("Couldn't read YAML from: {path}.")),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use camino::{Utf8Path, Utf8PathBuf};
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::{apply_files, domain_setting, load_documents_from_reader, ApplyOptions, ApplySummary, DefaultsConfig, STDIN_PATH};
+    use crate::observer::ConsoleObserver;
+    use crate::tags::TagContext;
+
+    #[test]
+    fn test_load_documents_from_stdin() -> TestResult {
+        let stdin = Utf8PathBuf::from(STDIN_PATH);
+
+        let documents = load_documents_from_reader("data:\n  com.example.a:\n    a: 1\n---\ndata:\n  com.example.b:\n    b: 2\n".as_bytes(), &stdin)?;
+        assert_eq!(documents.len(), 2);
+
+        let error = load_documents_from_reader("data: [\n".as_bytes(), &stdin).err().map(|e| e.to_string());
+        assert_eq!(error.as_deref(), Some("Invalid YAML at 'stdin'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expand_domain_globs() -> TestResult {
+        let data = serde_yaml::from_str(
+            "com.jetbrains.*:\n  analytics: false\n  theme: dark\ncom.jetbrains.goland:\n  theme: light\n/Library/Preferences/com.example.*:\n  a: 1\n",
+        )?;
+        let defaults = DefaultsConfig::for_document(data, false, &TagContext::for_yaml_path(Utf8Path::new("globs.yaml")))?;
+
+        let expanded = defaults
+            .expand_domain_globs_in(&["com.jetbrains.goland", "com.jetbrains.pycharm", "com.apple.dock"])
+            .0;
+
+        let mut domains: Vec<&str> = expanded.keys().map(String::as_str).collect();
+        domains.sort_unstable();
+        assert_eq!(domains, ["/Library/Preferences/com.example.*", "com.jetbrains.goland", "com.jetbrains.pycharm"]);
+
+        // Keys given for a domain by name win over the glob's.
+        assert_eq!(expanded["com.jetbrains.goland"]["theme"], Value::from("light"));
+        assert_eq!(expanded["com.jetbrains.goland"]["analytics"], Value::Boolean(false));
+        assert_eq!(expanded["com.jetbrains.pycharm"]["theme"], Value::from("dark"));
+
+        let ignore_keys = HashMap::from([("com.jetbrains.*".to_owned(), vec!["a"]), ("com.jetbrains.goland".to_owned(), vec!["b"])]);
+        assert_eq!(domain_setting(&ignore_keys, "com.jetbrains.goland"), Some(&vec!["b"]));
+        assert_eq!(domain_setting(&ignore_keys, "com.jetbrains.pycharm"), Some(&vec!["a"]));
+        assert_eq!(domain_setting(&ignore_keys, "com.apple.dock"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_kill_once_per_run() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let files = ["a", "b", "c"].map(|name| dir.join(format!("{name}.yaml")));
+        for (i, file) in files.iter().enumerate() {
+            let plist_path = dir.join(format!("com.example.kill{i}.plist"));
+            std::fs::write(file, format!("kill: [macos-defaults-test-process, Dock]\ndata:\n  {plist_path}:\n    a: 1\n"))?;
+        }
+
+        let mut summary = ApplySummary::default();
+        apply_files(&files, &ApplyOptions::default(), &ConsoleObserver::default(), &mut summary)?;
+
+        assert!(summary.changed);
+        assert_eq!(summary.kill, ["macos-defaults-test-process", "Dock"]);
+
+        // Nothing changes the second time, so nothing is restarted.
+        let mut summary = ApplySummary::default();
+        apply_files(&files, &ApplyOptions::default(), &ConsoleObserver::default(), &mut summary)?;
+        assert!(summary.kill.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_going() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let corrupt = dir.join("com.example.corrupt.plist");
+        std::fs::write(&corrupt, "not a plist")?;
+
+        let files = [dir.join("a.yaml"), dir.join("b.yaml")];
+        std::fs::write(&files[0], format!("data:\n  {corrupt}:\n    a: 1\n  {dir}/com.example.a.plist:\n    a: 1\n"))?;
+        std::fs::write(&files[1], format!("data:\n  {dir}/com.example.b.plist:\n    b: 1\n"))?;
+
+        // Without --keep-going the second file isn't applied.
+        let mut summary = ApplySummary::default();
+        assert!(apply_files(&files, &ApplyOptions::default(), &ConsoleObserver::default(), &mut summary).is_err());
+        assert!(!dir.join("com.example.b.plist").exists());
+
+        let options = ApplyOptions {
+            keep_going: true,
+            ..ApplyOptions::default()
+        };
+        let mut summary = ApplySummary::default();
+        apply_files(&files, &options, &ConsoleObserver::default(), &mut summary)?;
+
+        assert!(dir.join("com.example.b.plist").exists());
+        assert_eq!(summary.domains_applied, 2);
+        assert_eq!(summary.failed.len(), 1);
+
+        let failures = summary.failures().map(|e| e.to_string()).unwrap_or_default();
+        assert!(
+            failures.starts_with(&format!("1 domain failed to apply:\n  {corrupt} ({}): ", files[0])),
+            "{failures}"
+        );
+        assert!(failures.ends_with(&format!(
+            "\n    Restore {corrupt}.prev if it's a good backup, or apply with --repair to move {corrupt} aside and recreate it."
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_slowest_domains() {
+        let summary = ApplySummary {
+            domain_times: HashMap::from([
+                ("com.apple.dock".to_owned(), Duration::from_millis(400)),
+                ("com.apple.finder".to_owned(), Duration::from_millis(20)),
+                ("NSGlobalDomain".to_owned(), Duration::from_millis(90)),
+            ]),
+            ..ApplySummary::default()
+        };
+
+        assert_eq!(
+            summary.slowest_domains(2),
+            [("com.apple.dock", Duration::from_millis(400)), ("NSGlobalDomain", Duration::from_millis(90))]
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{self, Write as _};
+
+use camino::Utf8PathBuf;
+use clap::{Command, ValueEnum};
+use clap_complete::{generate, Shell};
+use color_eyre::eyre::Result;
+use itertools::Itertools;
+use tracing::warn;
+
+use crate::cmd::apply::process_path;
+use crate::defaults::domains;
+use crate::domain::Domain;
+
+/// Values the hidden `complete` command lists for the shell completion scripts.
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum CompletionKind {
+    /// Installed domains, for `dump --domain` and `explain`.
+    Domains,
+    /// YAML files in the config file's `paths`, for `apply`, `plan` and `status`.
+    YamlFiles,
+}
+
+const BIN_NAME: &str = "macos-defaults";
+
+/// How clap's bash script registers its completion function, and the replacement registering ours.
+const BASH_HOOK: &str = "complete -F _macos-defaults ";
+const BASH_DYNAMIC_HOOK: &str = "complete -F _macos-defaults-dynamic ";
+
+/// Finds the subcommand in `COMP_WORDS`, skipping global options and their values, and completes
+/// domains and configured YAML files by running the hidden `complete` command. Anything else is
+/// left to clap's completion function.
+const BASH_DYNAMIC: &str = r#"
+_macos-defaults-dynamic() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}" subcommand="" i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${COMP_WORDS[i]}" in
+            --config|--prefs-root|--user|--log-format) ((i++)) ;;
+            -*) ;;
+            *) subcommand="${COMP_WORDS[i]}"; break ;;
+        esac
+    done
+    if [[ ${subcommand} == dump && ( ${prev} == -d || ${prev} == --domain ) ]] ||
+        [[ ${subcommand} == explain && ${COMP_CWORD} -eq $((i + 1)) && ${cur} != -* ]]; then
+        COMPREPLY=( $(compgen -W "$(macos-defaults complete domains 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    if [[ ${subcommand} =~ ^(apply|plan|status)$ && ${cur} != -* && ${prev} != -* ]]; then
+        COMPREPLY=( $(compgen -W "$(macos-defaults complete yaml-files 2>/dev/null)" -- "${cur}") $(compgen -f -- "${cur}") )
+        return 0
+    fi
+    _macos-defaults "$@"
+}
+"#;
+
+/// As [`BASH_DYNAMIC`], for zsh. It has to be defined before clap's script calls or registers its
+/// completion function, which is replaced with this one.
+const ZSH_DYNAMIC: &str = r#"_macos-defaults-dynamic() {
+    local subcommand i
+    for ((i = 2; i < CURRENT; i++)); do
+        case $words[i] in
+            --config|--prefs-root|--user|--log-format) ((i++)) ;;
+            -*) ;;
+            *) subcommand=$words[i]; break ;;
+        esac
+    done
+    if [[ $subcommand == dump && $words[CURRENT-1] == (-d|--domain) ]] ||
+        [[ $subcommand == explain && $CURRENT -eq $((i + 1)) && $words[CURRENT] != -* ]]; then
+        local -a domains
+        domains=(${(f)"$(macos-defaults complete domains 2>/dev/null)"})
+        _wanted domains expl 'domain' compadd -a domains
+        return
+    fi
+    if [[ $subcommand == (apply|plan|status) && $words[CURRENT] != -* && $words[CURRENT-1] != -* ]]; then
+        local -a files
+        files=(${(f)"$(macos-defaults complete yaml-files 2>/dev/null)"})
+        _wanted yaml-files expl 'configured YAML file' compadd -a files
+    fi
+    _macos-defaults "$@"
+}
+
+"#;
+
+/// Markers of the block at the end of clap's zsh script that calls or registers its completion
+/// function, from the `if` to the `fi` after the `compdef`.
+const ZSH_HOOK_START: &str = "if [ \"$funcstack[1]\" = \"_macos-defaults\" ]; then";
+const ZSH_HOOK_COMPDEF: &str = "compdef _macos-defaults macos-defaults";
+const ZSH_HOOK_END: &str = "fi";
+
+const ZSH_DYNAMIC_HOOK: &str =
+    "if [ \"$funcstack[1]\" = \"_macos-defaults\" ]; then\n    _macos-defaults-dynamic \"$@\"\nelse\n    compdef _macos-defaults-dynamic macos-defaults\nfi";
+
+/// Fish merges these with clap's completions of the same options.
+const FISH_DYNAMIC: &str = r#"
+complete -c macos-defaults -n "__fish_macos_defaults_using_subcommand dump" -s d -l domain -f -a "(macos-defaults complete domains 2>/dev/null)"
+complete -c macos-defaults -n "__fish_macos_defaults_using_subcommand explain; and test (count (commandline -opc)) -le 2" -f -a "(macos-defaults complete domains 2>/dev/null)"
+complete -c macos-defaults -n "__fish_macos_defaults_using_subcommand apply plan status" -a "(macos-defaults complete yaml-files 2>/dev/null)"
+"#;
+
+/// `completions` command: clap's completion script, with dynamic completion of domains and
+/// configured YAML files added for bash, zsh and fish.
+pub fn completions(shell: Shell, command: &mut Command) -> Result<()> {
+    //
+    let mut script = Vec::new();
+    generate(shell, command, BIN_NAME, &mut script);
+
+    let script = add_dynamic_completions(shell, String::from_utf8(script)?);
+
+    io::stdout().lock().write_all(script.as_bytes())?;
+
+    Ok(())
+}
+
+/// Add dynamic completions to clap's script. If clap's script changed so they can't be hooked in,
+/// it's returned as is with a warning, which still completes everything else.
+fn add_dynamic_completions(shell: Shell, script: String) -> String {
+    let dynamic = match shell {
+        Shell::Bash if script.contains(BASH_HOOK) => Some(script.replace(BASH_HOOK, BASH_DYNAMIC_HOOK) + BASH_DYNAMIC),
+        Shell::Bash => None,
+        Shell::Zsh => replace_zsh_hook(&script),
+        Shell::Fish => Some(script.clone() + FISH_DYNAMIC),
+        _ => return script,
+    };
+
+    dynamic.unwrap_or_else(|| {
+        warn!("Couldn't find where the {shell} completion script registers its function, so domains and YAML files won't be completed.");
+        script
+    })
+}
+
+/// Replace the block of clap's zsh script that calls or registers its completion function with
+/// ours, found by its markers so changes to whitespace or the lines in between don't matter.
+fn replace_zsh_hook(script: &str) -> Option<String> {
+    let start = script.find(ZSH_HOOK_START)?;
+    let compdef = start + script[start..].find(ZSH_HOOK_COMPDEF)?;
+    let end = compdef + script[compdef..].find(ZSH_HOOK_END)? + ZSH_HOOK_END.len();
+
+    Some(format!("{}{ZSH_DYNAMIC}{ZSH_DYNAMIC_HOOK}{}", &script[..start], &script[end..]))
+}
+
+/// Hidden `complete` command, run by the completion scripts: list installed domains or configured
+/// YAML files, one per line. Nothing is printed on errors, which the shell would show as candidates.
+#[allow(clippy::print_stdout)]
+pub fn complete(kind: CompletionKind, config_paths: Vec<Utf8PathBuf>) {
+    let candidates: Vec<String> = match kind {
+        CompletionKind::Domains => domains(false)
+            .unwrap_or_default()
+            .iter()
+            .map(Domain::as_str)
+            .map(str::to_owned)
+            .sorted()
+            .dedup()
+            .collect(),
+        CompletionKind::YamlFiles => config_paths
+            .into_iter()
+            .filter_map(|path| process_path(path).ok())
+            .flatten()
+            .map(String::from)
+            .collect(),
+    };
+
+    for candidate in candidates {
+        println!("{candidate}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{Arg, Command};
+    use clap_complete::{generate, Shell};
+
+    use super::{add_dynamic_completions, replace_zsh_hook, BIN_NAME};
+
+    fn script(shell: Shell) -> String {
+        let mut command = Command::new(BIN_NAME).subcommand(Command::new("dump").arg(Arg::new("domain").short('d').long("domain")));
+
+        let mut script = Vec::new();
+        generate(shell, &mut command, BIN_NAME, &mut script);
+
+        add_dynamic_completions(shell, String::from_utf8(script).unwrap_or_default())
+    }
+
+    #[test]
+    fn test_dynamic_completions() {
+        let bash = script(Shell::Bash);
+        assert!(bash.contains("complete -F _macos-defaults-dynamic "));
+        assert!(!bash.contains("complete -F _macos-defaults "));
+
+        // clap's zsh script calls its function when it's autoloaded, so ours has to be defined first.
+        let zsh = script(Shell::Zsh);
+        let defined = zsh.find("_macos-defaults-dynamic() {");
+        assert!(defined.is_some());
+        assert!(defined < zsh.find("compdef _macos-defaults-dynamic macos-defaults"));
+        assert!(!zsh.contains("compdef _macos-defaults macos-defaults"));
+
+        // The hook is found by its markers, not its exact text.
+        let reindented = "#compdef macos-defaults\nif [ \"$funcstack[1]\" = \"_macos-defaults\" ]; then\n  _macos-defaults \"$@\"\nelse\n  compdef _macos-defaults macos-defaults\nfi\n";
+        assert!(replace_zsh_hook(reindented).is_some_and(|zsh| zsh.contains("compdef _macos-defaults-dynamic macos-defaults") && zsh.ends_with("fi\n")));
+
+        // Without it the script is left alone, rather than silently half changed.
+        assert_eq!(
+            add_dynamic_completions(Shell::Zsh, "#compdef macos-defaults\n".to_owned()),
+            "#compdef macos-defaults\n"
+        );
+
+        assert!(script(Shell::Fish).contains("(macos-defaults complete domains 2>/dev/null)"));
+    }
+}
@@ -0,0 +1,180 @@
+use std::fs;
+
+use camino::Utf8Path;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use plist::Value;
+use tracing::{debug, warn};
+
+use crate::cmd::dump::{round_trip_yaml, tag_uncommon_values};
+use crate::defaults::{read_plist, replace_data_in_plist};
+use crate::errors::DefaultsError as E;
+use crate::tags::{yaml_to_plist, TagContext};
+
+/// Format of plists written by `convert`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum PlistFormat {
+    /// Binary, as macOS writes them.
+    #[default]
+    Binary,
+    /// XML, which diffs cleanly in a repository.
+    Xml,
+}
+
+/// Format of a file `convert` reads or writes, from its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileFormat {
+    Plist,
+    Yaml,
+    Json,
+}
+
+impl FileFormat {
+    fn for_path(path: &Utf8Path) -> Result<Self> {
+        match path.extension().map(str::to_ascii_lowercase).as_deref() {
+            Some("plist") => Ok(Self::Plist),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            Some("json") => Ok(Self::Json),
+            _ => Err(eyre!(
+                "Can't tell the format of {path} from its extension, expected .plist, .yaml, .yml or .json."
+            )),
+        }
+    }
+}
+
+/// `convert` command: convert between plist (binary or XML), YAML and JSON files, picking the
+/// formats from the file extensions. YAML tags like `!file` are resolved as `apply` resolves them,
+/// and `Uid` values are written to YAML as `!uid` tags, as `dump` writes them.
+pub fn convert(input: &Utf8Path, output: &Utf8Path, plist_format: PlistFormat, strict: bool) -> Result<()> {
+    //
+    let output_format = FileFormat::for_path(output)?;
+    let mut value = read_value(input)?;
+
+    debug!("Converting {input} to {output}");
+
+    let bytes = match output_format {
+        FileFormat::Plist => {
+            let mut bytes = Vec::new();
+
+            match plist_format {
+                PlistFormat::Binary => value.to_writer_binary(&mut bytes)?,
+                PlistFormat::Xml => value.to_writer_xml(&mut bytes)?,
+            }
+
+            bytes
+        }
+        FileFormat::Yaml => {
+            let mut references = Vec::new();
+            tag_uncommon_values(&mut value, input.as_str(), &mut Vec::new(), &mut references)?;
+            hex_encode_data(&mut value, input, strict)?;
+
+            let mut bytes = round_trip_yaml(&value, &references)?;
+            bytes.push(b'\n');
+            bytes
+        }
+        FileFormat::Json => {
+            hex_encode_data(&mut value, input, strict)?;
+
+            let mut bytes = serde_json::to_vec_pretty(&value)?;
+            bytes.push(b'\n');
+            bytes
+        }
+    };
+
+    fs::write(output, bytes)?;
+
+    Ok(())
+}
+
+/// Read a plist, YAML or JSON file as a plist value.
+fn read_value(path: &Utf8Path) -> Result<Value> {
+    if FileFormat::for_path(path)? == FileFormat::Plist {
+        return Ok(read_plist(path)?);
+    }
+
+    let contents = fs::read_to_string(path).map_err(|e| E::FileRead {
+        path: path.to_owned(),
+        source: e,
+    })?;
+
+    Ok(match FileFormat::for_path(path)? {
+        FileFormat::Yaml => yaml_to_plist(serde_yaml::from_str(&contents)?, &TagContext::for_yaml_path(path))?,
+        _ => serde_json::from_str(&contents)?,
+    })
+}
+
+/// Replace binary data, which YAML and JSON can't represent, with hex strings, as `dump` does.
+/// With `strict`, binary data is an error instead.
+fn hex_encode_data(value: &mut Value, path: &Utf8Path, strict: bool) -> Result<()> {
+    if !contains_data(value) {
+        return Ok(());
+    }
+
+    if strict {
+        return Err(E::StrictCoercion { domain: path.to_string() }.into());
+    }
+
+    warn!("{path} contains binary data, which is written as hex-encoded strings. Converting back won't restore it as data.");
+
+    replace_data_in_plist(value)
+}
+
+fn contains_data(value: &Value) -> bool {
+    match value {
+        Value::Data(_) => true,
+        Value::Array(array) => array.iter().any(contains_data),
+        Value::Dictionary(dict) => dict.values().any(contains_data),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{convert, PlistFormat};
+
+    #[test]
+    fn test_convert() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let value = Value::from(Dictionary::from_iter([
+            ("autohide", Value::from(true)),
+            ("tilesize", Value::from(36)),
+            ("root", Value::Uid(plist::Uid::new(1))),
+        ]));
+        value.to_file_binary(dir.join("in.plist"))?;
+
+        convert(&dir.join("in.plist"), &dir.join("out.yaml"), PlistFormat::Binary, false)?;
+        let yaml = std::fs::read_to_string(dir.join("out.yaml"))?;
+        assert!(yaml.contains("autohide: true\n"));
+        assert!(yaml.contains("root: !uid 1\n"));
+
+        // The `!uid` tag turns back into a `Uid`, which only binary plists can have.
+        convert(&dir.join("out.yaml"), &dir.join("round-trip.plist"), PlistFormat::Binary, false)?;
+        assert_eq!(Value::from_file(dir.join("round-trip.plist"))?, value);
+
+        std::fs::write(dir.join("in.json"), r#"{"autohide": false, "persistent-others": ["a"]}"#)?;
+        convert(&dir.join("in.json"), &dir.join("out.plist"), PlistFormat::Xml, false)?;
+        assert_eq!(
+            Value::from_file(dir.join("out.plist"))?,
+            Value::from(Dictionary::from_iter([
+                ("autohide", Value::from(false)),
+                ("persistent-others", Value::Array(vec![Value::from("a")])),
+            ]))
+        );
+
+        let data = Value::from(Dictionary::from_iter([("blob", Value::Data(vec![0xca, 0xfe]))]));
+        data.to_file_binary(dir.join("data.plist"))?;
+        assert!(convert(&dir.join("data.plist"), &dir.join("data.json"), PlistFormat::Binary, true).is_err());
+        convert(&dir.join("data.plist"), &dir.join("data.json"), PlistFormat::Binary, false)?;
+        assert!(std::fs::read_to_string(dir.join("data.json"))?.contains(r#""blob": "cafe""#));
+
+        assert!(convert(&dir.join("in.plist"), &dir.join("out.txt"), PlistFormat::Binary, false).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use plist::{Dictionary, Value};
+use serde::Serialize;
+
+use crate::backend::PrefsBackend;
+use crate::cmd::plan::plan_sources;
+use crate::cmd::{ApplyOptions, OutputFormat};
+use crate::defaults::PlistWrite;
+use crate::planner::{Plan, Planner};
+use crate::presets::BundledPreset;
+
+/// Output format of `diff`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum DiffFormat {
+    #[default]
+    Yaml,
+    Json,
+    /// An XML plist array of the operations.
+    Plist,
+}
+
+/// One change `apply` would make, like a JSON Patch (RFC 6902) operation.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct DiffOp {
+    pub op: Op,
+    /// JSON Pointer to the key: the domain, then the key, then any nested keys,
+    /// e.g. `/com.apple.finder/DesktopViewSettings/IconViewSettings/iconSize`.
+    pub path: String,
+    /// The new value, for `add` and `replace`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    /// The value being replaced or removed. JSON Patch doesn't have it, but it makes the change
+    /// reviewable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_value: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Op {
+    Add,
+    Replace,
+    Remove,
+}
+
+/// `diff` command: print each change applying the presets and then the files would make, down to
+/// nested keys, for other tools to consume.
+pub fn diff(presets: &[&BundledPreset], files: &[Utf8PathBuf], options: &ApplyOptions, format: DiffFormat) -> Result<()> {
+    //
+    let plan = plan_sources(&mut Planner::default(), presets, files, options)?;
+    let ops = diff_ops(plan, options.write.backend.as_ref())?;
+
+    match format {
+        DiffFormat::Yaml => OutputFormat::Yaml.print(&ops),
+        DiffFormat::Json => OutputFormat::Json.print(&ops),
+        DiffFormat::Plist => {
+            plist::to_writer_xml(std::io::stdout().lock(), &ops)?;
+            Ok(())
+        }
+    }
+}
+
+/// The operations turning each plist's current contents into what the plan's last write to it
+/// would leave, failing on the first domain that couldn't be planned.
+fn diff_ops(mut plan: Plan, backend: &dyn PrefsBackend) -> Result<Vec<DiffOp>> {
+    if let Some(error) = plan
+        .documents
+        .iter_mut()
+        .flat_map(|document| &mut document.domains)
+        .find_map(|domain| domain.error.take())
+    {
+        return Err(error);
+    }
+
+    // In the order the plists are first written, with the last value written to each.
+    let mut written: Vec<&PlistWrite> = Vec::new();
+
+    for domain in plan.documents.iter().flat_map(|document| &document.domains) {
+        for write in &domain.writes {
+            match written.iter_mut().find(|earlier| earlier.path == write.path) {
+                Some(earlier) => *earlier = write,
+                None => written.push(write),
+            }
+        }
+    }
+
+    let mut ops = Vec::new();
+
+    for write in written {
+        let old = backend.read(&write.path)?.unwrap_or_else(|| Value::Dictionary(Dictionary::new()));
+        value_ops(format!("/{}", pointer_segment(write.domain.as_ref())), Some(&old), &write.value, &mut ops);
+    }
+
+    Ok(ops)
+}
+
+/// The operations turning `old` into `new` at `path`. Dictionaries are compared key by key, so
+/// changing one nested key is one operation; anything else is replaced as a whole.
+fn value_ops(path: String, old: Option<&Value>, new: &Value, ops: &mut Vec<DiffOp>) {
+    match (old, new) {
+        (None, new) => ops.push(DiffOp {
+            op: Op::Add,
+            path,
+            value: Some(new.clone()),
+            old_value: None,
+        }),
+        (Some(Value::Dictionary(old)), Value::Dictionary(new)) => {
+            for (key, new_value) in new {
+                if old.get(key) != Some(new_value) {
+                    value_ops(format!("{path}/{}", pointer_segment(key)), old.get(key), new_value, ops);
+                }
+            }
+
+            for (key, old_value) in old.iter().filter(|(key, _)| !new.contains_key(key)) {
+                ops.push(DiffOp {
+                    op: Op::Remove,
+                    path: format!("{path}/{}", pointer_segment(key)),
+                    value: None,
+                    old_value: Some(old_value.clone()),
+                });
+            }
+        }
+        (Some(old), new) => ops.push(DiffOp {
+            op: Op::Replace,
+            path,
+            value: Some(new.clone()),
+            old_value: Some(old.clone()),
+        }),
+    }
+}
+
+/// A key escaped for a JSON Pointer: `~` as `~0` and `/` as `~1`.
+fn pointer_segment(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+
+    use super::{value_ops, DiffOp, Op};
+
+    #[test]
+    fn test_value_ops() {
+        let old = Value::Dictionary(Dictionary::from_iter([
+            ("iconSize", Value::from(64)),
+            ("gridSpacing", Value::from(54)),
+            ("removed", Value::from(true)),
+        ]));
+        let new = Value::Dictionary(Dictionary::from_iter([
+            ("iconSize", Value::from(80)),
+            ("gridSpacing", Value::from(54)),
+            ("a/b", Value::from("new")),
+        ]));
+
+        let mut ops = Vec::new();
+        value_ops("/com.apple.finder/IconViewSettings".to_owned(), Some(&old), &new, &mut ops);
+
+        assert_eq!(
+            ops,
+            [
+                DiffOp {
+                    op: Op::Replace,
+                    path: "/com.apple.finder/IconViewSettings/iconSize".to_owned(),
+                    value: Some(Value::from(80)),
+                    old_value: Some(Value::from(64)),
+                },
+                DiffOp {
+                    op: Op::Add,
+                    path: "/com.apple.finder/IconViewSettings/a~1b".to_owned(),
+                    value: Some(Value::from("new")),
+                    old_value: None,
+                },
+                DiffOp {
+                    op: Op::Remove,
+                    path: "/com.apple.finder/IconViewSettings/removed".to_owned(),
+                    value: None,
+                    old_value: Some(Value::from(true)),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::fmt::Write as _;
+use std::fs;
+
+use camino::Utf8PathBuf;
+use color_eyre::eyre::Result;
+use tracing::debug;
+
+use crate::catalog;
+use crate::cmd::apply::{is_plist, load_documents, process_path};
+use crate::defaults::raw_plist_domain;
+use crate::errors::DefaultsError as E;
+
+/// `docs` command.
+#[allow(clippy::print_stdout)]
+pub fn docs(path: Utf8PathBuf, output: Option<Utf8PathBuf>) -> Result<()> {
+    //
+    let root = path.clone();
+    let mut markdown = String::from("# macOS Defaults\n");
+
+    for file in process_path(path)? {
+        debug!("Documenting {file}");
+
+        let name = file.strip_prefix(&root).ok().filter(|p| !p.as_str().is_empty()).unwrap_or(&file);
+        writeln!(markdown, "\n## {name}")?;
+
+        if is_plist(&file) {
+            let domain = raw_plist_domain(&file)?;
+            writeln!(markdown, "\nReplaces the whole `{domain}` plist.")?;
+            continue;
+        }
+
+        for config in load_documents(&file)? {
+            writeln!(markdown, "\n### {}\n", config.description.as_deref().unwrap_or("Untitled"))?;
+
+            if !config.quit.is_empty() {
+                writeln!(markdown, "Quits first: {}\n", config.quit.join(", "))?;
+            }
+
+            if let Some(kill) = &config.kill {
+                writeln!(markdown, "Restarts: {}\n", kill.join(", "))?;
+            }
+
+            if config.current_host {
+                writeln!(markdown, "Applies to the current host only.\n")?;
+            }
+
+            let Some(serde_yaml::Value::Mapping(domains)) = &config.data else {
+                return Err(E::MissingData { path: file }.into());
+            };
+
+            markdown.push_str("| Domain | Key | Value | Description |\n| --- | --- | --- | --- |\n");
+
+            for (domain, prefs) in domains {
+                let domain = inline_yaml(domain);
+
+                let serde_yaml::Value::Mapping(prefs) = prefs else {
+                    continue;
+                };
+
+                for (key, value) in prefs {
+                    let key = inline_yaml(key);
+                    let description = catalog::lookup(&domain, &key).map(|entry| entry.description.as_str()).unwrap_or_default();
+
+                    writeln!(
+                        markdown,
+                        "| `{}` | `{}` | `{}` | {} |",
+                        escape_cell(&domain),
+                        escape_cell(&key),
+                        escape_cell(&inline_yaml(value)),
+                        escape_cell(description)
+                    )?;
+                }
+            }
+        }
+    }
+
+    match output {
+        Some(path) => fs::write(path, markdown)?,
+        None => print!("{markdown}"),
+    }
+
+    Ok(())
+}
+
+/// Render a YAML value on a single line, in flow style.
+fn inline_yaml(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::Null => "null".to_owned(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        serde_yaml::Value::String(s) => s.clone(),
+        serde_yaml::Value::Sequence(sequence) => format!("[{}]", sequence.iter().map(inline_yaml).collect::<Vec<_>>().join(", ")),
+        serde_yaml::Value::Mapping(mapping) => format!(
+            "{{{}}}",
+            mapping
+                .iter()
+                .map(|(k, v)| format!("{}: {}", inline_yaml(k), inline_yaml(v)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        serde_yaml::Value::Tagged(tagged) => format!("{} {}", tagged.tag, inline_yaml(&tagged.value)),
+    }
+}
+
+/// Escape characters which would break a Markdown table cell.
+fn escape_cell(text: &str) -> String {
+    text.replace('|', "\\|").replace('\n', " ")
+}
@@ -0,0 +1,71 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use duct::cmd;
+use tracing::{debug, trace};
+
+use crate::defaults::{home_dir, user_plist_paths};
+use crate::environment::Environment;
+use crate::errors::DefaultsError as E;
+
+/// `doctor` command.
+#[allow(clippy::print_stdout)]
+pub fn doctor(fix_ownership: bool) -> Result<()> {
+    //
+    println!("{}", Environment::gather());
+
+    check_ownership(fix_ownership)
+}
+
+/// Find user preference plists owned by root, e.g. left behind by a `sudo tee` write fallback.
+///
+/// These silently break later writes by the app that owns them, so optionally `chown` them back
+/// to the owner of the home directory.
+#[allow(clippy::print_stdout)]
+fn check_ownership(fix: bool) -> Result<()> {
+    //
+    let home_dir = home_dir()?;
+    let home_metadata = fs::metadata(&home_dir).map_err(|e| E::FileRead {
+        path: home_dir.clone(),
+        source: e,
+    })?;
+
+    let (uid, gid) = (home_metadata.uid(), home_metadata.gid());
+    debug!("Home directory {home_dir} is owned by {uid}:{gid}");
+
+    if uid == 0 {
+        trace!("Home directory is owned by root, skipping ownership check.");
+        return Ok(());
+    }
+
+    let root_owned: Vec<_> = user_plist_paths()?
+        .into_iter()
+        .filter(|path| fs::symlink_metadata(path).is_ok_and(|m| m.uid() == 0))
+        .collect();
+
+    if root_owned.is_empty() {
+        println!("  {} No root owned plists found.", "✔".green());
+        return Ok(());
+    }
+
+    for path in &root_owned {
+        println!("  {} Owned by root: {}", "✖".red(), path.as_str().white());
+
+        if fix {
+            cmd!("sudo", "chown", format!("{uid}:{gid}"), path)
+                .stdout_null()
+                .run()
+                .map_err(|e| E::ChownFailed { path: path.clone(), source: e })?;
+
+            println!("    {} Fixed ownership: {uid}:{gid}", "✔".green());
+        }
+    }
+
+    if !fix {
+        println!("    Run `macos-defaults doctor --fix-ownership` to repair.");
+    }
+
+    Ok(())
+}
@@ -1,100 +1,912 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::prelude::*;
 
-use camino::Utf8PathBuf;
-use color_eyre::eyre::Result;
-use log::{debug, trace, warn};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
 use plist::{Dictionary, Value};
+use serde::Serialize;
+use tracing::{debug, info_span, trace, warn};
 use yaml_rust::{YamlEmitter, YamlLoader};
 
-use crate::defaults::{get_plist_value_type, plist_path, replace_data_in_plist, MacOSDefaults, NS_GLOBAL_DOMAIN};
+use crate::defaults::{
+    check_sandbox_divergence, did_you_mean, domain_for_path, domains as installed_domains, get_plist_value_type, plist_path, read_plist, replace_data_in_plist,
+    resolve_domain, sort_array_stable, sort_keys_nested, value_at_key_path_mut, MacOSDefaults, FILE_TAG, NS_GLOBAL_DOMAIN, UID_TAG,
+};
+use crate::domain::Domain;
 use crate::errors::DefaultsError as E;
+use crate::glob::glob_matches;
+use crate::nix::domains_to_nix;
+use crate::transformers::transformers;
+use crate::volatile::is_volatile;
+use crate::yaml_merge::merge_dump;
 
-/// `dump` command.
-pub fn dump(current_host: bool, output: Option<Utf8PathBuf>, global_domain: bool, domain: Option<String>) -> Result<()> {
+/// Options for the `dump` command.
+#[derive(Debug, Default)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct DumpOptions {
+    /// Read from the current host.
+    pub current_host: bool,
+
+    /// Plists containing binary data are an error rather than being hex-encoded.
+    pub strict: bool,
+
+    /// Processes to add to the emitted document's `kill` list.
+    pub kill: Vec<String>,
+
+    /// Description for the emitted document, defaults to the domain.
+    pub description: Option<String>,
+
+    /// Only print the resolved plist path, don't dump it.
+    pub show_path: bool,
+
+    /// Key paths of arrays whose order isn't meaningful, to sort into a stable order.
+    pub sort_arrays: Vec<String>,
+
+    /// Sort the keys of nested dictionaries, not only the top level ones.
+    pub sorted_nested: bool,
+
+    /// `Data` values larger than this many bytes are handled according to `large_values`.
+    pub max_value_size: Option<usize>,
+
+    /// What to do with `Data` values larger than `max_value_size`.
+    pub large_values: LargeValues,
+
+    /// Dump as YAML, or as the plist file itself.
+    pub format: DumpFormat,
+
+    /// Key path globs to dump, everything if empty.
+    pub keys: Vec<String>,
+
+    /// Key path globs to leave out.
+    pub exclude_keys: Vec<String>,
+
+    /// Merge into the existing output file, keeping its comments, key order and other documents.
+    pub merge: bool,
+
+    /// Dump every installed domain, warning about the ones that can't be dumped.
+    pub all: bool,
+
+    /// Write each domain to its own file in this directory, rather than all to one output.
+    pub output_dir: Option<Utf8PathBuf>,
+
+    /// Template for the file names in `output_dir`, with `{domain}` and `{vendor}` placeholders.
+    pub file_name: Option<String>,
+}
+
+/// Output format of `dump`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DumpFormat {
+    /// A YAML document per domain.
+    #[default]
+    Yaml,
+    /// The domain's plist as is, in binary format. `apply` accepts `.plist` files, replacing the whole domain.
+    Plist,
+    /// The domain's plist as is, in XML format.
+    Xml,
+    /// A Nix attribute set of the domains, for home-manager's `targets.darwin.defaults`.
+    Nix,
+}
+
+/// What to do with `Data` values larger than `--max-value-size`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum LargeValues {
+    /// Leave the key out, noting its size in a comment at the top of the document.
+    #[default]
+    Skip,
+    /// Write the value to a file next to the YAML output, referenced with a `!file` tag.
+    Sidecar,
+}
+
+/// `dump` command. Several domains are dumped as a stream of YAML documents, one per domain, or
+/// to a file each with `--output-dir`.
+pub fn dump(global_domain: bool, domains: Vec<String>, output: Option<&Utf8Path>, options: &DumpOptions) -> Result<()> {
     //
-    let domain = if global_domain {
-        NS_GLOBAL_DOMAIN.to_owned()
+    let mut domains = domains;
+
+    if global_domain {
+        domains.insert(0, NS_GLOBAL_DOMAIN.to_owned());
+    }
+
+    if options.all {
+        domains = installed_domains(options.current_host)?.iter().map(ToString::to_string).collect();
+    }
+
+    if domains.is_empty() {
+        return Err(E::MissingDomain {}.into());
+    }
+
+    if domains.len() > 1 && options.description.is_some() {
+        return Err(eyre!("--description can only be used when dumping a single domain."));
+    }
+
+    if options.merge && output.is_none() && options.output_dir.is_none() {
+        return Err(eyre!("--merge needs an output file or --output-dir to merge into."));
+    }
+
+    if matches!(options.format, DumpFormat::Plist | DumpFormat::Xml) && (!options.keys.is_empty() || !options.exclude_keys.is_empty()) {
+        return Err(eyre!(
+            "--keys and --exclude-keys can't be used with --format plist or xml, as applying a raw plist replaces the whole domain."
+        ));
+    }
+
+    if let Some(output_dir) = &options.output_dir {
+        return dump_to_dir(&domains, output_dir, options);
+    }
+
+    if options.format == DumpFormat::Nix && !options.show_path {
+        return dump_nix(&domains, output, options);
+    }
+
+    if matches!(options.format, DumpFormat::Plist | DumpFormat::Xml) && !options.show_path {
+        let [domain] = &domains[..] else {
+            return Err(eyre!(
+                "--format plist and xml can only be used when dumping a single domain, or with --output-dir."
+            ));
+        };
+
+        return dump_plist(domain, output, options);
+    }
+
+    let mut documents = Vec::new();
+
+    for domain in domains {
+        let _span = info_span!("domain", domain).entered();
+
+        match dump_domain(&domain, output, options) {
+            Ok(Some(document)) => documents.push(document),
+            Ok(None) => {}
+            Err(e) if options.all => warn!("Not dumping {domain}: {e}"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    write_documents(documents, output, options.merge)
+}
+
+/// Dump each domain to its own file in a directory, named by the `--file-name` template.
+fn dump_to_dir(domains: &[String], output_dir: &Utf8Path, options: &DumpOptions) -> Result<()> {
+    //
+    if options.format == DumpFormat::Nix {
+        return Err(eyre!(
+            "--output-dir can't be used with --format nix, which dumps all the domains as one attribute set."
+        ));
+    }
+
+    let template = options.file_name.as_deref().unwrap_or(match options.format {
+        DumpFormat::Plist | DumpFormat::Xml => "{domain}.plist",
+        _ => "{domain}.yaml",
+    });
+
+    if domains.len() > 1 && !template.contains("{domain}") {
+        return Err(eyre!(
+            "--file-name must contain {{domain}} when dumping several domains, so each gets its own file."
+        ));
+    }
+
+    for domain in domains {
+        let _span = info_span!("domain", domain).entered();
+
+        let path = output_dir.join(dump_file_name(template, resolve_domain(domain, options.current_host)?.as_str()));
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+                path: parent.to_owned(),
+                source: e,
+            })?;
+        }
+
+        let result = match options.format {
+            DumpFormat::Plist | DumpFormat::Xml if !options.show_path => dump_plist(domain, Some(&path), options),
+            _ => dump_domain(domain, Some(&path), options).and_then(|document| match document {
+                Some(document) => write_documents(vec![document], Some(&path), options.merge),
+                None => Ok(()),
+            }),
+        };
+
+        match result {
+            Ok(()) => debug!("Dumped {domain} to {path}"),
+            Err(e) if options.all => warn!("Not dumping {domain}: {e}"),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Name of a domain's file in `--output-dir`: the template with `{domain}` replaced by the domain
+/// and `{vendor}` by `apple` for Apple's domains or `third-party` for the rest.
+fn dump_file_name(template: &str, domain: &str) -> String {
+    let vendor = if domain == NS_GLOBAL_DOMAIN || domain.starts_with("com.apple.") {
+        "apple"
     } else {
-        domain.ok_or(E::MissingDomain {})?
+        "third-party"
+    };
+
+    template.replace("{domain}", &sanitize_file_name(domain)).replace("{vendor}", vendor)
+}
+
+/// Write dumped documents, each with the domain it's for, to a file or stdout. With `merge`, an
+/// existing file is updated rather than replaced.
+fn write_documents(documents: Vec<(String, String)>, output: Option<&Utf8Path>, merge: bool) -> Result<()> {
+    //
+    if documents.is_empty() {
+        return Ok(());
+    }
+
+    let mut yaml = match output.filter(|path| merge && path.exists()) {
+        Some(path) => merge_dump(&fs::read_to_string(path)?, &documents),
+        None => documents.into_iter().map(|(_, yaml)| yaml).collect(),
     };
 
+    if !yaml.ends_with('\n') {
+        yaml.push('\n');
+    }
+
+    match output {
+        Some(path) => File::create(path)?.write_all(yaml.as_bytes()),
+        None => std::io::stdout().write_all(yaml.as_bytes()),
+    }?;
+
+    Ok(())
+}
+
+/// Dump a domain's plist file as is, for settings that don't survive conversion to YAML.
+fn dump_plist(domain: &str, output: Option<&Utf8Path>, options: &DumpOptions) -> Result<()> {
+    //
+    let domain = resolve_domain(domain, options.current_host)?;
+    let plist_path = plist_path(&domain, options.current_host)?;
+
+    debug!("Plist path: {plist_path}");
+
+    let plist = read_domain_plist(domain.as_str(), &plist_path, options.current_host)?;
+
+    let mut bytes = Vec::new();
+
+    match options.format {
+        DumpFormat::Xml => plist.to_writer_xml(&mut bytes)?,
+        _ => plist.to_writer_binary(&mut bytes)?,
+    }
+
+    match output {
+        Some(path) => File::create(path)?.write_all(&bytes),
+        None => std::io::stdout().write_all(&bytes),
+    }?;
+
+    Ok(())
+}
+
+/// Dump domains as a Nix attribute set, for home-manager's `targets.darwin.defaults` (or
+/// `targets.darwin.currentHostDefaults`, with `--current-host`).
+fn dump_nix(domains: &[String], output: Option<&Utf8Path>, options: &DumpOptions) -> Result<()> {
+    //
+    let option = if options.current_host { "currentHostDefaults" } else { "defaults" };
+    let mut values = Dictionary::new();
+
+    for domain in domains {
+        let _span = info_span!("domain", domain).entered();
+
+        let (domain, plist_path) = resolve_dump_domain(domain, options.current_host)?;
+        check_sandbox_divergence(&domain, options.current_host)?;
+
+        let mut plist = read_domain_plist(domain.as_str(), &plist_path, options.current_host)?;
+
+        if let Value::Dictionary(dict) = &mut plist {
+            filter_keys(dict, "", &options.keys, &options.exclude_keys);
+            dict.sort_keys();
+        }
+
+        if options.sorted_nested {
+            sort_keys_nested(&mut plist);
+        }
+
+        for key_path in &options.sort_arrays {
+            if let Some(array @ Value::Array(_)) = value_at_key_path_mut(&mut plist, key_path) {
+                sort_array_stable(array);
+            } else {
+                warn!("No array found at key path {key_path:?} in domain {domain}, not sorting it.");
+            }
+        }
+
+        values.insert(domain.to_string(), plist);
+    }
+
+    let nix = format!(
+        "# Dumped by macos-defaults, for home-manager's `targets.darwin.{option}`, e.g.\n# `targets.darwin.{option} = import ./defaults.nix;`\n{}",
+        domains_to_nix(&values)
+    );
+
+    match output {
+        Some(path) => File::create(path)?.write_all(nix.as_bytes()),
+        None => std::io::stdout().write_all(nix.as_bytes()),
+    }?;
+
+    Ok(())
+}
+
+/// Read a domain's plist, suggesting installed domains with similar names if it doesn't have one.
+fn read_domain_plist(domain: &str, plist_path: &Utf8Path, current_host: bool) -> Result<Value> {
+    if !plist_path.exists() {
+        return Err(E::DomainNotFound {
+            domain: domain.to_owned(),
+            path: plist_path.to_owned(),
+            suggestion: did_you_mean(domain, current_host),
+        }
+        .into());
+    }
+
+    Ok(read_plist(plist_path)?)
+}
+
+/// Resolve a domain and its plist path. A plist path which resolves back to itself is dumped with
+/// its logical domain instead.
+fn resolve_dump_domain(domain: &str, current_host: bool) -> Result<(Domain, Utf8PathBuf)> {
+    //
+    let domain = resolve_domain(domain, current_host)?;
+
     debug!("Domain: {domain:?}");
     let plist_path = plist_path(&domain, current_host)?;
     debug!("Plist path: {plist_path}");
 
-    // TODO: Nicer error.
-    let plist: Value = plist::from_file(&plist_path).map_err(|e| E::PlistRead { path: plist_path, source: e })?;
+    if let Some(logical_domain) = domain_for_path(&plist_path) {
+        if logical_domain != domain && crate::defaults::plist_path(&logical_domain, current_host)? == plist_path {
+            eprintln!("{plist_path} is the plist for domain: {logical_domain}");
+            return Ok((logical_domain, plist_path));
+        }
+    }
+
+    Ok((domain, plist_path))
+}
+
+/// Dump a single domain as a YAML document, with the domain it's for, or print its path with
+/// `--show-path`.
+#[allow(clippy::print_stdout)]
+fn dump_domain(domain: &str, output: Option<&Utf8Path>, options: &DumpOptions) -> Result<Option<(String, String)>> {
+    //
+    let DumpOptions {
+        current_host,
+        strict,
+        kill,
+        description,
+        show_path,
+        sort_arrays,
+        sorted_nested,
+        max_value_size,
+        large_values,
+        keys,
+        exclude_keys,
+        ..
+    } = options;
+
+    let current_host = *current_host;
+    let (domain, plist_path) = resolve_dump_domain(domain, current_host)?;
+
+    check_sandbox_divergence(&domain, current_host)?;
+
+    // The rest of the dump only needs the domain's name.
+    let domain = domain.to_string();
+
+    if *show_path {
+        println!("{plist_path}");
+        return Ok(None);
+    }
+
+    let mut plist = read_domain_plist(&domain, &plist_path, current_host)?;
 
     trace!("Plist: {plist:?}");
 
-    // First pass.
-    let plist = if serde_yaml::to_string(&plist).is_ok() {
-        plist
-    } else {
-        warn!(
-            "Serializing plist value to YAML failed, assuming this is because it contained binary \
-             data and replacing that with hex-encoded binary data. This is incorrect, but allows \
-             the output to be printed."
-        );
-        let mut value = plist.clone();
+    if let Value::Dictionary(dict) = &mut plist {
+        filter_keys(dict, "", keys, exclude_keys);
+    }
 
-        replace_data_in_plist(&mut value).map_err(|e| E::EyreError { source: e })?;
+    let mut limiter = max_value_size.map(|max_size| LargeValueLimiter {
+        max_size,
+        policy: *large_values,
+        sidecar_dir: output.and_then(Utf8Path::parent).map(Utf8Path::to_path_buf),
+        domain: domain.clone(),
+        comments: Vec::new(),
+        references: Vec::new(),
+    });
 
-        serde_yaml::to_string(&value).map_err(|e| E::SerializationFailed {
-            domain: domain.clone(),
-            source: e,
-        })?;
-        value
-    };
+    if let Some(limiter) = &mut limiter {
+        limiter.limit(&mut plist, &mut Vec::new())?;
+    }
+
+    let mut uid_references = Vec::new();
+    tag_uncommon_values(&mut plist, &domain, &mut Vec::new(), &mut uid_references)?;
 
     // Sort the top level keys.
-    let mut value = plist
-        .as_dictionary()
-        .ok_or_else(|| E::NotADictionary {
-            domain: domain.clone(),
-            key: "Unknown".to_owned(),
-            plist_type: get_plist_value_type(&plist),
-        })?
-        .clone();
+    let mut value = match plist {
+        Value::Dictionary(dict) => dict,
+        plist => {
+            return Err(E::NotADictionary {
+                domain,
+                key: "Unknown".to_owned(),
+                plist_type: get_plist_value_type(&plist),
+            }
+            .into())
+        }
+    };
 
     value.sort_keys();
 
-    let data = serde_yaml::to_value(Dictionary::from_iter(vec![(domain.clone(), Value::Dictionary(value))]))?;
+    let volatile_keys: Vec<String> = value
+        .iter()
+        .filter(|(key, value)| is_volatile(key, value))
+        .map(|(key, _)| key.clone())
+        .collect();
+
+    let mut value = Value::Dictionary(value);
+
+    if *sorted_nested {
+        sort_keys_nested(&mut value);
+    }
+
+    for key_path in sort_arrays {
+        if let Some(array @ Value::Array(_)) = value_at_key_path_mut(&mut value, key_path) {
+            sort_array_stable(array);
+        } else {
+            warn!("No array found at key path {key_path:?} in domain {domain}, not sorting it.");
+        }
+    }
+
+    let data = yaml_data(&domain, value, *strict)?;
 
     // Wrap in the container struct.
     let defaults = MacOSDefaults {
-        description: Some(domain),
+        description: Some(description.clone().unwrap_or_else(|| domain.clone())),
         current_host,
-        kill: None,
-        sudo: false,
+        kill: (!kill.is_empty()).then(|| kill.clone()),
         data: Some(data),
+        ..MacOSDefaults::default()
     };
 
+    let (comments, mut references) = limiter.map(|l| (l.comments, l.references)).unwrap_or_default();
+    references.extend(uid_references);
+
     // Round-trip for yamllint valid YAML.
-    let yaml = round_trip_yaml(&defaults)?;
+    let mut yaml = comments.concat();
+    yaml.push_str(&annotate_volatile_keys(
+        &String::from_utf8(round_trip_yaml(&defaults, &references)?)?,
+        &volatile_keys,
+    ));
 
-    match output {
-        Some(path) => File::create(path)?.write(&yaml),
-        None => std::io::stdout().write(&yaml),
-    }?;
+    Ok(Some((domain, yaml)))
+}
 
-    Ok(())
+/// Convert a domain's plist to the YAML `data` of a document, serializing it once. YAML has no
+/// binary data, which would come out as a list of bytes, so unless `strict` it's replaced with
+/// hex-encoded data first.
+fn yaml_data(domain: &str, mut value: Value, strict: bool) -> Result<serde_yaml::Value> {
+    //
+    if contains_data(&value) {
+        if strict {
+            return Err(E::StrictCoercion { domain: domain.to_owned() }.into());
+        }
+
+        warn!("{domain} contains binary data, replacing that with hex-encoded binary data. This is incorrect, but allows the output to be printed.");
+
+        replace_data_in_plist(&mut value).map_err(|e| E::EyreError { source: e })?;
+    }
+
+    let data = Dictionary::from_iter(vec![(domain.to_owned(), value)]);
+
+    serde_yaml::to_value(data).map_err(|e| {
+        E::SerializationFailed {
+            domain: domain.to_owned(),
+            source: e,
+        }
+        .into()
+    })
+}
+
+/// Whether there's a `Data` value anywhere within a value.
+fn contains_data(value: &Value) -> bool {
+    match value {
+        Value::Data(_) => true,
+        Value::Array(array) => array.iter().any(contains_data),
+        Value::Dictionary(dict) => dict.values().any(contains_data),
+        _ => false,
+    }
 }
 
-fn round_trip_yaml(defaults: &MacOSDefaults) -> Result<Vec<u8>> {
+/// `references` are sidecar `!file` strings that get written as tagged values rather than quoted.
+pub(crate) fn round_trip_yaml(value: &impl Serialize, references: &[String]) -> Result<Vec<u8>> {
     //
     let mut buffer = Vec::new();
 
-    for doc in YamlLoader::load_from_str(&serde_yaml::to_string(&defaults)?)? {
+    for doc in YamlLoader::load_from_str(&serde_yaml::to_string(value)?)? {
         let mut content = String::new();
 
         let mut emitter = YamlEmitter::new(&mut content);
         emitter.compact(false);
         emitter.dump(&doc).ok();
 
+        for reference in references {
+            content = content.replace(&format!("\"{reference}\""), reference);
+        }
+
         buffer.write_all(content.as_ref())?;
     }
 
     Ok(buffer)
 }
+
+/// Add a trailing `# volatile` comment to the lines of a dumped domain's keys that look like
+/// machine-generated noise, to help prune a dump into a maintainable config.
+fn annotate_volatile_keys(yaml: &str, keys: &[String]) -> String {
+    //
+    // Top level keys of a domain are indented under `data:` and the domain.
+    const KEY_INDENT: &str = "    ";
+
+    let mut annotated = String::with_capacity(yaml.len());
+
+    for line in yaml.lines() {
+        annotated.push_str(line);
+
+        if let Some(rest) = line.strip_prefix(KEY_INDENT).filter(|rest| !rest.starts_with([' ', '-'])) {
+            let is_volatile = keys.iter().any(|key| {
+                let quoted = format!("\"{}\"", key.replace('\\', "\\\\").replace('"', "\\\""));
+
+                [key.as_str(), quoted.as_str()]
+                    .iter()
+                    .any(|key| rest.strip_prefix(key).is_some_and(|after| after == ":" || after.starts_with(": ")))
+            });
+
+            if is_volatile {
+                annotated.push_str(" # volatile");
+            }
+        }
+
+        annotated.push('\n');
+    }
+
+    annotated
+}
+
+/// Keep only the keys whose key path (e.g. `DesktopViewSettings.IconViewSettings.iconSize`) matches
+/// one of the `keys` globs, if there are any, and none of the `exclude_keys` globs.
+///
+/// A matching dictionary is kept whole (less excluded keys), one that doesn't match is kept with
+/// just the nested keys that do.
+fn filter_keys(dict: &mut Dictionary, prefix: &str, keys: &[String], exclude_keys: &[String]) {
+    //
+    for (key, mut value) in std::mem::take(dict) {
+        let key_path = if prefix.is_empty() { key.clone() } else { format!("{prefix}.{key}") };
+
+        if exclude_keys.iter().any(|pattern| glob_matches(pattern, &key_path, false)) {
+            trace!("Excluding {key_path}");
+            continue;
+        }
+
+        if keys.is_empty() || keys.iter().any(|pattern| glob_matches(pattern, &key_path, false)) {
+            if let Value::Dictionary(nested) = &mut value {
+                filter_keys(nested, &key_path, &[], exclude_keys);
+            }
+
+            dict.insert(key, value);
+            continue;
+        }
+
+        if let Value::Dictionary(nested) = &mut value {
+            if keys.iter().any(|pattern| glob_matches(pattern, &format!("{key_path}."), true)) {
+                filter_keys(nested, &key_path, keys, exclude_keys);
+
+                if !nested.is_empty() {
+                    dict.insert(key, value);
+                }
+            }
+        }
+    }
+}
+
+/// Replace `Uid` values with `!uid` references, and values a transformer recognizes with its tag,
+/// which `round_trip_yaml` writes as tagged values.
+/// Fails, naming the type, on plist values that can't be represented in YAML at all.
+pub(crate) fn tag_uncommon_values(value: &mut Value, domain: &str, key_path: &mut Vec<String>, references: &mut Vec<String>) -> Result<()> {
+    match value {
+        Value::Dictionary(dict) => {
+            for (key, value) in dict.iter_mut() {
+                key_path.push(key.clone());
+                tag_uncommon_values(value, domain, key_path, references)?;
+                key_path.pop();
+            }
+        }
+        Value::Array(array) => {
+            for value in array.iter_mut() {
+                tag_uncommon_values(value, domain, key_path, references)?;
+            }
+        }
+        Value::Uid(uid) => {
+            let reference = format!("{UID_TAG} {}", uid.get());
+            *value = Value::String(reference.clone());
+            references.push(reference);
+        }
+        Value::String(_) => {
+            if let Some(reference) = key_path.last().and_then(|key| transformers().collapse(key, value)) {
+                *value = Value::String(reference.clone());
+                references.push(reference);
+            }
+        }
+        value if get_plist_value_type(value) == "unknown" => {
+            return Err(E::UnsupportedPlistType {
+                domain: domain.to_owned(),
+                key: key_path.join("."),
+                plist_type: format!("{value:?}"),
+            }
+            .into());
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+/// Applies the `--max-value-size` policy to a plist.
+struct LargeValueLimiter {
+    max_size: usize,
+    policy: LargeValues,
+    /// Directory of the YAML output, which sidecar files are written relative to.
+    sidecar_dir: Option<Utf8PathBuf>,
+    domain: String,
+    /// Comments noting skipped values.
+    comments: Vec<String>,
+    /// `!file` references to sidecar files.
+    references: Vec<String>,
+}
+
+impl LargeValueLimiter {
+    fn limit(&mut self, value: &mut Value, key_path: &mut Vec<String>) -> Result<()> {
+        match value {
+            Value::Dictionary(dict) => {
+                let keys: Vec<String> = dict.keys().cloned().collect();
+
+                for key in keys {
+                    key_path.push(key.clone());
+
+                    let child = dict.get_mut(&key).ok_or(E::UnexpectedNone)?;
+                    let size = largest_data_size(child);
+
+                    // Skipping inside an array would change the array, so skip the whole key instead.
+                    if matches!(self.policy, LargeValues::Skip) && !matches!(child, Value::Dictionary(_)) && size > self.max_size {
+                        self.comments.push(format!(
+                            "# Skipped {}: {size} bytes of data is larger than --max-value-size.\n",
+                            key_path.join(".")
+                        ));
+                        dict.remove(&key);
+                    } else {
+                        self.limit(child, key_path)?;
+                    }
+
+                    key_path.pop();
+                }
+            }
+            Value::Array(array) => {
+                for (index, child) in array.iter_mut().enumerate() {
+                    key_path.push(index.to_string());
+                    self.limit(child, key_path)?;
+                    key_path.pop();
+                }
+            }
+            Value::Data(bytes) if matches!(self.policy, LargeValues::Sidecar) && bytes.len() > self.max_size => {
+                let sidecar_dir = self.sidecar_dir.as_ref().ok_or(E::SidecarWithoutOutput)?;
+
+                let relative_path = Utf8PathBuf::from(sanitize_file_name(&self.domain)).join(format!("{}.bin", sanitize_file_name(&key_path.join("."))));
+                let sidecar_path = sidecar_dir.join(&relative_path);
+
+                debug!("Writing {} bytes to sidecar file {sidecar_path}", bytes.len());
+
+                let parent = sidecar_path.parent().ok_or(E::UnexpectedNone)?;
+                fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+                    path: parent.to_owned(),
+                    source: e,
+                })?;
+                fs::write(&sidecar_path, &bytes)?;
+
+                let reference = format!("{FILE_TAG} ./{relative_path}");
+                *value = Value::String(reference.clone());
+                self.references.push(reference);
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+/// Size of the largest `Data` value anywhere within a value.
+fn largest_data_size(value: &Value) -> usize {
+    match value {
+        Value::Data(bytes) => bytes.len(),
+        Value::Array(array) => array.iter().map(largest_data_size).max().unwrap_or_default(),
+        Value::Dictionary(dict) => dict.values().map(largest_data_size).max().unwrap_or_default(),
+        _ => 0,
+    }
+}
+
+/// Make a string safe to use as a single file name component.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{
+        annotate_volatile_keys, dump_file_name, filter_keys, read_domain_plist, round_trip_yaml, tag_uncommon_values, yaml_data, LargeValueLimiter, LargeValues,
+    };
+    use crate::defaults::MacOSDefaults;
+
+    #[test]
+    fn test_annotate_volatile_keys() {
+        let yaml = "---\ndata:\n  com.apple.dock:\n    autohide: true\n    mod-count: 3\n    \"NSWindow Frame\": 0 0 10 10\n    nested:\n      mod-count: 1\n";
+        let keys = ["mod-count".to_owned(), "NSWindow Frame".to_owned()];
+
+        assert_eq!(
+            annotate_volatile_keys(yaml, &keys),
+            "---\ndata:\n  com.apple.dock:\n    autohide: true\n    mod-count: 3 # volatile\n    \"NSWindow Frame\": 0 0 10 10 # volatile\n    nested:\n      mod-count: 1\n"
+        );
+    }
+
+    #[test]
+    fn test_tag_uncommon_values() -> TestResult {
+        let mut plist = Value::Dictionary(Dictionary::from_iter([
+            ("root", Value::Uid(plist::Uid::new(1))),
+            ("objects", Value::Array(vec![Value::Uid(plist::Uid::new(7)), "$null".into()])),
+        ]));
+        let mut references = Vec::new();
+
+        tag_uncommon_values(&mut plist, "com.example", &mut Vec::new(), &mut references)?;
+        assert_eq!(references, ["!uid 1", "!uid 7"]);
+
+        let defaults = MacOSDefaults {
+            data: Some(serde_yaml::to_value(Dictionary::from_iter([("com.example", plist)]))?),
+            ..MacOSDefaults::default()
+        };
+        let yaml = String::from_utf8(round_trip_yaml(&defaults, &references)?)?;
+
+        assert!(yaml.contains("root: !uid 1\n"));
+        assert!(yaml.contains("- !uid 7\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let filtered = |keys: &[&str], exclude_keys: &[&str]| {
+            let mut dict = Dictionary::from_iter([
+                ("autohide", Value::from(true)),
+                ("NSWindow Frame Main", Value::from("0 0 10 10")),
+                (
+                    "Desktop",
+                    Value::from(Dictionary::from_iter([
+                        ("IconSize", Value::from(64)),
+                        ("IconTimestamp", Value::from(1)),
+                        ("Arrange", Value::from("name")),
+                    ])),
+                ),
+            ]);
+            let keys: Vec<String> = keys.iter().map(ToString::to_string).collect();
+            let exclude_keys: Vec<String> = exclude_keys.iter().map(ToString::to_string).collect();
+
+            filter_keys(&mut dict, "", &keys, &exclude_keys);
+            format!("{:?}", Value::from(dict))
+        };
+
+        assert_eq!(
+            filtered(&[], &["NSWindow Frame *", "*Timestamp"]),
+            r#"Dictionary({"autohide": Boolean(true), "Desktop": Dictionary({"IconSize": Integer(64), "Arrange": String("name")})})"#
+        );
+        assert_eq!(
+            filtered(&["Desktop.Icon*"], &[]),
+            r#"Dictionary({"Desktop": Dictionary({"IconSize": Integer(64), "IconTimestamp": Integer(1)})})"#
+        );
+        assert_eq!(
+            filtered(&["Desktop"], &["*.Arrange"]),
+            r#"Dictionary({"Desktop": Dictionary({"IconSize": Integer(64), "IconTimestamp": Integer(1)})})"#
+        );
+        assert_eq!(filtered(&["nothing"], &[]), "Dictionary({})");
+    }
+
+    #[test]
+    fn test_dump_file_name() {
+        assert_eq!(dump_file_name("{domain}.yaml", "com.apple.dock"), "com.apple.dock.yaml");
+        assert_eq!(dump_file_name("{vendor}/{domain}.yaml", "NSGlobalDomain"), "apple/NSGlobalDomain.yaml");
+        assert_eq!(
+            dump_file_name("{vendor}/{domain}.yaml", "org.example.App Name"),
+            "third-party/org.example.App_Name.yaml"
+        );
+    }
+
+    #[test]
+    fn test_read_domain_plist_missing() -> TestResult {
+        let path = camino::Utf8PathBuf::try_from(std::env::temp_dir())?.join("macos-defaults-missing/org.example.missing.plist");
+
+        let error = read_domain_plist("org.example.missing", &path, false).err().ok_or("plist is missing")?;
+        assert!(error.to_string().starts_with("Domain org.example.missing doesn't exist"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_yaml_data() -> TestResult {
+        let plist = || {
+            Value::Dictionary(Dictionary::from_iter([
+                ("autohide", Value::from(true)),
+                ("blob", Value::Data(vec![0xca, 0xfe])),
+            ]))
+        };
+
+        // Binary data is hex-encoded, or an error with `--strict`.
+        let data = yaml_data("com.apple.dock", plist(), false)?;
+        assert_eq!(data["com.apple.dock"]["autohide"], serde_yaml::Value::from(true));
+        assert_eq!(data["com.apple.dock"]["blob"], serde_yaml::Value::from("cafe"));
+        assert!(yaml_data("com.apple.dock", plist(), true).is_err());
+
+        let data = yaml_data(
+            "com.apple.dock",
+            Value::Dictionary(Dictionary::from_iter([("tilesize", Value::from(36))])),
+            true,
+        )?;
+        assert_eq!(data["com.apple.dock"]["tilesize"], serde_yaml::Value::from(36));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_value_limiter() -> TestResult {
+        let plist = || {
+            Value::Dictionary(Dictionary::from_iter([
+                ("small", Value::Data(vec![0; 4])),
+                ("large", Value::Data(vec![0; 64])),
+                ("nested", Value::Dictionary(Dictionary::from_iter([("large", Value::Data(vec![1; 64]))]))),
+                ("array", Value::Array(vec![Value::Data(vec![0; 64])])),
+            ]))
+        };
+        let limiter = |policy, sidecar_dir| LargeValueLimiter {
+            max_size: 16,
+            policy,
+            sidecar_dir,
+            domain: "com.example".to_owned(),
+            comments: Vec::new(),
+            references: Vec::new(),
+        };
+
+        // Skipped keys are noted in comments, and arrays are skipped whole.
+        let mut skipped = plist();
+        let mut skip = limiter(LargeValues::Skip, None);
+        skip.limit(&mut skipped, &mut Vec::new())?;
+
+        let dict = skipped.as_dictionary().ok_or("a dictionary")?;
+        assert!(dict.contains_key("small"));
+        assert!(!dict.contains_key("large") && !dict.contains_key("array"));
+        assert_eq!(dict["nested"].as_dictionary().map(Dictionary::len), Some(0));
+        assert_eq!(skip.comments.len(), 3);
+        assert!(skip
+            .comments
+            .contains(&"# Skipped nested.large: 64 bytes of data is larger than --max-value-size.\n".to_owned()));
+
+        // Sidecars need a directory to be written next to.
+        assert!(limiter(LargeValues::Sidecar, None).limit(&mut plist(), &mut Vec::new()).is_err());
+
+        let tmp = tempfile::tempdir()?;
+        let dir = camino::Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let mut sidecars = plist();
+        let mut sidecar = limiter(LargeValues::Sidecar, Some(dir.clone()));
+        sidecar.limit(&mut sidecars, &mut Vec::new())?;
+
+        assert_eq!(
+            sidecars.as_dictionary().and_then(|dict| dict["large"].as_string()),
+            Some("!file ./com.example/large.bin")
+        );
+        assert_eq!(std::fs::read(dir.join("com.example/nested.large.bin"))?, vec![1; 64]);
+        assert!(dir.join("com.example/array.0.bin").exists());
+        assert_eq!(sidecar.references.len(), 3);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use color_eyre::eyre::Result;
+use colored::Colorize;
+
+use crate::catalog::{catalog, CatalogEntry};
+use crate::errors::DefaultsError as E;
+
+/// `explain` command.
+pub fn explain(domain: &str, key: Option<&str>) -> Result<()> {
+    //
+    let keys = catalog().get(domain).ok_or_else(|| E::NotInCatalog {
+        domain: domain.to_owned(),
+        key: None,
+    })?;
+
+    match key {
+        Some(key) => {
+            let entry = keys.get(key).ok_or_else(|| E::NotInCatalog {
+                domain: domain.to_owned(),
+                key: Some(key.to_owned()),
+            })?;
+
+            print_entry(domain, key, entry);
+        }
+        None => {
+            for (key, entry) in keys {
+                print_entry(domain, key, entry);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::print_stdout)]
+fn print_entry(domain: &str, key: &str, entry: &CatalogEntry) {
+    println!("{} {} {}", domain.bold(), key.bold().white(), format!("({})", entry.value_type).dimmed());
+    println!("  {}", entry.description);
+
+    for value in &entry.values {
+        println!("    {} {value}", "•".blue());
+    }
+
+    if let Some(kill) = &entry.kill {
+        println!("  Restart: {kill}");
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::SystemTime;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use plist::{Date, Dictionary, Value};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::cmd::apply::{is_domain_glob, is_plist, load_documents, process_path, DefaultsConfig};
+use crate::defaults::{raw_plist_domain, read_plist, ARRAY_ADD, BANG, DICT_ADD, ELLIPSIS, IF_UNSET, NS_GLOBAL_DOMAIN};
+use crate::errors::DefaultsError as E;
+use crate::secrets;
+use crate::tags::TagContext;
+
+/// Payload type of managed preferences, as in Profile Manager's custom settings.
+const MANAGED_PREFERENCES: &str = "com.apple.ManagedClient.preferences";
+
+/// The settings of one domain, by how they're managed.
+#[derive(Debug, Default, PartialEq)]
+struct ManagedDomain {
+    /// Enforced values, from plain keys.
+    forced: Dictionary,
+    /// Values set once, that the user can change afterwards, from `!once` keys and `once: true` documents.
+    once: Dictionary,
+}
+
+/// `export-profile` command: convert YAML files into a configuration profile of managed
+/// preferences, to deploy the same settings with MDM. Set-once settings are timestamped with
+/// `set_once_timestamp`, or else when the newest file was modified.
+pub fn export_profile(path: Utf8PathBuf, output: Option<Utf8PathBuf>, identifier: &str, name: &str, set_once_timestamp: Option<Date>) -> Result<()> {
+    //
+    let mut domains = BTreeMap::new();
+    let mut modified = SystemTime::UNIX_EPOCH;
+
+    for file in process_path(path)? {
+        debug!("Exporting {file}");
+        collect_file(&file, &mut domains)?;
+
+        if let Ok(file_modified) = fs::metadata(&file).and_then(|metadata| metadata.modified()) {
+            modified = modified.max(file_modified);
+        }
+    }
+
+    let profile = build_profile(&domains, identifier, name, set_once_timestamp.unwrap_or_else(|| modified.into()));
+
+    match output {
+        Some(path) => profile.to_file_xml(&path).map_err(|e| E::PlistWrite { path, source: e })?,
+        None => profile.to_writer_xml(std::io::stdout().lock())?,
+    }
+
+    Ok(())
+}
+
+/// Add the settings of a YAML or raw `.plist` file to `domains`; later files win, as when applying.
+fn collect_file(file: &Utf8PathBuf, domains: &mut BTreeMap<String, ManagedDomain>) -> Result<()> {
+    //
+    if is_plist(file) {
+        let domain = raw_plist_domain(file)?;
+
+        let Value::Dictionary(prefs) = read_plist(file)? else {
+            return Err(unsupported(file, domain.as_str(), None, "the plist isn't a dictionary"));
+        };
+
+        let managed = domains.entry(profile_domain(file, domain.as_str())?).or_default();
+
+        for (key, value) in prefs {
+            managed.once.remove(&key);
+            managed.forced.insert(key, value);
+        }
+
+        return Ok(());
+    }
+
+    for config in load_documents(file)? {
+        if let Some(reason) = config.skip_reason() {
+            debug!("Leaving out a document of {file}: {reason}");
+            continue;
+        }
+
+        if config.current_host {
+            return Err(E::UnsupportedInProfile {
+                path: file.clone(),
+                setting: "current_host".to_owned(),
+                reason: "profiles can't target the current host's preferences",
+            }
+            .into());
+        }
+
+        let data = config.data.ok_or_else(|| E::MissingData { path: file.clone() })?;
+        let defaults = DefaultsConfig::for_document(data, config.once, &TagContext::for_yaml_path(file))?;
+
+        for (domain, prefs) in defaults.0 {
+            if prefs.contains_key(BANG) {
+                return Err(unsupported(file, &domain, Some(BANG), "profiles can't remove other keys"));
+            }
+
+            let managed = domains.entry(profile_domain(file, &domain)?).or_default();
+
+            for (key, value) in prefs {
+                // A profile is a file anyone deploying it can read, so secrets don't go in it.
+                if secrets::contains_secret(&value) {
+                    warn!("Leaving {domain} {key} out of the profile, as its value is a secret.");
+                    managed.forced.remove(&key);
+                    managed.once.remove(&key);
+                    continue;
+                }
+
+                let (value, once) = match value {
+                    Value::Dictionary(mut dict) if dict.len() == 1 => match dict.remove(IF_UNSET) {
+                        Some(value) => (value, true),
+                        None => (Value::Dictionary(dict), false),
+                    },
+                    value => (value, false),
+                };
+
+                if let Some(reason) = merge_operator(&value) {
+                    return Err(unsupported(file, &domain, Some(&key), reason));
+                }
+
+                let (set, other) = if once {
+                    (&mut managed.once, &mut managed.forced)
+                } else {
+                    (&mut managed.forced, &mut managed.once)
+                };
+
+                other.remove(&key);
+                set.insert(key, strip_ellipsis_keys(value));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The name a profile uses for a domain: `.GlobalPreferences` for the global domain. Plist paths
+/// can't be managed.
+fn profile_domain(file: &Utf8Path, domain: &str) -> Result<String> {
+    if domain == NS_GLOBAL_DOMAIN {
+        return Ok(".GlobalPreferences".to_owned());
+    }
+
+    if domain.contains('/') || domain.starts_with('~') {
+        return Err(unsupported(file, domain, None, "profiles manage domains, not plist files"));
+    }
+
+    if is_domain_glob(domain) {
+        return Err(unsupported(file, domain, None, "profiles need exact domains, not globs"));
+    }
+
+    Ok(domain.to_owned())
+}
+
+/// Why a value can't be expressed in a profile, which only sets whole values: it merges with the
+/// existing value.
+fn merge_operator(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Dictionary(dict) if dict.contains_key(ARRAY_ADD) || dict.contains_key(DICT_ADD) => Some("profiles can't add to existing values"),
+        Value::Dictionary(dict) if dict.contains_key(BANG) => Some("profiles can't remove other keys"),
+        Value::Dictionary(dict) if dict.contains_key(IF_UNSET) => Some("only top level keys can be set once"),
+        Value::Dictionary(dict) => dict.values().find_map(merge_operator),
+        Value::Array(array) if array.iter().any(|v| v.as_string() == Some(ELLIPSIS)) => Some("profiles can't keep the existing array elements"),
+        Value::Array(array) => array.iter().find_map(merge_operator),
+        _ => None,
+    }
+}
+
+/// Remove the `"..."` keys dictionaries may have for compatibility, which merge by default anyway.
+fn strip_ellipsis_keys(value: Value) -> Value {
+    match value {
+        Value::Dictionary(dict) => Value::Dictionary(
+            dict.into_iter()
+                .filter(|(key, _)| key != ELLIPSIS)
+                .map(|(key, value)| (key, strip_ellipsis_keys(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+fn unsupported(file: &Utf8Path, domain: &str, key: Option<&str>, reason: &'static str) -> color_eyre::Report {
+    let setting = key.map_or_else(|| domain.to_owned(), |key| format!("{domain} {key}"));
+
+    E::UnsupportedInProfile {
+        path: file.to_owned(),
+        setting,
+        reason,
+    }
+    .into()
+}
+
+/// A configuration profile with a managed preferences payload per domain. UUIDs are derived from
+/// the identifier, so exporting again gives the same profile, which MDM servers treat as an update.
+fn build_profile(domains: &BTreeMap<String, ManagedDomain>, identifier: &str, name: &str, set_once_timestamp: Date) -> Value {
+    let uuid = |id: &str| Uuid::new_v5(&Uuid::NAMESPACE_URL, id.as_bytes()).hyphenated().to_string().to_uppercase();
+
+    let payloads = domains
+        .iter()
+        .map(|(domain, managed)| {
+            let payload_identifier = format!("{identifier}.{domain}");
+
+            let mut settings = Dictionary::new();
+
+            if !managed.forced.is_empty() {
+                settings.insert("Forced".to_owned(), Value::Array(vec![mcx_settings(&managed.forced, None)]));
+            }
+
+            if !managed.once.is_empty() {
+                settings.insert("Set-Once".to_owned(), Value::Array(vec![mcx_settings(&managed.once, Some(set_once_timestamp))]));
+            }
+
+            Value::Dictionary(Dictionary::from_iter([
+                (
+                    "PayloadContent",
+                    Value::Dictionary(Dictionary::from_iter([(domain.clone(), Value::Dictionary(settings))])),
+                ),
+                ("PayloadDisplayName", Value::from(domain.as_str())),
+                ("PayloadEnabled", Value::Boolean(true)),
+                ("PayloadIdentifier", Value::from(payload_identifier.as_str())),
+                ("PayloadType", Value::from(MANAGED_PREFERENCES)),
+                ("PayloadUUID", Value::from(uuid(&payload_identifier))),
+                ("PayloadVersion", Value::from(1)),
+            ]))
+        })
+        .collect();
+
+    Value::Dictionary(Dictionary::from_iter([
+        ("PayloadContent", Value::Array(payloads)),
+        ("PayloadDisplayName", Value::from(name)),
+        ("PayloadIdentifier", Value::from(identifier)),
+        ("PayloadType", Value::from("Configuration")),
+        ("PayloadUUID", Value::from(uuid(identifier))),
+        ("PayloadVersion", Value::from(1)),
+    ]))
+}
+
+/// An entry of a `Forced` or `Set-Once` list. Set-once settings need a timestamp, and are applied
+/// again when it changes.
+fn mcx_settings(settings: &Dictionary, timestamp: Option<Date>) -> Value {
+    let mut entry = Dictionary::from_iter([("mcx_preference_settings", Value::Dictionary(settings.clone()))]);
+
+    if let Some(timestamp) = timestamp {
+        entry.insert("mcx_data_timestamp".to_owned(), Value::Date(timestamp));
+    }
+
+    Value::Dictionary(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use camino::Utf8PathBuf;
+    use plist::{Date, Value};
+    use testresult::TestResult;
+
+    use super::{build_profile, collect_file};
+    use crate::secrets;
+
+    #[test]
+    fn test_export_profile() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let file = dir.join("dock.yaml");
+        std::fs::write(
+            &file,
+            "data:\n  com.apple.dock:\n    autohide: true\n    tilesize: !once 36\n  NSGlobalDomain:\n    AppleShowAllExtensions: true\n",
+        )?;
+
+        let mut domains = BTreeMap::new();
+        collect_file(&file, &mut domains)?;
+
+        let timestamp = Date::from_xml_format("2024-06-01T00:00:00Z")?;
+        let profile = build_profile(&domains, "com.example.defaults", "Example", timestamp);
+        let payloads = profile
+            .as_dictionary()
+            .and_then(|p| p.get("PayloadContent"))
+            .and_then(Value::as_array)
+            .ok_or("payloads")?;
+
+        assert_eq!(payloads.len(), 2);
+
+        let dock = payloads[1].as_dictionary().ok_or("dock payload")?;
+        assert_eq!(dock.get("PayloadIdentifier"), Some(&Value::from("com.example.defaults.com.apple.dock")));
+        assert_eq!(dock.get("PayloadType"), Some(&Value::from("com.apple.ManagedClient.preferences")));
+
+        let settings = dock
+            .get("PayloadContent")
+            .and_then(Value::as_dictionary)
+            .and_then(|c| c.get("com.apple.dock"))
+            .and_then(Value::as_dictionary)
+            .ok_or("dock settings")?;
+
+        let mcx = |kind: &str| {
+            settings
+                .get(kind)
+                .and_then(Value::as_array)
+                .and_then(|a| a[0].as_dictionary())
+                .and_then(|e| e.get("mcx_preference_settings"))
+                .cloned()
+        };
+
+        assert_eq!(
+            mcx("Forced"),
+            Some(Value::Dictionary(plist::Dictionary::from_iter([("autohide", Value::Boolean(true))])))
+        );
+        assert_eq!(
+            mcx("Set-Once"),
+            Some(Value::Dictionary(plist::Dictionary::from_iter([("tilesize", Value::from(36))])))
+        );
+
+        // The global domain is exported by its plist name.
+        let global = payloads[0].as_dictionary().and_then(|p| p.get("PayloadDisplayName")).ok_or("global payload")?;
+        assert_eq!(global, &Value::from(".GlobalPreferences"));
+
+        // Exporting again gives the same profile.
+        assert_eq!(profile, build_profile(&domains, "com.example.defaults", "Example", timestamp));
+
+        // Secrets are left out.
+        secrets::remember(Value::from("s3cret-profile-value"));
+        let secret = dir.join("secret.yaml");
+        std::fs::write(&secret, "data:\n  com.example.app:\n    token: s3cret-profile-value\n    user: me\n")?;
+        let mut domains = BTreeMap::new();
+        collect_file(&secret, &mut domains)?;
+        assert_eq!(
+            domains.get("com.example.app").map(|managed| &managed.forced),
+            Some(&plist::Dictionary::from_iter([("user", Value::from("me"))]))
+        );
+
+        // Merging with existing values can't be exported.
+        let merge = dir.join("merge.yaml");
+        std::fs::write(&merge, "data:\n  com.apple.dock:\n    persistent-apps: !array-add [a]\n")?;
+        assert!(collect_file(&merge, &mut BTreeMap::new()).is_err());
+
+        let path = dir.join("path.yaml");
+        std::fs::write(&path, "data:\n  /Library/Preferences/com.apple.loginwindow:\n    showInputMenu: true\n")?;
+        assert!(collect_file(&path, &mut BTreeMap::new()).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,28 @@
+use color_eyre::eyre::Result;
+use tracing::{debug, trace};
+
+use crate::defaults::{domains, find_key_paths, plist_path, read_plist};
+
+/// `find` command.
+#[allow(clippy::print_stdout)]
+pub fn find(needle: &str, current_host: bool) -> Result<()> {
+    //
+    for domain in domains(current_host)? {
+        let plist_path = plist_path(&domain, current_host)?;
+        trace!("Searching {domain} at {plist_path}");
+
+        let plist = match read_plist(&plist_path) {
+            Ok(plist) => plist,
+            Err(e) => {
+                debug!("Skipping unreadable plist {plist_path}: {e}");
+                continue;
+            }
+        };
+
+        for key_path in find_key_paths(&plist, needle) {
+            println!("{domain}: {key_path}");
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use tracing::{debug, warn};
+
+use crate::cmd::apply::{is_plist, load_documents, process_path, DefaultsConfig, STDIN_PATH};
+use crate::defaults::{add_containers, plist_path, raw_plist_domain, read_plist, resolve_domain};
+use crate::errors::DefaultsError as E;
+use crate::fingerprints::{Fingerprints, PlistFingerprint};
+use crate::tags::TagContext;
+
+/// `fingerprint` command: record a hash of every plist the YAML files set, and of the keys they
+/// set in it, for `status --fast` to compare against. `apply` does this for the files it applies,
+/// so it's only needed for changes made since in other ways.
+#[allow(clippy::print_stdout)]
+pub fn fingerprint(path: Utf8PathBuf) -> Result<()> {
+    //
+    let mut fingerprints = Fingerprints::load()?;
+
+    for file in process_path(path)? {
+        let plists = fingerprint_file(&file)?;
+
+        println!("{} {file}: {} plists", "✔".green(), plists.len());
+        fingerprints.record(&file, plists);
+    }
+
+    fingerprints.save()
+}
+
+/// Refresh the fingerprints of the files an apply just applied, so `status --fast` compares
+/// against what it wrote. Failing to only leaves older fingerprints, so it's a warning.
+pub fn refresh_fingerprints(files: &[Utf8PathBuf]) {
+    let refresh = || -> Result<()> {
+        let mut fingerprints = Fingerprints::load()?;
+
+        // Stdin has been read already.
+        for file in files.iter().filter(|file| file.as_str() != STDIN_PATH) {
+            fingerprints.record(file, fingerprint_file(file)?);
+        }
+
+        fingerprints.save()
+    };
+
+    if let Err(e) = refresh() {
+        warn!("Couldn't refresh the fingerprints for `status --fast`: {e}");
+    }
+}
+
+/// Fingerprint the plists a file sets.
+fn fingerprint_file(file: &Utf8PathBuf) -> Result<BTreeMap<Utf8PathBuf, PlistFingerprint>> {
+    debug!("Fingerprinting {file}");
+
+    let mut plists = BTreeMap::new();
+
+    for (plist_path, (domain, keys)) in managed_keys(file)? {
+        let fingerprint = PlistFingerprint::take(domain, &plist_path, keys.into_iter().collect())?;
+        plists.insert(plist_path, fingerprint);
+    }
+
+    Ok(plists)
+}
+
+/// The plists a file sets keys in, with their domain and the top level keys it sets.
+fn managed_keys(file: &Utf8PathBuf) -> Result<BTreeMap<Utf8PathBuf, (String, BTreeSet<String>)>> {
+    let mut plists: BTreeMap<Utf8PathBuf, (String, BTreeSet<String>)> = BTreeMap::new();
+
+    if is_plist(file) {
+        let domain = raw_plist_domain(file)?;
+        let keys = read_plist(file)?.into_dictionary().map(|dict| dict.into_iter().map(|(key, _)| key).collect());
+
+        plists.insert(plist_path(&domain, false)?, (domain.to_string(), keys.unwrap_or_default()));
+        return Ok(plists);
+    }
+
+    for config in load_documents(file)? {
+        if let Some(reason) = config.skip_reason() {
+            debug!("Skipping a document of {file}: {reason}");
+            continue;
+        }
+
+        let data = config.data.ok_or_else(|| E::MissingData { path: file.clone() })?;
+        add_containers(&config.containers, &config.group_containers);
+        let defaults = DefaultsConfig::for_document(data, config.once, &TagContext::for_yaml_path(file))?.expand_domain_globs(config.current_host);
+
+        for (domain, prefs) in defaults.0 {
+            let resolved = resolve_domain(&domain, config.current_host)?;
+            let path = plist_path(&resolved, config.current_host)?;
+
+            plists
+                .entry(path)
+                .or_insert_with(|| (resolved.to_string(), BTreeSet::new()))
+                .1
+                .extend(prefs.into_keys());
+        }
+    }
+
+    Ok(plists)
+}
+
+/// Plists whose managed keys changed since `file` was fingerprinted, by domain, or `None` if it
+/// hasn't been.
+pub(crate) fn changed_since_fingerprint(file: &Utf8Path, fingerprints: &Fingerprints) -> Result<Option<BTreeSet<String>>> {
+    let Some(plists) = fingerprints.get(file) else {
+        return Ok(None);
+    };
+
+    let mut changed = BTreeSet::new();
+
+    for (plist_path, fingerprint) in plists {
+        if fingerprint.changed(plist_path)? {
+            changed.insert(fingerprint.domain.clone());
+        }
+    }
+
+    Ok(Some(changed))
+}
@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use tracing::{debug, warn};
+
+use crate::cmd::apply::{is_plist, load_documents, process_path};
+use crate::defaults::raw_plist_domain;
+
+/// Output format of `graph`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum GraphFormat {
+    /// Graphviz, e.g. for `dot -Tsvg`.
+    #[default]
+    Dot,
+    /// Mermaid flowchart, which GitHub renders in Markdown.
+    Mermaid,
+}
+
+/// What a node of the graph stands for, which decides its shape.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    File,
+    Document,
+    Domain,
+    Kill,
+}
+
+/// Files, documents, domains and kill targets, and how they relate.
+#[derive(Debug, Default)]
+struct Graph {
+    /// Labels and kinds, indexed by node number.
+    nodes: Vec<(String, NodeKind)>,
+    /// (from, to, label)
+    edges: Vec<(usize, usize, &'static str)>,
+    /// Domain and kill target nodes are shared by the documents that name them.
+    shared: BTreeMap<(String, bool), usize>,
+}
+
+impl Graph {
+    fn add_node(&mut self, label: String, kind: NodeKind) -> usize {
+        self.nodes.push((label, kind));
+        self.nodes.len() - 1
+    }
+
+    fn shared_node(&mut self, label: &str, kind: NodeKind) -> usize {
+        let key = (label.to_owned(), kind == NodeKind::Kill);
+
+        if let Some(&node) = self.shared.get(&key) {
+            return node;
+        }
+
+        let node = self.add_node(label.to_owned(), kind);
+        self.shared.insert(key, node);
+        node
+    }
+}
+
+/// `graph` command: render how the files, documents, domains and kill targets of a YAML tree
+/// relate, and the `after` ordering between documents.
+#[allow(clippy::print_stdout)]
+pub fn graph(path: Utf8PathBuf, format: GraphFormat) -> Result<()> {
+    //
+    let graph = build_graph(path)?;
+
+    print!(
+        "{}",
+        match format {
+            GraphFormat::Dot => render_dot(&graph),
+            GraphFormat::Mermaid => render_mermaid(&graph),
+        }
+    );
+
+    Ok(())
+}
+
+fn build_graph(path: Utf8PathBuf) -> Result<Graph> {
+    let root = path.clone();
+    let mut graph = Graph::default();
+
+    // Document ids -> nodes, and the `after`s to resolve once every file has been read.
+    let mut ids: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    let mut afters = Vec::new();
+
+    for file in process_path(path)? {
+        debug!("Graphing {file}");
+
+        let file_node = graph.add_node(relative_name(&root, &file).to_string(), NodeKind::File);
+
+        if is_plist(&file) {
+            let domain = graph.shared_node(raw_plist_domain(&file)?.as_str(), NodeKind::Domain);
+            graph.edges.push((file_node, domain, "replaces"));
+            continue;
+        }
+
+        for (index, config) in load_documents(&file)?.into_iter().enumerate() {
+            let label = config.description.clone().unwrap_or_else(|| format!("document {}", index + 1));
+            let document = graph.add_node(label, NodeKind::Document);
+            graph.edges.push((file_node, document, ""));
+
+            if let Some(id) = config.id {
+                ids.entry(id).or_default().push(document);
+            }
+
+            afters.extend(config.after.into_iter().map(|id| (id, document)));
+
+            if let Some(serde_yaml::Value::Mapping(domains)) = &config.data {
+                for domain in domains.keys().filter_map(serde_yaml::Value::as_str) {
+                    let domain = graph.shared_node(domain, NodeKind::Domain);
+                    graph.edges.push((document, domain, "writes"));
+                }
+            }
+
+            for target in config.kill.iter().flatten() {
+                let target = graph.shared_node(target, NodeKind::Kill);
+                graph.edges.push((document, target, "restarts"));
+            }
+        }
+    }
+
+    for (id, document) in afters {
+        let Some(before) = ids.get(&id) else {
+            warn!("No document has the id {id}, which a document is ordered after.");
+            continue;
+        };
+
+        for &before in before {
+            graph.edges.push((before, document, "before"));
+        }
+    }
+
+    Ok(graph)
+}
+
+/// A file's path relative to the directory given, or the file itself.
+fn relative_name<'a>(root: &Utf8Path, file: &'a Utf8Path) -> &'a Utf8Path {
+    file.strip_prefix(root).ok().filter(|p| !p.as_str().is_empty()).unwrap_or(file)
+}
+
+fn render_dot(graph: &Graph) -> String {
+    let mut dot = String::from("digraph macos_defaults {\n  rankdir=LR;\n");
+
+    for (node, (label, kind)) in graph.nodes.iter().enumerate() {
+        let shape = match kind {
+            NodeKind::File => "note",
+            NodeKind::Document => "box",
+            NodeKind::Domain => "ellipse",
+            NodeKind::Kill => "octagon",
+        };
+
+        let _ = writeln!(dot, "  n{node} [label=\"{}\", shape={shape}];", escape_dot(label));
+    }
+
+    for (from, to, label) in &graph.edges {
+        if label.is_empty() {
+            let _ = writeln!(dot, "  n{from} -> n{to};");
+        } else {
+            let _ = writeln!(dot, "  n{from} -> n{to} [label=\"{label}\"];");
+        }
+    }
+
+    dot.push_str("}\n");
+    dot
+}
+
+fn render_mermaid(graph: &Graph) -> String {
+    let mut mermaid = String::from("flowchart LR\n");
+
+    for (node, (label, kind)) in graph.nodes.iter().enumerate() {
+        let label = escape_mermaid(label);
+
+        let _ = match kind {
+            NodeKind::File => writeln!(mermaid, "  n{node}[\"{label}\"]"),
+            NodeKind::Document => writeln!(mermaid, "  n{node}(\"{label}\")"),
+            NodeKind::Domain => writeln!(mermaid, "  n{node}([\"{label}\"])"),
+            NodeKind::Kill => writeln!(mermaid, "  n{node}{{{{\"{label}\"}}}}"),
+        };
+    }
+
+    for (from, to, label) in &graph.edges {
+        if label.is_empty() {
+            let _ = writeln!(mermaid, "  n{from} --> n{to}");
+        } else {
+            let _ = writeln!(mermaid, "  n{from} -->|{label}| n{to}");
+        }
+    }
+
+    mermaid
+}
+
+fn escape_dot(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn escape_mermaid(label: &str) -> String {
+    label.replace('"', "#quot;").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{build_graph, render_dot, render_mermaid};
+
+    #[test]
+    fn test_graph() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        std::fs::write(
+            dir.join("a.yaml"),
+            "id: global\ndescription: Global\ndata:\n  NSGlobalDomain:\n    AppleShowAllExtensions: true\n",
+        )?;
+        std::fs::write(
+            dir.join("b.yaml"),
+            "description: Dock\nafter: [global]\nkill: [Dock]\ndata:\n  com.apple.dock:\n    autohide: true\n  NSGlobalDomain:\n    AppleInterfaceStyle: Dark\n",
+        )?;
+
+        let graph = build_graph(dir.clone())?;
+
+        // Two files, two documents, two domains (the global one shared) and a kill target.
+        assert_eq!(graph.nodes.len(), 7);
+
+        let dot = render_dot(&graph);
+        assert!(dot.contains("n0 [label=\"a.yaml\", shape=note];"));
+        assert!(dot.contains("n6 [label=\"Dock\", shape=octagon];"));
+        assert!(dot.contains("n1 -> n4 [label=\"before\"];"));
+
+        let mermaid = render_mermaid(&graph);
+        assert!(mermaid.starts_with("flowchart LR\n"));
+        assert!(mermaid.contains("n2([\"NSGlobalDomain\"])"));
+        assert!(mermaid.contains("n4 -->|writes| n2"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use itertools::Itertools;
+
+use crate::catalog::lookup;
+use crate::cmd::apply::load_documents_from_reader;
+use crate::errors::DefaultsError as E;
+use crate::presets::{find_preset, BundledPreset};
+
+/// `init` command.
+#[allow(clippy::print_stdout)]
+pub fn init(path: &Utf8Path, preset: &str, force: bool) -> Result<()> {
+    //
+    let preset = find_preset(preset)?;
+
+    let files = preset_domains(preset)?
+        .iter()
+        .map(|(domain, values)| Ok((path.join(file_name(domain)), render_domain(preset, domain, values)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    if !force {
+        if let Some((existing, _)) = files.iter().find(|(file, _)| file.exists()) {
+            return Err(E::FileExists { path: existing.clone() }.into());
+        }
+    }
+
+    fs::create_dir_all(path).map_err(|e| E::DirCreation {
+        path: path.to_path_buf(),
+        source: e,
+    })?;
+
+    for (file, yaml) in files {
+        fs::write(&file, yaml)?;
+        println!("  {} {}", "✔".green(), file.as_str().white());
+    }
+
+    println!("\nReview the files, then run: macos-defaults apply {path}");
+
+    Ok(())
+}
+
+/// File name for a domain, e.g. `dock.yaml` for `com.apple.dock`.
+fn file_name(domain: &str) -> String {
+    let name = match domain {
+        "NSGlobalDomain" => "global",
+        _ => domain.strip_prefix("com.apple.").unwrap_or(domain),
+    };
+
+    format!("{}.yaml", name.to_lowercase())
+}
+
+/// The preset's values and processes to kill for each domain, from all of its documents.
+#[derive(Debug, Default)]
+struct PresetDomain {
+    kill: BTreeSet<String>,
+    keys: Vec<(String, serde_yaml::Value)>,
+}
+
+/// Group a preset's values by domain, as each starter file is for one domain.
+fn preset_domains(preset: &BundledPreset) -> Result<BTreeMap<String, PresetDomain>> {
+    //
+    let path = Utf8PathBuf::from(format!("preset:{}", preset.name));
+    let mut domains: BTreeMap<String, PresetDomain> = BTreeMap::new();
+
+    for document in load_documents_from_reader(preset.yaml.as_bytes(), &path)? {
+        let Some(serde_yaml::Value::Mapping(data)) = document.data else {
+            continue;
+        };
+
+        for (domain, keys) in data {
+            let (Some(domain), serde_yaml::Value::Mapping(keys)) = (domain.as_str(), keys) else {
+                continue;
+            };
+
+            let entry = domains.entry(domain.to_owned()).or_default();
+            entry.kill.extend(document.kill.iter().flatten().cloned());
+            entry
+                .keys
+                .extend(keys.into_iter().filter_map(|(key, value)| Some((key.as_str()?.to_owned(), value))));
+        }
+    }
+
+    Ok(domains)
+}
+
+/// A YAML document with the preset's values for a domain, commented from the built-in catalog.
+fn render_domain(preset: &BundledPreset, domain: &str, values: &PresetDomain) -> Result<String> {
+    //
+    let mut yaml = format!(
+        "# Generated by `macos-defaults init --preset {}` from v{} of the bundled preset.\n---\n",
+        preset.name, preset.version
+    );
+
+    writeln!(yaml, "description: {}", scalar(&domain.into()))?;
+
+    let kill = values
+        .keys
+        .iter()
+        .filter_map(|(key, _)| lookup(domain, key)?.kill.clone())
+        .chain(values.kill.iter().cloned())
+        .unique()
+        .sorted()
+        .join(", ");
+
+    if !kill.is_empty() {
+        writeln!(yaml, "kill: [{kill}]")?;
+    }
+
+    writeln!(yaml, "\ndata:\n  {}:", scalar(&domain.into()))?;
+
+    for (key, value) in &values.keys {
+        if let Some(entry) = lookup(domain, key) {
+            writeln!(yaml, "    # {}", entry.description)?;
+
+            if !entry.values.is_empty() {
+                writeln!(yaml, "    # Values: {}", entry.values.join(", "))?;
+            }
+        }
+
+        writeln!(yaml, "    {}: {}", scalar(&key.as_str().into()), scalar(value))?;
+    }
+
+    Ok(yaml)
+}
+
+/// Render a scalar YAML value, quoted if needed.
+fn scalar(value: &serde_yaml::Value) -> String {
+    serde_yaml::to_string(value).map(|s| s.trim_end().to_owned()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{file_name, preset_domains, render_domain};
+    use crate::cmd::apply::load_documents_from_reader;
+    use crate::presets::find_preset;
+
+    #[test]
+    fn test_render_domain() -> TestResult {
+        let path = Utf8PathBuf::from("dock.yaml");
+        let preset = find_preset("developer-workstation")?;
+        let domains = preset_domains(preset)?;
+
+        let yaml = render_domain(preset, "com.apple.dock", &domains["com.apple.dock"])?;
+        assert!(yaml.contains("    # Automatically hide and show the Dock.\n    autohide: true\n"));
+
+        let documents = load_documents_from_reader(yaml.as_bytes(), &path)?;
+        assert_eq!(documents.len(), 1);
+        assert_eq!(documents[0].kill.as_deref(), Some(&["Dock".to_owned()][..]));
+
+        let data = documents[0].data.as_ref().ok_or("has data")?;
+        assert_eq!(data["com.apple.dock"]["autohide-delay"], serde_yaml::Value::from(0.0));
+
+        // Values from several of the preset's documents end up in the domain's one file.
+        let global = &domains["NSGlobalDomain"];
+        assert!(global.keys.iter().any(|(key, _)| key == "KeyRepeat"));
+        assert!(global.keys.iter().any(|(key, _)| key == "AppleShowAllExtensions"));
+        assert!(global.kill.contains("Finder"));
+
+        assert!(!preset_domains(find_preset("minimal")?)?.contains_key("com.apple.menuextra.clock"));
+
+        assert_eq!(file_name("NSGlobalDomain"), "global.yaml");
+        assert_eq!(file_name("com.apple.AdLib"), "adlib.yaml");
+
+        Ok(())
+    }
+}
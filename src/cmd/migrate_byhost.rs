@@ -0,0 +1,47 @@
+use color_eyre::eyre::Result;
+use colored::Colorize;
+
+use crate::defaults::{byhost_plist_paths, get_hardware_uuid, is_hardware_uuid, migrate_byhost_plist, ByHostMigration};
+use crate::errors::DefaultsError as E;
+
+/// `migrate-byhost` command: move the `ByHost` plists of the hardware UUID `from`, e.g. from before a
+/// logic board replacement, to the current Mac's hardware UUID.
+#[allow(clippy::print_stdout)]
+pub fn migrate_byhost(from: &str, dry_run: bool) -> Result<()> {
+    //
+    if !is_hardware_uuid(from) {
+        return Err(E::InvalidHardwareUuid {
+            uuid: from.to_owned(),
+            reason: "expected a UUID like 01234567-89AB-CDEF-0123-456789ABCDEF",
+        }
+        .into());
+    }
+
+    let current = get_hardware_uuid()?;
+
+    if current.eq_ignore_ascii_case(from) {
+        return Err(E::InvalidHardwareUuid {
+            uuid: from.to_owned(),
+            reason: "it's the current Mac's hardware UUID, so there's nothing to migrate",
+        }
+        .into());
+    }
+
+    let paths = byhost_plist_paths(from)?;
+
+    if paths.is_empty() {
+        println!("No ByHost plists found for {from}.");
+        return Ok(());
+    }
+
+    let verb = |done: &'static str, planned: &'static str| if dry_run { planned } else { done };
+
+    for old_path in paths {
+        match migrate_byhost_plist(&old_path, &current, dry_run)? {
+            ByHostMigration::Renamed(new_path) => println!("{} {} {old_path} -> {new_path}", "✔".green(), verb("Renamed", "Would rename")),
+            ByHostMigration::Merged(new_path) => println!("{} {} {old_path} into {new_path}", "✔".green(), verb("Merged", "Would merge")),
+        }
+    }
+
+    Ok(())
+}
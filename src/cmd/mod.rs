@@ -1,5 +1,45 @@
+pub mod agent;
 pub mod apply;
+pub mod completions;
+pub mod convert;
+pub mod diff;
+pub mod docs;
+pub mod doctor;
 pub mod dump;
+pub mod explain;
+pub mod export_profile;
+pub mod find;
+pub mod fingerprint;
+pub mod graph;
+pub mod init;
+pub mod migrate_byhost;
+pub mod plan;
+pub mod preset;
+pub mod restart;
+pub mod schema;
+pub mod status;
+pub mod teardown;
+pub mod upgrade_impact;
 
-pub use apply::{apply_defaults, process_path};
-pub use dump::dump;
+pub use agent::{install_agent, uninstall_agent};
+pub use apply::{apply_files, apply_preset, process_path, restart_processes, ApplyOptions, ApplySummary, ReportFormat};
+pub use completions::{complete, completions, CompletionKind};
+pub use convert::{convert, PlistFormat};
+pub use diff::{diff, DiffFormat};
+pub use docs::docs;
+pub use doctor::doctor;
+pub use dump::{dump, DumpFormat, DumpOptions, LargeValues};
+pub use explain::explain;
+pub use export_profile::export_profile;
+pub use find::find;
+pub use fingerprint::fingerprint;
+pub use graph::{graph, GraphFormat};
+pub use init::init;
+pub use migrate_byhost::migrate_byhost;
+pub use plan::{assert_idempotent, plan, OutputFormat, PlanFormat};
+pub use preset::{preset_list, preset_show};
+pub use restart::restart;
+pub use schema::schema;
+pub use status::status;
+pub use teardown::teardown;
+pub use upgrade_impact::upgrade_impact;
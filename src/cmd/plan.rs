@@ -0,0 +1,323 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+
+use camino::Utf8PathBuf;
+use clap::ValueEnum;
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use itertools::Itertools;
+use plist::{Dictionary, Value};
+use serde::Serialize;
+
+use crate::ansible::AnsibleReport;
+use crate::cmd::apply::{load_documents_from_reader, preset_path};
+use crate::cmd::ApplyOptions;
+use crate::errors::DefaultsError as E;
+use crate::planner::{Plan, Planner};
+use crate::presets::BundledPreset;
+use crate::schedule::{load_scheduled, Document, SourceDocument};
+use crate::tree::{render_change, render_removal};
+
+/// Output format of machine-readable reports, like `plan`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+impl OutputFormat {
+    /// Print a report to stdout.
+    #[allow(clippy::print_stdout)]
+    pub fn print<T: Serialize>(self, report: &T) -> Result<()> {
+        match self {
+            Self::Yaml => print!("{}", serde_yaml::to_string(report)?),
+            Self::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        }
+
+        Ok(())
+    }
+}
+
+/// Output format of `plan`.
+#[derive(Debug, Default, Clone, Copy, ValueEnum)]
+pub enum PlanFormat {
+    #[default]
+    Yaml,
+    Json,
+    /// The result an Ansible module returns in check mode: `changed`, `msg`, and a `diff` of each
+    /// domain's keys.
+    Ansible,
+    /// A colored tree of the changes, for reading.
+    Tree,
+}
+
+/// `plan` command: print what applying the presets and then the files would do, without changing anything.
+pub fn plan(presets: &[&BundledPreset], files: &[Utf8PathBuf], options: &ApplyOptions, format: PlanFormat) -> Result<()> {
+    //
+    let plan = match plan_sources(&mut Planner::default(), presets, files, options) {
+        Ok(plan) => plan,
+        Err(error) => {
+            // Ansible only reads stdout, so the error is reported there too.
+            if matches!(format, PlanFormat::Ansible) {
+                OutputFormat::Json.print(&AnsibleReport::failure(&error.to_string())?)?;
+            }
+
+            return Err(error);
+        }
+    };
+
+    match format {
+        PlanFormat::Yaml => OutputFormat::Yaml.print(&plan),
+        PlanFormat::Json => OutputFormat::Json.print(&plan),
+        PlanFormat::Ansible => OutputFormat::Json.print(&ansible_report(&plan).result()?),
+        PlanFormat::Tree => {
+            print_tree(&plan);
+            Ok(())
+        }
+    }
+}
+
+/// Each document's changes, domain by domain, as a tree.
+#[allow(clippy::print_stdout)]
+fn print_tree(plan: &Plan) {
+    print!("{}", render_tree(plan));
+}
+
+fn render_tree(plan: &Plan) -> String {
+    let mut tree = String::new();
+
+    for document in plan
+        .documents
+        .iter()
+        .filter(|document| document.changes_anything() || document.domains.iter().any(|domain| domain.error.is_some()))
+    {
+        let _ = match &document.description {
+            Some(description) => writeln!(
+                tree,
+                "{} {} {}",
+                "▶".green(),
+                description.bold().white(),
+                format!("({})", document.source).dimmed()
+            ),
+            None => writeln!(tree, "{} {}", "▶".green(), document.source.as_str().bold().white()),
+        };
+
+        for domain in &document.domains {
+            if let Some(error) = &domain.error {
+                let _ = writeln!(tree, "  {} {}: {error}", "✖".red(), domain.domain);
+            }
+
+            for write in &domain.writes {
+                let _ = writeln!(tree, "  {} {}", write.domain.to_string().bold(), format!("({})", write.path).dimmed());
+
+                for change in &write.changes {
+                    let (old_value, new_value) = change.shown_values();
+                    tree.push_str(&render_change(&change.key, old_value.as_deref(), &new_value, 4));
+                }
+
+                for key in &write.removed {
+                    tree.push_str(&render_removal(key, 4));
+                }
+            }
+        }
+
+        if !document.kill.is_empty() {
+            let _ = writeln!(tree, "  {} Restarting: {}", "✖".blue(), document.kill.join(", "));
+        }
+    }
+
+    if tree.is_empty() {
+        tree.push_str("Nothing to change.\n");
+    }
+
+    tree
+}
+
+/// The changes and errors of a plan, as `apply --report ansible` would report them.
+fn ansible_report(plan: &Plan) -> AnsibleReport {
+    let mut report = AnsibleReport::default();
+
+    for domain in plan.documents.iter().flat_map(|document| &document.domains) {
+        if let Some(error) = &domain.error {
+            report.add_error(&domain.domain, &error.to_string());
+        }
+
+        for write in &domain.writes {
+            for change in &write.changes {
+                let (old_value, new_value) = change.shown_values();
+                report.add_change(write.domain.as_str(), &change.key, old_value.as_deref(), &new_value);
+            }
+        }
+    }
+
+    report
+}
+
+/// `apply --assert-idempotent`: plan the apply twice, the second time against the result of the
+/// first, and fail if the second would still leave any plist different, e.g. because of `!uuid`
+/// or `!now`. Only the final state of each plist is compared, so documents overriding each other's
+/// keys don't count. Keys matching the drift ignore patterns are left out, and volatile keys the
+/// documents don't set.
+pub fn assert_idempotent(presets: &[&BundledPreset], files: &[Utf8PathBuf], options: &ApplyOptions) -> Result<()> {
+    //
+    let mut planner = Planner::default();
+
+    plan_sources(&mut planner, presets, files, options)?;
+    let first = planner.planned().clone();
+
+    let second = plan_sources(&mut planner, presets, files, options)?;
+
+    // The last document to write each plist, and its domain's drift ignore patterns.
+    let mut writers = BTreeMap::new();
+    // The keys of each plist the documents set, rather than e.g. remove by overwriting a domain.
+    let mut managed = BTreeSet::new();
+
+    for document in &second.documents {
+        for domain in &document.domains {
+            for write in &domain.writes {
+                writers.insert(&write.path, (&document.source, &write.domain, domain.drift_ignore.as_deref()));
+                managed.extend(write.changes.iter().map(|change| (&write.path, &change.key)));
+            }
+        }
+    }
+
+    let mut keys = Vec::new();
+
+    for (path, (source, domain, patterns)) in writers {
+        // Plists the first pass didn't change are as they are on disk.
+        let before = match first.get(path) {
+            Some(value) => Some(value.clone()),
+            None => options.write.backend.read(path)?,
+        };
+
+        let before = before.as_ref().and_then(Value::as_dictionary);
+        let after = planner.planned().get(path).and_then(Value::as_dictionary);
+
+        keys.extend(
+            before
+                .into_iter()
+                .chain(after)
+                .flat_map(Dictionary::keys)
+                .unique()
+                .filter(|key| before.and_then(|dict| dict.get(key)) != after.and_then(|dict| dict.get(key)))
+                .filter(|key| !options.drift_ignore.is_ignored(key, patterns, managed.contains(&(path, *key))))
+                .map(|key| format!("{source}: {domain} {key}")),
+        );
+    }
+
+    if keys.is_empty() {
+        return Ok(());
+    }
+
+    Err(E::NotIdempotent { keys: keys.join("\n") }.into())
+}
+
+/// Plan applying the presets and then the files, on top of what the planner already planned.
+pub(crate) fn plan_sources(planner: &mut Planner, presets: &[&BundledPreset], files: &[Utf8PathBuf], options: &ApplyOptions) -> Result<Plan> {
+    //
+    let mut plan = Plan::default();
+
+    for preset in presets {
+        let path = preset_path(preset);
+
+        for config in load_documents_from_reader(preset.yaml.as_bytes(), &path)? {
+            plan.documents.push(planner.plan_document(config, &path, options)?);
+        }
+    }
+
+    for SourceDocument { path, document, .. } in load_scheduled(files)? {
+        plan.documents.push(match document {
+            Document::Plist => planner.plan_plist_file(&path, options)?,
+            Document::Yaml(config) => planner.plan_document(*config, &path, options)?,
+        });
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{assert_idempotent, plan_sources};
+    use crate::cmd::ApplyOptions;
+    use crate::planner::Planner;
+
+    #[test]
+    fn test_assert_idempotent() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.idempotent.plist");
+
+        let stable = dir.join("stable.yaml");
+        std::fs::write(
+            &stable,
+            format!("data:\n  {plist_path}:\n    id: !uuid-stable seed\n    first_run: !now {{if_unset: true}}\n"),
+        )?;
+        assert_idempotent(&[], &[stable], &ApplyOptions::default())?;
+
+        let random = dir.join("random.yaml");
+        std::fs::write(&random, format!("data:\n  {plist_path}:\n    id: !uuid\n"))?;
+        assert!(assert_idempotent(&[], &[random], &ApplyOptions::default()).is_err());
+
+        // Unless the key is one drift comparisons ignore.
+        let ignored = dir.join("ignored.yaml");
+        std::fs::write(
+            &ignored,
+            format!("drift_ignore:\n  {plist_path}: [id]\ndata:\n  {plist_path}:\n    id: !uuid\n"),
+        )?;
+        assert_idempotent(&[], &[ignored], &ApplyOptions::default())?;
+
+        // The built-in volatile keys aren't ignored when a document sets them.
+        let builtin = dir.join("builtin.yaml");
+        std::fs::write(&builtin, format!("data:\n  {plist_path}:\n    LastLaunchTimestamp: !now\n"))?;
+        assert!(assert_idempotent(&[], &[builtin], &ApplyOptions::default()).is_err());
+
+        // A later document overriding a key of an earlier one still ends up the same.
+        let layered = dir.join("layered.yaml");
+        std::fs::write(
+            &layered,
+            format!("data:\n  {plist_path}:\n    theme: light\n---\ndata:\n  {plist_path}:\n    theme: dark\n"),
+        )?;
+        assert_idempotent(&[], &[layered], &ApplyOptions::default())?;
+
+        // Nothing is written.
+        assert!(!plist_path.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_create() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.new.plist");
+
+        let options = ApplyOptions {
+            no_create: true,
+            ..ApplyOptions::default()
+        };
+
+        let missing = [dir.join("missing.yaml")];
+        std::fs::write(&missing[0], format!("data:\n  {plist_path}:\n    a: 1\n"))?;
+        let plan = plan_sources(&mut Planner::default(), &[], &missing, &options)?;
+        assert!(plan.documents[0].domains[0].error.is_some());
+
+        // Allowed without --no-create, or with `create: true`.
+        let plan = plan_sources(&mut Planner::default(), &[], &missing, &ApplyOptions::default())?;
+        assert!(plan.documents[0].domains[0].error.is_none());
+
+        let create = dir.join("create.yaml");
+        std::fs::write(
+            &create,
+            format!("create: true\ndata:\n  {plist_path}:\n    a: 1\n---\ndata:\n  {plist_path}:\n    b: 2\n"),
+        )?;
+        let plan = plan_sources(&mut Planner::default(), &[], &[create], &options)?;
+        // The second document sees the plist the first one creates.
+        assert!(plan.documents.iter().all(|document| document.domains[0].error.is_none()));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,32 @@
+use color_eyre::eyre::Result;
+use colored::Colorize;
+
+use crate::presets::{find_preset, PRESETS};
+
+/// `preset list` command.
+#[allow(clippy::print_stdout)]
+pub fn preset_list() {
+    //
+    let width = PRESETS.iter().map(|preset| preset.name.len()).max().unwrap_or_default();
+
+    for preset in PRESETS {
+        println!(
+            "{} {} {}",
+            format!("{:width$}", preset.name).bold(),
+            format!("v{}", preset.version).dimmed(),
+            preset.summary
+        );
+    }
+}
+
+/// `preset show` command, printing the preset's YAML so it can be reviewed or copied.
+#[allow(clippy::print_stdout)]
+pub fn preset_show(name: &str) -> Result<()> {
+    //
+    let preset = find_preset(name)?;
+
+    println!("# {} v{}", preset.name, preset.version);
+    print!("{}", preset.yaml);
+
+    Ok(())
+}
@@ -0,0 +1,391 @@
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use duct::{cmd, Expression};
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessesToUpdate, Signal, System, UpdateKind};
+use tracing::{debug, warn};
+
+use crate::cmd::agent::gui_domain;
+use crate::defaults::{home_dir, prefs_owner_uid, prefs_user, target_root};
+use crate::errors::DefaultsError as E;
+
+/// `restart` command: restart processes so they pick up hand-edited preferences, as a document's
+/// `kill` list does after applying it.
+#[allow(clippy::print_stdout)]
+pub fn restart(targets: &[String]) -> Result<()> {
+    //
+    let mut not_running = Vec::new();
+
+    for target in targets {
+        let killed = kill_processes(target);
+
+        if killed.is_empty() {
+            println!("    {} Not running: {}", "-".dimmed(), target.white());
+            not_running.push(target.clone());
+            continue;
+        }
+
+        for (pid, name) in &killed {
+            println!("    {} Restarting: {} ({pid})", "✖".blue(), name.white());
+        }
+
+        kill_if_still_running(&killed);
+    }
+
+    if not_running.is_empty() {
+        return Ok(());
+    }
+
+    Err(E::NoMatchingProcess {
+        targets: not_running.join(", "),
+    }
+    .into())
+}
+
+/// How long processes sent `SIGTERM` by `restart` get to exit before they're sent `SIGKILL`.
+const RESTART_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Wait for processes sent `SIGTERM` to exit, and send `SIGKILL` to any still running after
+/// [`RESTART_TIMEOUT`], as a hung process ignores the former.
+fn kill_if_still_running(killed: &[(Pid, String)]) {
+    let deadline = Instant::now() + RESTART_TIMEOUT;
+
+    loop {
+        let sys = running_processes();
+        let running: Vec<_> = killed.iter().filter_map(|(pid, name)| Some((sys.process(*pid)?, name))).collect();
+
+        if running.is_empty() {
+            return;
+        }
+
+        if Instant::now() >= deadline {
+            for (process, name) in running {
+                warn!(
+                    "{name} ({}) didn't exit within {} seconds, killing it.",
+                    process.pid(),
+                    RESTART_TIMEOUT.as_secs()
+                );
+                process.kill();
+            }
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+/// Prefix of kill targets that are restarted through `launchd` rather than signalled, e.g.
+/// `service:SystemUIServer`, as `launchd` may throttle relaunching jobs that keep exiting.
+pub(crate) const SERVICE_PREFIX: &str = "service:";
+
+/// Send `SIGTERM` to every process named exactly `target`, or, if `target` is a bundle identifier
+/// like `com.apple.dock`, running from an app bundle with that identifier. macOS relaunches the
+/// ones it manages, like the Dock and Finder. `service:` targets are restarted with
+/// [`kickstart_service`] instead. Nothing is restarted under a `--target-root`.
+///
+/// Returns the PID and name of each process signalled.
+pub fn kill_processes(target: &str) -> Vec<(Pid, String)> {
+    if target_root().is_some() {
+        debug!("Not restarting {target} under a target root");
+        return Vec::new();
+    }
+
+    if let Some(service) = target.strip_prefix(SERVICE_PREFIX) {
+        return kickstart_service(service);
+    }
+
+    let sys = running_processes();
+
+    matching_processes(&sys, target)
+        .filter_map(|process| {
+            let name = process.name().to_string_lossy().into_owned();
+            debug!("Process running: {} {name}", process.pid());
+
+            process.kill_with(Signal::Term).filter(|killed| *killed).map(|_| (process.pid(), name))
+        })
+        .collect()
+}
+
+/// Send `SIGTERM` to the `cfprefsd` of the user whose preferences are written (`--user`'s, or
+/// the current user's), rather than to every user's and the system's, as [`kill_processes`] would.
+///
+/// Returns the PID of each process signalled.
+pub(crate) fn restart_cfprefsd() -> Vec<Pid> {
+    if target_root().is_some() {
+        debug!("Not restarting cfprefsd under a target root");
+        return Vec::new();
+    }
+
+    let sys = running_processes();
+
+    let Some(owner) = prefs_owner_uid().or_else(current_uid) else {
+        warn!("Unable to tell which user's cfprefsd to restart");
+        return Vec::new();
+    };
+
+    matching_processes(&sys, "cfprefsd")
+        .filter(|process| process.user_id().is_some_and(|uid| **uid == owner))
+        .filter_map(|process| process.kill_with(Signal::Term).filter(|killed| *killed).map(|_| process.pid()))
+        .collect()
+}
+
+/// The uid this process runs as.
+pub(crate) fn current_uid() -> Option<u32> {
+    let own_pid = sysinfo::get_current_pid().ok()?;
+
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(ProcessesToUpdate::Some(&[own_pid]), ProcessRefreshKind::new().with_user(UpdateKind::Always));
+
+    sys.process(own_pid)?.effective_user_id().map(|uid| **uid)
+}
+
+/// Restart the running `launchd` job of the user's GUI session for `service`, a job label (e.g.
+/// `com.apple.SystemUIServer.agent`) or the name of the process it runs (e.g. `SystemUIServer`),
+/// with `launchctl kickstart -k`.
+///
+/// Returns the PID and label of the job restarted, if it was running.
+fn kickstart_service(service: &str) -> Vec<(Pid, String)> {
+    // The user's session, rather than root's under `--user`.
+    let domain = match home_dir().and_then(|home_dir| gui_domain(&home_dir)) {
+        Ok(domain) => domain,
+        Err(e) => {
+            warn!("Unable to find the launchd domain to restart {service} in: {e}");
+            return Vec::new();
+        }
+    };
+
+    let print = match cmd!("launchctl", "print", &domain).stderr_null().read() {
+        Ok(print) => print,
+        Err(e) => {
+            warn!("Unable to list the launchd jobs of {domain}: {e}");
+            return Vec::new();
+        }
+    };
+
+    let pids: Vec<u32> = matching_processes(&running_processes(), service)
+        .map(|process| process.pid().as_u32())
+        .collect();
+
+    let Some((pid, label)) = launchd_job(&print, service, &pids) else {
+        debug!("No running launchd job for {service} in {domain}");
+        return Vec::new();
+    };
+
+    let target = format!("{domain}/{label}");
+
+    debug!("Restarting {target}");
+
+    match cmd!("launchctl", "kickstart", "-k", &target).stdout_null().stderr_capture().run() {
+        Ok(_) => vec![(Pid::from_u32(pid), label)],
+        Err(e) => {
+            warn!("Unable to restart {target} with launchctl: {e}");
+            Vec::new()
+        }
+    }
+}
+
+/// The PID and label of the running job in the `services` of `launchctl print` output whose label
+/// is `service`, or whose PID is one of `pids`.
+fn launchd_job(print: &str, service: &str, pids: &[u32]) -> Option<(u32, String)> {
+    print
+        .lines()
+        .skip_while(|line| line.trim() != "services = {")
+        .skip(1)
+        .take_while(|line| line.trim() != "}")
+        .find_map(|line| {
+            // The PID, the last exit status, and the label.
+            let mut fields = line.split_whitespace();
+            let pid = fields.next()?.parse::<u32>().ok().filter(|pid| *pid != 0)?;
+            let label = fields.nth(1)?;
+
+            (label == service || pids.contains(&pid)).then(|| (pid, label.to_owned()))
+        })
+}
+
+/// How long an app gets to quit gracefully before it's killed.
+const QUIT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ask a running app (by name or bundle identifier) to quit with `osascript`, so it saves its state
+/// before its preferences change rather than overwriting them when it quits later. Killed if it
+/// hasn't quit within [`QUIT_TIMEOUT`].
+pub(crate) fn quit_app(target: &str) {
+    let script = format!(
+        "with timeout of {} seconds\ntell application {} to quit\nend timeout",
+        QUIT_TIMEOUT.as_secs(),
+        applescript_app(target)
+    );
+
+    if let Err(e) = as_prefs_owner("osascript", &["-e", &script]).stdout_null().stderr_capture().run() {
+        debug!("osascript couldn't quit {target}: {e}");
+    }
+
+    let deadline = Instant::now() + QUIT_TIMEOUT;
+
+    while is_running(target) && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(200));
+    }
+
+    if is_running(target) {
+        warn!("{target} didn't quit within {} seconds, killing it.", QUIT_TIMEOUT.as_secs());
+        kill_processes(target);
+    }
+}
+
+/// Launch an app by name or bundle identifier, in the background.
+pub(crate) fn relaunch_app(target: &str) -> Result<(), E> {
+    let by = if is_bundle_id(target) { "-b" } else { "-a" };
+
+    as_prefs_owner("open", &["-g", by, target])
+        .stdout_null()
+        .run()
+        .map(|_| ())
+        .map_err(|e| E::AppLaunch {
+            app: target.to_owned(),
+            source: e,
+        })
+}
+
+/// A command run by the user whose preferences are written. Under `--user`, where this runs as
+/// root, that's in their GUI session with `launchctl asuser`, as them, so apps are quit and
+/// launched in their session rather than root's.
+fn as_prefs_owner(program: &str, args: &[&str]) -> Expression {
+    match (prefs_owner_uid(), prefs_user()) {
+        (Some(uid), Some(user)) => {
+            let uid = uid.to_string();
+            let user = format!("--user={user}");
+            cmd("launchctl", ["asuser", &uid, "sudo", &user, program].iter().chain(args))
+        }
+        _ => cmd(program, args),
+    }
+}
+
+/// Whether a process named `target`, or an app with the bundle identifier `target`, is running.
+/// Never under a `--target-root`, so apps aren't quit.
+pub(crate) fn is_running(target: &str) -> bool {
+    target_root().is_none() && matching_processes(&running_processes(), target).next().is_some()
+}
+
+fn running_processes() -> System {
+    let mut sys = System::new();
+    sys.refresh_processes_specifics(
+        ProcessesToUpdate::All,
+        ProcessRefreshKind::new().with_exe(UpdateKind::OnlyIfNotSet).with_user(UpdateKind::OnlyIfNotSet),
+    );
+    sys
+}
+
+/// Processes, other than this one, named exactly `target` or running from an app bundle with the
+/// bundle identifier `target`. With `--user`, only that user's processes.
+fn matching_processes<'a>(sys: &'a System, target: &'a str) -> impl Iterator<Item = &'a Process> {
+    let own_pid = sysinfo::get_current_pid().ok();
+    let owner = prefs_owner_uid();
+
+    sys.processes().values().filter(move |process| {
+        Some(process.pid()) != own_pid
+            && owner.map_or(true, |owner| process.user_id().is_some_and(|uid| **uid == owner))
+            && (process.name() == OsStr::from_bytes(target.as_bytes())
+                || (is_bundle_id(target) && process.exe().and_then(app_bundle).and_then(bundle_identifier).as_deref() == Some(target)))
+    })
+}
+
+fn is_bundle_id(target: &str) -> bool {
+    target.contains('.')
+}
+
+/// What follows `application` in an `osascript` script: `id "com.apple.Safari"` or `"Safari"`.
+fn applescript_app(target: &str) -> String {
+    let quoted = target.replace('\\', "\\\\").replace('"', "\\\"");
+
+    if is_bundle_id(target) {
+        format!("id \"{quoted}\"")
+    } else {
+        format!("\"{quoted}\"")
+    }
+}
+
+/// The innermost `.app` bundle an executable is in, e.g. `/System/Library/CoreServices/Dock.app`.
+fn app_bundle(exe: &Path) -> Option<&Path> {
+    exe.ancestors().skip(1).find(|path| path.extension() == Some(OsStr::new("app")))
+}
+
+/// The `CFBundleIdentifier` of an app bundle.
+fn bundle_identifier(app: &Path) -> Option<String> {
+    let info = plist::Value::from_file(app.join("Contents/Info.plist")).ok()?;
+    info.as_dictionary()?.get("CFBundleIdentifier")?.as_string().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{app_bundle, applescript_app, bundle_identifier, launchd_job};
+
+    #[test]
+    fn test_applescript_app() {
+        assert_eq!(applescript_app("Safari"), r#""Safari""#);
+        assert_eq!(applescript_app("com.apple.Safari"), r#"id "com.apple.Safari""#);
+        assert_eq!(applescript_app(r#"My "App""#), r#""My \"App\"""#);
+    }
+
+    #[test]
+    fn test_launchd_job() {
+        let print = "gui/501 = {
+\ttype = login
+\tservices = {
+\t\t       0      -  \tcom.apple.Finder
+\t\t     712      -  \tcom.apple.SystemUIServer.agent
+\t\t     834     -9  \tcom.apple.controlcenter
+\t}
+
+\tendpoints = {
+\t\t     999      -  \tcom.apple.example.endpoint
+\t}
+}
+";
+
+        assert_eq!(
+            launchd_job(print, "SystemUIServer", &[712]),
+            Some((712, "com.apple.SystemUIServer.agent".to_owned()))
+        );
+        assert_eq!(
+            launchd_job(print, "com.apple.controlcenter", &[]),
+            Some((834, "com.apple.controlcenter".to_owned()))
+        );
+        // Jobs that aren't running have no PID.
+        assert_eq!(launchd_job(print, "com.apple.Finder", &[]), None);
+        // Only services are jobs.
+        assert_eq!(launchd_job(print, "com.apple.example.endpoint", &[]), None);
+    }
+
+    #[test]
+    fn test_app_bundle() -> TestResult {
+        assert_eq!(
+            app_bundle(Path::new("/System/Library/CoreServices/Dock.app/Contents/MacOS/Dock")),
+            Some(Path::new("/System/Library/CoreServices/Dock.app"))
+        );
+        assert_eq!(
+            app_bundle(Path::new(
+                "/Applications/Xcode.app/Contents/Developer/Applications/Simulator.app/Contents/MacOS/Simulator"
+            )),
+            Some(Path::new("/Applications/Xcode.app/Contents/Developer/Applications/Simulator.app"))
+        );
+        assert_eq!(app_bundle(Path::new("/usr/sbin/cfprefsd")), None);
+
+        let tmp = tempfile::tempdir()?;
+        let app = tmp.path().join("Example.app");
+        std::fs::create_dir_all(app.join("Contents"))?;
+        Value::from(Dictionary::from_iter([("CFBundleIdentifier", Value::from("com.example.app"))])).to_file_xml(app.join("Contents/Info.plist"))?;
+
+        assert_eq!(bundle_identifier(&app).as_deref(), Some("com.example.app"));
+
+        Ok(())
+    }
+}
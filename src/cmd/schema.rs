@@ -0,0 +1,123 @@
+use color_eyre::eyre::Result;
+use serde_json::{json, Value};
+
+use crate::defaults::{ARRAY_ADD, BANG, DICT_ADD, ELLIPSIS, IF_UNSET};
+use crate::tags::VALUE_TAGS;
+use crate::transformers::transformers;
+
+/// `schema` command: print a JSON Schema of the YAML files `apply` reads, for editors to complete
+/// and validate them, e.g. VS Code with the YAML language server.
+#[allow(clippy::print_stdout)]
+pub fn schema() -> Result<()> {
+    //
+    println!("{}", serde_json::to_string_pretty(&defaults_schema())?);
+
+    Ok(())
+}
+
+/// Schema of a `MacOSDefaults` document. JSON Schema can't describe YAML tags, so they're listed
+/// in `x-yaml-custom-tags`, in the format of the YAML language server's `yaml.customTags` setting.
+fn defaults_schema() -> Value {
+    let string_list = |description: &str| json!({"type": "array", "items": {"type": "string"}, "description": description});
+    let per_domain = |description: &str, values: Value| json!({"type": "object", "description": description, "additionalProperties": values});
+
+    json!({
+        "$schema": "http://json-schema.org/draft-07/schema#",
+        "title": "macos-defaults",
+        "description": "A document of macOS defaults to apply with `macos-defaults apply`.",
+        "type": "object",
+        "additionalProperties": false,
+        "properties": {
+            "description": {"type": "string", "description": "Description of the document, shown when it's applied."},
+            "enabled": {"type": "boolean", "description": "Set to false to keep the document without applying it."},
+            "skip": {"type": "string", "description": "Don't apply the document, for this reason."},
+            "id": {"type": "string", "description": "Name other documents can list in `after`. Several documents may share one."},
+            "priority": {"type": "integer", "description": "Documents with a higher priority are applied first, among those whose `after` is satisfied."},
+            "after": string_list("Ids of documents that have to be applied before this one, from any file."),
+            "kill": string_list(
+                "Processes to restart if anything changed. `service:<name or label>` restarts a launchd job with `launchctl kickstart -k`."
+            ),
+            "quit": string_list("Apps (by name or bundle identifier) to quit before writing, if anything changed."),
+            "relaunch": {"type": "boolean", "description": "Launch the `quit` apps that were running again after writing."},
+            "sudo": {"type": "boolean", "description": "Prompt for superuser privileges before applying the document."},
+            "current_host": {"type": "boolean", "description": "Write the ByHost plists of the current hardware UUID."},
+            "once": {"type": "boolean", "description": "Only set keys that don't already have a value."},
+            "create": {"type": "boolean", "description": "Allow creating plists for domains that don't have one yet, with `apply --no-create`."},
+            "ignore_keys": per_domain("Per domain, keys that are never compared or written.", string_list("Keys.")),
+            "drift_ignore": per_domain(
+                "Per domain, key patterns that `status` and `apply --assert-idempotent` don't compare.",
+                string_list("Key patterns.")
+            ),
+            "containers": per_domain("Per domain, the sandbox container its plist is in.", json!({"type": "string"})),
+            "group_containers": per_domain("Per domain, the group container its plist is in.", json!({"type": "string"})),
+            "data": per_domain(
+                "Per domain (e.g. `com.apple.dock`, `NSGlobalDomain` or an absolute path to a plist), the keys to set.",
+                json!({"$ref": "#/definitions/dictionary"})
+            ),
+        },
+        "definitions": {
+            "value": {
+                "description": "A plist value. Tagged values (see `x-yaml-custom-tags`) are resolved when applying.",
+                "anyOf": [
+                    {"type": ["string", "number", "boolean"]},
+                    {"type": "array", "items": {"$ref": "#/definitions/value"}},
+                    {"$ref": "#/definitions/dictionary"},
+                ],
+            },
+            "dictionary": {
+                "type": "object",
+                "additionalProperties": {"$ref": "#/definitions/value"},
+                "properties": {
+                    ELLIPSIS: {"description": "Keep the existing keys or array elements, merging these into them."},
+                    BANG: {"description": "Replace the existing dictionary or array entirely."},
+                    IF_UNSET: {"$ref": "#/definitions/value", "description": "Use this value, unless the key already has one."},
+                    ARRAY_ADD: {"type": "array", "items": {"$ref": "#/definitions/value"}, "description": "Append these elements to the existing array."},
+                    DICT_ADD: {"$ref": "#/definitions/dictionary", "description": "Add these keys to the existing dictionary."},
+                },
+            },
+        },
+        "x-yaml-custom-tags": custom_tags(),
+    })
+}
+
+/// Every value tag, e.g. `!file scalar` and `!file mapping`, with the transformers' tags as scalars.
+fn custom_tags() -> Vec<String> {
+    let transformer_tags: Vec<_> = transformers().tags().map(|tag| (tag, &["scalar"][..])).collect();
+
+    VALUE_TAGS
+        .iter()
+        .copied()
+        .chain(transformer_tags)
+        .flat_map(|(tag, kinds)| kinds.iter().map(move |kind| format!("{tag} {kind}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::defaults_schema;
+
+    #[test]
+    fn test_defaults_schema() -> TestResult {
+        let schema = defaults_schema();
+
+        let properties = schema["properties"].as_object().ok_or("No properties")?;
+        for field in ["description", "kill", "sudo", "current_host", "data"] {
+            assert!(properties.contains_key(field), "{field}");
+        }
+
+        let tags: Vec<_> = schema["x-yaml-custom-tags"]
+            .as_array()
+            .ok_or("No custom tags")?
+            .iter()
+            .filter_map(serde_json::Value::as_str)
+            .collect();
+        assert!(tags.contains(&"!file scalar"));
+        assert!(tags.contains(&"!file mapping"));
+        assert!(tags.contains(&"!array-add sequence"));
+        assert!(tags.contains(&"!color scalar"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
+use camino::Utf8PathBuf;
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use tracing::debug;
+
+use crate::backend::PrefsBackend;
+use crate::cmd::apply::{domain_setting, is_plist, load_documents, process_path, ApplyOptions, DefaultsConfig};
+use crate::cmd::fingerprint::changed_since_fingerprint;
+use crate::defaults::{add_containers, pending_changes, WriteOptions};
+use crate::errors::DefaultsError as E;
+use crate::fingerprints::Fingerprints;
+use crate::planner::Planner;
+use crate::tags::TagContext;
+use crate::volatile::DriftIgnore;
+
+/// How far a YAML file is from the current defaults.
+#[derive(Debug, Default)]
+struct FileStatus {
+    /// Number of keys that applying the file would change.
+    drifted_keys: usize,
+    /// Domains with drifted keys.
+    domains: BTreeSet<String>,
+}
+
+/// `status` command. Keys matching `drift_ignore` (or a document's `drift_ignore` for the domain)
+/// don't count as drifted. With `fast`, only compare the plists against the fingerprints taken by
+/// the `fingerprint` command.
+#[allow(clippy::print_stdout)]
+pub fn status(path: Utf8PathBuf, drift_ignore: &DriftIgnore, fast: bool, backend: Rc<dyn PrefsBackend>) -> Result<()> {
+    //
+    let root = path.clone();
+    let files = process_path(path)?;
+
+    let names: Vec<String> = files
+        .iter()
+        .map(|file| file.strip_prefix(&root).ok().filter(|p| !p.as_str().is_empty()).unwrap_or(file).to_string())
+        .collect();
+
+    let width = names.iter().map(String::len).max().unwrap_or_default();
+
+    if fast {
+        let fingerprints = Fingerprints::load()?;
+
+        for (file, name) in files.iter().zip(names) {
+            match changed_since_fingerprint(file, &fingerprints) {
+                Ok(Some(changed)) if changed.is_empty() => println!("{name:<width$}  {} unchanged", "✔".green()),
+                Ok(Some(changed)) => println!("{name:<width$}  {} changed: {}", "✖".red(), changed.into_iter().collect::<Vec<_>>().join(", ")),
+                Ok(None) => println!("{name:<width$}  {} not fingerprinted, run `macos-defaults fingerprint`", "?".yellow()),
+                Err(e) => println!("{name:<width$}  {} error: {e}", "✖".red()),
+            }
+        }
+
+        return Ok(());
+    }
+
+    let options = ApplyOptions {
+        write: WriteOptions {
+            backend,
+            ..WriteOptions::default()
+        },
+        ..ApplyOptions::default()
+    };
+
+    for (file, name) in files.iter().zip(names) {
+        debug!("Checking {file}");
+
+        match file_status(file, drift_ignore, &options) {
+            Ok(status) if status.drifted_keys == 0 => println!("{name:<width$}  {} in sync", "✔".green()),
+            Ok(status) => println!(
+                "{name:<width$}  {} {} drifted: {}",
+                "✖".red(),
+                status.drifted_keys,
+                status.domains.into_iter().collect::<Vec<_>>().join(", ")
+            ),
+            Err(e) => {
+                debug!("{e:?}");
+                println!("{name:<width$}  {} error: {e}", "✖".red());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn file_status(file: &Utf8PathBuf, drift_ignore: &DriftIgnore, options: &ApplyOptions) -> Result<FileStatus> {
+    let mut status = FileStatus::default();
+
+    if is_plist(file) {
+        let plan = Planner::default().plan_plist_file(file, options)?;
+
+        for domain in plan.domains {
+            if let Some(error) = domain.error {
+                return Err(error);
+            }
+
+            let drifted_keys = domain
+                .writes
+                .iter()
+                .flat_map(|write| {
+                    let changed = write.changes.iter().map(|change| (&change.key, true));
+                    changed.chain(write.removed.iter().map(|key| (key, false)))
+                })
+                .filter(|(key, managed)| !drift_ignore.is_ignored(key, None, *managed))
+                .count();
+
+            if drifted_keys > 0 {
+                status.drifted_keys += drifted_keys;
+                status.domains.insert(domain.domain);
+            }
+        }
+
+        return Ok(status);
+    }
+
+    for config in load_documents(file)? {
+        if let Some(reason) = config.skip_reason() {
+            debug!("Skipping a document of {file}: {reason}");
+            continue;
+        }
+
+        let data = config.data.ok_or_else(|| E::MissingData { path: file.clone() })?;
+        add_containers(&config.containers, &config.group_containers);
+        let defaults = DefaultsConfig::for_document(data, config.once, &TagContext::for_yaml_path(file))?.expand_domain_globs(config.current_host);
+
+        for (domain, prefs) in defaults.0 {
+            let ignore_keys = domain_setting(&config.ignore_keys, &domain).map(Vec::as_slice).unwrap_or_default();
+            let patterns = domain_setting(&config.drift_ignore, &domain).map(Vec::as_slice);
+
+            let drifted_keys = pending_changes(options.write.backend.as_ref(), &domain, prefs, ignore_keys, config.current_host)?
+                .iter()
+                .filter(|change| !drift_ignore.is_ignored(&change.key, patterns, true))
+                .count();
+
+            if drifted_keys > 0 {
+                status.drifted_keys += drifted_keys;
+                status.domains.insert(domain);
+            }
+        }
+    }
+
+    Ok(status)
+}
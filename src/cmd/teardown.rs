@@ -0,0 +1,77 @@
+use std::rc::Rc;
+
+use camino::Utf8Path;
+use color_eyre::eyre::Result;
+use colored::Colorize;
+use itertools::Itertools;
+use tracing::{info, warn};
+
+use crate::backend::PrefsBackend;
+use crate::defaults::{execute_plist_write, plan_revert, WriteOptions};
+use crate::manifest::Manifest;
+use crate::observer::ConsoleObserver;
+use crate::tree::{render_change, render_removal};
+
+/// `teardown` command: revert every key that files at or under `path` ever set, from the
+/// managed-keys manifest `apply` keeps. Keys are restored to the value they had before the first
+/// apply changed them, or removed if they didn't exist. Keys another file still sets are kept.
+#[allow(clippy::print_stdout)]
+pub fn teardown(path: &Utf8Path, dry_run: bool, backend: Rc<dyn PrefsBackend>) -> Result<()> {
+    //
+    let mut manifest = Manifest::load()?;
+    let released = manifest.release(path);
+
+    if released.is_empty() {
+        warn!("Nothing in the managed-keys manifest was set by {path}.");
+        return Ok(());
+    }
+
+    let options = WriteOptions {
+        backend,
+        ..WriteOptions::default()
+    };
+
+    for (plist_path, keys) in released {
+        for key in keys.iter().filter(|key| !key.other_sources.is_empty()) {
+            info!("Keeping {plist_path} {}, as it's also set by {}", key.key, key.other_sources.iter().join(", "));
+        }
+
+        let secrets: Vec<_> = keys.iter().filter(|key| key.secret).map(|key| key.key.clone()).collect();
+
+        let originals: Vec<_> = keys
+            .into_iter()
+            .filter(|key| key.other_sources.is_empty())
+            .map(|key| (key.key, key.original))
+            .collect();
+
+        let Some(mut write) = plan_revert(options.backend.as_ref(), &plist_path, &originals)? else {
+            continue;
+        };
+
+        // The secrets aren't resolved by a teardown, so the manifest says which keys have them.
+        for change in &mut write.changes {
+            change.redacted |= secrets.contains(&change.key);
+        }
+
+        println!("  {} {}", "▶".green(), write.domain.to_string().bold().white());
+
+        for change in &write.changes {
+            let (old_value, new_value) = change.shown_values();
+            print!("{}", render_change(&change.key, old_value.as_deref(), &new_value, 4));
+        }
+
+        for key in &write.removed {
+            print!("{}", render_removal(key, 4));
+        }
+
+        if !dry_run {
+            execute_plist_write(&write, &options, &ConsoleObserver::default())?;
+        }
+    }
+
+    if !dry_run {
+        manifest.save()?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use camino::Utf8PathBuf;
+use color_eyre::eyre::Result;
+use serde::Serialize;
+
+use crate::changelog::{changes, parse_version, Affects, Change};
+use crate::cmd::apply::{is_plist, load_documents};
+use crate::cmd::OutputFormat;
+use crate::defaults::MacOSDefaults;
+
+/// Behavior changes since a version that are relevant to a config.
+#[derive(Debug, Serialize)]
+struct UpgradeImpact {
+    from: String,
+    to: &'static str,
+    changes: Vec<ChangeImpact>,
+}
+
+#[derive(Debug, Serialize)]
+struct ChangeImpact {
+    #[serde(flatten)]
+    change: &'static Change,
+    /// `file: domain [key]` locations the change affects, empty for changes that affect every config.
+    affected: Vec<String>,
+}
+
+/// `upgrade-impact` command.
+pub fn upgrade_impact(from: &str, files: &[Utf8PathBuf], format: OutputFormat) -> Result<()> {
+    //
+    let from_version = parse_version(from)?;
+
+    // Raw `.plist` files have no YAML to be affected by changes.
+    let documents = files
+        .iter()
+        .filter(|file| !is_plist(file))
+        .map(|file| Ok((file, load_documents(file)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut impact = UpgradeImpact {
+        from: from.to_owned(),
+        to: env!("CARGO_PKG_VERSION"),
+        changes: Vec::new(),
+    };
+
+    for change in changes() {
+        if parse_version(&change.version)? <= from_version {
+            continue;
+        }
+
+        let affected: Vec<String> = documents
+            .iter()
+            .flat_map(|(file, documents)| documents.iter().flat_map(move |document| affected_locations(&change.affects, file, document)))
+            .collect();
+
+        if change.affects.always || !affected.is_empty() {
+            impact.changes.push(ChangeImpact { change, affected });
+        }
+    }
+
+    format.print(&impact)
+}
+
+/// Locations in a document that a change affects.
+fn affected_locations(affects: &Affects, file: &Utf8PathBuf, document: &MacOSDefaults) -> Vec<String> {
+    let mut locations = Vec::new();
+
+    let Some(serde_yaml::Value::Mapping(domains)) = &document.data else {
+        return locations;
+    };
+
+    for (domain, prefs) in domains {
+        let Some(domain) = domain.as_str() else {
+            continue;
+        };
+
+        if affects.domain(domain) {
+            locations.push(format!("{file}: {domain}"));
+        }
+
+        let serde_yaml::Value::Mapping(prefs) = prefs else {
+            continue;
+        };
+
+        for (key, value) in prefs {
+            let Some(key) = key.as_str() else {
+                continue;
+            };
+
+            if affects.key(key) || has_affected_tag(affects, value) {
+                locations.push(format!("{file}: {domain} {key}"));
+            }
+        }
+    }
+
+    locations
+}
+
+/// Whether a value, or anything nested in it, has a tag the change affects.
+fn has_affected_tag(affects: &Affects, value: &serde_yaml::Value) -> bool {
+    match value {
+        serde_yaml::Value::Tagged(tagged) => affects.tag(&tagged.tag.to_string()) || has_affected_tag(affects, &tagged.value),
+        serde_yaml::Value::Sequence(sequence) => sequence.iter().any(|value| has_affected_tag(affects, value)),
+        serde_yaml::Value::Mapping(mapping) => mapping.values().any(|value| has_affected_tag(affects, value)),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::affected_locations;
+    use crate::changelog::changes;
+    use crate::cmd::apply::load_documents_from_reader;
+
+    #[test]
+    fn test_affected_locations() -> TestResult {
+        let path = Utf8PathBuf::from("test.yaml");
+        let yaml = "data:\n  dock:\n    autohide: true\n  NSGlobalDomain:\n    .a.b: 1\n    c:\n      - !uuid\n      - !uuid-stable x\n";
+        let documents = load_documents_from_reader(yaml.as_bytes(), &path)?;
+
+        let affected = |id: &str| {
+            let change = changes().iter().find(|change| change.id == id).ok_or("known change")?;
+            Ok::<_, &str>(affected_locations(&change.affects, &path, &documents[0]))
+        };
+
+        assert_eq!(affected("friendly-domain-names")?, ["test.yaml: dock"]);
+        assert_eq!(affected("key-path-expansion")?, ["test.yaml: NSGlobalDomain .a.b"]);
+        assert_eq!(affected("uuid-tags")?, ["test.yaml: NSGlobalDomain c"]);
+        assert!(affected("now-relative-dates")?.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+//! Persistent options from `~/.config/macos-defaults/config.yaml` (or `--config`).
+//!
+//! Command line arguments take precedence over the config file.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use serde::Deserialize;
+use tracing_subscriber::filter::LevelFilter;
+
+use crate::defaults::{home_dir, WriteFormat};
+use crate::errors::DefaultsError as E;
+use crate::phases::Phases;
+
+/// Options read from the config file, all of them optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// Keep plist backups in this directory, rather than next to each plist.
+    pub backup_dir: Option<Utf8PathBuf>,
+
+    /// Default for `apply --backup-depth`.
+    pub backup_depth: Option<usize>,
+
+    /// Set to `false` to not back up plists, like `apply --no-backup`.
+    pub backup: Option<bool>,
+
+    /// Default for `apply --write-format`.
+    pub write_format: Option<WriteFormat>,
+
+    /// Default for `apply --exit-code`.
+    pub exit_code: Option<i32>,
+
+    /// Processes to restart whenever a YAML document changes anything, e.g. `cfprefsd`.
+    #[serde(default)]
+    pub kill: Vec<String>,
+
+    /// Key patterns `status` and `apply --assert-idempotent` don't compare, instead of the built-in
+    /// list of volatile keys like `NSWindow Frame *`. Set to `[]` to compare every key.
+    pub drift_ignore: Option<Vec<String>>,
+
+    /// Per domain, keys that are never written, in addition to a document's own `ignore_keys`.
+    /// The only way to keep keys of a raw `.plist` file's domain.
+    #[serde(default)]
+    pub ignore_keys: HashMap<String, Vec<String>>,
+
+    /// Per domain, the sandbox container its plist is in, when the container isn't named after
+    /// the domain. YAML documents can add their own.
+    #[serde(default)]
+    pub containers: HashMap<String, String>,
+
+    /// Per domain, the group container (`~/Library/Group Containers/{group}`) its plist is in.
+    #[serde(default)]
+    pub group_containers: HashMap<String, String>,
+
+    /// Set to `false` to skip checking at startup that this is a supported macOS version.
+    pub version_check: Option<bool>,
+
+    /// Log level before `-v` and `-q` make it more or less verbose.
+    pub log_level: Option<LogLevel>,
+
+    /// Files or directories to apply when `apply` is run without a path.
+    #[serde(default)]
+    pub paths: Vec<Utf8PathBuf>,
+
+    /// Files or directories to apply per bootstrap phase when `apply` is run without a path,
+    /// instead of `paths`. See [`crate::phases`].
+    #[serde(default)]
+    pub phases: Phases,
+}
+
+/// Log level names accepted in the config file.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Off => Self::OFF,
+            LogLevel::Error => Self::ERROR,
+            LogLevel::Warn => Self::WARN,
+            LogLevel::Info => Self::INFO,
+            LogLevel::Debug => Self::DEBUG,
+            LogLevel::Trace => Self::TRACE,
+        }
+    }
+}
+
+impl Config {
+    /// Load the config file at `path`, or the default config file if it exists.
+    ///
+    /// The default is `$XDG_CONFIG_HOME/macos-defaults/config.yaml`, falling back to
+    /// `~/.config/macos-defaults/config.yaml`.
+    pub fn load(path: Option<&Utf8Path>) -> Result<Self> {
+        let path = if let Some(path) = path {
+            path.to_path_buf()
+        } else {
+            let path = default_config_path()?;
+
+            if !path.exists() {
+                return Ok(Self::default());
+            }
+
+            path
+        };
+
+        let contents = fs::read_to_string(&path).map_err(|e| E::FileRead { path: path.clone(), source: e })?;
+
+        let mut config: Self = serde_yaml::from_str(&contents).map_err(|e| E::InvalidYaml { path, source: e })?;
+
+        config.backup_dir = config.backup_dir.map(|dir| expand_tilde(&dir)).transpose()?;
+        config.paths = config.paths.iter().map(|path| expand_tilde(path)).collect::<Result<_>>()?;
+
+        for paths in config.phases.values_mut() {
+            *paths = paths.iter().map(|path| expand_tilde(path)).collect::<Result<_>>()?;
+        }
+
+        Ok(config)
+    }
+}
+
+fn default_config_path() -> Result<Utf8PathBuf> {
+    Ok(config_dir()?.join("config.yaml"))
+}
+
+/// `$XDG_CONFIG_HOME/macos-defaults`, falling back to `~/.config/macos-defaults`.
+pub fn config_dir() -> Result<Utf8PathBuf> {
+    let config_dir = match env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => Utf8PathBuf::from(dir),
+        _ => home_dir()?.join(".config"),
+    };
+
+    Ok(config_dir.join("macos-defaults"))
+}
+
+/// Expand a leading `~/` to the home directory.
+fn expand_tilde(path: &Utf8Path) -> Result<Utf8PathBuf> {
+    match path.strip_prefix("~") {
+        Ok(relative) => Ok(home_dir()?.join(relative)),
+        Err(_) => Ok(path.to_path_buf()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+
+    use super::Config;
+
+    #[test]
+    fn test_config_parses() -> TestResult {
+        let config: Config = serde_yaml::from_str(
+            "backup_dir: ~/.local/state/macos-defaults\nbackup_depth: 3\nexit_code: 2\nkill: [cfprefsd]\nlog_level: info\npaths: [~/dotfiles/defaults]\n",
+        )?;
+
+        assert_eq!(config.backup_depth, Some(3));
+        assert_eq!(config.exit_code, Some(2));
+        assert_eq!(config.kill, ["cfprefsd"]);
+        assert_eq!(config.paths.len(), 1);
+
+        assert!(serde_yaml::from_str::<Config>("unknown: true\n").is_err());
+
+        Ok(())
+    }
+}
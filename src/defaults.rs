@@ -2,38 +2,102 @@
 //
 // NB: Most of this code originated from: https://github.com/gibfahn/up-rs, MIT & Apache 2.0 licensed.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::{self, File};
 use std::io::Read;
 use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
 use color_eyre::eyre::{eyre, Result};
 use duct::cmd;
-use log::{debug, info, trace, warn};
+use itertools::Itertools;
 use plist::{Dictionary, Value};
 use serde::{Deserialize, Serialize};
+use tempfile::TempDir;
+use tracing::{debug, debug_span, error, info, info_span, trace, warn};
 
+use super::backend::{FileBackend, PrefsBackend};
+use super::catalog;
+use super::cmd::restart::{current_uid, restart_cfprefsd};
+use super::domain::Domain;
 use super::errors::DefaultsError as E;
+use super::observer::ApplyObserver;
+use super::secrets;
+use super::transaction::Transaction;
+use super::tree::render_change;
 
 /// A value in an array that means "insert existing values here"
-const ELLIPSIS: &str = "...";
+pub const ELLIPSIS: &str = "...";
 /// A value in a dictionary or domain that means "delete any keys not specified here".
-const BANG: &str = "!";
+pub const BANG: &str = "!";
 
 pub const NS_GLOBAL_DOMAIN: &str = "NSGlobalDomain";
 
-#[derive(Debug, Serialize, Deserialize)]
+/// A dictionary with only this key means "use the wrapped value, unless the key already has one".
+pub const IF_UNSET: &str = "!if-unset";
+/// A dictionary with only this key means "append the wrapped array to the existing array", like
+/// `defaults write -array-add`. Also the YAML tag for it.
+pub const ARRAY_ADD: &str = "!array-add";
+/// A dictionary with only this key means "add the wrapped keys to the existing dictionary", like
+/// `defaults write -dict-add`. Also the YAML tag for it.
+pub const DICT_ADD: &str = "!dict-add";
+
+/// YAML tag for a value read from a file, relative to the YAML file.
+pub const FILE_TAG: &str = "!file";
+
+/// YAML tag for a keyed archive `Uid`, which would otherwise round-trip as a plain integer.
+pub const UID_TAG: &str = "!uid";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub(super) struct MacOSDefaults {
+#[allow(clippy::struct_excessive_bools)] // They're YAML fields.
+pub struct MacOSDefaults {
     /// Description of the task.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub description: Option<String>,
 
-    /// List of processes to kill if updates were needed.
+    /// Set to false to keep the document without applying it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub enabled: Option<bool>,
+
+    /// Don't apply the document, for this reason, e.g. `skip: Breaks the Dock on macOS 15.1`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skip: Option<String>,
+
+    /// Name other documents can list in `after`. Several documents may share one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+
+    /// Documents with a higher priority are applied first, among those whose `after` is satisfied.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub priority: i32,
+
+    /// Ids of documents that have to be applied before this one, from any file.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub after: Vec<String>,
+
+    /// List of processes to kill if updates were needed. `service:<name or label>` restarts a
+    /// `launchd` job with `launchctl kickstart -k` instead.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub kill: Option<Vec<String>>,
 
+    /// Apps (by name or bundle identifier) to quit gracefully before writing, if updates are needed,
+    /// so they don't overwrite the changes with their own state when they quit later.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quit: Vec<String>,
+
+    /// Launch the `quit` apps that were running again after writing.
+    #[serde(default = "default_false", skip_serializing_if = "std::ops::Not::not")]
+    pub relaunch: bool,
+
     /// Set to true to prompt for superuser privileges before running.
     /// This will allow all subtasks that up executes in this iteration.
     #[serde(default = "default_false")]
@@ -43,6 +107,33 @@ pub(super) struct MacOSDefaults {
     #[serde(default = "default_false")]
     pub current_host: bool,
 
+    /// Only set keys that don't already have a value, so later changes by the user are kept.
+    #[serde(default = "default_false", skip_serializing_if = "std::ops::Not::not")]
+    pub once: bool,
+
+    /// Allow creating plists for domains that don't have one yet, with `apply --no-create`.
+    #[serde(default = "default_false", skip_serializing_if = "std::ops::Not::not")]
+    pub create: bool,
+
+    /// Per domain, keys that are never compared or written, e.g. counters the OS keeps updating.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub ignore_keys: HashMap<String, Vec<String>>,
+
+    /// Per domain, key patterns that `status` and `apply --assert-idempotent` don't compare,
+    /// replacing the built-in list of volatile keys for that domain. Unlike `ignore_keys`, they're still written.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub drift_ignore: HashMap<String, Vec<String>>,
+
+    /// Per domain, the sandbox container its plist is in, when the container isn't named after the
+    /// domain. Also used by later documents.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub containers: HashMap<String, String>,
+
+    /// Per domain, the group container (`~/Library/Group Containers/{group}`) its plist is in.
+    /// Also used by later documents.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub group_containers: HashMap<String, String>,
+
     // This field must be the last one in order for the yaml serializer in the generate functions
     // to be able to serialise it properly.
     /// Set of data provided to the Run library.
@@ -50,11 +141,224 @@ pub(super) struct MacOSDefaults {
     pub data: Option<serde_yaml::Value>,
 }
 
+impl MacOSDefaults {
+    /// Why the document isn't applied, if it's disabled with `enabled: false` or `skip`.
+    pub fn skip_reason(&self) -> Option<&str> {
+        match (&self.skip, self.enabled) {
+            (Some(reason), _) => Some(reason),
+            (None, Some(false)) => Some("enabled: false"),
+            (None, _) => None,
+        }
+    }
+}
+
 /// Used for serde defaults above.
 const fn default_false() -> bool {
     false
 }
 
+#[allow(clippy::trivially_copy_pass_by_ref)] // serde passes fields by reference.
+const fn is_zero(value: &i32) -> bool {
+    *value == 0
+}
+
+/// Options controlling how plist files are written.
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct WriteOptions {
+    /// Treat a missing plist file as an error rather than creating it.
+    pub strict: bool,
+
+    /// Copy a plist to its `.prev` backup before changing it.
+    pub backup: bool,
+
+    /// Number of older valid backups to keep as `.prev.1`, `.prev.2`, ... in addition to `.prev`.
+    pub backup_depth: usize,
+
+    /// Directory to keep backups in, rather than next to each plist.
+    pub backup_dir: Option<Utf8PathBuf>,
+
+    /// If a domain has both a container and a `~/Library/Preferences/` plist, write to both.
+    pub also_write_unsandboxed: bool,
+
+    /// Re-read each plist after writing it, and check the changed values persisted.
+    pub verify: Option<Verify>,
+
+    /// How many times to write a plist again, after restarting the user's cfprefsd, when the
+    /// changed values didn't persist. Implies verifying the file if `verify` isn't given.
+    pub write_retries: u32,
+
+    /// How XML plists are formatted when they're rewritten.
+    pub xml_format: XmlFormat,
+
+    /// Whether new plists are created as XML or binary.
+    pub write_format: WriteFormat,
+
+    /// Also convert existing plists to `write_format` when they're written, rather than keeping
+    /// the format they're in.
+    pub convert_format: bool,
+
+    /// What to do with a plist that's a symlink, e.g. into a dotfiles repository.
+    pub symlinks: Symlinks,
+
+    /// For `current_host` documents, merge into the values macOS reads (the `ByHost` plist's keys
+    /// over the any-host plist's) rather than the `ByHost` plist alone.
+    pub layered_read: bool,
+
+    /// Plan a corrupt plist as if it were empty, and move it aside before writing the new values
+    /// to a fresh one, rather than failing the domain.
+    pub repair: bool,
+
+    /// How plists are read for planning, and how changed keys are written.
+    pub backend: Rc<dyn PrefsBackend>,
+
+    /// With `apply --transactional`, where each plist is kept as it was before its first write,
+    /// to roll back to if a later domain fails.
+    pub transaction: Option<Rc<Transaction>>,
+}
+
+/// What `apply` does when a plist it would change is a symlink.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Symlinks {
+    /// Write the changes to the file the symlink points to, keeping the symlink.
+    #[default]
+    Follow,
+    /// Leave the symlink and the file it points to untouched, skipping the changes.
+    Preserve,
+    /// Fail the domain.
+    Error,
+}
+
+/// Format of plists written by `apply`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteFormat {
+    /// Create new plists as binary, as macOS does, and keep existing ones in their format.
+    #[default]
+    Auto,
+    /// XML, which diffs cleanly in a repository.
+    Xml,
+    /// Binary.
+    Binary,
+}
+
+impl WriteFormat {
+    /// Whether to write a plist as binary. An existing plist keeps its format, given by
+    /// `existing_binary`, unless `convert` is set.
+    fn is_binary(self, existing_binary: Option<bool>, convert: bool) -> bool {
+        match (self, existing_binary) {
+            (Self::Auto, Some(existing_binary)) => existing_binary,
+            (_, Some(existing_binary)) if !convert => existing_binary,
+            (Self::Xml, _) => false,
+            (Self::Auto | Self::Binary, _) => true,
+        }
+    }
+}
+
+/// Formatting of rewritten XML plists, so files tracked verbatim in a repository diff cleanly.
+#[derive(Debug, Clone)]
+pub struct XmlFormat {
+    /// Indentation of nested elements.
+    pub indent: XmlIndent,
+
+    /// End the file with a newline after `</plist>`, as `plutil` does.
+    pub trailing_newline: bool,
+
+    /// Include the `<!DOCTYPE plist ...>` declaration.
+    pub doctype: bool,
+}
+
+impl Default for XmlFormat {
+    fn default() -> Self {
+        Self {
+            indent: XmlIndent::Tabs,
+            trailing_newline: false,
+            doctype: true,
+        }
+    }
+}
+
+/// Indentation of XML plists: `tab`, or a number of spaces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum XmlIndent {
+    Tabs,
+    Spaces(u8),
+}
+
+impl std::str::FromStr for XmlIndent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" | "tabs" => Ok(Self::Tabs),
+            spaces => spaces
+                .parse()
+                .map(Self::Spaces)
+                .map_err(|_| format!("expected `tab` or a number of spaces, got {spaces:?}")),
+        }
+    }
+}
+
+impl XmlFormat {
+    /// Serialize a plist as XML in this format.
+    fn to_bytes(&self, plist_value: &Value) -> Result<Vec<u8>, plist::Error> {
+        let options = match self.indent {
+            XmlIndent::Tabs => plist::XmlWriteOptions::default().indent(b'\t', 1),
+            XmlIndent::Spaces(count) => plist::XmlWriteOptions::default().indent(b' ', usize::from(count)),
+        };
+
+        let mut plist_bytes = Vec::new();
+        plist_value.to_writer_xml_with_options(&mut plist_bytes, &options)?;
+
+        if !self.doctype {
+            // The declaration is a line of its own in the prologue.
+            let doctype = plist_bytes.windows(9).position(|window| window == b"<!DOCTYPE");
+            let line_end = doctype.and_then(|start| plist_bytes[start..].iter().position(|&b| b == b'\n').map(|end| start + end));
+
+            if let (Some(start), Some(end)) = (doctype, line_end) {
+                plist_bytes.drain(start..=end);
+            }
+        }
+
+        if self.trailing_newline && !plist_bytes.ends_with(b"\n") {
+            plist_bytes.push(b'\n');
+        }
+
+        Ok(plist_bytes)
+    }
+}
+
+/// How `--verify` re-reads a plist after writing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Verify {
+    /// Read the plist file directly.
+    File,
+    /// Read the file, and also read the values back through `defaults export`, i.e. via cfprefsd.
+    Defaults,
+}
+
+impl Default for WriteOptions {
+    fn default() -> Self {
+        Self {
+            strict: false,
+            backup: true,
+            backup_depth: 1,
+            backup_dir: None,
+            also_write_unsandboxed: false,
+            verify: None,
+            write_retries: 0,
+            xml_format: XmlFormat::default(),
+            write_format: WriteFormat::default(),
+            convert_format: false,
+            symlinks: Symlinks::default(),
+            layered_read: false,
+            repair: false,
+            backend: Rc::new(FileBackend),
+            transaction: None,
+        }
+    }
+}
+
 /**
 Get the path to the plist file given a domain.
 
@@ -68,8 +372,14 @@ you can work out which plist files are actually being read on the machine.
 As far as I can tell, the rules are:
 
 - `NSGlobalDomain` -> `~/Library/Preferences/.GlobalPreferences.plist`
+- `~/Library/Containers/{container}/Data/Library/Preferences/{domain}.plist` if the config file or
+  a YAML document names a container for the domain (see [`add_containers`]), whether or not it exists.
+- `~/Library/Group Containers/{group}/Library/Preferences/{domain}.plist` likewise, for a group
+  container named for the domain.
 - `~/Library/Containers/{domain}/Data/Library/Preferences/{domain}.plist` if it exists.
-- `~/Library/Preferences/{domain}.plist`
+- `~/Library/Preferences/{domain}.plist` if it exists.
+- `~/Library/Group Containers/{*}/Library/Preferences/{domain}.plist` if exactly one group
+  container has it.
 
 If none of these exist then create `~/Library/Preferences/{domain}.plist`.
 
@@ -84,32 +394,39 @@ matches the container folder.
 - [macOS Containers and defaults](https://lapcatsoftware.com/articles/containers.html)
 - [Preference settings: where to find them in Mojave](https://eclecticlight.co/2019/08/28/preference-settings-where-to-find-them-in-mojave/)
 */
-pub(super) fn plist_path(domain: &str, current_host: bool) -> Result<Utf8PathBuf> {
-    // User passed an absolute path -> use it directly.
-    if domain.starts_with('/') {
-        return Ok(Utf8PathBuf::from(domain));
-    }
-
-    let home_dir = dirs::home_dir().ok_or_else(|| eyre!("Expected to be able to calculate the user's home directory."))?;
-    let home_dir = Utf8PathBuf::try_from(home_dir)?;
-
-    // Global Domain -> hard coded value.
-    if domain == NS_GLOBAL_DOMAIN {
-        let mut plist_path = home_dir;
-        let filename = plist_filename(".GlobalPreferences", current_host)?;
-        extend_with_prefs_folders(current_host, &mut plist_path, &filename);
-        return Ok(plist_path);
-    }
+pub(super) fn plist_path(domain: &Domain, current_host: bool) -> Result<Utf8PathBuf> {
+    let domain = match domain {
+        // User passed an absolute path -> use it directly.
+        Domain::Path(path) => return Ok(target_path(path)),
+        Domain::Named(name) => name,
+        // Global Domain -> hard coded value.
+        Domain::Global => {
+            let mut plist_path = home_dir()?;
+            let filename = plist_filename(".GlobalPreferences", current_host)?;
+            extend_with_prefs_folders(current_host, &mut plist_path, &filename);
+            return Ok(plist_path);
+        }
+    };
 
-    // If passed com.foo.bar.plist, trim it to com.foo.bar
-    let domain = domain.trim_end_matches(".plist");
+    let home_dir = home_dir()?;
     let filename = plist_filename(domain, current_host)?;
+    let container = container_for(domain);
 
     let mut sandboxed_plist_path = home_dir.clone();
-    sandboxed_plist_path.extend(&["Library", "Containers", domain, "Data"]);
+    sandboxed_plist_path.extend(&["Library", "Containers", container.as_deref().unwrap_or(domain), "Data"]);
     extend_with_prefs_folders(current_host, &mut sandboxed_plist_path, &filename);
 
-    if sandboxed_plist_path.exists() {
+    if container.is_some() {
+        trace!("Using the container given for {domain}.");
+        return Ok(sandboxed_plist_path);
+    }
+
+    if let Some(group) = group_container_for(domain) {
+        trace!("Using the group container given for {domain}.");
+        return Ok(group_container_plist_path(&home_dir, &group, current_host, &filename));
+    }
+
+    if container_plist_exists(&sandboxed_plist_path) {
         trace!("Sandboxed plist path exists.");
         return Ok(sandboxed_plist_path);
     }
@@ -122,622 +439,3393 @@ pub(super) fn plist_path(domain: &str, current_host: bool) -> Result<Utf8PathBuf
     // }
 
     trace!("Sandboxed plist path does not exist.");
-    let mut plist_path = home_dir;
+    let mut plist_path = home_dir.clone();
     extend_with_prefs_folders(current_host, &mut plist_path, &filename);
 
+    if !plist_path.exists() {
+        if let Some(group_plist_path) = find_group_container_plist(&home_dir, current_host, &filename) {
+            trace!("Found {domain} in a group container.");
+            return Ok(group_plist_path);
+        }
+    }
+
     // We return this even if it doesn't yet exist.
     Ok(plist_path)
 }
 
-/// Take a directory path, and add on the directories and files containing the application's
-/// preferences. Normally this is `./Library/Preferences/{domain}.plist`, but if `current_host` is
-/// `true`, then we need to look in the `ByHost` subfolder.
-fn extend_with_prefs_folders(current_host: bool, plist_path: &mut Utf8PathBuf, filename: &str) {
-    if current_host {
-        plist_path.extend(&["Library", "Preferences", "ByHost", filename]);
-    } else {
-        plist_path.extend(&["Library", "Preferences", filename]);
-    }
-}
+/// Folders searched for an application bundle when a domain is given as an app name.
+const APPLICATION_DIRS: &[&str] = &["/Applications", "/System/Applications", "~/Applications"];
 
-/// Get the expected filename for a plist file. Normally it's just the preference name + `.plist`,
-/// but if it's a currentHost setup, then we need to include the current host UUID as well.
-fn plist_filename(domain: &str, current_host: bool) -> Result<String, E> {
-    if current_host {
-        return Ok(format!(
-            "{domain}.{hardware_uuid}.plist",
-            hardware_uuid = get_hardware_uuid().map_err(|e| E::EyreError { source: e })?
-        ));
+/**
+Resolve a friendly domain name, as written in a YAML file or passed to `dump --domain`, to the
+domain used to find its plist.
+
+- Surrounding whitespace is trimmed, and runs of whitespace are collapsed to a single space.
+- `Apple Global Domain`, `-g` and `-globalDomain` (like the `defaults` command) -> `NSGlobalDomain`.
+- A name with a plist of its own (e.g. `~/Library/Preferences/My App.plist`) is used as is.
+- Otherwise an application name (e.g. `Visual Studio Code`, like `defaults -app`) resolves to
+  the `CFBundleIdentifier` of the matching `.app` bundle.
+*/
+pub(super) fn resolve_domain(name: &str, current_host: bool) -> Result<Domain> {
+    // Paths are used as is.
+    if name.starts_with('/') {
+        return Ok(name.parse()?);
     }
 
-    Ok(format!("{domain}.plist"))
-}
+    let name = name.split_whitespace().collect::<Vec<_>>().join(" ");
 
-/// String representation of a plist Value's type.
-pub(super) fn get_plist_value_type(plist: &plist::Value) -> &'static str {
-    match plist {
-        p if p.as_array().is_some() => "array",
-        p if p.as_boolean().is_some() => "boolean",
-        p if p.as_date().is_some() => "date",
-        p if p.as_real().is_some() => "real",
-        p if p.as_signed_integer().is_some() => "signed_integer",
-        p if p.as_unsigned_integer().is_some() => "unsigned_integer",
-        p if p.as_string().is_some() => "string",
-        p if p.as_dictionary().is_some() => "dictionary",
-        p if p.as_data().is_some() => "data",
-        _ => "unknown",
+    if let Ok(domain) = name.parse::<Domain>() {
+        if domain == Domain::Global || plist_path(&domain, current_host)?.exists() {
+            return Ok(domain);
+        }
     }
-}
 
-/// Check whether a plist file is in the binary plist format or the XML plist format.
-fn is_binary(file: &Utf8Path) -> Result<bool, E> {
-    let mut f = File::open(file).map_err(|e| E::FileRead {
-        path: file.to_path_buf(),
-        source: e,
-    })?;
-    let mut magic = [0; 8];
+    let home_dir = home_dir()?;
+    let app_dirs: Vec<Utf8PathBuf> = APPLICATION_DIRS
+        .iter()
+        .map(|dir| {
+            dir.strip_prefix("~/")
+                .map_or_else(|| target_path(Utf8Path::new(dir)), |relative| home_dir.join(relative))
+        })
+        .collect();
 
-    // read exactly 8 bytes
-    f.read_exact(&mut magic).map_err(|e| E::FileRead {
-        path: file.to_path_buf(),
-        source: e,
-    })?;
+    if let Some(bundle_id) = app_bundle_identifier(&app_dirs, &name) {
+        debug!("Resolved application {name:?} to domain {bundle_id}");
+        return Ok(bundle_id.parse()?);
+    }
 
-    Ok(&magic == b"bplist00")
+    Ok(name.parse()?)
 }
 
-/// Write a `HashMap` of key-value pairs to a plist file.
-pub(super) fn write_defaults_values(domain: &str, mut prefs: HashMap<String, plist::Value>, current_host: bool) -> Result<bool> {
-    let plist_path = plist_path(domain, current_host)?;
+/// Find an application named `name` (case-insensitively, with or without `.app`) in `app_dirs`,
+/// and return its bundle identifier.
+fn app_bundle_identifier(app_dirs: &[Utf8PathBuf], name: &str) -> Option<String> {
+    let wanted = name.trim_end_matches(".app").to_lowercase();
+
+    app_dirs
+        .iter()
+        .flat_map(|dir| dir.read_dir_utf8().into_iter().flatten().filter_map(Result::ok))
+        .map(camino::Utf8DirEntry::into_path)
+        .filter(|path| path.extension() == Some("app") && path.file_stem().is_some_and(|stem| stem.to_lowercase() == wanted))
+        .find_map(|path| {
+            let info = plist::Value::from_file(path.join("Contents/Info.plist")).ok()?;
+            info.as_dictionary()?.get("CFBundleIdentifier")?.as_string().map(str::to_owned)
+        })
+}
 
-    debug!("Plist path: {plist_path}");
+/// Work out the logical domain of a plist file from its path, the reverse of [`plist_path`].
+///
+/// Handles `.GlobalPreferences`, `ByHost` files (dropping the hardware UUID suffix), and container
+/// plists whose file name matches the container folder.
+pub(super) fn domain_for_path(path: &Utf8Path) -> Option<Domain> {
+    let mut name = path.file_name()?.strip_suffix(".plist")?;
 
-    let plist_path_exists = plist_path.exists();
+    let mut parent = path.parent()?;
 
-    let mut plist_value: plist::Value = if plist_path_exists {
-        plist::from_file(&plist_path).map_err(|e| E::PlistRead {
-            path: plist_path.clone(),
-            source: e,
-        })?
-    } else {
-        plist::Value::Dictionary(Dictionary::new())
-    };
+    if parent.file_name() == Some("ByHost") {
+        let (domain, uuid) = name.rsplit_once('.')?;
+        if !is_hardware_uuid(uuid) {
+            return None;
+        }
+        name = domain;
+        parent = parent.parent()?;
+    }
 
-    trace!("Plist: {plist_value:?}");
+    if !parent.ends_with("Library/Preferences") {
+        return None;
+    }
 
-    // Whether we changed anything.
-    let mut values_changed = false;
+    if name == ".GlobalPreferences" {
+        return Some(Domain::Global);
+    }
 
-    // If we have a key "!", wipe out the existing array.
-    if prefs.contains_key(BANG) {
-        plist_value = Value::from(Dictionary::new());
-        prefs.remove(BANG);
+    // ~/Library/Containers/{domain}/Data/Library/Preferences/{domain}.plist
+    let ancestors: Vec<_> = parent.ancestors().skip(2).take(3).filter_map(Utf8Path::file_name).collect();
+    if let ["Data", container, "Containers"] = ancestors.as_slice() {
+        if *container != name {
+            return None;
+        }
     }
 
-    for (key, mut new_value) in prefs {
-        let old_value = plist_value
-            .as_dictionary()
-            .ok_or_else(|| E::NotADictionary {
-                domain: domain.to_owned(),
-                key: key.clone(),
-                plist_type: get_plist_value_type(&plist_value),
-            })?
-            .get(&key);
+    name.parse().ok()
+}
 
-        debug!(
-            "Working out whether we need to change the default {domain} {key}: {old_value:?} -> \
-             {new_value:?}"
-        );
+/// Whether a string looks like a hardware UUID, as in `ByHost` plist file names.
+pub(super) fn is_hardware_uuid(uuid: &str) -> bool {
+    uuid.len() == 36 && uuid.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
 
-        // Performs merge operations
-        merge_value(&mut new_value, old_value);
+/// Domains whose sandbox container isn't named after them, see [`add_containers`].
+static CONTAINERS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
 
-        if let Some(old_value) = old_value {
-            if old_value == &new_value {
-                trace!("Nothing to do, values already match: {key:?} = {new_value:?}");
-                continue;
-            }
+/// Domains kept in a group container, see [`add_containers`].
+static GROUP_CONTAINERS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// Resolve domains to plists in the given sandbox containers rather than ones named after them,
+/// e.g. `com.apple.Safari.SafeBrowsing: com.apple.Safari` for a domain Safari keeps in its own
+/// container, or in the given group containers, e.g. `com.microsoft.office: UBF8T346G9.Office`.
+/// Comes from the config file's `containers` and `group_containers`, and each YAML document's.
+pub fn add_containers(containers: &HashMap<String, String>, group_containers: &HashMap<String, String>) {
+    let mut added = add_to(&CONTAINERS, containers);
+    added.extend(add_to(&GROUP_CONTAINERS, group_containers));
+
+    if !added.is_empty() {
+        forget_missing_container_plists(&added);
+    }
+}
+
+/// Add domain to container mappings, returning the containers that are new or changed.
+fn add_to(known: &Mutex<BTreeMap<String, String>>, containers: &HashMap<String, String>) -> BTreeSet<String> {
+    let mut known = known.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut added = BTreeSet::new();
+
+    for (domain, container) in containers {
+        match known.insert(domain.clone(), container.clone()) {
+            Some(previous) if previous == *container => continue,
+            Some(previous) => warn!("The container of {domain} changed from {previous} to {container}."),
+            None => {}
         }
+        added.insert(container.clone());
+    }
+
+    added
+}
 
-        values_changed = true;
+/// The container given for a domain, if any.
+fn container_for(domain: &str) -> Option<String> {
+    CONTAINERS.lock().unwrap_or_else(PoisonError::into_inner).get(domain).cloned()
+}
 
-        info!("Changing default {domain} {key}: {old_value:?} -> {new_value:?}",);
+/// The group container given for a domain, if any.
+fn group_container_for(domain: &str) -> Option<String> {
+    GROUP_CONTAINERS.lock().unwrap_or_else(PoisonError::into_inner).get(domain).cloned()
+}
 
-        let plist_type = get_plist_value_type(&plist_value);
+/// `~/Library/Group Containers/{group}/Library/Preferences/{filename}`.
+fn group_container_plist_path(home_dir: &Utf8Path, group: &str, current_host: bool, filename: &str) -> Utf8PathBuf {
+    let mut plist_path = home_dir.join("Library/Group Containers").join(group);
+    extend_with_prefs_folders(current_host, &mut plist_path, filename);
+    plist_path
+}
 
-        trace!("Plist type: {plist_type:?}");
+/// The group containers of each home directory, listed once per run, as every domain without a
+/// plist elsewhere looks for one in them.
+static GROUP_CONTAINER_NAMES: Mutex<BTreeMap<Utf8PathBuf, Vec<String>>> = Mutex::new(BTreeMap::new());
+
+/// The names of the group containers in a home directory, e.g. `UBF8T346G9.Office`.
+fn group_container_names(home_dir: &Utf8Path) -> Vec<String> {
+    let mut names = GROUP_CONTAINER_NAMES.lock().unwrap_or_else(PoisonError::into_inner);
+
+    names
+        .entry(home_dir.to_owned())
+        .or_insert_with(|| {
+            home_dir
+                .join("Library/Group Containers")
+                .read_dir_utf8()
+                .into_iter()
+                .flatten()
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name().to_owned())
+                .collect()
+        })
+        .clone()
+}
 
-        plist_value
-            .as_dictionary_mut()
-            .ok_or_else(|| E::NotADictionary {
-                domain: domain.to_owned(),
-                key: key.clone(),
-                plist_type,
-            })?
-            .insert(key, new_value);
+/// The one group container with a plist of this name, if only one has it. Group containers are
+/// named after a team ID and group (e.g. `UBF8T346G9.Office`), not the domains in them.
+fn find_group_container_plist(home_dir: &Utf8Path, current_host: bool, filename: &str) -> Option<Utf8PathBuf> {
+    let found: Vec<Utf8PathBuf> = group_container_names(home_dir)
+        .iter()
+        .map(|group| group_container_plist_path(home_dir, group, current_host, filename))
+        .filter(|path| container_plist_exists(path))
+        .collect();
+
+    match found.as_slice() {
+        [path] => Some(path.clone()),
+        [] => None,
+        paths => {
+            warn!(
+                "{filename} is in several group containers, set `group_containers` for its domain to pick one:\n  {}",
+                paths.iter().join("\n  ")
+            );
+            None
+        }
     }
+}
 
-    if !values_changed {
-        return Ok(values_changed);
-    }
+/// Container plist paths found missing during this run. Nothing creates containers while
+/// applying, so a miss is remembered rather than checked again for every document that names the
+/// domain, which adds up with network home directories.
+static MISSING_CONTAINER_PLISTS: Mutex<BTreeSet<Utf8PathBuf>> = Mutex::new(BTreeSet::new());
 
-    if plist_path_exists {
-        let backup_path = Utf8PathBuf::from(format!("{plist_path}.prev"));
+/// Container plist lookups answered from [`MISSING_CONTAINER_PLISTS`], and checked on disk.
+static CONTAINER_CACHE_HITS: AtomicUsize = AtomicUsize::new(0);
+static CONTAINER_CACHE_CHECKS: AtomicUsize = AtomicUsize::new(0);
 
-        trace!("Backing up plist file {plist_path} -> {backup_path}",);
+/// Whether a container plist exists, remembering the ones that don't for the rest of the run.
+fn container_plist_exists(path: &Utf8Path) -> bool {
+    let mut missing = MISSING_CONTAINER_PLISTS.lock().unwrap_or_else(PoisonError::into_inner);
 
-        // TODO: Handle sudo case and not being able to backup.
-        fs::copy(&plist_path, &backup_path).map_err(|e| E::FileCopy {
-            from_path: plist_path.clone(),
-            to_path: backup_path.clone(),
-            source: e,
-        })?;
-    } else {
-        warn!("Defaults plist doesn't exist, creating it: {plist_path}");
+    if missing.contains(path) {
+        CONTAINER_CACHE_HITS.fetch_add(1, Ordering::Relaxed);
+        return false;
+    }
 
-        let plist_dirpath = plist_path.parent().ok_or(E::UnexpectedNone)?;
+    CONTAINER_CACHE_CHECKS.fetch_add(1, Ordering::Relaxed);
 
-        fs::create_dir_all(plist_dirpath).map_err(|e| E::DirCreation {
-            path: plist_dirpath.to_owned(),
-            source: e,
-        })?;
+    let exists = path.exists();
+
+    if !exists {
+        missing.insert(path.to_owned());
     }
 
-    write_plist(plist_path_exists, &plist_path, &plist_value)?;
-    trace!("Plist updated at {plist_path}");
+    exists
+}
 
-    Ok(values_changed)
+/// Forget the misses inside the given containers, so newly mapped domains are looked up again.
+fn forget_missing_container_plists(containers: &BTreeSet<String>) {
+    MISSING_CONTAINER_PLISTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .retain(|path| !path.components().any(|component| containers.contains(component.as_str())));
 }
 
-/// Write a plist file to a path. Will fall back to trying to use sudo if a normal write fails.
-fn write_plist(plist_path_exists: bool, plist_path: &Utf8Path, plist_value: &plist::Value) -> Result<(), E> {
-    //
-    let should_write_binary = !plist_path_exists || is_binary(plist_path)?;
+/// Log how many container plist lookups the cache of missing ones saved, at `-vvv` and up.
+pub fn log_container_cache_stats() {
+    let hits = CONTAINER_CACHE_HITS.load(Ordering::Relaxed);
+    let checks = CONTAINER_CACHE_CHECKS.load(Ordering::Relaxed);
 
-    let write_result = if should_write_binary {
-        trace!("Writing binary plist");
-        plist::to_file_binary(plist_path, &plist_value)
-    } else {
-        trace!("Writing xml plist");
-        plist::to_file_xml(plist_path, &plist_value)
-    };
+    if hits + checks > 0 {
+        let missing = MISSING_CONTAINER_PLISTS.lock().unwrap_or_else(PoisonError::into_inner).len();
+        debug!("Container plist lookups: {checks} checked on disk ({missing} missing), {hits} answered from the cache.");
+    }
+}
 
-    let Err(plist_error) = write_result else {
-        return Ok(());
+/// If a domain has a container plist, and a plist in `~/Library/Preferences/` also exists, return
+/// the path of the latter. Warns if the two files have different contents, as only the container
+/// plist is read by the app.
+pub(super) fn check_sandbox_divergence(domain: &Domain, current_host: bool) -> Result<Option<Utf8PathBuf>> {
+    let Domain::Named(domain) = domain else {
+        return Ok(None);
     };
 
-    let io_error = match plist_error.into_io() {
-        Ok(io_error) => io_error,
-        Err(plist_error) => {
-            return Err(E::PlistWrite {
-                path: plist_path.to_path_buf(),
-                source: plist_error,
-            })
-        }
-    };
+    let filename = plist_filename(domain, current_host)?;
 
-    trace!("Tried to write plist file, got IO error {io_error:?}, trying again with sudo");
+    let container = container_for(domain);
+
+    let mut sandboxed_plist_path = home_dir()?;
+    sandboxed_plist_path.extend(&["Library", "Containers", container.as_deref().unwrap_or(domain), "Data"]);
+    extend_with_prefs_folders(current_host, &mut sandboxed_plist_path, &filename);
 
-    let mut plist_bytes = Vec::new();
+    let mut unsandboxed_plist_path = home_dir()?;
+    extend_with_prefs_folders(current_host, &mut unsandboxed_plist_path, &filename);
 
-    if should_write_binary {
-        plist::to_writer_binary(&mut plist_bytes, &plist_value)
-    } else {
-        plist::to_writer_xml(&mut plist_bytes, &plist_value)
+    if !container_plist_exists(&sandboxed_plist_path) || !unsandboxed_plist_path.exists() {
+        return Ok(None);
     }
-    .map_err(|e| E::PlistWrite {
-        path: Utf8Path::new("/dev/stdout").to_path_buf(),
-        source: e,
-    })?;
 
-    cmd!("sudo", "tee", plist_path)
-        .stdin_bytes(plist_bytes)
-        .stdout_null()
-        .run()
-        .map_err(|e| E::PlistSudoWrite {
-            path: plist_path.to_path_buf(),
-            source: e,
-        })
-        .map(|_| ())?;
-    Ok(())
-}
+    let sandboxed = read_plist(&sandboxed_plist_path).ok();
+    let unsandboxed = read_plist(&unsandboxed_plist_path).ok();
 
-/// Combines plist values using the following operations:
-/// * Merges dictionaries so new keys apply and old keys are let untouched
-/// * Replaces "..." in arrays with a copy of the old array (duplicates removed)
-///
-/// This operation is performed recursively on dictionaries.
-fn merge_value(new_value: &mut Value, old_value: Option<&Value>) {
-    deep_merge_dictionaries(new_value, old_value);
-    replace_ellipsis_array(new_value, old_value);
+    if sandboxed != unsandboxed {
+        warn!(
+            "Both a sandboxed and an unsandboxed plist exist for {domain} with different contents, only the sandboxed one is \
+             used:\n  {sandboxed_plist_path}\n  {unsandboxed_plist_path}"
+        );
+    }
+
+    Ok(Some(unsandboxed_plist_path))
 }
 
-/// Replace `...` values in an input array.
-/// You end up with: [<new values before ...>, <old values>, <new values after ...>]
-/// But any duplicates between old and new values are removed, with the first value taking
-/// precedence.
-fn replace_ellipsis_array(new_value: &mut Value, old_value: Option<&Value>) {
-    //
-    let Value::Array(new_array) = new_value else {
-        trace!("Value isn't an array, skipping ellipsis replacement...");
-        return;
-    };
+/// Directory standing in for the home directory when resolving preferences, see [`set_prefs_root`].
+static PREFS_ROOT: OnceLock<Utf8PathBuf> = OnceLock::new();
 
-    let ellipsis = plist::Value::from(ELLIPSIS);
+/// Resolve domains under `root` rather than the current user's home directory, e.g. a secondary
+/// user's home (`/Users/test`) or an iOS Simulator's data directory
+/// (`~/Library/Developer/CoreSimulator/Devices/{udid}/data`).
+pub fn set_prefs_root(root: Utf8PathBuf) -> Result<()> {
+    if !root.is_dir() {
+        return Err(eyre!("Preferences root is not a directory: {root}"));
+    }
 
-    let Some(position) = new_array.iter().position(|x| x == &ellipsis) else {
-        trace!("New value doesn't contain ellipsis, skipping ellipsis replacement...");
-        return;
-    };
+    debug!("Preferences root: {root}");
+    PREFS_ROOT.set(root).map_err(|root| eyre!("Preferences root was already set to: {root}"))
+}
 
-    let Some(old_array) = old_value.and_then(plist::Value::as_array) else {
-        trace!("Old value wasn't an array, skipping ellipsis replacement...");
-        new_array.remove(position);
-        return;
-    };
+/// Hardware UUID of the Mac pretended to be under `--simulate`, see [`set_simulated`].
+pub const SIMULATED_HARDWARE_UUID: &str = "00000000-0000-0000-0000-000000000000";
 
-    let array_copy: Vec<_> = std::mem::take(new_array);
+/// Set for a `--simulate` run, see [`set_simulated`].
+static SIMULATED: OnceLock<()> = OnceLock::new();
 
-    trace!("Performing array ellipsis replacement...");
+/// Pretend to be a Mac, e.g. to plan YAML files in CI on Linux: resolve domains under `root`, or a
+/// new empty home directory if none is given, and use [`SIMULATED_HARDWARE_UUID`] for `ByHost`
+/// plists.
+pub fn set_simulated(root: Option<Utf8PathBuf>) -> Result<()> {
+    let root = if let Some(root) = root { root } else { simulated_home()? };
 
-    for element in array_copy {
-        if element == ellipsis {
-            for old_element in old_array {
-                if new_array.contains(old_element) {
-                    continue;
-                }
-                new_array.push(old_element.clone());
-            }
-        } else if !new_array.contains(&element) {
-            new_array.push(element);
+    SIMULATED.set(()).map_err(|()| eyre!("Simulation was already set up."))?;
+    set_prefs_root(root)
+}
+
+/// The home directory made by [`simulated_home`], until [`remove_simulated_home`].
+static SIMULATED_HOME: Mutex<Option<TempDir>> = Mutex::new(None);
+
+/// A new empty directory to stand in for the home directory under `--simulate`, so nothing left
+/// by an earlier run is planned against. It replaces, and so removes, an earlier one.
+fn simulated_home() -> Result<Utf8PathBuf> {
+    let home = tempfile::Builder::new().prefix("macos-defaults-simulate-").tempdir()?;
+    let path = Utf8PathBuf::try_from(home.path().to_path_buf())?;
+
+    *SIMULATED_HOME.lock().unwrap_or_else(PoisonError::into_inner) = Some(home);
+
+    Ok(path)
+}
+
+/// Remove the `--simulate` home directory, if one was made. Statics aren't dropped, so this has
+/// to be called before exiting.
+pub fn remove_simulated_home() {
+    if let Some(home) = SIMULATED_HOME.lock().unwrap_or_else(PoisonError::into_inner).take() {
+        if let Err(e) = home.close() {
+            warn!("Couldn't remove the simulated home directory: {e}");
         }
     }
 }
 
-// Recursively merge dictionaries, unless the new value is empty `{}`.
-// If a dictionary
-// * is empty `{}`
-// * contains a key `{}`
-// Then the merge step will be skipped for it and its children.
-fn deep_merge_dictionaries(new_value: &mut Value, old_value: Option<&Value>) {
-    //
-    let Value::Dictionary(new_dict) = new_value else {
-        trace!("New value is not a dictionary, Skipping merge...");
-        return;
-    };
+/// Directory standing in for `/` under `--target-root`, see [`set_target_root`].
+static TARGET_ROOT: OnceLock<Utf8PathBuf> = OnceLock::new();
+
+/// Resolve every path under `root` as if it were `/`, so complete applies can run on any OS (e.g.
+/// in CI) and write real plists to check: the home directory becomes `{root}{home}`, absolute
+/// domains like `/Library/Preferences/com.apple.loginwindow` are looked up under `root`, as are
+/// managed preferences and applications, and `ByHost` plists use [`SIMULATED_HARDWARE_UUID`]. No
+/// processes are restarted.
+pub fn set_target_root(root: &Utf8Path) -> Result<()> {
+    if !root.is_dir() {
+        return Err(eyre!("Target root is not a directory: {root}"));
+    }
 
-    if new_dict.is_empty() {
-        trace!("New value is an empty dictionary. Skipping merge...");
-        return;
+    let root = root.canonicalize_utf8()?;
+    let home = rooted(&root, &home_dir()?);
+
+    fs::create_dir_all(&home).map_err(|e| E::DirCreation { path: home.clone(), source: e })?;
+
+    debug!("Target root: {root}");
+    TARGET_ROOT.set(root).map_err(|root| eyre!("Target root was already set to: {root}"))?;
+    SIMULATED.set(()).map_err(|()| eyre!("Simulation was already set up."))?;
+
+    set_prefs_root(home)
+}
+
+/// The `--target-root`, if one was given.
+pub fn target_root() -> Option<&'static Utf8Path> {
+    TARGET_ROOT.get().map(Utf8PathBuf::as_path)
+}
+
+/// An absolute path, under the `--target-root` if one was given.
+pub fn target_path(path: &Utf8Path) -> Utf8PathBuf {
+    match TARGET_ROOT.get() {
+        Some(root) => rooted(root, path),
+        None => path.to_owned(),
     }
+}
 
-    // the "..." key is no longer used, and its merging behavior is performed by default. ignore it, for compatibility with older YAML.
-    new_dict.remove(ELLIPSIS);
+fn rooted(root: &Utf8Path, path: &Utf8Path) -> Utf8PathBuf {
+    root.join(path.strip_prefix("/").unwrap_or(path))
+}
 
-    let Some(old_dict) = old_value.and_then(plist::Value::as_dictionary) else {
-        trace!("Old value wasn't a dict. Skipping merge...");
-        return;
+/// Owner (uid, gid) of the `--user` whose preferences are being changed, see [`set_user`].
+static PREFS_OWNER: OnceLock<(u32, u32)> = OnceLock::new();
+
+/// Name of the `--user` whose preferences are being changed.
+static PREFS_USER: OnceLock<String> = OnceLock::new();
+
+/// Operate on another user's preferences, as root: resolve domains under their home directory (as
+/// with `--prefs-root`), and make them the owner of the files written.
+pub fn set_user(user: &str) -> Result<()> {
+    if current_uid() != Some(0) {
+        return Err(E::UserNeedsRoot { user: user.to_owned() }.into());
+    }
+
+    let home = user_home(user)?;
+    let metadata = fs::metadata(&home).map_err(|e| E::FileRead { path: home.clone(), source: e })?;
+
+    debug!("Writing preferences of {user} ({}:{}) in {home}", metadata.uid(), metadata.gid());
+
+    PREFS_OWNER
+        .set((metadata.uid(), metadata.gid()))
+        .map_err(|_| eyre!("Preferences owner was already set."))?;
+
+    PREFS_USER.set(user.to_owned()).map_err(|_| eyre!("Preferences user was already set."))?;
+
+    set_prefs_root(home)
+}
+
+/// The `--prefs-root` (or `--user`'s home directory), if one was given.
+pub fn prefs_root() -> Option<&'static Utf8Path> {
+    PREFS_ROOT.get().map(Utf8PathBuf::as_path)
+}
+
+/// The name of the `--user` whose preferences are being changed, if one was given.
+pub fn prefs_user() -> Option<&'static str> {
+    PREFS_USER.get().map(String::as_str)
+}
+
+/// The uid of the `--user` whose preferences are being changed, if one was given.
+pub(crate) fn prefs_owner_uid() -> Option<u32> {
+    PREFS_OWNER.get().map(|(uid, _)| *uid)
+}
+
+/// A user's home directory, from Directory Services.
+fn user_home(user: &str) -> Result<Utf8PathBuf, E> {
+    let output = cmd!("dscl", ".", "-read", format!("/Users/{user}"), "NFSHomeDirectory")
+        .stderr_null()
+        .read()
+        .map_err(|e| E::UserLookup {
+            user: user.to_owned(),
+            source: e,
+        })?;
+
+    parse_home_directory(&output).ok_or(E::UnknownUser { user: user.to_owned() })
+}
+
+/// The path in `dscl` output like `NFSHomeDirectory: /Users/test`. Values with spaces are on the
+/// next line.
+fn parse_home_directory(output: &str) -> Option<Utf8PathBuf> {
+    let mut lines = output.lines();
+    let value = lines.find_map(|line| line.strip_prefix("NFSHomeDirectory:"))?.trim();
+    let home = if value.is_empty() { lines.next()?.trim() } else { value };
+
+    home.starts_with('/').then(|| Utf8PathBuf::from(home))
+}
+
+/// Give a file or directory written for the `--user` to them. Does nothing without `--user`.
+pub(crate) fn set_prefs_owner(path: &Utf8Path) -> Result<(), E> {
+    let Some((uid, gid)) = PREFS_OWNER.get() else {
+        return Ok(());
     };
 
-    // for each value, recursively invoke this to merge any child dictionaries.
-    // also perform array ellipsis replacement.
-    // this occurs even if "!" is present.
-    for (key, new_child_value) in &mut *new_dict {
-        let old_child_value = old_dict.get(key);
-        merge_value(new_child_value, old_child_value);
+    cmd!("chown", format!("{uid}:{gid}"), path)
+        .stdout_null()
+        .run()
+        .map(|_| ())
+        .map_err(|e| E::ChownFailed {
+            path: path.to_owned(),
+            source: e,
+        })
+}
+
+/// The current user's home directory, or the `--prefs-root` if one was given.
+pub(super) fn home_dir() -> Result<Utf8PathBuf> {
+    if let Some(root) = PREFS_ROOT.get() {
+        return Ok(root.clone());
     }
 
-    if new_dict.contains_key(BANG) {
-        trace!("Dictionary contains key '!'. Skipping merge...");
-        new_dict.remove(BANG);
-        return;
+    let home_dir = dirs::home_dir().ok_or_else(|| eyre!("Expected to be able to calculate the user's home directory."))?;
+    Ok(Utf8PathBuf::try_from(home_dir)?)
+}
+
+/// Take a directory path, and add on the directories and files containing the application's
+/// preferences. Normally this is `./Library/Preferences/{domain}.plist`, but if `current_host` is
+/// `true`, then we need to look in the `ByHost` subfolder.
+fn extend_with_prefs_folders(current_host: bool, plist_path: &mut Utf8PathBuf, filename: &str) {
+    if current_host {
+        plist_path.extend(&["Library", "Preferences", "ByHost", filename]);
+    } else {
+        plist_path.extend(&["Library", "Preferences", filename]);
     }
+}
 
-    trace!("Performing deep merge...");
+/// Get the expected filename for a plist file. Normally it's just the preference name + `.plist`,
+/// but if it's a currentHost setup, then we need to include the current host UUID as well.
+fn plist_filename(domain: &str, current_host: bool) -> Result<String, E> {
+    if current_host {
+        return Ok(format!(
+            "{domain}.{hardware_uuid}.plist",
+            hardware_uuid = get_hardware_uuid().map_err(|e| E::EyreError { source: e })?
+        ));
+    }
 
-    for (key, old_value) in old_dict {
-        if !new_dict.contains_key(key) {
-            new_dict.insert(key.clone(), old_value.clone());
+    Ok(format!("{domain}.plist"))
+}
+
+/// Suggest installed domains with names close to `domain`, in case it's a typo: `Did you mean
+/// com.apple.finder?`, or `None` if there aren't any.
+pub(super) fn did_you_mean(domain: &str, current_host: bool) -> Option<String> {
+    let installed = domains(current_host).unwrap_or_default();
+    let similar = closest_matches(domain, installed.iter().map(Domain::as_str));
+
+    (!similar.is_empty()).then(|| format!("Did you mean {}?", similar.join(", ")))
+}
+
+/// Up to three of `candidates` within a few edits of `needle`, ignoring case, closest first.
+fn closest_matches<'a>(needle: &str, candidates: impl IntoIterator<Item = &'a str>) -> Vec<String> {
+    let needle = needle.to_lowercase();
+    let max_distance = (needle.chars().count() / 5).max(2);
+
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(&needle, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| (1..=max_distance).contains(distance))
+        .sorted()
+        .dedup()
+        .take(3)
+        .map(|(_, candidate)| candidate.to_owned())
+        .collect()
+}
+
+/// Levenshtein distance: the number of single character insertions, deletions or substitutions
+/// that turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
         }
+
+        previous = current;
     }
+
+    previous[b.len()]
 }
 
-/// Get the hardware UUID of the current Mac.
-/// You can get the Hardware UUID from:
-/// <https://apple.stackexchange.com/questions/342042/how-can-i-query-the-hardware-uuid-of-a-mac-programmatically-from-a-command-line>
-fn get_hardware_uuid() -> Result<String> {
-    let raw_output = cmd!("ioreg", "-d2", "-a", "-c", "IOPlatformExpertDevice").read()?;
-    let ioreg_output: IoregOutput = plist::from_bytes(raw_output.as_bytes())?;
-    Ok(ioreg_output
-        .io_registry_entry_children
+/**
+List the logical domains that have a plist file for the current user.
+
+This mirrors the resolution rules of [`plist_path`]: plists in `~/Library/Preferences/` (or its
+`ByHost` subfolder if `current_host` is `true`), plus any container plist whose file name matches
+its container folder. The global preferences file is reported as `NSGlobalDomain`.
+*/
+pub(super) fn domains(current_host: bool) -> Result<Vec<Domain>> {
+    let home_dir = home_dir()?;
+
+    let mut prefs_dir = home_dir.join("Library/Preferences");
+    if current_host {
+        prefs_dir.push("ByHost");
+    }
+
+    let suffix = if current_host {
+        format!(".{}.plist", get_hardware_uuid()?)
+    } else {
+        ".plist".to_owned()
+    };
+
+    let mut domains = Vec::new();
+
+    for entry in prefs_dir.read_dir_utf8().into_iter().flatten().filter_map(Result::ok) {
+        if let Some(domain) = entry.file_name().strip_suffix(&suffix).and_then(|domain| domain.parse().ok()) {
+            domains.push(domain);
+        }
+    }
+
+    let containers_dir = home_dir.join("Library/Containers");
+
+    for entry in containers_dir.read_dir_utf8().into_iter().flatten().filter_map(Result::ok) {
+        let container = entry.file_name();
+        let mut container_plist = entry.path().join("Data");
+        extend_with_prefs_folders(current_host, &mut container_plist, &format!("{container}{suffix}"));
+
+        if let Some(domain) = container_plist.exists().then(|| container.parse().ok()).flatten() {
+            domains.push(domain);
+        }
+    }
+
+    // Group containers are shared by several apps, so every plist in them is a domain.
+    for entry in home_dir
+        .join("Library/Group Containers")
+        .read_dir_utf8()
         .into_iter()
-        .next()
-        .ok_or_else(|| eyre!("Failed to get the Hardware UUID for the current Mac."))?
-        .io_platform_uuid)
+        .flatten()
+        .filter_map(Result::ok)
+    {
+        let group_prefs_dir = entry
+            .path()
+            .join(if current_host { "Library/Preferences/ByHost" } else { "Library/Preferences" });
+
+        for entry in group_prefs_dir.read_dir_utf8().into_iter().flatten().filter_map(Result::ok) {
+            if let Some(domain) = entry.file_name().strip_suffix(&suffix).and_then(|domain| domain.parse().ok()) {
+                domains.push(domain);
+            }
+        }
+    }
+
+    domains.sort();
+    domains.dedup();
+
+    Ok(domains)
 }
 
-/// XML output returned by `ioreg -d2 -a -c IOPlatformExpertDevice`
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct IoregOutput {
-    /// The set of `IORegistry` entries.
-    #[serde(rename = "IORegistryEntryChildren")]
-    io_registry_entry_children: Vec<IoRegistryEntryChildren>,
+/// All plist files in the user's preferences folders: `~/Library/Preferences/`, its `ByHost`
+/// subfolder, and the preferences folder of every container and group container.
+pub(super) fn user_plist_paths() -> Result<Vec<Utf8PathBuf>> {
+    let home_dir = home_dir()?;
+
+    let mut prefs_dirs = vec![home_dir.join("Library/Preferences"), home_dir.join("Library/Preferences/ByHost")];
+
+    for entry in home_dir.join("Library/Containers").read_dir_utf8().into_iter().flatten().filter_map(Result::ok) {
+        prefs_dirs.push(entry.path().join("Data/Library/Preferences"));
+        prefs_dirs.push(entry.path().join("Data/Library/Preferences/ByHost"));
+    }
+
+    for entry in home_dir
+        .join("Library/Group Containers")
+        .read_dir_utf8()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+    {
+        prefs_dirs.push(entry.path().join("Library/Preferences"));
+        prefs_dirs.push(entry.path().join("Library/Preferences/ByHost"));
+    }
+
+    let mut paths: Vec<_> = prefs_dirs
+        .iter()
+        .flat_map(|dir| dir.read_dir_utf8().into_iter().flatten().filter_map(Result::ok))
+        .map(camino::Utf8DirEntry::into_path)
+        .filter(|path| path.extension() == Some("plist"))
+        .collect();
+
+    paths.sort();
+
+    Ok(paths)
 }
 
-/// A specific `IORegistry` entry.
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct IoRegistryEntryChildren {
-    /// The platform UUID.
-    #[serde(rename = "IOPlatformUUID")]
-    io_platform_uuid: String,
+/// `ByHost` plist files for the hardware UUID `uuid`, in `~/Library/Preferences/ByHost/` and
+/// every container's preferences folder.
+pub(super) fn byhost_plist_paths(uuid: &str) -> Result<Vec<Utf8PathBuf>> {
+    let suffix = format!(".{uuid}.plist");
+
+    Ok(user_plist_paths()?
+        .into_iter()
+        .filter(|path| path.parent().and_then(Utf8Path::file_name) == Some("ByHost"))
+        .filter(|path| {
+            path.file_name()
+                .is_some_and(|name| name.len() > suffix.len() && name.to_uppercase().ends_with(&suffix.to_uppercase()))
+        })
+        .collect())
 }
 
-/// Helper to allow serializing plists containing binary data to yaml.
-/// Replace binary data attributes to work around <https://github.com/dtolnay/serde-yaml/issues/91>.
-pub fn replace_data_in_plist(value: &mut Value) -> Result<()> {
-    let mut stringified_data_value = match value {
-        Value::Array(arr) => {
-            for el in arr.iter_mut() {
-                replace_data_in_plist(el)?;
+/// How a `ByHost` plist was moved to the current hardware UUID.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum ByHostMigration {
+    /// Renamed, as there was no plist for the current UUID yet.
+    Renamed(Utf8PathBuf),
+    /// Merged into the existing plist for the current UUID, whose values win, and removed.
+    Merged(Utf8PathBuf),
+}
+
+/// Move a `ByHost` plist for an old hardware UUID to `uuid`, in the same folder.
+///
+/// If the current UUID already has a plist (e.g. written since the migration), keys it doesn't
+/// have are copied over from the old one, and the old one removed.
+pub(super) fn migrate_byhost_plist(old_path: &Utf8Path, uuid: &str, dry_run: bool) -> Result<ByHostMigration> {
+    let file_name = old_path.file_name().ok_or(E::UnexpectedNone)?;
+    let (domain, _) = file_name
+        .strip_suffix(".plist")
+        .and_then(|name| name.rsplit_once('.'))
+        .ok_or(E::UnexpectedNone)?;
+    let new_path = old_path.with_file_name(format!("{domain}.{uuid}.plist"));
+
+    if !new_path.exists() {
+        if !dry_run {
+            fs::rename(old_path, &new_path).map_err(|e| E::FileRename {
+                from_path: old_path.to_owned(),
+                to_path: new_path.clone(),
+                source: e,
+            })?;
+        }
+
+        return Ok(ByHostMigration::Renamed(new_path));
+    }
+
+    let old_value = read_plist(old_path)?;
+    let mut new_value = read_plist(&new_path)?;
+
+    let (Some(old_dict), Some(new_dict)) = (old_value.as_dictionary(), new_value.as_dictionary_mut()) else {
+        return Err(eyre!("Expected both {old_path} and {new_path} to be plist dictionaries."));
+    };
+
+    for (key, value) in old_dict {
+        if !new_dict.contains_key(key) {
+            new_dict.insert(key.clone(), value.clone());
+        }
+    }
+
+    if !dry_run {
+        write_plist(true, &new_path, &new_value, &XmlFormat::default(), WriteFormat::Auto, false)?;
+        fs::remove_file(old_path).map_err(|e| E::FileRemove {
+            path: old_path.to_owned(),
+            source: e,
+        })?;
+    }
+
+    Ok(ByHostMigration::Merged(new_path))
+}
+
+/// Recursively search a plist value for keys or string values containing `needle` (case
+/// insensitive), returning the dotted key path of every match. Array elements are addressed by
+/// their index.
+pub(super) fn find_key_paths(value: &Value, needle: &str) -> Vec<String> {
+    fn walk(value: &Value, needle: &str, path: &mut Vec<String>, matches: &mut Vec<String>) {
+        match value {
+            Value::Dictionary(dict) => {
+                for (key, child) in dict {
+                    path.push(key.clone());
+                    if key.to_lowercase().contains(needle) {
+                        matches.push(path.join("."));
+                    }
+                    walk(child, needle, path, matches);
+                    path.pop();
+                }
             }
-            return Ok(());
+            Value::Array(array) => {
+                for (index, child) in array.iter().enumerate() {
+                    path.push(index.to_string());
+                    walk(child, needle, path, matches);
+                    path.pop();
+                }
+            }
+            Value::String(string) if string.to_lowercase().contains(needle) => {
+                let key_path = path.join(".");
+                if matches.last() != Some(&key_path) {
+                    matches.push(key_path);
+                }
+            }
+            _ => {}
         }
-        Value::Dictionary(dict) => {
-            for (_, v) in dict.iter_mut() {
-                replace_data_in_plist(v)?;
+    }
+
+    let mut matches = Vec::new();
+    walk(value, &needle.to_lowercase(), &mut Vec::new(), &mut matches);
+    matches
+}
+
+/// Get a mutable reference to the value at a dotted key path (as printed by `find`), where array
+/// elements are addressed by their index.
+pub(super) fn value_at_key_path_mut<'a>(value: &'a mut Value, key_path: &str) -> Option<&'a mut Value> {
+    key_path.split('.').try_fold(value, |value, segment| match value {
+        Value::Dictionary(dict) => dict.get_mut(segment),
+        Value::Array(array) => array.get_mut(segment.parse::<usize>().ok()?),
+        _ => None,
+    })
+}
+
+/// Sort the elements of an array whose order isn't meaningful into a stable order, so that dumps
+/// from different machines can be diffed. Elements are compared by their representation with
+/// recursively sorted dictionary keys.
+pub(super) fn sort_array_stable(value: &mut Value) {
+    fn sorted(value: &Value) -> Value {
+        match value {
+            Value::Dictionary(dict) => {
+                let mut dict: Dictionary = dict.iter().map(|(k, v)| (k.clone(), sorted(v))).collect();
+                dict.sort_keys();
+                Value::Dictionary(dict)
             }
-            return Ok(());
+            Value::Array(array) => Value::Array(array.iter().map(sorted).collect()),
+            _ => value.clone(),
         }
-        Value::Data(bytes) => Value::String(hex::encode(bytes)),
-        _ => {
-            return Ok(());
+    }
+
+    if let Value::Array(array) = value {
+        array.sort_by_cached_key(|element| format!("{:?}", sorted(element)));
+    }
+}
+
+/// Sort the keys of every dictionary in a value, however deeply nested, e.g. in arrays. Arrays
+/// keep their order.
+pub(super) fn sort_keys_nested(value: &mut Value) {
+    match value {
+        Value::Dictionary(dict) => {
+            dict.sort_keys();
+            dict.values_mut().for_each(sort_keys_nested);
+        }
+        Value::Array(array) => array.iter_mut().for_each(sort_keys_nested),
+        _ => {}
+    }
+}
+
+/// String representation of a plist Value's type.
+pub(super) fn get_plist_value_type(plist: &plist::Value) -> &'static str {
+    match plist {
+        p if p.as_array().is_some() => "array",
+        p if p.as_boolean().is_some() => "boolean",
+        p if p.as_date().is_some() => "date",
+        p if p.as_real().is_some() => "real",
+        p if p.as_signed_integer().is_some() => "signed_integer",
+        p if p.as_unsigned_integer().is_some() => "unsigned_integer",
+        p if p.as_string().is_some() => "string",
+        p if p.as_dictionary().is_some() => "dictionary",
+        p if p.as_data().is_some() => "data",
+        p if p.as_uid().is_some() => "uid",
+        _ => "unknown",
+    }
+}
+
+/// Check whether a plist file is in the binary plist format or the XML plist format.
+fn is_binary(file: &Utf8Path) -> Result<bool, E> {
+    let mut f = File::open(file).map_err(|e| E::FileRead {
+        path: file.to_path_buf(),
+        source: e,
+    })?;
+    let mut magic = [0; 8];
+
+    // read exactly 8 bytes
+    f.read_exact(&mut magic).map_err(|e| E::FileRead {
+        path: file.to_path_buf(),
+        source: e,
+    })?;
+
+    Ok(&magic == b"bplist00")
+}
+
+/// The plists planning a domain reads: the ones it's written to, and with `layered_read` the
+/// any-host plists under `ByHost` ones.
+pub(super) fn domain_read_paths(domain: &str, current_host: bool, options: &WriteOptions) -> Result<Vec<Utf8PathBuf>> {
+    let plist_paths = domain_plist_paths(&resolve_domain(domain, current_host)?, current_host, options)?;
+
+    let any_host_paths: Vec<_> = plist_paths
+        .iter()
+        .filter(|_| options.layered_read)
+        .filter_map(|plist_path| any_host_plist_path(plist_path))
+        .collect();
+
+    Ok(plist_paths.into_iter().chain(any_host_paths).collect())
+}
+
+/// The plists a domain is written to: its plist, and with `also_write_unsandboxed` the
+/// unsandboxed one too.
+fn domain_plist_paths(domain: &Domain, current_host: bool, options: &WriteOptions) -> Result<Vec<Utf8PathBuf>> {
+    let plist_path = plist_path(domain, current_host)?;
+
+    let mut plist_paths = Vec::new();
+
+    if let Some(unsandboxed_plist_path) = check_sandbox_divergence(domain, current_host)? {
+        if options.also_write_unsandboxed {
+            info!("Also writing unsandboxed plist {unsandboxed_plist_path}");
+            plist_paths.push(unsandboxed_plist_path);
+        }
+    }
+
+    plist_paths.push(plist_path);
+
+    Ok(plist_paths)
+}
+
+/// Plan the plist writes needed to apply a `HashMap` of key-value pairs to a domain: its plist,
+/// and with `also_write_unsandboxed` the unsandboxed one too. Only plists with changes are returned.
+///
+/// `planned` holds the contents of plists that earlier planned writes will produce, which are
+/// merged into instead of the files on disk.
+pub(super) fn plan_domain_writes(
+    domain: &str,
+    prefs: &HashMap<String, plist::Value>,
+    ignore_keys: &[String],
+    current_host: bool,
+    options: &WriteOptions,
+    planned: &HashMap<Utf8PathBuf, Value>,
+) -> Result<Vec<PlistWrite>> {
+    let domain = &resolve_domain(domain, current_host)?;
+    let _span = info_span!("domain", %domain).entered();
+
+    let mut writes = Vec::new();
+
+    for plist_path in domain_plist_paths(domain, current_host, options)? {
+        let any_host_path = any_host_plist_path(&plist_path).filter(|_| options.layered_read);
+
+        let MergedPlist { exists, value, changes } = match (planned.get(&plist_path), any_host_path) {
+            (planned_value, Some(any_host_path)) => {
+                let (exists, by_host) = match planned_value {
+                    Some(planned) => (true, planned.clone()),
+                    None => read_plist_or_empty(options.backend.as_ref(), &plist_path, options.repair)?,
+                };
+
+                let any_host = match planned.get(&any_host_path) {
+                    Some(planned) => planned.clone(),
+                    None => read_plist_or_empty(options.backend.as_ref(), &any_host_path, options.repair)?.1,
+                };
+
+                let (value, changes) = merge_layered(domain, by_host, any_host, prefs.clone(), ignore_keys)?;
+                MergedPlist { exists, value, changes }
+            }
+            (Some(planned), None) => {
+                let (value, changes) = merge_into_plist(domain, planned.clone(), prefs.clone(), ignore_keys)?;
+                MergedPlist { exists: true, value, changes }
+            }
+            (None, None) => merge_plist_values(options.backend.as_ref(), domain, &plist_path, prefs.clone(), ignore_keys, options.repair)?,
+        };
+
+        if !changes.is_empty() {
+            writes.push(PlistWrite {
+                domain: domain.clone(),
+                needs_sudo: needs_sudo(&plist_path),
+                path: plist_path,
+                exists,
+                changes,
+                removed: Vec::new(),
+                value,
+            });
+        }
+    }
+
+    Ok(writes)
+}
+
+/// The domain a raw `.plist` file applies to: the domain of its path if it's in a preferences
+/// directory, otherwise its file name, e.g. `com.apple.dock` for `dotfiles/com.apple.dock.plist`
+/// or, for the current host, `dotfiles/com.apple.dock.{uuid}.plist`.
+pub(super) fn raw_plist_domain(path: &Utf8Path) -> Result<Domain, E> {
+    if let Some(domain) = domain_for_path(path) {
+        return Ok(domain);
+    }
+
+    let name = path.file_stem().unwrap_or_default();
+
+    match name.rsplit_once('.') {
+        Some((domain, uuid)) if is_hardware_uuid(uuid) => domain.parse(),
+        _ => name.parse(),
+    }
+}
+
+/// Whether a raw `.plist` file is for the current host: it's named like the `ByHost` plists macOS
+/// writes, `{domain}.{uuid}.plist`.
+pub(super) fn raw_plist_current_host(path: &Utf8Path) -> bool {
+    path.file_stem()
+        .and_then(|name| name.rsplit_once('.'))
+        .is_some_and(|(_, uuid)| is_hardware_uuid(uuid))
+}
+
+/// Plan replacing a domain's plist with a whole new value, as for a raw `.plist` file.
+///
+/// Unlike [`plan_domain_writes`] nothing is merged: merge markers like `"..."` and `"!"` are
+/// written as they are, and keys the new value doesn't have are removed. `ignore_keys` are left as
+/// they are, whether the new value has them or not.
+pub(super) fn plan_domain_replace(
+    domain: &Domain,
+    value: &Value,
+    ignore_keys: &[String],
+    current_host: bool,
+    options: &WriteOptions,
+    planned: &HashMap<Utf8PathBuf, Value>,
+) -> Result<Vec<PlistWrite>> {
+    let _span = info_span!("domain", %domain).entered();
+
+    let new_dict = value.as_dictionary().ok_or_else(|| E::NotADictionary {
+        domain: domain.to_string(),
+        key: "Unknown".to_owned(),
+        plist_type: get_plist_value_type(value),
+    })?;
+
+    let mut writes = Vec::new();
+
+    for plist_path in domain_plist_paths(domain, current_host, options)? {
+        let (exists, old_value) = match planned.get(&plist_path) {
+            Some(planned) => (true, planned.clone()),
+            None => read_plist_or_empty(options.backend.as_ref(), &plist_path, options.repair)?,
+        };
+
+        let old_dict = old_value.as_dictionary().ok_or_else(|| E::NotADictionary {
+            domain: domain.to_string(),
+            key: "Unknown".to_owned(),
+            plist_type: get_plist_value_type(&old_value),
+        })?;
+
+        let mut new_dict = new_dict.clone();
+
+        for key in ignore_keys {
+            match old_dict.get(key) {
+                Some(old_value) => new_dict.insert(key.clone(), old_value.clone()),
+                None => new_dict.remove(key),
+            };
+        }
+
+        let changes: Vec<KeyChange> = new_dict
+            .iter()
+            .filter(|(key, new_value)| old_dict.get(key) != Some(new_value))
+            .map(|(key, new_value)| KeyChange::new(key.clone(), old_dict.get(key).cloned(), new_value.clone()))
+            .collect();
+
+        let removed: Vec<String> = old_dict.keys().filter(|key| !new_dict.contains_key(key)).cloned().collect();
+
+        if !changes.is_empty() || !removed.is_empty() {
+            writes.push(PlistWrite {
+                domain: domain.clone(),
+                needs_sudo: needs_sudo(&plist_path),
+                path: plist_path,
+                exists,
+                changes,
+                removed,
+                value: Value::Dictionary(new_dict),
+            });
+        }
+    }
+
+    Ok(writes)
+}
+
+/// Whether writing to an existing plist will need the `sudo` fallback.
+fn needs_sudo(plist_path: &Utf8Path) -> bool {
+    plist_path.exists()
+        && fs::OpenOptions::new()
+            .append(true)
+            .open(plist_path)
+            .is_err_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
+/// Plan restoring keys of a plist to the values they had before `apply` first changed them, or
+/// removing them if they didn't exist, for `teardown`. `None` if there's nothing to change.
+pub(crate) fn plan_revert(backend: &dyn PrefsBackend, plist_path: &Utf8Path, originals: &[(String, Option<Value>)]) -> Result<Option<PlistWrite>> {
+    let (exists, mut value) = read_plist_or_empty(backend, plist_path, false)?;
+
+    if !exists {
+        return Ok(None);
+    }
+
+    let domain = raw_plist_domain(plist_path)?;
+    let plist_type = get_plist_value_type(&value);
+
+    let dict = value.as_dictionary_mut().ok_or_else(|| E::NotADictionary {
+        domain: domain.to_string(),
+        key: "Unknown".to_owned(),
+        plist_type,
+    })?;
+
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+
+    for (key, original) in originals {
+        match original {
+            Some(original) if dict.get(key) != Some(original) => {
+                let old_value = dict.insert(key.clone(), original.clone());
+
+                changes.push(KeyChange::new(key.clone(), old_value, original.clone()));
+            }
+            None if dict.remove(key).is_some() => removed.push(key.clone()),
+            _ => {}
+        }
+    }
+
+    if changes.is_empty() && removed.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(PlistWrite {
+        domain,
+        needs_sudo: needs_sudo(plist_path),
+        path: plist_path.to_owned(),
+        exists,
+        changes,
+        removed,
+        value,
+    }))
+}
+
+/// A plist file that applying new values will rewrite.
+#[derive(Debug, Serialize)]
+pub struct PlistWrite {
+    /// The resolved domain.
+    pub domain: Domain,
+    pub path: Utf8PathBuf,
+    /// Whether the plist file already exists, otherwise it's created.
+    pub exists: bool,
+    /// Whether the file isn't writable by the current user, so is written with `sudo`.
+    pub needs_sudo: bool,
+    pub changes: Vec<KeyChange>,
+    /// Top level keys a replaced plist no longer has.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+    /// The whole plist with the new values merged in.
+    #[serde(skip)]
+    pub value: Value,
+}
+
+/// A key whose value would be changed by applying new values.
+#[derive(Debug)]
+pub struct KeyChange {
+    pub key: String,
+    /// `None` if the key doesn't exist yet.
+    pub old_value: Option<Value>,
+    pub new_value: Value,
+    /// Whether either value is, or contains, a secret, so the values are never shown.
+    pub redacted: bool,
+}
+
+/// A [`KeyChange`] as it's serialized, with redacted values.
+#[derive(Serialize)]
+struct ShownKeyChange<'a> {
+    key: &'a str,
+    #[serde(serialize_with = "serialize_readable_value")]
+    old_value: Option<Value>,
+    #[serde(serialize_with = "serialize_readable_value")]
+    new_value: Value,
+}
+
+impl Serialize for KeyChange {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (old_value, new_value) = self.shown_values();
+
+        ShownKeyChange {
+            key: &self.key,
+            old_value: old_value.map(Cow::into_owned),
+            new_value: new_value.into_owned(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl KeyChange {
+    pub fn new(key: String, old_value: Option<Value>, new_value: Value) -> Self {
+        let redacted = secrets::contains_secret(&new_value) || old_value.as_ref().is_some_and(secrets::contains_secret);
+
+        Self {
+            key,
+            old_value,
+            new_value,
+            redacted,
+        }
+    }
+
+    /// The old and new values to show, which are [`REDACTED`](secrets::REDACTED) if the change is
+    /// redacted.
+    pub fn shown_values(&self) -> (Option<Cow<'_, Value>>, Cow<'_, Value>) {
+        if !self.redacted {
+            return (self.old_value.as_ref().map(Cow::Borrowed), Cow::Borrowed(&self.new_value));
+        }
+
+        let redacted = || Cow::Owned(Value::String(secrets::REDACTED.to_owned()));
+
+        (self.old_value.as_ref().map(|_| redacted()), redacted())
+    }
+}
+
+/// Serialize plist values with binary data hex-encoded, as YAML can't represent it.
+fn serialize_readable_value<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Serialize + Clone + Into<Option<Value>>,
+    S: serde::Serializer,
+{
+    match value.clone().into() {
+        Some(mut value) => {
+            replace_data_in_plist(&mut value).map_err(serde::ser::Error::custom)?;
+            value.serialize(serializer)
         }
+        None => serializer.serialize_none(),
+    }
+}
+
+/// The result of merging new values into a plist, before anything is written.
+#[derive(Debug)]
+pub struct MergedPlist {
+    /// Whether the plist file already exists.
+    pub exists: bool,
+    /// The whole plist with the new values merged in.
+    pub value: Value,
+    /// The keys that changed, in the order they were merged.
+    pub changes: Vec<KeyChange>,
+}
+
+/// Keys that applying `prefs` to a domain would change, as read by `backend`, without writing anything.
+pub(super) fn pending_changes(
+    backend: &dyn PrefsBackend,
+    domain: &str,
+    prefs: HashMap<String, plist::Value>,
+    ignore_keys: &[String],
+    current_host: bool,
+) -> Result<Vec<KeyChange>> {
+    let domain = resolve_domain(domain, current_host)?;
+    let plist_path = plist_path(&domain, current_host)?;
+
+    Ok(merge_plist_values(backend, &domain, &plist_path, prefs, ignore_keys, false)?.changes)
+}
+
+/// Merge key-value pairs into the current contents of the plist file at `plist_path`, as read by
+/// `backend`.
+///
+/// `ignore_keys` are left as they are, even if `prefs` has a value for them or wipes the domain with
+/// `"!"`. With `repair` a corrupt plist is merged into as if it were empty.
+fn merge_plist_values(
+    backend: &dyn PrefsBackend,
+    domain: &Domain,
+    plist_path: &Utf8Path,
+    prefs: HashMap<String, plist::Value>,
+    ignore_keys: &[String],
+    repair: bool,
+) -> Result<MergedPlist> {
+    debug!("Plist path: {plist_path}");
+
+    let (plist_path_exists, plist_value) = read_plist_or_empty(backend, plist_path, repair)?;
+
+    let (value, changes) = merge_into_plist(domain, plist_value, prefs, ignore_keys)?;
+
+    Ok(MergedPlist {
+        exists: plist_path_exists,
+        value,
+        changes,
+    })
+}
+
+/// The any-host plist next to a `ByHost` one, e.g. `~/Library/Preferences/com.apple.dock.plist` for
+/// `~/Library/Preferences/ByHost/com.apple.dock.{uuid}.plist`. `None` if it isn't a `ByHost` plist.
+fn any_host_plist_path(plist_path: &Utf8Path) -> Option<Utf8PathBuf> {
+    let by_host_dir = plist_path.parent().filter(|dir| dir.file_name() == Some("ByHost"))?;
+    let (name, uuid) = plist_path.file_name()?.strip_suffix(".plist")?.rsplit_once('.')?;
+
+    is_hardware_uuid(uuid).then(|| by_host_dir.parent().map(|dir| dir.join(format!("{name}.plist"))))?
+}
+
+/// Merge key-value pairs as `CFPreferences` layers a `current_host` domain, for `--layered-read`:
+/// old values (for changes, `"..."` and nested merges) come from the `ByHost` plist, falling back
+/// to the any-host plist, and only the keys that change are written to the `ByHost` plist. Wiping
+/// the domain with `"!"` only looks at the `ByHost` plist.
+fn merge_layered(
+    domain: &Domain,
+    by_host: Value,
+    any_host: Value,
+    prefs: HashMap<String, plist::Value>,
+    ignore_keys: &[String],
+) -> Result<(Value, Vec<KeyChange>)> {
+    let (Value::Dictionary(mut by_host_dict), Value::Dictionary(mut layered)) = (by_host.clone(), any_host) else {
+        return merge_into_plist(domain, by_host, prefs, ignore_keys);
     };
-    mem::swap(value, &mut stringified_data_value);
 
-    Ok(())
-}
+    if prefs.contains_key(BANG) {
+        return merge_into_plist(domain, by_host, prefs, ignore_keys);
+    }
+
+    for (key, value) in by_host_dict.clone() {
+        layered.insert(key, value);
+    }
+
+    let (merged, changes) = merge_into_plist(domain, Value::Dictionary(layered), prefs, ignore_keys)?;
+
+    let merged = merged.into_dictionary().unwrap_or_default();
+
+    for KeyChange { key, .. } in &changes {
+        match merged.get(key) {
+            Some(value) => by_host_dict.insert(key.clone(), value.clone()),
+            None => by_host_dict.remove(key),
+        };
+    }
+
+    Ok((Value::Dictionary(by_host_dict), changes))
+}
+
+/// Plists bigger than this aren't read. Real ones are at most tens of MB; anything larger is
+/// corrupt or not a preferences file, and parsing it could exhaust memory.
+pub const MAX_PLIST_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Read a plist file, failing with a descriptive error rather than attempting huge allocations on
+/// files over [`MAX_PLIST_SIZE`] or binary plists whose trailer points outside the file.
+pub(super) fn read_plist(plist_path: &Utf8Path) -> Result<Value, E> {
+    let size = fs::metadata(plist_path)
+        .map_err(|e| E::FileRead {
+            path: plist_path.to_owned(),
+            source: e,
+        })?
+        .len();
+
+    if size > MAX_PLIST_SIZE {
+        return Err(E::PlistTooLarge {
+            path: plist_path.to_owned(),
+            size,
+            max: MAX_PLIST_SIZE,
+        });
+    }
+
+    let bytes = fs::read(plist_path).map_err(|e| E::FileRead {
+        path: plist_path.to_owned(),
+        source: e,
+    })?;
+
+    if let Err(reason) = check_binary_trailer(&bytes) {
+        return Err(E::CorruptPlist {
+            path: plist_path.to_owned(),
+            reason,
+        });
+    }
+
+    Value::from_reader(std::io::Cursor::new(bytes)).map_err(|e| E::PlistRead {
+        path: plist_path.to_owned(),
+        source: e,
+    })
+}
+
+/// Check the trailer of a binary plist (the last 32 bytes) describes objects and an offset table
+/// that fit in the file. Other formats aren't checked.
+fn check_binary_trailer(bytes: &[u8]) -> Result<(), &'static str> {
+    const HEADER_LEN: u64 = 8;
+    const TRAILER_LEN: usize = 32;
+
+    if !bytes.starts_with(b"bplist00") {
+        return Ok(());
+    }
+
+    let Some(trailer) = bytes.len().checked_sub(TRAILER_LEN).map(|start| &bytes[start..]) else {
+        return Err("it's too short to have a trailer");
+    };
+
+    let be_u64 = |range: std::ops::Range<usize>| trailer[range].iter().fold(0_u64, |n, byte| (n << 8) | u64::from(*byte));
+
+    let offset_size = u64::from(trailer[6]);
+    let ref_size = trailer[7];
+    let num_objects = be_u64(8..16);
+    let top_object = be_u64(16..24);
+    let offset_table_start = be_u64(24..32);
+    let body_end = bytes.len() as u64 - TRAILER_LEN as u64;
+
+    if !(1..=8).contains(&offset_size) || !(1..=8).contains(&ref_size) {
+        return Err("its offset or object reference size isn't between 1 and 8 bytes");
+    }
+
+    // Every object takes at least a byte.
+    if num_objects > body_end || top_object >= num_objects {
+        return Err("its object count doesn't fit in the file");
+    }
+
+    let offset_table_end = num_objects.checked_mul(offset_size).and_then(|len| len.checked_add(offset_table_start));
+
+    if offset_table_start < HEADER_LEN || offset_table_end.map_or(true, |end| end > body_end) {
+        return Err("its offset table is outside the file");
+    }
+
+    Ok(())
+}
+
+/// Whether a plist file exists, and its contents, or an empty dictionary if it doesn't, as read by
+/// `backend`. With `repair` a corrupt plist also reads as an empty dictionary, for `apply --repair`
+/// to replace.
+fn read_plist_or_empty(backend: &dyn PrefsBackend, plist_path: &Utf8Path, repair: bool) -> Result<(bool, Value)> {
+    match backend.read(plist_path) {
+        Ok(Some(value)) => Ok((true, value)),
+        Ok(None) => Ok((false, Value::Dictionary(Dictionary::new()))),
+        Err(e) if repair && is_corrupt(&e) => {
+            warn!("{e} Recreating it from the YAML alone, as --repair was given.");
+            Ok((true, Value::Dictionary(Dictionary::new())))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether reading a plist failed because of what's in it, rather than e.g. its permissions.
+const fn is_corrupt(error: &E) -> bool {
+    matches!(error, E::CorruptPlist { .. } | E::PlistRead { .. } | E::PlistTooLarge { .. })
+}
+
+/// Move a corrupt plist out of the way as `<name>.corrupt-<unix time>`, so a fresh one can be
+/// written in its place. Returns whether it was corrupt, as read by `backend`.
+fn move_aside_if_corrupt(backend: &dyn PrefsBackend, plist_path: &Utf8Path) -> Result<bool, E> {
+    let Err(e) = backend.read(plist_path) else {
+        return Ok(false);
+    };
+
+    if !is_corrupt(&e) {
+        return Err(e);
+    }
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let to_path = Utf8PathBuf::from(format!("{plist_path}.corrupt-{timestamp}"));
+
+    error!("{e} Moving it aside to {to_path}, and recreating it from the YAML alone.");
+
+    fs::rename(plist_path, &to_path).map_err(|e| E::FileRename {
+        from_path: plist_path.to_owned(),
+        to_path,
+        source: e,
+    })?;
+
+    Ok(true)
+}
+
+/// Merge key-value pairs into a plist value, as [`merge_plist_values`] does for a file, returning
+/// the merged value and the keys that changed.
+fn merge_into_plist(
+    domain: &Domain,
+    mut plist_value: Value,
+    mut prefs: HashMap<String, plist::Value>,
+    ignore_keys: &[String],
+) -> Result<(Value, Vec<KeyChange>)> {
+    trace!("Plist: {:?}", secrets::redact(&plist_value));
+
+    // Keys we changed, if any.
+    let mut changes = Vec::new();
+
+    for key in ignore_keys {
+        if prefs.remove(key).is_some() {
+            debug!("Ignoring value for {domain} {key}, as it's in ignore_keys");
+        }
+    }
+
+    // If we have a key "!", wipe out the existing array, other than ignored keys.
+    if prefs.contains_key(BANG) {
+        let ignored = plist_value
+            .as_dictionary()
+            .map(|dict| {
+                dict.iter()
+                    .filter(|(key, _)| ignore_keys.contains(key))
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        plist_value = Value::Dictionary(ignored);
+        prefs.remove(BANG);
+    }
+
+    expand_key_paths(&mut prefs);
+
+    // Sorted, so that changes are reported and new keys are added in a stable order.
+    let mut prefs: Vec<_> = prefs.into_iter().collect();
+    prefs.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    for (key, mut new_value) in prefs {
+        let _span = debug_span!("key", key).entered();
+        let old_value = plist_value
+            .as_dictionary()
+            .ok_or_else(|| E::NotADictionary {
+                domain: domain.to_string(),
+                key: key.clone(),
+                plist_type: get_plist_value_type(&plist_value),
+            })?
+            .get(&key);
+
+        debug!(
+            "Working out whether we need to change the default {domain} {key}: {:?} -> {:?}",
+            old_value.map(secrets::redact),
+            secrets::redact(&new_value)
+        );
+
+        // Performs merge operations
+        merge_value(&mut new_value, old_value);
+
+        if let Some(old_value) = old_value {
+            if old_value == &new_value {
+                trace!("Nothing to do, values already match: {key:?} = {:?}", secrets::redact(&new_value));
+                continue;
+            }
+        }
+
+        changes.push(KeyChange::new(key.clone(), old_value.cloned(), new_value.clone()));
+
+        let plist_type = get_plist_value_type(&plist_value);
+
+        trace!("Plist type: {plist_type:?}");
+
+        plist_value
+            .as_dictionary_mut()
+            .ok_or_else(|| E::NotADictionary {
+                domain: domain.to_string(),
+                key: key.clone(),
+                plist_type,
+            })?
+            .insert(key, new_value);
+    }
+
+    Ok((plist_value, changes))
+}
+
+/// Write a planned plist through the options' backend, then report the keys it changed.
+pub(super) fn execute_plist_write(write: &PlistWrite, options: &WriteOptions, observer: &dyn ApplyObserver) -> Result<bool> {
+    let PlistWrite { domain, changes, removed, .. } = write;
+    let _span = info_span!("domain", %domain).entered();
+
+    if !options.backend.write(write, options)? {
+        return Ok(false);
+    }
+
+    for change in changes {
+        let key = &change.key;
+        let (old_value, new_value) = change.shown_values();
+
+        info!(
+            "Changing default {domain} {}",
+            render_change(key, old_value.as_deref(), &new_value, 0).trim_end()
+        );
+
+        if let Some(entry) = catalog::lookup(domain.as_str(), key) {
+            info!("{domain} {key}: {}", entry.description);
+        }
+
+        observer.on_key_changed(domain.as_str(), key, old_value.as_deref(), &new_value);
+    }
+
+    for key in removed {
+        info!("Removing default {domain} {key}");
+    }
+
+    Ok(true)
+}
+
+/// Write a planned change to a plist file, for the backends that change files in place. Handles
+/// what's the same however the keys are written: symlinks, `--repair`, backups, creating missing
+/// plists and their directories, ownership, `--verify` and `--write-retries`. `write_keys` makes
+/// the change itself, given whether the plist exists. Returns whether it was written.
+pub(super) fn write_plist_file(write: &PlistWrite, options: &WriteOptions, mut write_keys: impl FnMut(bool) -> Result<(), E>) -> Result<bool> {
+    let PlistWrite {
+        path: plist_path,
+        changes,
+        removed,
+        value: plist_value,
+        ..
+    } = write;
+
+    let symlink_target = symlink_target(plist_path)?;
+
+    if let Some(target) = &symlink_target {
+        match options.symlinks {
+            Symlinks::Follow => info!("{plist_path} is a symlink, writing through it to {target}"),
+            Symlinks::Preserve => {
+                warn!(
+                    "{plist_path} is a symlink to {target}, leaving it untouched: {} changes skipped",
+                    changes.len() + removed.len()
+                );
+                return Ok(false);
+            }
+            Symlinks::Error => {
+                return Err(E::SymlinkedPlist {
+                    path: plist_path.clone(),
+                    target: target.clone(),
+                }
+                .into())
+            }
+        }
+    }
+
+    if let Some(transaction) = &options.transaction {
+        // Through a symlink, the file it points to is what's written, so what's kept and restored,
+        // leaving the symlink in place.
+        match &symlink_target {
+            Some(target) => transaction.record(&resolve_symlink(plist_path, target))?,
+            None => transaction.record(plist_path)?,
+        }
+    }
+
+    // A corrupt plist being repaired is moved aside rather than backed up, which would push the
+    // last good backup out.
+    let repaired = options.repair && plist_path.exists() && move_aside_if_corrupt(options.backend.as_ref(), plist_path)?;
+
+    // Checked again, as the plan may be older than the file.
+    let plist_path_exists = plist_path.exists();
+
+    if plist_path_exists {
+        // TODO: Handle sudo case and not being able to backup.
+        if options.backup {
+            let backup_base = match &options.backup_dir {
+                Some(backup_dir) => backup_base_in_dir(backup_dir, plist_path)?,
+                None => plist_path.clone(),
+            };
+
+            backup_plist(plist_path, &backup_base, options.backup_depth)?;
+        }
+    } else if !repaired {
+        if options.strict {
+            return Err(E::MissingPlist { path: plist_path.clone() }.into());
+        }
+
+        warn!("Defaults plist doesn't exist, creating it: {plist_path}");
+
+        let plist_dirpath = plist_path.parent().ok_or(E::UnexpectedNone)?;
+
+        // Directories created for another user's preferences are theirs.
+        let created: Vec<&Utf8Path> = plist_dirpath.ancestors().take_while(|dir| !dir.exists()).collect();
+
+        fs::create_dir_all(plist_dirpath).map_err(|e| E::DirCreation {
+            path: plist_dirpath.to_owned(),
+            source: e,
+        })?;
+
+        for dir in created {
+            set_prefs_owner(dir)?;
+        }
+    }
+
+    let changed_keys: Vec<String> = changes.iter().map(|change| change.key.clone()).collect();
+
+    // Retrying needs to know whether the write took.
+    let verify = options.verify.or((options.write_retries > 0).then_some(Verify::File));
+
+    write_with_retries(
+        options.write_retries,
+        |attempt| {
+            write_keys(plist_path_exists || attempt > 0)?;
+
+            set_prefs_owner(plist_path)?;
+            trace!("Plist updated at {plist_path}");
+
+            Ok(())
+        },
+        || verify.map_or(Ok(()), |verify| verify_plist_values(plist_path, plist_value, &changed_keys, verify)),
+        |delay| {
+            // cfprefsd may have rewritten the file from its cache, which it won't do once restarted.
+            restart_cfprefsd();
+            thread::sleep(delay);
+        },
+    )?;
+
+    Ok(true)
+}
+
+/// How long to wait before the first retry of a write that didn't persist, doubled for each retry
+/// after it.
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Write with `write`, given the attempt number, and if `verify` finds the values didn't persist,
+/// call `before_retry` with the delay before the next attempt and write again, up to `retries`
+/// times.
+fn write_with_retries(
+    retries: u32,
+    mut write: impl FnMut(u32) -> Result<(), E>,
+    mut verify: impl FnMut() -> Result<(), E>,
+    mut before_retry: impl FnMut(Duration),
+) -> Result<(), E> {
+    for attempt in 0..retries {
+        write(attempt)?;
+
+        match verify() {
+            Err(e @ E::VerifyFailed { .. }) => {
+                let delay = WRITE_RETRY_DELAY.saturating_mul(1 << attempt.min(8));
+                warn!("{e}. Restarting cfprefsd and writing it again in {}ms.", delay.as_millis());
+
+                before_retry(delay);
+            }
+            result => return result,
+        }
+    }
+
+    write(retries)?;
+    verify()
+}
+
+/// Where a plist points to, if it's a symlink.
+fn symlink_target(plist_path: &Utf8Path) -> Result<Option<Utf8PathBuf>, E> {
+    match plist_path.symlink_metadata() {
+        Ok(metadata) if metadata.is_symlink() => plist_path.read_link_utf8().map(Some).map_err(|e| E::FileRead {
+            path: plist_path.to_owned(),
+            source: e,
+        }),
+        _ => Ok(None),
+    }
+}
+
+/// The file a symlinked plist points to, following any further symlinks, as an absolute path.
+fn resolve_symlink(plist_path: &Utf8Path, target: &Utf8Path) -> Utf8PathBuf {
+    plist_path
+        .canonicalize_utf8()
+        .unwrap_or_else(|_| plist_path.parent().map_or_else(|| target.to_owned(), |dir| dir.join(target)))
+}
+
+/// Re-read a plist that was just written, and check that `keys` have the values that were written.
+///
+/// Catches changes that didn't take, e.g. because cfprefsd immediately rewrote the file with its
+/// cached values, or sandbox restrictions silently reverted it.
+fn verify_plist_values(plist_path: &Utf8Path, expected: &Value, keys: &[String], verify: Verify) -> Result<(), E> {
+    let mut readers = vec![("file", read_plist(plist_path))];
+
+    if verify == Verify::Defaults {
+        let exported = cmd!("defaults", "export", plist_path, "-")
+            .stdout_capture()
+            .run()
+            .map_err(|e| E::VerifyReadFailed {
+                path: plist_path.to_path_buf(),
+                source: e,
+            })?
+            .stdout;
+
+        let exported = plist::Value::from_reader(std::io::Cursor::new(exported)).map_err(|e| E::PlistRead {
+            path: plist_path.to_path_buf(),
+            source: e,
+        });
+
+        readers.push(("defaults export", exported));
+    }
+
+    for (source, actual) in readers {
+        let actual = actual?;
+
+        let mismatched: Vec<String> = keys
+            .iter()
+            .filter(|key| actual.as_dictionary().and_then(|dict| dict.get(key)) != expected.as_dictionary().and_then(|dict| dict.get(key)))
+            .cloned()
+            .collect();
+
+        if !mismatched.is_empty() {
+            return Err(E::VerifyFailed {
+                path: plist_path.to_path_buf(),
+                source_name: source,
+                keys: mismatched.join(", "),
+            });
+        }
+
+        trace!("Verified {} changed keys in {plist_path} via {source}", keys.len());
+    }
+
+    Ok(())
+}
+
+/// Expand flat key paths like `".DesktopViewSettings.IconViewSettings.iconSize": 80` into nested
+/// dictionaries, which are then merged with the existing values as usual.
+///
+/// As keys may legitimately contain dots (e.g. `com.apple.swipescrolldirection`), only keys starting
+/// with a `.` are key paths, so what a key means never depends on what's already in the plist.
+fn expand_key_paths(prefs: &mut HashMap<String, Value>) {
+    let key_paths: Vec<String> = prefs.keys().filter(|key| key_path_segments(key).is_some()).cloned().collect();
+
+    for key_path in key_paths {
+        let (Some(value), Some(segments)) = (prefs.remove(&key_path), key_path_segments(&key_path)) else {
+            continue;
+        };
+
+        trace!("Expanding key path {key_path:?}");
+
+        let Some((first, rest)) = segments.split_first() else {
+            continue;
+        };
+
+        let target = prefs.entry((*first).to_owned()).or_insert_with(|| Value::Dictionary(Dictionary::new()));
+        insert_at_key_path(target, rest, value);
+    }
+}
+
+/// Split a key path like `.DesktopViewSettings.IconViewSettings.iconSize` into its segments.
+/// Segments containing dots are quoted, e.g. `."com.apple.dock".tilesize`.
+///
+/// Returns `None` if the key isn't a well-formed key path.
+fn key_path_segments(key: &str) -> Option<Vec<&str>> {
+    let mut rest = key.strip_prefix('.')?;
+    let mut segments = Vec::new();
+
+    loop {
+        let segment = if let Some(quoted) = rest.strip_prefix('"') {
+            let (segment, after) = quoted.split_once('"')?;
+            rest = after;
+            segment
+        } else {
+            let (segment, after) = rest.split_at(rest.find('.').unwrap_or(rest.len()));
+            rest = after;
+            segment
+        };
+
+        if segment.is_empty() {
+            return None;
+        }
+
+        segments.push(segment);
+
+        if rest.is_empty() {
+            return Some(segments);
+        }
+
+        rest = rest.strip_prefix('.')?;
+    }
+}
+
+/// Insert a value at a key path below `target`, creating dictionaries as needed.
+fn insert_at_key_path(target: &mut Value, segments: &[&str], value: Value) {
+    let Some((first, rest)) = segments.split_first() else {
+        *target = value;
+        return;
+    };
+
+    if target.as_dictionary().is_none() {
+        *target = Value::Dictionary(Dictionary::new());
+    }
+
+    if let Some(dict) = target.as_dictionary_mut() {
+        if !dict.contains_key(first) {
+            dict.insert((*first).to_owned(), Value::Dictionary(Dictionary::new()));
+        }
+
+        if let Some(child) = dict.get_mut(first) {
+            insert_at_key_path(child, rest, value);
+        }
+    }
+}
+
+/// Path of a backup generation of a plist file: `{base}.prev` for generation 0, otherwise
+/// `{base}.prev.{generation}`. `base` is the plist path, or its mirror in a backup directory.
+fn backup_path(base: &Utf8Path, generation: usize) -> Utf8PathBuf {
+    if generation == 0 {
+        Utf8PathBuf::from(format!("{base}.prev"))
+    } else {
+        Utf8PathBuf::from(format!("{base}.prev.{generation}"))
+    }
+}
+
+/// Where backups of a plist go in `backup_dir`: its path relative to the home directory (or
+/// absolute path) mirrored inside it, so plists with the same file name don't collide.
+fn backup_base_in_dir(backup_dir: &Utf8Path, plist_path: &Utf8Path) -> Result<Utf8PathBuf> {
+    let home_dir = home_dir()?;
+    let relative = plist_path
+        .strip_prefix(&home_dir)
+        .or_else(|_| plist_path.strip_prefix("/"))
+        .unwrap_or(plist_path);
+
+    let base = backup_dir.join(relative);
+    let parent = base.parent().ok_or(E::UnexpectedNone)?;
+
+    fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+        path: parent.to_owned(),
+        source: e,
+    })?;
+
+    Ok(base)
+}
+
+/// Copy a plist file to its `.prev` backup before it gets overwritten.
+///
+/// If a `.prev` backup already exists and is a valid plist, it's first stashed as `.prev.1` (and
+/// older generations shifted up, keeping at most `depth` of them), so that a run which wrote a
+/// corrupt file can't also destroy the last good backup. Invalid backups are not stashed.
+///
+/// Generations older than `depth`, e.g. left over from a run with a larger depth, are removed.
+fn backup_plist(plist_path: &Utf8Path, backup_base: &Utf8Path, depth: usize) -> Result<(), E> {
+    let latest_backup = backup_path(backup_base, 0);
+
+    for generation in (depth + 1).. {
+        let stale_backup = backup_path(backup_base, generation);
+
+        if !stale_backup.exists() {
+            break;
+        }
+
+        trace!("Removing backup beyond the retention depth: {stale_backup}");
+
+        fs::remove_file(&stale_backup).map_err(|e| E::FileRemove { path: stale_backup, source: e })?;
+    }
+
+    if depth > 0 && latest_backup.exists() {
+        if read_plist(&latest_backup).is_ok() {
+            for generation in (0..depth).rev() {
+                let from_path = backup_path(backup_base, generation);
+
+                if !from_path.exists() {
+                    continue;
+                }
+
+                let to_path = backup_path(backup_base, generation + 1);
+
+                trace!("Stashing backup {from_path} -> {to_path}");
+
+                fs::rename(&from_path, &to_path).map_err(|e| E::FileRename { from_path, to_path, source: e })?;
+            }
+        } else {
+            warn!("Existing backup {latest_backup} isn't a valid plist, replacing it without stashing.");
+        }
+    }
+
+    debug!("Backing up plist file {plist_path} -> {latest_backup}");
+
+    clone_file(plist_path, &latest_backup).map_err(|e| E::FileCopy {
+        from_path: plist_path.to_path_buf(),
+        to_path: latest_backup.clone(),
+        source: e,
+    })?;
+
+    set_prefs_owner(&latest_backup)
+}
+
+/// Copy a file, replacing `to`. On APFS the copy is a copy-on-write clone, which takes no extra
+/// space or time however big the file is; elsewhere it's a full copy.
+pub(crate) fn clone_file(from: &Utf8Path, to: &Utf8Path) -> std::io::Result<()> {
+    // clonefile(2) won't replace an existing file, and `fs::copy` only clones a new one.
+    match fs::remove_file(to) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        use std::ffi::CString;
+
+        if let (Ok(from_c), Ok(to_c)) = (CString::new(from.as_str()), CString::new(to.as_str())) {
+            // SAFETY: Both are valid NUL-terminated paths that outlive the call.
+            if unsafe { libc::clonefile(from_c.as_ptr(), to_c.as_ptr(), 0) } == 0 {
+                return Ok(());
+            }
+
+            trace!("Couldn't clone {from}, copying it instead: {}", std::io::Error::last_os_error());
+        }
+    }
+
+    fs::copy(from, to).map(|_| ())
+}
+
+/// Write a plist file to a path, in the format `write_format` and `convert` pick. Will fall back to
+/// trying to use sudo if a normal write fails.
+pub(super) fn write_plist(
+    plist_path_exists: bool,
+    plist_path: &Utf8Path,
+    plist_value: &plist::Value,
+    xml_format: &XmlFormat,
+    write_format: WriteFormat,
+    convert: bool,
+) -> Result<(), E> {
+    //
+    let existing_binary = if plist_path_exists { Some(is_binary(plist_path)?) } else { None };
+    let should_write_binary = write_format.is_binary(existing_binary, convert);
+
+    let plist_bytes = if should_write_binary {
+        trace!("Writing binary plist");
+        let mut plist_bytes = Vec::new();
+        plist::to_writer_binary(&mut plist_bytes, &plist_value).map(|()| plist_bytes)
+    } else {
+        trace!("Writing xml plist");
+        xml_format.to_bytes(plist_value)
+    }
+    .map_err(|e| E::PlistWrite {
+        path: plist_path.to_path_buf(),
+        source: e,
+    })?;
+
+    let Err(io_error) = fs::write(plist_path, &plist_bytes) else {
+        return Ok(());
+    };
+
+    trace!("Tried to write plist file, got IO error {io_error:?}, trying again with sudo");
+
+    cmd!("sudo", "tee", plist_path)
+        .stdin_bytes(plist_bytes)
+        .stdout_null()
+        .run()
+        .map_err(|e| E::PlistSudoWrite {
+            path: plist_path.to_path_buf(),
+            source: e,
+        })
+        .map(|_| ())?;
+    Ok(())
+}
+
+/// Combines plist values using the following operations:
+/// * Merges dictionaries so new keys apply and old keys are let untouched
+/// * Replaces "..." in arrays with a copy of the old array (duplicates removed)
+///
+/// This operation is performed recursively on dictionaries.
+fn merge_value(new_value: &mut Value, old_value: Option<&Value>) {
+    if resolve_operator(new_value, old_value) {
+        return;
+    }
+
+    deep_merge_dictionaries(new_value, old_value);
+    replace_ellipsis_array(new_value, old_value);
+}
+
+/// Wrap a value so it's only set if the key doesn't already have a value.
+pub fn if_unset(value: Value) -> Value {
+    Value::Dictionary(Dictionary::from_iter([(IF_UNSET, value)]))
+}
+
+/// Unwrap a single key operator dictionary, combining the wrapped value with the old value:
+///
+/// - `{"!if-unset": value}`: keep the old value if there is one, otherwise use the wrapped value.
+/// - `{"!array-add": [...]}`: the old array with the wrapped elements appended.
+/// - `{"!dict-add": {...}}`: the old dictionary with the wrapped keys added or replaced.
+///
+/// Returns whether there was an operator, in which case the value needs no further merging.
+fn resolve_operator(new_value: &mut Value, old_value: Option<&Value>) -> bool {
+    //
+    let Some(dict) = new_value.as_dictionary_mut() else {
+        return false;
+    };
+
+    if dict.len() != 1 {
+        return false;
+    }
+
+    if let Some(value) = dict.remove(IF_UNSET) {
+        *new_value = match old_value {
+            Some(old_value) => {
+                trace!("Value is already set, keeping it: {:?}", secrets::redact(old_value));
+                old_value.clone()
+            }
+            None => value,
+        };
+    } else if let Some(Value::Array(added)) = dict.remove(ARRAY_ADD) {
+        let mut array = old_value.and_then(Value::as_array).cloned().unwrap_or_default();
+
+        trace!("Appending {} elements to {} existing elements", added.len(), array.len());
+        array.extend(added);
+        *new_value = Value::Array(array);
+    } else if let Some(Value::Dictionary(added)) = dict.remove(DICT_ADD) {
+        let mut old_dict = old_value.and_then(Value::as_dictionary).cloned().unwrap_or_default();
+
+        trace!("Adding {} keys to {} existing keys", added.len(), old_dict.len());
+        for (key, value) in added {
+            old_dict.insert(key, value);
+        }
+        *new_value = Value::Dictionary(old_dict);
+    } else {
+        return false;
+    }
+
+    true
+}
+
+/// Replace `...` values in an input array.
+/// You end up with: [<new values before ...>, <old values>, <new values after ...>]
+/// But any duplicates between old and new values are removed, with the first value taking
+/// precedence.
+fn replace_ellipsis_array(new_value: &mut Value, old_value: Option<&Value>) {
+    //
+    let Value::Array(new_array) = new_value else {
+        trace!("Value isn't an array, skipping ellipsis replacement...");
+        return;
+    };
+
+    let ellipsis = plist::Value::from(ELLIPSIS);
+
+    let Some(position) = new_array.iter().position(|x| x == &ellipsis) else {
+        trace!("New value doesn't contain ellipsis, skipping ellipsis replacement...");
+        return;
+    };
+
+    let Some(old_array) = old_value.and_then(plist::Value::as_array) else {
+        trace!("Old value wasn't an array, skipping ellipsis replacement...");
+        new_array.remove(position);
+        return;
+    };
+
+    let array_copy: Vec<_> = std::mem::take(new_array);
+
+    trace!("Performing array ellipsis replacement...");
+
+    for element in array_copy {
+        if element == ellipsis {
+            for old_element in old_array {
+                if new_array.contains(old_element) {
+                    continue;
+                }
+                new_array.push(old_element.clone());
+            }
+        } else if !new_array.contains(&element) {
+            new_array.push(element);
+        }
+    }
+}
+
+// Recursively merge dictionaries, unless the new value is empty `{}`.
+// If a dictionary
+// * is empty `{}`
+// * contains a key `{}`
+// Then the merge step will be skipped for it and its children.
+fn deep_merge_dictionaries(new_value: &mut Value, old_value: Option<&Value>) {
+    //
+    let Value::Dictionary(new_dict) = new_value else {
+        trace!("New value is not a dictionary, Skipping merge...");
+        return;
+    };
+
+    if new_dict.is_empty() {
+        trace!("New value is an empty dictionary. Skipping merge...");
+        return;
+    }
+
+    // the "..." key is no longer used, and its merging behavior is performed by default. ignore it, for compatibility with older YAML.
+    new_dict.remove(ELLIPSIS);
+
+    let old_dict = old_value.and_then(plist::Value::as_dictionary);
+
+    // for each value, recursively invoke this to merge any child dictionaries.
+    // also perform array ellipsis replacement.
+    // this occurs even if "!" is present, or there's no old dict, so markers are never written.
+    for (key, new_child_value) in &mut *new_dict {
+        let old_child_value = old_dict.and_then(|old_dict| old_dict.get(key));
+        merge_value(new_child_value, old_child_value);
+    }
+
+    if new_dict.remove(BANG).is_some() {
+        trace!("Dictionary contains key '!'. Skipping merge...");
+        return;
+    }
+
+    let Some(old_dict) = old_dict else {
+        trace!("Old value wasn't a dict. Skipping merge...");
+        return;
+    };
+
+    trace!("Performing deep merge...");
+
+    for (key, old_value) in old_dict {
+        if !new_dict.contains_key(key) {
+            new_dict.insert(key.clone(), old_value.clone());
+        }
+    }
+}
+
+/// Get the hardware UUID of the current Mac, or [`SIMULATED_HARDWARE_UUID`] under `--simulate`.
+/// You can get the Hardware UUID from:
+/// <https://apple.stackexchange.com/questions/342042/how-can-i-query-the-hardware-uuid-of-a-mac-programmatically-from-a-command-line>
+pub(super) fn get_hardware_uuid() -> Result<String> {
+    if SIMULATED.get().is_some() {
+        return Ok(SIMULATED_HARDWARE_UUID.to_owned());
+    }
+
+    let raw_output = cmd!("ioreg", "-d2", "-a", "-c", "IOPlatformExpertDevice").read()?;
+    let ioreg_output: IoregOutput = plist::from_bytes(raw_output.as_bytes())?;
+    Ok(ioreg_output
+        .io_registry_entry_children
+        .into_iter()
+        .next()
+        .ok_or_else(|| eyre!("Failed to get the Hardware UUID for the current Mac."))?
+        .io_platform_uuid)
+}
+
+/// XML output returned by `ioreg -d2 -a -c IOPlatformExpertDevice`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct IoregOutput {
+    /// The set of `IORegistry` entries.
+    #[serde(rename = "IORegistryEntryChildren")]
+    io_registry_entry_children: Vec<IoRegistryEntryChildren>,
+}
+
+/// A specific `IORegistry` entry.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct IoRegistryEntryChildren {
+    /// The platform UUID.
+    #[serde(rename = "IOPlatformUUID")]
+    io_platform_uuid: String,
+}
+
+/// Helper to allow serializing plists containing binary data to yaml.
+/// Replace binary data attributes to work around <https://github.com/dtolnay/serde-yaml/issues/91>.
+pub fn replace_data_in_plist(value: &mut Value) -> Result<()> {
+    let mut stringified_data_value = match value {
+        Value::Array(arr) => {
+            for el in arr.iter_mut() {
+                replace_data_in_plist(el)?;
+            }
+            return Ok(());
+        }
+        Value::Dictionary(dict) => {
+            for (_, v) in dict.iter_mut() {
+                replace_data_in_plist(v)?;
+            }
+            return Ok(());
+        }
+        Value::Data(bytes) => Value::String(hex::encode(bytes)),
+        _ => {
+            return Ok(());
+        }
+    };
+    mem::swap(value, &mut stringified_data_value);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use testresult::TestResult;
+    use tracing::info;
+
+    use crate::defaults::deep_merge_dictionaries;
+    use crate::domain::Domain;
+
+    use super::{
+        app_bundle_identifier, backup_path, backup_plist, domain_for_path, expand_key_paths, find_key_paths, replace_ellipsis_array, sort_array_stable,
+        value_at_key_path_mut, NS_GLOBAL_DOMAIN,
+    };
+
+    #[test]
+    fn plist_path_tests() -> TestResult {
+        let home_dir = dirs::home_dir().expect("Expected to be able to calculate the user's home directory.");
+
+        {
+            let domain_path = super::plist_path(&Domain::Global, false)?;
+            assert_eq!(home_dir.join("Library/Preferences/.GlobalPreferences.plist"), domain_path);
+        }
+
+        {
+            let mut expected_plist_path = home_dir.join(
+                "Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.Safari.\
+                 plist",
+            );
+            if !expected_plist_path.exists() {
+                expected_plist_path = home_dir.join("Library/Preferences/com.apple.Safari.plist");
+            }
+            let domain_path = super::plist_path(&"com.apple.Safari".parse()?, false)?;
+            assert_eq!(expected_plist_path, domain_path);
+        }
+
+        // Per-host preference (`current_host` is true).
+        {
+            let domain_path = super::plist_path(&Domain::Global, true)?;
+            let hardware_uuid = super::get_hardware_uuid()?;
+            assert_eq!(
+                home_dir.join(format!("Library/Preferences/ByHost/.GlobalPreferences.{hardware_uuid}.plist")),
+                domain_path
+            );
+        }
+
+        // Per-host sandboxed preference (`current_host` is true and the sandboxed plist exists).
+        {
+            let domain_path = super::plist_path(&"com.apple.Safari".parse()?, true)?;
+            let hardware_uuid = super::get_hardware_uuid()?;
+            assert_eq!(
+                home_dir.join(format!(
+                    "Library/Containers/com.apple.Safari/Data/Library/Preferences/ByHost/com.\
+                     apple.Safari.{hardware_uuid}.plist"
+                )),
+                domain_path
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_hardware_uuid() -> TestResult {
+        use duct::cmd;
+
+        let system_profiler_output = cmd!("system_profiler", "SPHardwareDataType").read()?;
+
+        let expected_value = system_profiler_output
+            .lines()
+            .find_map(|line| line.contains("UUID").then(|| line.split_whitespace().last().unwrap_or_default()))
+            .unwrap_or_default();
+
+        let actual_value = super::get_hardware_uuid()?;
+        assert_eq!(expected_value, actual_value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_binary() -> TestResult {
+        // Modified version of ~/Library/Preferences/com.apple.humanunderstanding.plist
+        let binary_plist_as_hex = "62706c6973743030d101025f10124861736847656e657261746f722e73616c744f10201111111122222222333333334444444455555555666666667777777788888888080b200000000000000101000000000000000300000000000000000000000000000043";
+        let expected_yaml = "HashGenerator.salt: \
+                             '1111111122222222333333334444444455555555666666667777777788888888'\n";
+
+        let binary_plist = hex::decode(binary_plist_as_hex)?;
+
+        let mut value: plist::Value = plist::from_bytes(&binary_plist)?;
+        info!("Value before: {value:?}");
+        super::replace_data_in_plist(&mut value)?;
+        info!("Value after: {value:?}");
+        let yaml_string = serde_yaml::to_string(&value)?;
+        info!("Yaml value: {yaml_string}");
+        assert_eq!(expected_yaml, yaml_string);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deep_merge_dictionaries() {
+        use plist::{Dictionary, Value};
+
+        let old_value = Dictionary::from_iter([
+            ("foo", Value::from(10)), // !!! takes precedence
+            ("fub", 11.into()),       // !!!
+            ("bar", 12.into()),       // !
+            ("baz", 13.into()),       // !
+        ])
+        .into();
+        let mut new_value = Dictionary::from_iter([
+            ("bar", Value::from(22)), // !!
+            ("baz", 23.into()),       // !! takes precedence
+        ])
+        .into();
+
+        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+
+        let expected = Dictionary::from_iter([
+            ("foo", Value::from(10)), // from new
+            ("fub", 11.into()),
+            ("bar", 22.into()), // from old
+            ("baz", 23.into()),
+        ])
+        .into();
+
+        assert_eq!(new_value, expected);
+    }
+
+    #[test]
+    fn test_replace_ellipsis_dict_nested() {
+        use plist::{Dictionary, Value};
+
+        let old_value = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([(
+                "level_2",
+                Dictionary::from_iter([
+                    ("foo", Value::from(10)), //
+                    ("bar", 20.into()),
+                    ("baz", 30.into()),
+                ]),
+            )]),
+        )])
+        .into();
+
+        let mut new_value = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([(
+                "level_2",
+                Dictionary::from_iter([
+                    ("baz", Value::from(90)), //
+                ]),
+            )]),
+        )])
+        .into();
+
+        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+
+        let expected = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([(
+                "level_2",
+                Dictionary::from_iter([
+                    ("foo", Value::from(10)), //
+                    ("bar", 20.into()),
+                    ("baz", 90.into()),
+                ]),
+            )]),
+        )])
+        .into();
+
+        assert_eq!(new_value, expected);
+    }
+
+    #[test]
+    fn test_replace_ellipsis_dict_nested_bang() {
+        use plist::{Dictionary, Value};
+
+        let old_value = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([(
+                "level_2",
+                Dictionary::from_iter([
+                    ("foo", Value::from(10)), //
+                    ("bar", 20.into()),
+                    ("baz", 30.into()),
+                ]),
+            )]),
+        )])
+        .into();
+
+        let mut new_value = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([(
+                "level_2",
+                Dictionary::from_iter([
+                    ("!", Value::from("")), //
+                    ("baz", 90.into()),     //
+                ]),
+            )]),
+        )])
+        .into();
+
+        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+
+        let expected = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([("level_2", Dictionary::from_iter([("baz", Value::from(90))]))]),
+        )])
+        .into();
+
+        assert_eq!(new_value, expected);
+    }
+
+    #[test]
+    fn test_replace_ellipsis_dict_nested_without_old_value() {
+        use plist::{Dictionary, Value};
+
+        // A new plist, or one wiped by a top-level "!", has no old value to merge, but nested
+        // markers must still be resolved rather than written.
+        let mut new_value = Dictionary::from_iter([
+            ("!", Value::from("")),
+            (
+                "level_1",
+                Dictionary::from_iter([
+                    ("...", Value::from("")), //
+                    ("level_2", Dictionary::from_iter([("!", Value::from("")), ("baz", 90.into())]).into()),
+                ])
+                .into(),
+            ),
+        ])
+        .into();
+
+        deep_merge_dictionaries(&mut new_value, None);
+
+        let expected = Dictionary::from_iter([(
+            "level_1",
+            Dictionary::from_iter([("level_2", Dictionary::from_iter([("baz", Value::from(90))]))]),
+        )])
+        .into();
+
+        assert_eq!(new_value, expected);
+    }
+
+    #[test]
+    fn test_replace_ellipsis_array() {
+        let old_value = vec![
+            10.into(), // !
+            20.into(), // !
+            30.into(), // !
+            40.into(), // !
+        ]
+        .into();
+        let mut new_value = vec![
+            30.into(), // !!!
+            20.into(), // !!!
+            "...".into(),
+            60.into(), // !!
+            50.into(), // !!
+            40.into(), // !!
+        ]
+        .into();
+
+        replace_ellipsis_array(&mut new_value, Some(&old_value));
+
+        let expected = vec![
+            30.into(), // from new array before "..."
+            20.into(),
+            10.into(), // from old array
+            40.into(),
+            60.into(), // from new array after "..."
+            50.into(),
+        ]
+        .into();
+
+        assert_eq!(new_value, expected);
+    }
+
+    #[test]
+    fn test_find_key_paths() {
+        use plist::{Dictionary, Value};
+
+        let value = Dictionary::from_iter([
+            ("tilesize", Value::from(36)),
+            (
+                "persistent-apps",
+                vec![Value::from(Dictionary::from_iter([("file-label", Value::from("TileSizeApp"))]))].into(),
+            ),
+            ("autohide", true.into()),
+        ])
+        .into();
+
+        assert_eq!(find_key_paths(&value, "TILESIZE"), vec!["tilesize", "persistent-apps.0.file-label"]);
+        assert!(find_key_paths(&value, "missing").is_empty());
+    }
+
+    #[test]
+    fn test_backup_plist_rotation() -> TestResult {
+        use camino::Utf8PathBuf;
+        use plist::Value;
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.backup.plist");
+
+        for generation in 1..=3 {
+            Value::from(generation).to_file_xml(&plist_path)?;
+            backup_plist(&plist_path, &plist_path, 1)?;
+        }
+
+        assert_eq!(Value::from_file(backup_path(&plist_path, 0))?, Value::from(3));
+        assert_eq!(Value::from_file(backup_path(&plist_path, 1))?, Value::from(2));
+        assert!(!backup_path(&plist_path, 2).exists());
+
+        // A corrupt latest backup must not replace the stashed good one.
+        std::fs::write(backup_path(&plist_path, 0), b"not a plist")?;
+        backup_plist(&plist_path, &plist_path, 1)?;
+        assert_eq!(Value::from_file(backup_path(&plist_path, 1))?, Value::from(2));
+
+        // Generations beyond a reduced depth are pruned.
+        backup_plist(&plist_path, &plist_path, 2)?;
+        backup_plist(&plist_path, &plist_path, 2)?;
+        assert!(backup_path(&plist_path, 2).exists());
+        backup_plist(&plist_path, &plist_path, 0)?;
+        assert!(backup_path(&plist_path, 0).exists());
+        assert!(!backup_path(&plist_path, 1).exists());
+        assert!(!backup_path(&plist_path, 2).exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_domain_for_path() {
+        use camino::Utf8Path;
+
+        for (path, expected) in [
+            ("/Users/me/Library/Preferences/com.apple.dock.plist", Some("com.apple.dock")),
+            ("/Users/me/Library/Preferences/.GlobalPreferences.plist", Some(NS_GLOBAL_DOMAIN)),
+            (
+                "/Users/me/Library/Preferences/ByHost/com.apple.screensaver.01234567-89AB-CDEF-0123-456789ABCDEF.plist",
+                Some("com.apple.screensaver"),
+            ),
+            (
+                "/Users/me/Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.Safari.plist",
+                Some("com.apple.Safari"),
+            ),
+            (
+                "/Users/me/Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.other.plist",
+                None,
+            ),
+            ("/tmp/com.apple.dock.plist", None),
+        ] {
+            assert_eq!(domain_for_path(Utf8Path::new(path)).as_ref().map(Domain::as_str), expected, "{path}");
+        }
+    }
+
+    #[test]
+    fn test_execute_plist_write_observer() -> TestResult {
+        use std::cell::RefCell;
+        use std::collections::HashMap;
+
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{execute_plist_write, merge_plist_values, FileBackend, PlistWrite, WriteOptions};
+        use crate::observer::ApplyObserver;
+
+        #[derive(Default)]
+        struct Recorder(RefCell<Vec<String>>);
+
+        impl ApplyObserver for Recorder {
+            fn on_key_changed(&self, domain: &str, key: &str, old_value: Option<&Value>, new_value: &Value) {
+                self.0.borrow_mut().push(format!("{domain} {key}: {old_value:?} -> {new_value:?}"));
+            }
+        }
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.observer.plist");
+        Value::from(Dictionary::from_iter([("same", Value::from(1)), ("changed", Value::from(1))])).to_file_xml(&plist_path)?;
+
+        let prefs = HashMap::from([("same".to_owned(), Value::from(1)), ("changed".to_owned(), Value::from(2))]);
+        let recorder = Recorder::default();
+
+        let merged = merge_plist_values(&FileBackend, &"com.example.observer".parse()?, &plist_path, prefs, &[], false)?;
+        let write = PlistWrite {
+            domain: "com.example.observer".parse()?,
+            path: plist_path.clone(),
+            exists: merged.exists,
+            needs_sudo: false,
+            changes: merged.changes,
+            removed: Vec::new(),
+            value: merged.value,
+        };
+
+        execute_plist_write(&write, &WriteOptions::default(), &recorder)?;
+        assert_eq!(recorder.0.into_inner(), ["com.example.observer changed: Some(Integer(1)) -> Integer(2)"]);
+        assert_eq!(Value::from_file(&plist_path)?, write.value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_plist_write_symlinks() -> TestResult {
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{execute_plist_write, KeyChange, PlistWrite, Symlinks, WriteOptions};
+        use crate::observer::ConsoleObserver;
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let target = dir.join("dotfiles.plist");
+        let plist_path = dir.join("com.example.symlink.plist");
+        let old_value = Value::from(Dictionary::from_iter([("key", Value::from(1))]));
+        old_value.to_file_xml(&target)?;
+        std::os::unix::fs::symlink(&target, &plist_path)?;
+
+        let write = PlistWrite {
+            domain: "com.example.symlink".parse()?,
+            path: plist_path.clone(),
+            exists: true,
+            needs_sudo: false,
+            changes: vec![KeyChange::new("key".to_owned(), Some(Value::from(1)), Value::from(2))],
+            removed: Vec::new(),
+            value: Value::from(Dictionary::from_iter([("key", Value::from(2))])),
+        };
+        let options = |symlinks| WriteOptions {
+            backup: false,
+            symlinks,
+            ..WriteOptions::default()
+        };
+
+        assert!(execute_plist_write(&write, &options(Symlinks::Error), &ConsoleObserver::default()).is_err());
+        assert!(!execute_plist_write(&write, &options(Symlinks::Preserve), &ConsoleObserver::default())?);
+        assert_eq!(Value::from_file(&target)?, old_value);
+
+        assert!(execute_plist_write(&write, &options(Symlinks::Follow), &ConsoleObserver::default())?);
+        assert!(plist_path.symlink_metadata()?.is_symlink());
+        assert_eq!(Value::from_file(&target)?, write.value);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_domain_writes_stacks() -> TestResult {
+        use std::collections::HashMap;
+
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{plan_domain_writes, WriteOptions};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.plan.plist");
+        Value::from(Dictionary::from_iter([("a", Value::from(1))])).to_file_xml(&plist_path)?;
+
+        let domain = plist_path.as_str();
+        let mut planned = HashMap::new();
+
+        let writes = plan_domain_writes(
+            domain,
+            &HashMap::from([("a".to_owned(), Value::from(1))]),
+            &[],
+            false,
+            &WriteOptions::default(),
+            &planned,
+        )?;
+        assert!(writes.is_empty());
+
+        let writes = plan_domain_writes(
+            domain,
+            &HashMap::from([("b".to_owned(), Value::from(2))]),
+            &[],
+            false,
+            &WriteOptions::default(),
+            &planned,
+        )?;
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].exists);
+        planned.insert(writes[0].path.clone(), writes[0].value.clone());
+
+        // A later write is planned on top of the earlier one, and nothing was written yet.
+        let writes = plan_domain_writes(
+            domain,
+            &HashMap::from([("c".to_owned(), Value::from(3))]),
+            &[],
+            false,
+            &WriteOptions::default(),
+            &planned,
+        )?;
+        assert_eq!(
+            writes[0].value,
+            Value::from(Dictionary::from_iter([("a", Value::from(1)), ("b", Value::from(2)), ("c", Value::from(3))]))
+        );
+        assert_eq!(Value::from_file(&plist_path)?, Value::from(Dictionary::from_iter([("a", Value::from(1))])));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> TestResult {
+        use std::collections::HashMap;
+
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{execute_plist_write, plan_domain_writes, WriteOptions};
+        use crate::observer::ConsoleObserver;
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.repair.plist");
+        std::fs::write(&plist_path, "not a plist")?;
+
+        let prefs = HashMap::from([("a".to_owned(), Value::from(1))]);
+        assert!(plan_domain_writes(plist_path.as_str(), &prefs, &[], false, &WriteOptions::default(), &HashMap::new()).is_err());
+
+        let options = WriteOptions {
+            repair: true,
+            ..WriteOptions::default()
+        };
+        let writes = plan_domain_writes(plist_path.as_str(), &prefs, &[], false, &options, &HashMap::new())?;
+        assert_eq!(writes.len(), 1);
+        assert!(writes[0].exists);
+        assert_eq!(writes[0].changes[0].old_value, None);
+
+        // Planning leaves the corrupt file alone, executing moves it aside instead of backing it up.
+        assert_eq!(std::fs::read_to_string(&plist_path)?, "not a plist");
+        assert!(execute_plist_write(&writes[0], &options, &ConsoleObserver::default())?);
+        assert_eq!(Value::from_file(&plist_path)?, Value::from(Dictionary::from_iter([("a", Value::from(1))])));
+        assert!(!dir.join("com.example.repair.plist.prev").exists());
+
+        let moved: Vec<String> = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+            .collect::<std::io::Result<_>>()?;
+        assert!(moved.iter().any(|name| name.starts_with("com.example.repair.plist.corrupt-")), "{moved:?}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_with_retries() {
+        use std::cell::{Cell, RefCell};
+        use std::time::Duration;
+
+        use super::{write_with_retries, E, WRITE_RETRY_DELAY};
+
+        let verify_failed = || {
+            Err(E::VerifyFailed {
+                path: "com.example.retry.plist".into(),
+                source_name: "file",
+                keys: "key".to_owned(),
+            })
+        };
+
+        // The values persist on the third write: two retries, backing off.
+        let writes = RefCell::new(Vec::new());
+        let delays = RefCell::new(Vec::new());
+        let result = write_with_retries(
+            3,
+            |attempt| {
+                writes.borrow_mut().push(attempt);
+                Ok(())
+            },
+            || if writes.borrow().len() < 3 { verify_failed() } else { Ok(()) },
+            |delay| delays.borrow_mut().push(delay),
+        );
+
+        assert!(result.is_ok());
+        assert_eq!(writes.into_inner(), [0, 1, 2]);
+        assert_eq!(delays.into_inner(), [WRITE_RETRY_DELAY, WRITE_RETRY_DELAY * 2]);
+
+        // Out of retries, the last verify failure is returned.
+        let retried = Cell::new(0);
+        let result = write_with_retries(1, |_| Ok(()), verify_failed, |_| retried.set(retried.get() + 1));
+        assert!(matches!(result, Err(E::VerifyFailed { .. })));
+        assert_eq!(retried.get(), 1);
+
+        // Other errors aren't retried.
+        let result = write_with_retries(3, |_| Ok(()), || Err(E::UnexpectedNone), |_: Duration| retried.set(retried.get() + 1));
+        assert!(matches!(result, Err(E::UnexpectedNone)));
+        assert_eq!(retried.get(), 1);
+    }
+
+    #[test]
+    fn test_plan_domain_replace() -> TestResult {
+        use std::collections::HashMap;
+
+        use camino::{Utf8Path, Utf8PathBuf};
+        use plist::{Dictionary, Value};
+
+        use super::{plan_domain_replace, raw_plist_current_host, raw_plist_domain, WriteOptions};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.replace.plist");
+        Value::from(Dictionary::from_iter([("same", Value::from(1)), ("gone", Value::from(true))])).to_file_xml(&plist_path)?;
+
+        // Merge markers are written as they are, not merged.
+        let value = Value::from(Dictionary::from_iter([
+            ("same", Value::from(1)),
+            ("array", Value::Array(vec!["...".into(), "new".into()])),
+        ]));
+
+        let domain = plist_path.as_str().parse()?;
+
+        let writes = plan_domain_replace(&domain, &value, &[], false, &WriteOptions::default(), &HashMap::new())?;
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].value, value);
+        assert_eq!(writes[0].changes.iter().map(|change| change.key.as_str()).collect::<Vec<_>>(), ["array"]);
+        assert_eq!(writes[0].removed, ["gone"]);
+
+        let unchanged = Value::from_file(&plist_path)?;
+        assert!(plan_domain_replace(&domain, &unchanged, &[], false, &WriteOptions::default(), &HashMap::new())?.is_empty());
+
+        assert!(plan_domain_replace(&domain, &Value::from(1), &[], false, &WriteOptions::default(), &HashMap::new()).is_err());
+
+        // Ignored keys keep their current values, and aren't removed.
+        let ignored = ["same".to_owned(), "gone".to_owned(), "array".to_owned()];
+        let writes = plan_domain_replace(&domain, &value, &ignored, false, &WriteOptions::default(), &HashMap::new())?;
+        assert!(writes.is_empty());
+
+        let ignored = ["gone".to_owned()];
+        let writes = plan_domain_replace(&domain, &value, &ignored, false, &WriteOptions::default(), &HashMap::new())?;
+        assert_eq!(writes[0].value.as_dictionary().and_then(|dict| dict.get("gone")), Some(&Value::from(true)));
+        assert!(writes[0].removed.is_empty());
+
+        assert_eq!(raw_plist_domain(Utf8Path::new("dotfiles/com.apple.dock.plist"))?, "com.apple.dock");
+        assert_eq!(raw_plist_domain(Utf8Path::new("dotfiles/.GlobalPreferences.plist"))?, Domain::Global);
+
+        let by_host = Utf8Path::new("dotfiles/com.apple.dock.00000000-0000-1000-8000-0A1B2C3D4E5F.plist");
+        assert_eq!(raw_plist_domain(by_host)?, "com.apple.dock");
+        assert!(raw_plist_current_host(by_host));
+        assert!(!raw_plist_current_host(Utf8Path::new("dotfiles/com.apple.dock.plist")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_plist_values_ignore_keys() -> TestResult {
+        use std::collections::HashMap;
+
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{merge_plist_values, FileBackend, BANG};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.ignore.plist");
+        Value::from(Dictionary::from_iter([("mod-count", Value::from(41)), ("old", Value::from(1))])).to_file_xml(&plist_path)?;
+
+        let ignore_keys = ["mod-count".to_owned()];
+
+        let prefs = HashMap::from([("mod-count".to_owned(), Value::from(0))]);
+        assert!(
+            merge_plist_values(&FileBackend, &"com.example.ignore".parse()?, &plist_path, prefs, &ignore_keys, false)?
+                .changes
+                .is_empty()
+        );
+
+        // Wiping the domain keeps ignored keys.
+        let prefs = HashMap::from([(BANG.to_owned(), Value::from(Dictionary::new())), ("new".to_owned(), Value::from(2))]);
+        let merged = merge_plist_values(&FileBackend, &"com.example.ignore".parse()?, &plist_path, prefs, &ignore_keys, false)?;
+        assert_eq!(
+            merged.value,
+            Value::from(Dictionary::from_iter([("mod-count", Value::from(41)), ("new", Value::from(2))]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_layered() -> TestResult {
+        use std::collections::HashMap;
+
+        use camino::Utf8Path;
+        use plist::{Dictionary, Value};
+
+        use super::{any_host_plist_path, merge_layered, ELLIPSIS};
+
+        assert_eq!(
+            any_host_plist_path(Utf8Path::new(
+                "/u/Library/Preferences/ByHost/com.apple.dock.01234567-89AB-CDEF-0123-456789ABCDEF.plist"
+            )),
+            Some(Utf8Path::new("/u/Library/Preferences/com.apple.dock.plist").to_owned())
+        );
+        assert_eq!(any_host_plist_path(Utf8Path::new("/u/Library/Preferences/com.apple.dock.plist")), None);
+
+        let domain = "com.example.layered".parse()?;
+        let by_host = Value::from(Dictionary::from_iter([("b", Value::from(2))]));
+        let any_host = Value::from(Dictionary::from_iter([
+            ("a", Value::from(1)),
+            ("list", Value::Array(vec![Value::from(1), Value::from(2)])),
+        ]));
+
+        // Already set for every host, so nothing changes.
+        let prefs = HashMap::from([("a".to_owned(), Value::from(1))]);
+        let (value, changes) = merge_layered(&domain, by_host.clone(), any_host.clone(), prefs, &[])?;
+        assert!(changes.is_empty());
+        assert_eq!(value, by_host);
+
+        // "..." extends the any-host array, and only the keys that change are written per host.
+        let prefs = HashMap::from([
+            ("a".to_owned(), Value::from(1)),
+            ("list".to_owned(), Value::Array(vec![Value::from(ELLIPSIS), Value::from(3)])),
+        ]);
+        let (value, changes) = merge_layered(&domain, by_host, any_host, prefs, &[])?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            value,
+            Value::from(Dictionary::from_iter([
+                ("b", Value::from(2)),
+                ("list", Value::Array(vec![Value::from(1), Value::from(2), Value::from(3)])),
+            ]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_byhost_plist() -> TestResult {
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{migrate_byhost_plist, ByHostMigration};
+
+        const OLD: &str = "00000000-0000-0000-0000-000000000001";
+        const NEW: &str = "00000000-0000-0000-0000-000000000002";
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().join("ByHost"))?;
+        std::fs::create_dir_all(&dir)?;
+
+        let old_path = dir.join(format!("com.apple.screensaver.{OLD}.plist"));
+        let new_path = dir.join(format!("com.apple.screensaver.{NEW}.plist"));
+        let old_dict = Value::from(Dictionary::from_iter([("idleTime", Value::from(300)), ("moduleDict", Value::from("Flurry"))]));
+
+        old_dict.to_file_binary(&old_path)?;
+        assert_eq!(migrate_byhost_plist(&old_path, NEW, true)?, ByHostMigration::Renamed(new_path.clone()));
+        assert!(old_path.exists());
+
+        assert_eq!(migrate_byhost_plist(&old_path, NEW, false)?, ByHostMigration::Renamed(new_path.clone()));
+        assert!(!old_path.exists());
+        assert_eq!(Value::from_file(&new_path)?, old_dict);
+
+        // The current UUID's values win over the old ones.
+        Value::from(Dictionary::from_iter([("idleTime", Value::from(600))])).to_file_binary(&new_path)?;
+        old_dict.to_file_binary(&old_path)?;
+
+        assert_eq!(migrate_byhost_plist(&old_path, NEW, false)?, ByHostMigration::Merged(new_path.clone()));
+        assert!(!old_path.exists());
+        assert_eq!(
+            Value::from_file(&new_path)?,
+            Value::from(Dictionary::from_iter([("idleTime", Value::from(600)), ("moduleDict", Value::from("Flurry"))]))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_xml_format() -> TestResult {
+        use plist::{Dictionary, Value};
+
+        use super::{XmlFormat, XmlIndent};
+
+        let value = Value::from(Dictionary::from_iter([(
+            "nested",
+            Value::from(Dictionary::from_iter([("key", Value::from(1))])),
+        )]));
+
+        let default = String::from_utf8(XmlFormat::default().to_bytes(&value)?)?;
+        assert!(default.contains("<!DOCTYPE plist"));
+        assert!(default.contains("\n\t\t<key>key</key>"));
+        assert!(default.ends_with("</plist>"));
+
+        let custom = XmlFormat {
+            indent: XmlIndent::Spaces(2),
+            trailing_newline: true,
+            doctype: false,
+        };
+        let custom = String::from_utf8(custom.to_bytes(&value)?)?;
+        assert!(!custom.contains("<!DOCTYPE"));
+        assert!(custom.contains("\n    <key>key</key>"));
+        assert!(custom.ends_with("</plist>\n"));
+
+        assert_eq!("tab".parse::<XmlIndent>(), Ok(XmlIndent::Tabs));
+        assert_eq!("4".parse::<XmlIndent>(), Ok(XmlIndent::Spaces(4)));
+        assert!("four".parse::<XmlIndent>().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_format() {
+        use super::WriteFormat;
+
+        // New plists.
+        assert!(WriteFormat::Auto.is_binary(None, false));
+        assert!(!WriteFormat::Xml.is_binary(None, false));
+        assert!(WriteFormat::Binary.is_binary(None, false));
+
+        // Existing plists keep their format unless converted, which `auto` never does.
+        assert!(WriteFormat::Xml.is_binary(Some(true), false));
+        assert!(!WriteFormat::Xml.is_binary(Some(true), true));
+        assert!(WriteFormat::Binary.is_binary(Some(false), true));
+        assert!(!WriteFormat::Auto.is_binary(Some(false), true));
+    }
+
+    #[test]
+    fn test_verify_plist_values() -> TestResult {
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        use super::{verify_plist_values, Verify};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist_path = dir.join("com.example.verify.plist");
+
+        let expected = Value::from(Dictionary::from_iter([("a", Value::from(1)), ("b", Value::from(2))]));
+        // As if something reverted "b" after it was written.
+        Value::from(Dictionary::from_iter([("a", Value::from(1)), ("b", Value::from(0))])).to_file_xml(&plist_path)?;
+
+        assert!(verify_plist_values(&plist_path, &expected, &["a".to_owned()], Verify::File).is_ok());
+
+        let error = verify_plist_values(&plist_path, &expected, &["a".to_owned(), "b".to_owned()], Verify::File).expect_err("b was reverted");
+        assert!(matches!(error, crate::errors::DefaultsError::VerifyFailed { ref keys, .. } if keys == "b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_if_unset() {
+        use plist::{Dictionary, Value};
+
+        use super::{merge_value, IF_UNSET};
 
-#[cfg(test)]
-mod tests {
-    use log::info;
-    use testresult::TestResult;
+        let wrapped = || Value::from(Dictionary::from_iter([(IF_UNSET, Value::from(2))]));
 
-    use crate::defaults::deep_merge_dictionaries;
+        let mut new_value = wrapped();
+        merge_value(&mut new_value, None);
+        assert_eq!(new_value, Value::from(2));
 
-    use super::{replace_ellipsis_array, NS_GLOBAL_DOMAIN};
+        let mut new_value = wrapped();
+        merge_value(&mut new_value, Some(&Value::from(1)));
+        assert_eq!(new_value, Value::from(1));
+
+        // Nested in a dictionary, only the wrapped key keeps its old value.
+        let mut new_value = Value::from(Dictionary::from_iter([("stamp", wrapped()), ("other", Value::from(3))]));
+        let old_value = Value::from(Dictionary::from_iter([("stamp", Value::from(1)), ("other", Value::from(1))]));
+        merge_value(&mut new_value, Some(&old_value));
+        assert_eq!(
+            new_value,
+            Value::from(Dictionary::from_iter([("stamp", Value::from(1)), ("other", Value::from(3))]))
+        );
+    }
 
     #[test]
-    fn plist_path_tests() -> TestResult {
-        let home_dir = dirs::home_dir().expect("Expected to be able to calculate the user's home directory.");
+    fn test_array_add_dict_add() {
+        use plist::{Dictionary, Value};
 
-        {
-            let domain_path = super::plist_path(NS_GLOBAL_DOMAIN, false)?;
-            assert_eq!(home_dir.join("Library/Preferences/.GlobalPreferences.plist"), domain_path);
-        }
+        use super::{merge_value, ARRAY_ADD, DICT_ADD};
 
-        {
-            let mut expected_plist_path = home_dir.join(
-                "Library/Containers/com.apple.Safari/Data/Library/Preferences/com.apple.Safari.\
-                 plist",
-            );
-            if !expected_plist_path.exists() {
-                expected_plist_path = home_dir.join("Library/Preferences/com.apple.Safari.plist");
-            }
-            let domain_path = super::plist_path("com.apple.Safari", false)?;
-            assert_eq!(expected_plist_path, domain_path);
-        }
+        let array_add = || Value::from(Dictionary::from_iter([(ARRAY_ADD, Value::Array(vec!["b".into(), "c".into()]))]));
 
-        // Per-host preference (`current_host` is true).
-        {
-            let domain_path = super::plist_path(NS_GLOBAL_DOMAIN, true)?;
-            let hardware_uuid = super::get_hardware_uuid()?;
-            assert_eq!(
-                home_dir.join(format!("Library/Preferences/ByHost/.GlobalPreferences.{hardware_uuid}.plist")),
-                domain_path
-            );
+        // Appends without de-duplicating, like `defaults write -array-add`.
+        let mut new_value = array_add();
+        merge_value(&mut new_value, Some(&Value::Array(vec!["a".into(), "b".into()])));
+        assert_eq!(new_value, Value::Array(vec!["a".into(), "b".into(), "b".into(), "c".into()]));
+
+        let mut new_value = array_add();
+        merge_value(&mut new_value, None);
+        assert_eq!(new_value, Value::Array(vec!["b".into(), "c".into()]));
+
+        // Replaces whole values of the given keys, rather than deep merging them.
+        let nested = |k: &str| Value::from(Dictionary::from_iter([(k, Value::from(1))]));
+        let mut new_value = Value::from(Dictionary::from_iter([(DICT_ADD, Value::from(Dictionary::from_iter([("x", nested("new"))])))]));
+        let old_value = Value::from(Dictionary::from_iter([("x", nested("old")), ("y", Value::from(2))]));
+        merge_value(&mut new_value, Some(&old_value));
+        assert_eq!(new_value, Value::from(Dictionary::from_iter([("x", nested("new")), ("y", Value::from(2))])));
+    }
+
+    #[test]
+    fn test_app_bundle_identifier() -> TestResult {
+        use camino::Utf8PathBuf;
+        use plist::{Dictionary, Value};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        for (app, bundle_id) in [("Visual Studio Code", "com.microsoft.VSCode"), ("Café Ünïcode", "com.example.cafe")] {
+            let contents = dir.join(format!("{app}.app/Contents"));
+            std::fs::create_dir_all(&contents)?;
+            Value::from(Dictionary::from_iter([("CFBundleIdentifier", Value::from(bundle_id))])).to_file_xml(contents.join("Info.plist"))?;
         }
 
-        // Per-host sandboxed preference (`current_host` is true and the sandboxed plist exists).
-        {
-            let domain_path = super::plist_path("com.apple.Safari", true)?;
-            let hardware_uuid = super::get_hardware_uuid()?;
-            assert_eq!(
-                home_dir.join(format!(
-                    "Library/Containers/com.apple.Safari/Data/Library/Preferences/ByHost/com.\
-                     apple.Safari.{hardware_uuid}.plist"
-                )),
-                domain_path
-            );
+        let app_dirs = [dir.clone()];
+
+        assert_eq!(app_bundle_identifier(&app_dirs, "Visual Studio Code").as_deref(), Some("com.microsoft.VSCode"));
+        assert_eq!(
+            app_bundle_identifier(&app_dirs, "visual studio code.app").as_deref(),
+            Some("com.microsoft.VSCode")
+        );
+        assert_eq!(app_bundle_identifier(&app_dirs, "CAFÉ ÜNÏCODE").as_deref(), Some("com.example.cafe"));
+        assert_eq!(app_bundle_identifier(&app_dirs, "Visual Studio"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_global_domain_aliases() -> TestResult {
+        for alias in ["Apple Global Domain", "  apple   global domain ", "-g", "-globalDomain", "NSGlobalDomain"] {
+            assert_eq!(super::resolve_domain(alias, false)?, NS_GLOBAL_DOMAIN, "{alias:?}");
         }
 
         Ok(())
     }
 
     #[test]
-    fn test_get_hardware_uuid() -> TestResult {
-        use duct::cmd;
+    fn test_sort_keys_nested() {
+        use plist::{Dictionary, Value};
 
-        let system_profiler_output = cmd!("system_profiler", "SPHardwareDataType").read()?;
+        use super::sort_keys_nested;
 
-        let expected_value = system_profiler_output
-            .lines()
-            .find_map(|line| line.contains("UUID").then(|| line.split_whitespace().last().unwrap_or_default()))
+        let dict = |keys: &[&str]| Value::from(keys.iter().map(|key| ((*key).to_owned(), Value::from(1))).collect::<Dictionary>());
+        let keys = |value: &Value| value.as_dictionary().map(|dict| dict.keys().cloned().collect::<Vec<_>>()).unwrap_or_default();
+
+        let mut value = Value::from(Dictionary::from_iter([
+            ("view", dict(&["b", "a"])),
+            ("list", Value::from(vec![dict(&["d", "c"]), Value::from("z"), Value::from("y")])),
+        ]));
+        sort_keys_nested(&mut value);
+
+        assert_eq!(keys(&value), ["list", "view"]);
+        assert_eq!(
+            keys(&value.as_dictionary().and_then(|dict| dict.get("view")).cloned().unwrap_or(Value::from(0))),
+            ["a", "b"]
+        );
+
+        // Arrays keep their order, but dictionaries in them are sorted.
+        let list = value
+            .as_dictionary()
+            .and_then(|dict| dict.get("list"))
+            .and_then(Value::as_array)
+            .cloned()
             .unwrap_or_default();
+        assert_eq!(keys(&list[0]), ["c", "d"]);
+        assert_eq!(list[1..], [Value::from("z"), Value::from("y")]);
+    }
 
-        let actual_value = super::get_hardware_uuid()?;
-        assert_eq!(expected_value, actual_value);
+    #[test]
+    fn test_sort_array_stable() {
+        use plist::{Dictionary, Value};
+
+        let element = |name: &str, id: i64| Value::from(Dictionary::from_iter([("name", Value::from(name)), ("id", id.into())]));
+        // Same elements, different key order within the dictionaries.
+        let element_reversed = |name: &str, id: i64| Value::from(Dictionary::from_iter([("id", Value::from(id)), ("name", name.into())]));
+
+        let mut first = Value::from(Dictionary::from_iter([("items", Value::from(vec![element("b", 2), element("a", 1)]))]));
+        let mut second = Value::from(Dictionary::from_iter([(
+            "items",
+            Value::from(vec![element_reversed("a", 1), element_reversed("b", 2)]),
+        )]));
+
+        for value in [&mut first, &mut second] {
+            sort_array_stable(value_at_key_path_mut(value, "items").expect("items exists"));
+        }
+
+        let names = |value: &mut Value| -> Vec<String> {
+            value_at_key_path_mut(value, "items")
+                .and_then(|items| items.as_array().cloned())
+                .unwrap_or_default()
+                .iter()
+                .filter_map(|item| item.as_dictionary()?.get("name")?.as_string().map(str::to_owned))
+                .collect()
+        };
+
+        assert_eq!(names(&mut first), vec!["a", "b"]);
+        assert_eq!(names(&mut second), vec!["a", "b"]);
+        assert!(value_at_key_path_mut(&mut first, "items.5").is_none());
+    }
+
+    #[test]
+    fn test_expand_key_paths() {
+        use std::collections::HashMap;
+
+        use plist::{Dictionary, Value};
+
+        let mut prefs = HashMap::from([
+            (".DesktopViewSettings.IconViewSettings.iconSize".to_owned(), Value::from(80)),
+            (".DesktopViewSettings.IconViewSettings.gridSpacing".to_owned(), Value::from(54)),
+            (r#"."com.apple.dock".tilesize"#.to_owned(), Value::from(36)),
+            // Not key paths: a plain dotted key, and a malformed path.
+            ("com.apple.swipescrolldirection".to_owned(), Value::from(false)),
+            (".unterminated.\"quote".to_owned(), Value::from(1)),
+        ]);
+
+        expand_key_paths(&mut prefs);
+
+        let expected = HashMap::from([
+            (
+                "DesktopViewSettings".to_owned(),
+                Value::from(Dictionary::from_iter([(
+                    "IconViewSettings",
+                    Dictionary::from_iter([("iconSize", Value::from(80)), ("gridSpacing", 54.into())]),
+                )])),
+            ),
+            ("com.apple.dock".to_owned(), Value::from(Dictionary::from_iter([("tilesize", Value::from(36))]))),
+            ("com.apple.swipescrolldirection".to_owned(), Value::from(false)),
+            (".unterminated.\"quote".to_owned(), Value::from(1)),
+        ]);
+
+        assert_eq!(prefs, expected);
+    }
+
+    #[test]
+    fn test_container_plist_cache() -> TestResult {
+        use std::sync::atomic::Ordering;
+
+        use camino::Utf8PathBuf;
+
+        use std::collections::HashMap;
+
+        use super::{add_containers, container_plist_exists, CONTAINER_CACHE_HITS};
+
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let present = dir.join("com.example.present.plist");
+        std::fs::write(&present, "")?;
+        assert!(container_plist_exists(&present));
+        assert!(container_plist_exists(&present));
+
+        // A miss is remembered for the rest of the run, even if the file shows up.
+        let missing = dir.join("com.example.missing.plist");
+        assert!(!container_plist_exists(&missing));
+
+        let hits = CONTAINER_CACHE_HITS.load(Ordering::Relaxed);
+        std::fs::write(&missing, "")?;
+        assert!(!container_plist_exists(&missing));
+        assert!(CONTAINER_CACHE_HITS.load(Ordering::Relaxed) > hits);
+
+        // Mapping a domain to a container forgets the misses inside it.
+        let container = format!("com.example.container-cache-{}", std::process::id());
+        let in_container = dir.join(&container).join("com.example.mapped.plist");
+        assert!(!container_plist_exists(&in_container));
+        std::fs::create_dir_all(dir.join(&container))?;
+        std::fs::write(&in_container, "")?;
+        assert!(!container_plist_exists(&in_container));
+
+        add_containers(&HashMap::from([("com.example.mapped".to_owned(), container)]), &HashMap::new());
+        assert!(container_plist_exists(&in_container));
 
         Ok(())
     }
 
     #[test]
-    fn test_serialize_binary() -> TestResult {
-        // Modified version of ~/Library/Preferences/com.apple.humanunderstanding.plist
-        let binary_plist_as_hex = "62706c6973743030d101025f10124861736847656e657261746f722e73616c744f10201111111122222222333333334444444455555555666666667777777788888888080b200000000000000101000000000000000300000000000000000000000000000043";
-        let expected_yaml = "HashGenerator.salt: \
-                             '1111111122222222333333334444444455555555666666667777777788888888'\n";
+    fn test_containers() -> TestResult {
+        use std::collections::HashMap;
 
-        let binary_plist = hex::decode(binary_plist_as_hex)?;
+        use super::{add_containers, plist_path};
 
-        let mut value: plist::Value = plist::from_bytes(&binary_plist)?;
-        info!("Value before: {value:?}");
-        super::replace_data_in_plist(&mut value)?;
-        info!("Value after: {value:?}");
-        let yaml_string = serde_yaml::to_string(&value)?;
-        info!("Yaml value: {yaml_string}");
-        assert_eq!(expected_yaml, yaml_string);
+        add_containers(
+            &HashMap::from([("com.example.helper".to_owned(), "com.example.app".to_owned())]),
+            &HashMap::from([("com.example.grouped".to_owned(), "TEAMID.group".to_owned())]),
+        );
+
+        // Used whether or not the container exists.
+        let path = plist_path(&"com.example.helper".parse()?, false)?;
+        assert!(path.ends_with("Library/Containers/com.example.app/Data/Library/Preferences/com.example.helper.plist"));
+
+        let path = plist_path(&"com.example.grouped".parse()?, false)?;
+        assert!(path.ends_with("Library/Group Containers/TEAMID.group/Library/Preferences/com.example.grouped.plist"));
+
+        let path = plist_path(&"com.example.unmapped".parse()?, false)?;
+        assert!(path.ends_with("Library/Preferences/com.example.unmapped.plist"));
+        assert!(!path.as_str().contains("Containers"));
 
         Ok(())
     }
 
     #[test]
-    fn test_deep_merge_dictionaries() {
-        use plist::{Dictionary, Value};
+    fn test_find_group_container_plist() -> TestResult {
+        use camino::Utf8PathBuf;
 
-        let old_value = Dictionary::from_iter([
-            ("foo", Value::from(10)), // !!! takes precedence
-            ("fub", 11.into()),       // !!!
-            ("bar", 12.into()),       // !
-            ("baz", 13.into()),       // !
-        ])
-        .into();
-        let mut new_value = Dictionary::from_iter([
-            ("bar", Value::from(22)), // !!
-            ("baz", 23.into()),       // !! takes precedence
-        ])
-        .into();
+        use super::find_group_container_plist;
 
-        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+        let tmp = tempfile::tempdir()?;
+        let home = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let office = home.join("Library/Group Containers/UBF8T346G9.Office/Library/Preferences");
+        std::fs::create_dir_all(&office)?;
+        std::fs::write(office.join("com.microsoft.office.plist"), "")?;
+        std::fs::write(office.join("com.example.shared.plist"), "")?;
 
-        let expected = Dictionary::from_iter([
-            ("foo", Value::from(10)), // from new
-            ("fub", 11.into()),
-            ("bar", 22.into()), // from old
-            ("baz", 23.into()),
-        ])
-        .into();
+        let other = home.join("Library/Group Containers/2BUA8C4S2C.com.example/Library/Preferences");
+        std::fs::create_dir_all(&other)?;
+        std::fs::write(other.join("com.example.shared.plist"), "")?;
 
-        assert_eq!(new_value, expected);
+        assert_eq!(
+            find_group_container_plist(&home, false, "com.microsoft.office.plist"),
+            Some(office.join("com.microsoft.office.plist"))
+        );
+
+        // Missing, or ambiguous.
+        assert_eq!(find_group_container_plist(&home, false, "com.example.missing.plist"), None);
+        assert_eq!(find_group_container_plist(&home, false, "com.example.shared.plist"), None);
+
+        // The group containers are only listed once.
+        let added = home.join("Library/Group Containers/A1B2C3D4E5.added/Library/Preferences");
+        std::fs::create_dir_all(&added)?;
+        std::fs::write(added.join("com.example.added.plist"), "")?;
+        assert_eq!(find_group_container_plist(&home, false, "com.example.added.plist"), None);
+
+        Ok(())
     }
 
     #[test]
-    fn test_replace_ellipsis_dict_nested() {
+    fn test_read_plist_limits() -> TestResult {
+        use camino::Utf8PathBuf;
         use plist::{Dictionary, Value};
 
-        let old_value = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([(
-                "level_2",
-                Dictionary::from_iter([
-                    ("foo", Value::from(10)), //
-                    ("bar", 20.into()),
-                    ("baz", 30.into()),
-                ]),
-            )]),
-        )])
-        .into();
+        use super::{read_plist, MAX_PLIST_SIZE};
+        use crate::errors::DefaultsError as E;
 
-        let mut new_value = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([(
-                "level_2",
-                Dictionary::from_iter([
-                    ("baz", Value::from(90)), //
-                ]),
-            )]),
-        )])
-        .into();
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
 
-        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+        let plist_path = dir.join("com.example.limits.plist");
+        let value = Value::from(Dictionary::from_iter([("a", Value::from(1)), ("b", Value::from("two"))]));
+        value.to_file_binary(&plist_path)?;
+        assert_eq!(read_plist(&plist_path)?, value);
 
-        let expected = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([(
-                "level_2",
-                Dictionary::from_iter([
-                    ("foo", Value::from(10)), //
-                    ("bar", 20.into()),
-                    ("baz", 90.into()),
-                ]),
-            )]),
-        )])
-        .into();
+        // An offset table past the end of the file.
+        let mut bytes = std::fs::read(&plist_path)?;
+        let len = bytes.len();
+        bytes[len - 8..].copy_from_slice(&u64::MAX.to_be_bytes());
+        std::fs::write(&plist_path, &bytes)?;
+        assert!(matches!(read_plist(&plist_path), Err(E::CorruptPlist { .. })));
 
-        assert_eq!(new_value, expected);
+        // An object count far larger than the file.
+        value.to_file_binary(&plist_path)?;
+        let mut bytes = std::fs::read(&plist_path)?;
+        bytes[len - 24..len - 16].copy_from_slice(&(1_u64 << 40).to_be_bytes());
+        std::fs::write(&plist_path, &bytes)?;
+        assert!(matches!(read_plist(&plist_path), Err(E::CorruptPlist { .. })));
+
+        // Sparse, so this doesn't take up any space.
+        std::fs::File::create(&plist_path)?.set_len(MAX_PLIST_SIZE + 1)?;
+        assert!(matches!(read_plist(&plist_path), Err(E::PlistTooLarge { .. })));
+
+        Ok(())
     }
 
     #[test]
-    fn test_replace_ellipsis_dict_nested_bang() {
-        use plist::{Dictionary, Value};
+    fn test_closest_matches() {
+        use super::{closest_matches, edit_distance};
 
-        let old_value = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([(
-                "level_2",
-                Dictionary::from_iter([
-                    ("foo", Value::from(10)), //
-                    ("bar", 20.into()),
-                    ("baz", 30.into()),
-                ]),
-            )]),
-        )])
-        .into();
+        assert_eq!(edit_distance("finder", "finderr"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "dock"), 4);
 
-        let mut new_value = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([(
-                "level_2",
-                Dictionary::from_iter([
-                    ("!", Value::from("")), //
-                    ("baz", 90.into()),     //
-                ]),
-            )]),
-        )])
-        .into();
+        let installed = ["com.apple.finder", "com.apple.dock", "com.apple.Safari", "com.apple.finder.plugins"];
 
-        deep_merge_dictionaries(&mut new_value, Some(&old_value));
+        assert_eq!(closest_matches("com.apple.finderr", installed), ["com.apple.finder"]);
+        assert_eq!(closest_matches("com.apple.safary", installed), ["com.apple.Safari"]);
+        assert!(closest_matches("org.example.unrelated", installed).is_empty());
+    }
 
-        let expected = Dictionary::from_iter([(
-            "level_1",
-            Dictionary::from_iter([("level_2", Dictionary::from_iter([("baz", Value::from(90))]))]),
-        )])
-        .into();
+    #[test]
+    fn test_parse_home_directory() {
+        use camino::Utf8PathBuf;
 
-        assert_eq!(new_value, expected);
+        use super::parse_home_directory;
+
+        assert_eq!(parse_home_directory("NFSHomeDirectory: /Users/test\n"), Some(Utf8PathBuf::from("/Users/test")));
+        assert_eq!(
+            parse_home_directory("NFSHomeDirectory:\n /Users/with space\n"),
+            Some(Utf8PathBuf::from("/Users/with space"))
+        );
+        assert_eq!(parse_home_directory("No such key: NFSHomeDirectory\n"), None);
     }
 
     #[test]
-    fn test_replace_ellipsis_array() {
-        let old_value = vec![
-            10.into(), // !
-            20.into(), // !
-            30.into(), // !
-            40.into(), // !
-        ]
-        .into();
-        let mut new_value = vec![
-            30.into(), // !!!
-            20.into(), // !!!
-            "...".into(),
-            60.into(), // !!
-            50.into(), // !!
-            40.into(), // !!
-        ]
-        .into();
+    fn test_simulated_home() -> TestResult {
+        let first = super::simulated_home()?;
+        assert_eq!(first.read_dir_utf8()?.count(), 0);
 
-        replace_ellipsis_array(&mut new_value, Some(&old_value));
+        // Each run plans against its own empty home directory, and doesn't leave it behind.
+        let second = super::simulated_home()?;
+        assert_ne!(first, second);
+        assert!(!first.exists());
 
-        let expected = vec![
-            30.into(), // from new array before "..."
-            20.into(),
-            10.into(), // from old array
-            40.into(),
-            60.into(), // from new array after "..."
-            50.into(),
-        ]
-        .into();
+        super::remove_simulated_home();
+        assert!(!second.exists());
 
-        assert_eq!(new_value, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_rooted() {
+        use camino::{Utf8Path, Utf8PathBuf};
+
+        use super::rooted;
+
+        let root = Utf8Path::new("/tmp/root");
+
+        assert_eq!(rooted(root, Utf8Path::new("/Users/me")), Utf8PathBuf::from("/tmp/root/Users/me"));
+        assert_eq!(
+            rooted(root, Utf8Path::new("/Library/Preferences/com.apple.loginwindow.plist")),
+            Utf8PathBuf::from("/tmp/root/Library/Preferences/com.apple.loginwindow.plist")
+        );
     }
 }
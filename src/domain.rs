@@ -0,0 +1,151 @@
+//! Preferences domains, validated when they're parsed rather than when their plist is written.
+
+use std::fmt;
+use std::str::FromStr;
+
+use camino::Utf8PathBuf;
+use serde::Serialize;
+
+use crate::defaults::NS_GLOBAL_DOMAIN;
+use crate::errors::DefaultsError as E;
+
+/// Names accepted by the `defaults` command for the global domain, compared case-insensitively.
+pub const GLOBAL_DOMAIN_ALIASES: &[&str] = &[NS_GLOBAL_DOMAIN, "Apple Global Domain", "-g", "-globalDomain", ".GlobalPreferences"];
+
+/// A preferences domain, as used to find its plist.
+///
+/// Parsed from `NSGlobalDomain` (or one of its aliases), an application domain like
+/// `com.apple.dock`, or an absolute path to a plist file. Friendly names like app names are
+/// resolved to a domain by [`resolve_domain`](crate::defaults::resolve_domain).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Domain {
+    /// `NSGlobalDomain`, stored in `.GlobalPreferences.plist`.
+    Global,
+    /// An application domain, usually a reverse-DNS name like `com.apple.dock`, without a `.plist` suffix.
+    Named(String),
+    /// An absolute path to a plist file, used as is.
+    Path(Utf8PathBuf),
+}
+
+impl Domain {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Global => NS_GLOBAL_DOMAIN,
+            Self::Named(name) => name,
+            Self::Path(path) => path.as_str(),
+        }
+    }
+}
+
+impl FromStr for Domain {
+    type Err = E;
+
+    fn from_str(domain: &str) -> Result<Self, E> {
+        let invalid = |reason| E::InvalidDomain {
+            domain: domain.to_owned(),
+            reason,
+        };
+
+        if GLOBAL_DOMAIN_ALIASES.iter().any(|alias| alias.eq_ignore_ascii_case(domain)) {
+            return Ok(Self::Global);
+        }
+
+        if domain.starts_with('/') {
+            return Ok(Self::Path(Utf8PathBuf::from(domain)));
+        }
+
+        // As with the `defaults` command, `com.foo.bar.plist` is the domain `com.foo.bar`.
+        let name = domain.strip_suffix(".plist").unwrap_or(domain);
+
+        if name.is_empty() {
+            return Err(invalid("it's empty"));
+        }
+
+        if name.contains('/') {
+            return Err(invalid("only absolute paths may contain a `/`"));
+        }
+
+        if name.chars().any(char::is_control) {
+            return Err(invalid("it contains control characters"));
+        }
+
+        if name.trim() != name {
+            return Err(invalid("it starts or ends with whitespace"));
+        }
+
+        if name.split('.').any(str::is_empty) {
+            return Err(invalid("it has an empty component between dots, e.g. `com..apple`"));
+        }
+
+        Ok(Self::Named(name.to_owned()))
+    }
+}
+
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl AsRef<str> for Domain {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl PartialEq<str> for Domain {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for Domain {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl Serialize for Domain {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::Domain;
+
+    #[test]
+    fn test_parse_domain() -> TestResult {
+        assert_eq!("NSGlobalDomain".parse::<Domain>()?, Domain::Global);
+        assert_eq!("-g".parse::<Domain>()?, Domain::Global);
+        assert_eq!("com.apple.dock".parse::<Domain>()?, Domain::Named("com.apple.dock".to_owned()));
+        assert_eq!("com.apple.dock.plist".parse::<Domain>()?, "com.apple.dock");
+        assert_eq!("My App".parse::<Domain>()?, "My App");
+        assert_eq!(
+            "/Library/Preferences/com.apple.loginwindow".parse::<Domain>()?,
+            Domain::Path(Utf8PathBuf::from("/Library/Preferences/com.apple.loginwindow"))
+        );
+
+        for invalid in [
+            "",
+            ".plist",
+            "com..apple",
+            ".com.apple",
+            "com.apple.",
+            "Library/com.apple.dock",
+            " com.apple.dock",
+            "com\napple",
+        ] {
+            assert!(invalid.parse::<Domain>().is_err(), "{invalid:?}");
+        }
+
+        assert_eq!(Domain::Global.to_string(), "NSGlobalDomain");
+        assert_eq!(serde_yaml::to_string(&Domain::Named("com.apple.dock".to_owned()))?, "com.apple.dock\n");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,94 @@
+//! What about this machine decides whether preference writes behave as expected: the macOS
+//! version, the hardware UUID `ByHost` plists are named after, whether System Integrity Protection
+//! guards some system preferences, and who's logged in at the console, whose cfprefsd serves the
+//! preferences apps see. Printed at the start of a verbose run, and by `doctor`.
+
+use std::fmt;
+
+use duct::cmd;
+use sysinfo::System;
+use tracing::debug;
+
+use crate::defaults::get_hardware_uuid;
+
+/// A summary of the machine, with `None` for anything that couldn't be found out.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Environment {
+    pub os_version: Option<String>,
+    pub hardware_uuid: Option<String>,
+    pub sip_enabled: Option<bool>,
+    pub console_user: Option<String>,
+}
+
+impl Environment {
+    /// Find out what's known about this machine. Slow enough (it runs `ioreg` and `csrutil`) to
+    /// only do once per run.
+    pub fn gather() -> Self {
+        let run = |program: &str, args: &[&str]| cmd(program, args).stderr_null().read().map_err(|e| debug!("Unable to run {program}: {e}")).ok();
+
+        Self {
+            os_version: System::os_version(),
+            hardware_uuid: get_hardware_uuid().map_err(|e| debug!("{e}")).ok(),
+            sip_enabled: run("csrutil", &["status"]).as_deref().and_then(parse_sip_status),
+            console_user: run("stat", &["-f", "%Su", "/dev/console"])
+                .map(|user| user.trim().to_owned())
+                .filter(|user| !user.is_empty()),
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unknown = || "unknown".to_owned();
+        let sip = self.sip_enabled.map(|enabled| if enabled { "enabled" } else { "disabled" });
+
+        writeln!(f, "macOS version: {}", self.os_version.clone().unwrap_or_else(unknown))?;
+        writeln!(f, "Hardware UUID: {}", self.hardware_uuid.clone().unwrap_or_else(unknown))?;
+        writeln!(f, "SIP:           {}", sip.unwrap_or("unknown"))?;
+        writeln!(f, "Console user:  {}", self.console_user.clone().unwrap_or_else(unknown))
+    }
+}
+
+/// Whether `csrutil status` says SIP is enabled, e.g. `System Integrity Protection status: enabled.`
+/// Partly enabled custom configurations count as enabled.
+fn parse_sip_status(output: &str) -> Option<bool> {
+    let status = output.split_once("status:")?.1.trim_start();
+
+    if status.starts_with("enabled") {
+        Some(true)
+    } else if status.starts_with("disabled") {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sip_status, Environment};
+
+    #[test]
+    fn test_parse_sip_status() {
+        assert_eq!(parse_sip_status("System Integrity Protection status: enabled.\n"), Some(true));
+        assert_eq!(parse_sip_status("System Integrity Protection status: disabled.\n"), Some(false));
+        assert_eq!(
+            parse_sip_status("System Integrity Protection status: enabled (Custom Configuration).\n\nConfiguration:\n\tKext Signing: disabled"),
+            Some(true)
+        );
+        assert_eq!(parse_sip_status("command not found"), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let environment = Environment {
+            os_version: Some("14.2.1".to_owned()),
+            sip_enabled: Some(false),
+            ..Environment::default()
+        };
+
+        assert_eq!(
+            environment.to_string(),
+            "macOS version: 14.2.1\nHardware UUID: unknown\nSIP:           disabled\nConsole user:  unknown\n"
+        );
+    }
+}
@@ -1,8 +1,11 @@
 // NB: Most of this code originated from: https://github.com/gibfahn/up-rs, MIT & Apache 2.0 licensed.
 
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::Report;
 use thiserror::Error;
 
+use crate::phases::{Checkpoint, Phase};
+
 #[derive(Error, Debug)]
 pub enum DefaultsError {
     #[error("Unable to create dir at: {path}.")]
@@ -15,6 +18,71 @@ pub enum DefaultsError {
         source: std::io::Error,
     },
 
+    #[error("Unable to rename file. From: {from_path} To: {to_path}")]
+    FileRename {
+        from_path: Utf8PathBuf,
+        to_path: Utf8PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("{path} already exists, use --force to overwrite it.")]
+    FileExists { path: Utf8PathBuf },
+
+    #[error("{} failed to apply:\n{failures}", if *.count == 1 { "1 domain".to_owned() } else { format!("{} domains", .count) })]
+    DomainsFailed { count: usize, failures: String },
+
+    #[error("Applying again would still change these keys, so the apply isn't idempotent:\n{keys}")]
+    NotIdempotent { keys: String },
+
+    #[error("Unable to load LaunchAgent {path} with launchctl.")]
+    AgentLoad { path: Utf8PathBuf, source: std::io::Error },
+
+    #[error("Unable to remove file: {path}")]
+    FileRemove { path: Utf8PathBuf, source: std::io::Error },
+
+    #[error("Values written to {path} didn't persist when read back via {source_name}: {keys}")]
+    VerifyFailed {
+        path: Utf8PathBuf,
+        source_name: &'static str,
+        keys: String,
+    },
+
+    #[error("Unable to read back {path} with `defaults export`")]
+    VerifyReadFailed { path: Utf8PathBuf, source: std::io::Error },
+
+    #[error("No path to apply was given, and the config file has no `paths`.")]
+    NoPathsToApply,
+
+    #[error("{path} is a symlink to {target}, not writing to it. Use `--symlinks follow` to write through it.")]
+    SymlinkedPlist { path: Utf8PathBuf, target: Utf8PathBuf },
+
+    #[error("Unable to relaunch {app} with `open`.")]
+    AppLaunch { app: String, source: std::io::Error },
+
+    #[error("{document} is to be applied after {id:?}, but no document has that id.")]
+    UnknownDocumentId { document: String, id: String },
+
+    #[error("These documents are each to be applied after another, in a cycle: {documents}")]
+    DocumentCycle { documents: String },
+
+    #[error("No running process matched: {targets}")]
+    NoMatchingProcess { targets: String },
+
+    #[error("Invalid hardware UUID {uuid:?}: {reason}.")]
+    InvalidHardwareUuid { uuid: String, reason: &'static str },
+
+    #[error("The config file has no `phases` to apply.")]
+    NoPhases,
+
+    #[error("There's no bootstrap to continue, run `apply` without `--continue` to start from the first phase.")]
+    NoPhaseInProgress,
+
+    #[error("The bootstrap stopped before phase {phase}, but the config file no longer has it.")]
+    PhaseNotConfigured { phase: Phase },
+
+    #[error("{checkpoint} before continuing with phase {phase}.")]
+    CheckpointNotReached { phase: Phase, checkpoint: Checkpoint },
+
     #[error("Failed to read bytes from path {path}")]
     FileRead { path: Utf8PathBuf, source: std::io::Error },
 
@@ -24,13 +92,43 @@ pub enum DefaultsError {
     #[error("Failed to read Plist file {path}.")]
     PlistRead { path: Utf8PathBuf, source: plist::Error },
 
+    #[error("Not reading {path}: it's {size} bytes, over the {max} byte limit for a plist.")]
+    PlistTooLarge { path: Utf8PathBuf, size: u64, max: u64 },
+
+    #[error("Not reading {path}, it looks corrupt: {reason}.")]
+    CorruptPlist { path: Utf8PathBuf, reason: &'static str },
+
     #[error("Failed to write value to plist file {path}")]
     PlistWrite { path: Utf8PathBuf, source: plist::Error },
 
+    #[error("Unable to set {key} in {path} with `defaults`.")]
+    DefaultsWrite { path: Utf8PathBuf, key: String, source: std::io::Error },
+
+    #[error("Unable to set the changed keys of {path} through CFPreferences.")]
+    CfPreferencesWrite { path: Utf8PathBuf },
+
+    #[error("Unable to read the keys of {path} through CFPreferences.")]
+    CfPreferencesRead { path: Utf8PathBuf },
+
     #[error("Failed to write a value to plist file {path} as sudo.")]
     PlistSudoWrite { path: Utf8PathBuf, source: std::io::Error },
 
-    #[error("Invalid YAML at '{path}'")]
+    #[error("Failed to change the owner of {path}.")]
+    ChownFailed { path: Utf8PathBuf, source: std::io::Error },
+
+    #[error("Changing the preferences of {user} needs root, run with sudo.")]
+    UserNeedsRoot { user: String },
+
+    #[error("Unable to look up the user {user}.")]
+    UserLookup { user: String, source: std::io::Error },
+
+    #[error("No user named {user}, or they have no home directory.")]
+    UnknownUser { user: String },
+
+    #[error("--stdin was given, but stdin is a terminal rather than a here-doc, pipe or file.")]
+    StdinIsTerminal,
+
+    #[error("Invalid YAML at '{}'", source_name(.path))]
     InvalidYaml { path: Utf8PathBuf, source: serde_yaml::Error },
 
     #[error("Failed to serialize plist to YAML. Domain: {domain:?}")]
@@ -39,6 +137,85 @@ pub enum DefaultsError {
     #[error("Failed to deserialize the YAML file or string.")]
     DeserializationFailed { source: serde_yaml::Error },
 
+    #[error("Couldn't parse YAML data key in: {}", source_name(.path))]
+    MissingData { path: Utf8PathBuf },
+
+    #[error("Can't represent a plist value of type {plist_type} in YAML.\nDomain: {domain:?}\nKey: {key:?}")]
+    UnsupportedPlistType { domain: String, key: String, plist_type: String },
+
+    #[error("Defaults plist doesn't exist and --strict was given: {path}")]
+    MissingPlist { path: Utf8PathBuf },
+
+    #[error("Domain {domain} doesn't exist, there's no {path}.{}", .suggestion.as_ref().map_or(String::new(), |suggestion| format!(" {suggestion}")))]
+    DomainNotFound {
+        domain: String,
+        path: Utf8PathBuf,
+        suggestion: Option<String>,
+    },
+
+    #[error("{domain} doesn't have a plist, and --no-create is set. {hint}")]
+    NoCreate { domain: String, hint: String },
+
+    #[error("Domain {domain:?} contains binary data that can't be represented in YAML and --strict was given.")]
+    StrictCoercion { domain: String },
+
+    #[error("Writing large values to sidecar files needs an output path.")]
+    SidecarWithoutOutput,
+
+    #[error("No catalog entry for domain {domain:?}{}", key.as_ref().map(|k| format!(" key {k:?}")).unwrap_or_default())]
+    NotInCatalog { domain: String, key: Option<String> },
+
+    #[error("No bundled preset named {name:?}, available presets: {available}")]
+    UnknownPreset { name: String, available: String },
+
+    #[error("Invalid version {version:?}, expected e.g. 0.2.0")]
+    InvalidVersion { version: String },
+
+    #[error("Invalid domain {domain:?}: {reason}.")]
+    InvalidDomain { domain: String, reason: &'static str },
+
+    #[error("Invalid value for {domain} {key}: {reason}.")]
+    InvalidValue { domain: String, key: String, reason: String },
+
+    #[error("Unknown YAML tag {tag}")]
+    UnknownTag { tag: String },
+
+    #[error("The value of a {tag} tag must be {expected}.")]
+    InvalidTagValue { tag: &'static str, expected: &'static str },
+
+    #[error(
+        "macos-defaults only changes preferences on macOS, not {os}. Use --simulate to plan YAML files against an empty home \
+         directory, or --force to run anyway."
+    )]
+    UnsupportedOs { os: String },
+
+    #[error("Only plan, diff and commands that don't use this Mac's preferences, like convert, can run with --simulate.")]
+    NotSimulatable,
+
+    #[error("Only the file backend can write under --target-root, the others change this Mac's preferences through cfprefsd.")]
+    TargetRootBackend,
+
+    #[error("macOS {version} isn't supported, macos-defaults needs macOS {minimum} or later. Use --force to run anyway.")]
+    UnsupportedMacosVersion { version: String, minimum: String },
+
+    #[error("Can't export {setting} from {path} to a profile: {reason}.")]
+    UnsupportedInProfile { path: Utf8PathBuf, setting: String, reason: &'static str },
+
+    #[error("SHA-256 of {path} is {actual}, expected {expected}.")]
+    ChecksumMismatch { path: Utf8PathBuf, expected: String, actual: String },
+
+    #[error(
+        "No age identity to decrypt !secret values: set MACOS_DEFAULTS_AGE_IDENTITY to an identity file, create {path}, \
+         or add one to the keychain (service `macos-defaults`, account `age-identity`)."
+    )]
+    NoSecretIdentity { path: Utf8PathBuf },
+
+    #[error("No keychain item with service `{service}` and account `{account}`, add it with `security add-generic-password -s <service> -a <account> -w`.")]
+    KeychainItemNotFound { service: String, account: String },
+
+    #[error("Unable to decrypt a !secret value with `age`.")]
+    SecretDecrypt { source: std::io::Error },
+
     #[error("Expected a domain, but didn't find one.")]
     MissingDomain {},
 
@@ -48,6 +225,90 @@ pub enum DefaultsError {
     #[error("Eyre error.")]
     EyreError { source: color_eyre::Report },
 
-    #[error("failed to split YAML file {path}")]
+    #[error("failed to split YAML from {}", source_name(.path))]
     YamlSplitError { path: Utf8PathBuf, source: yaml_split::YamlSplitError },
 }
+
+/// How a YAML source is named in errors: the `-` path is stdin.
+fn source_name(path: &Utf8Path) -> &str {
+    if path == "-" {
+        "stdin"
+    } else {
+        path.as_str()
+    }
+}
+
+/// What to do about an error that a user can fix by hand, for the `apply --keep-going` summary.
+pub fn remedy(report: &Report) -> Option<String> {
+    report.chain().find_map(|cause| {
+        if let Some(error) = cause.downcast_ref::<DefaultsError>() {
+            return match error {
+                DefaultsError::CorruptPlist { path, .. } | DefaultsError::PlistRead { path, .. } | DefaultsError::PlistTooLarge { path, .. } => Some(format!(
+                    "Restore {path}.prev if it's a good backup, or apply with --repair to move {path} aside and recreate it."
+                )),
+                DefaultsError::PlistSudoWrite { .. } | DefaultsError::ChownFailed { .. } => {
+                    Some("Check the file's owner, `macos-defaults doctor --fix-ownership` gives root-owned plists back to you.".to_owned())
+                }
+                DefaultsError::NotADictionary { key, .. } => Some(format!("Set {key} as a whole in the YAML, rather than keys inside it.")),
+                _ => None,
+            };
+        }
+
+        cause
+            .downcast_ref::<std::io::Error>()
+            .filter(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+            .map(|_| "Check the file's owner and permissions, or run `macos-defaults doctor`.".to_owned())
+    })
+}
+
+/// Process exit codes, so that scripts can tell the different failure modes apart.
+///
+/// When changes were applied the `apply --exit-code` value is used instead of `Success`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Nothing failed, and either nothing changed or no `--exit-code` was given.
+    Success = 0,
+    /// Any error which doesn't fit a more specific code.
+    Failure = 1,
+    /// A YAML file couldn't be split, parsed or deserialized.
+    ParseError = 3,
+    /// A file couldn't be read or written due to its permissions.
+    PermissionDenied = 4,
+    /// Some domains were written before a later one failed.
+    PartialSuccess = 5,
+    /// Nothing failed and nothing changed, with `apply --detailed-exit-codes`.
+    Unchanged = 6,
+}
+
+impl ExitCode {
+    /// Classify an error report. `partial` should be true if any domain was applied before the failure.
+    pub fn from_report(report: &Report, partial: bool) -> Self {
+        for cause in report.chain() {
+            if let Some(error) = cause.downcast_ref::<DefaultsError>() {
+                match error {
+                    DefaultsError::InvalidYaml { .. }
+                    | DefaultsError::DeserializationFailed { .. }
+                    | DefaultsError::YamlSplitError { .. }
+                    | DefaultsError::MissingData { .. }
+                    | DefaultsError::UnknownTag { .. }
+                    | DefaultsError::InvalidTagValue { .. } => return Self::ParseError,
+                    DefaultsError::PlistSudoWrite { .. } => return Self::PermissionDenied,
+                    _ => {}
+                }
+            }
+
+            if cause
+                .downcast_ref::<std::io::Error>()
+                .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+            {
+                return Self::PermissionDenied;
+            }
+        }
+
+        if partial {
+            Self::PartialSuccess
+        } else {
+            Self::Failure
+        }
+    }
+}
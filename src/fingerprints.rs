@@ -0,0 +1,233 @@
+//! Fingerprints of the plists a config manages, so `status --fast` can tell whether anything
+//! changed since they were taken without planning an apply.
+//!
+//! Kept in `$XDG_STATE_HOME/macos-defaults/fingerprints.plist` (falling back to
+//! `~/.local/state/macos-defaults/fingerprints.plist`), as a dictionary of YAML files to the plists
+//! they set: `{file: {path: {domain, modified, size, sha256, keys, keys_sha256}}}`. A plist with
+//! the same modification time and size is unchanged without being read, and one whose file hash
+//! still matches without being parsed; otherwise only the hash of its managed keys decides.
+//!
+//! `apply` refreshes the fingerprints of the files it applies, as does the `fingerprint` command.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::time::UNIX_EPOCH;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use plist::{Dictionary, Value};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+
+use crate::defaults::read_plist;
+use crate::errors::DefaultsError as E;
+use crate::manifest::{absolute, state_dir};
+
+/// YAML files to the fingerprints of the plists they set.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Fingerprints {
+    files: BTreeMap<Utf8PathBuf, BTreeMap<Utf8PathBuf, PlistFingerprint>>,
+}
+
+/// A plist's contents when it was fingerprinted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlistFingerprint {
+    pub domain: String,
+    /// Modification time and size of the file, zero if it didn't exist.
+    #[serde(default)]
+    modified: u64,
+    #[serde(default)]
+    size: u64,
+    /// SHA-256 of the whole file, empty if it didn't exist.
+    sha256: String,
+    /// The top level keys the YAML file sets.
+    keys: Vec<String>,
+    /// SHA-256 of the managed keys and their values.
+    keys_sha256: String,
+}
+
+impl PlistFingerprint {
+    /// Fingerprint the plist at `path`, of which the YAML file sets `keys`.
+    pub fn take(domain: String, path: &Utf8Path, keys: Vec<String>) -> Result<Self> {
+        let (modified, size) = file_stamp(path);
+        let bytes = read_bytes(path)?;
+        let keys_sha256 = keys_sha256(path, bytes.as_deref(), &keys)?;
+
+        Ok(Self {
+            domain,
+            modified,
+            size,
+            sha256: bytes.as_deref().map(sha256).unwrap_or_default(),
+            keys,
+            keys_sha256,
+        })
+    }
+
+    /// Whether the managed keys of the plist at `path` changed since it was fingerprinted. The
+    /// plist is only read if its modification time or size changed, and only parsed if its
+    /// contents did.
+    pub fn changed(&self, path: &Utf8Path) -> Result<bool> {
+        if file_stamp(path) == (self.modified, self.size) {
+            trace!("{path} wasn't modified");
+            return Ok(false);
+        }
+
+        let bytes = read_bytes(path)?;
+
+        if bytes.as_deref().map(sha256).unwrap_or_default() == self.sha256 {
+            trace!("{path} is unchanged");
+            return Ok(false);
+        }
+
+        debug!("{path} changed, comparing its managed keys");
+        Ok(keys_sha256(path, bytes.as_deref(), &self.keys)? != self.keys_sha256)
+    }
+}
+
+impl Fingerprints {
+    /// Read the fingerprints, or none if nothing has been fingerprinted yet.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&fingerprints_path()?)
+    }
+
+    fn load_from(path: &Utf8Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(plist::from_file(path).map_err(|e| E::PlistRead {
+            path: path.to_owned(),
+            source: e,
+        })?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&fingerprints_path()?)
+    }
+
+    fn save_to(&self, path: &Utf8Path) -> Result<()> {
+        debug!("Saving fingerprints to {path}");
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+                path: parent.to_owned(),
+                source: e,
+            })?;
+        }
+
+        plist::to_file_xml(path, self).map_err(|e| E::PlistWrite {
+            path: path.to_owned(),
+            source: e,
+        })?;
+
+        Ok(())
+    }
+
+    /// Replace the fingerprints of the plists a YAML file sets.
+    pub fn record(&mut self, file: &Utf8Path, plists: BTreeMap<Utf8PathBuf, PlistFingerprint>) {
+        self.files.insert(absolute(file), plists);
+    }
+
+    /// The fingerprints of the plists a YAML file sets, if it has been fingerprinted.
+    pub fn get(&self, file: &Utf8Path) -> Option<&BTreeMap<Utf8PathBuf, PlistFingerprint>> {
+        self.files.get(&absolute(file))
+    }
+}
+
+/// A file's modification time, in nanoseconds since the epoch, and size, or zeros if it doesn't
+/// exist.
+pub(crate) fn file_stamp(path: &Utf8Path) -> (u64, u64) {
+    let Ok(metadata) = fs::metadata(path) else {
+        return (0, 0);
+    };
+
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| u64::try_from(since.as_nanos()).unwrap_or(u64::MAX));
+
+    (modified, metadata.len())
+}
+
+/// A plist file's bytes, or `None` if it doesn't exist.
+fn read_bytes(path: &Utf8Path) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    Ok(Some(fs::read(path).map_err(|e| E::FileRead {
+        path: path.to_owned(),
+        source: e,
+    })?))
+}
+
+fn sha256(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Hash of the `keys` a plist has, with their values, as an XML plist of just those keys.
+fn keys_sha256(path: &Utf8Path, bytes: Option<&[u8]>, keys: &[String]) -> Result<String> {
+    let plist = match bytes {
+        Some(_) => read_plist(path)?,
+        None => Value::Dictionary(Dictionary::new()),
+    };
+
+    let managed: Dictionary = keys
+        .iter()
+        .filter_map(|key| Some((key.clone(), plist.as_dictionary()?.get(key)?.clone())))
+        .collect();
+
+    let mut xml = Vec::new();
+    Value::Dictionary(managed).to_writer_xml(&mut xml)?;
+
+    Ok(sha256(&xml))
+}
+
+fn fingerprints_path() -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("fingerprints.plist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use camino::Utf8PathBuf;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{Fingerprints, PlistFingerprint};
+
+    #[test]
+    fn test_fingerprints() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let plist = dir.join("com.example.app.plist");
+        let write = |autohide: bool, other: i64| {
+            Value::Dictionary(Dictionary::from_iter([("autohide", Value::from(autohide)), ("other", Value::from(other))])).to_file_binary(&plist)
+        };
+
+        write(true, 1)?;
+        let fingerprint = PlistFingerprint::take("com.example.app".to_owned(), &plist, vec!["autohide".to_owned()])?;
+        assert!(!fingerprint.changed(&plist)?);
+
+        // Keys the YAML file doesn't set don't count.
+        write(true, 2)?;
+        assert!(!fingerprint.changed(&plist)?);
+
+        write(false, 2)?;
+        assert!(fingerprint.changed(&plist)?);
+
+        let mut fingerprints = Fingerprints::default();
+        fingerprints.record(&dir.join("app.yaml"), BTreeMap::from([(plist.clone(), fingerprint.clone())]));
+        fingerprints.save_to(&dir.join("fingerprints.plist"))?;
+
+        let fingerprints = Fingerprints::load_from(&dir.join("fingerprints.plist"))?;
+        assert_eq!(fingerprints.get(&dir.join("app.yaml")), Some(&BTreeMap::from([(plist, fingerprint)])));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,35 @@
+//! The globs key and domain patterns are written with, e.g. in `dump --keys`, `drift_ignore` and
+//! domain names like `com.apple.*`.
+
+/// Match `text` against a glob where `*` matches any characters (including the `.` between nested
+/// keys, as keys can contain dots too) and `?` any one character. With `partial`, also match if
+/// `text` is the start of something the glob matches.
+pub fn glob_matches(pattern: &str, text: &str, partial: bool) -> bool {
+    fn matches(pattern: &[char], text: &[char], partial: bool) -> bool {
+        match (pattern.split_first(), text.split_first()) {
+            (None, None) => true,
+            (None, Some(_)) => false,
+            (Some(_), None) => partial || pattern.iter().all(|&c| c == '*'),
+            (Some(('*', rest)), Some((_, text_rest))) => matches(rest, text, partial) || matches(pattern, text_rest, partial),
+            (Some(('?', rest)), Some((_, text_rest))) => matches(rest, text_rest, partial),
+            (Some((p, rest)), Some((t, text_rest))) => p == t && matches(rest, text_rest, partial),
+        }
+    }
+
+    matches(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>(), partial)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("autohide*", "autohide-delay", false));
+        assert!(glob_matches("NSWindow Frame ?", "NSWindow Frame A", false));
+        assert!(glob_matches("*Timestamp*", "Stats.LastTimestamp", false));
+        assert!(!glob_matches("autohide", "autohide-delay", false));
+        assert!(glob_matches("Desktop.Icon*.size", "Desktop.", true));
+        assert!(!glob_matches("Desktop.Icon*.size", "Other.", true));
+    }
+}
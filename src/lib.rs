@@ -0,0 +1,45 @@
+//! Declarative macOS defaults, applied from YAML files.
+//!
+//! The `macos-defaults` binary is a CLI over this library. Other tools can embed it, e.g. to
+//! apply YAML files with [`cmd::apply_files`], follow along with an [`observer::ApplyObserver`],
+//! or add YAML tags with a [`transformers::Transformer`].
+
+// stdout is for the data and reports commands output, so it can be piped into files and other
+// tools; logs and other diagnostics go to stderr. Functions printing a command's output allow
+// `print_stdout` explicitly.
+#![deny(clippy::all, clippy::pedantic, clippy::unwrap_used, clippy::print_stdout)]
+#![allow(clippy::module_name_repetitions, clippy::missing_errors_doc, clippy::missing_panics_doc)]
+// The modules were written for the binary; don't churn every accessor for the library target.
+#![allow(clippy::must_use_candidate, clippy::implicit_hasher)]
+
+pub mod ansible;
+pub mod backend;
+pub mod catalog;
+pub mod changelog;
+pub mod cmd;
+pub mod config;
+pub mod defaults;
+pub mod domain;
+pub mod environment;
+pub mod errors;
+pub mod fingerprints;
+pub mod glob;
+pub mod managed;
+pub mod manifest;
+pub mod nix;
+pub mod notify;
+pub mod observer;
+pub mod phases;
+pub mod planner;
+pub mod platform;
+pub mod presets;
+pub mod schedule;
+pub mod secrets;
+pub mod state_cache;
+pub mod tags;
+pub mod transaction;
+pub mod transformers;
+pub mod tree;
+pub mod validate;
+pub mod volatile;
+pub mod yaml_merge;
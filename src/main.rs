@@ -1,4 +1,7 @@
-#![deny(clippy::all, clippy::pedantic, clippy::unwrap_used)]
+// stdout is for the data and reports commands output, so it can be piped into files and other
+// tools; logs and other diagnostics go to stderr. Functions printing a command's output allow
+// `print_stdout` explicitly.
+#![deny(clippy::all, clippy::pedantic, clippy::unwrap_used, clippy::print_stdout)]
 #![allow(
     clippy::module_name_repetitions,
     clippy::missing_errors_doc,
@@ -7,30 +10,58 @@
     // Ignore clippy for the generated file from shadow-rs.
     // https://github.com/baoyachi/shadow-rs/issues/151
     clippy::non_ascii_literal,
-    clippy::print_stdout,
     clippy::needless_raw_strings,
     clippy::needless_raw_string_hashes
 )]
 
-use std::fs;
-use std::io;
+use std::cell::RefCell;
+use std::io::{self, IsTerminal};
+use std::rc::Rc;
 
 use camino::Utf8PathBuf;
+use clap::builder::PossibleValuesParser;
 use clap::crate_authors;
-use clap::{ArgGroup, CommandFactory, Parser, Subcommand, ValueHint};
-use clap_complete::{generate, Shell as CompletionShell};
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand, ValueEnum, ValueHint};
+use clap_complete::Shell as CompletionShell;
+use clap_verbosity_flag::Level;
 use color_eyre::eyre::Result;
+use colored::Colorize;
+use itertools::Itertools;
 use shadow_rs::shadow;
+use tracing::{info, info_span, warn};
+use tracing_subscriber::filter::LevelFilter as TracingLevelFilter;
+use uuid::Uuid;
 
 // https://crates.io/crates/shadow-rs
 shadow!(build);
 
-mod cmd;
-mod defaults;
-mod errors;
-
-use self::cmd::{apply_defaults, dump, process_path};
-use crate::errors::DefaultsError as E;
+use macos_defaults::ansible::AnsibleReport;
+use macos_defaults::backend::{Backend, DryRunBackend, PrefsBackend};
+use macos_defaults::cmd::apply::STDIN_PATH;
+use macos_defaults::cmd::fingerprint::refresh_fingerprints;
+use macos_defaults::cmd::restart::kill_processes;
+use macos_defaults::cmd::{
+    apply_files, apply_preset, assert_idempotent, complete, completions, convert, diff, docs, doctor, dump, explain, export_profile, find, fingerprint, graph,
+    init, install_agent, migrate_byhost, plan, preset_list, preset_show, process_path, restart, restart_processes, schema, status, teardown, uninstall_agent,
+    upgrade_impact, ApplyOptions, ApplySummary, CompletionKind, DiffFormat, DumpFormat, DumpOptions, GraphFormat, LargeValues, OutputFormat, PlanFormat,
+    PlistFormat, ReportFormat,
+};
+use macos_defaults::config::Config;
+use macos_defaults::defaults::{
+    add_containers, log_container_cache_stats, prefs_root, prefs_user, remove_simulated_home, set_prefs_root, set_simulated, set_target_root, set_user,
+    target_path, target_root, Symlinks, Verify, WriteFormat, WriteOptions, XmlFormat, XmlIndent,
+};
+use macos_defaults::environment::Environment;
+use macos_defaults::errors::{DefaultsError as E, ExitCode};
+use macos_defaults::manifest::Manifest;
+use macos_defaults::notify::notify_apply;
+use macos_defaults::observer::{AnsibleObserver, ApplyObserver, ConsoleObserver, JsonObserver, ProgressObserver};
+use macos_defaults::phases::{finish_phase, phases_to_apply, NextPhase, Phases};
+use macos_defaults::platform::check_platform;
+use macos_defaults::presets::{find_preset, preset_names, BundledPreset};
+use macos_defaults::state_cache::StateCache;
+use macos_defaults::transaction::Transaction;
+use macos_defaults::volatile::DriftIgnore;
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -41,32 +72,75 @@ use crate::errors::DefaultsError as E;
     subcommand_required=true,
     arg_required_else_help=true,
 )]
-#[allow(clippy::upper_case_acronyms)]
+#[allow(clippy::upper_case_acronyms, clippy::struct_excessive_bools)]
 struct CLI {
     /// Don’t actually run anything.
     #[arg(short, long)]
     dry_run: bool,
 
+    /// Treat warnings (e.g. a missing plist file, binary data in a dump) as errors.
+    #[arg(long)]
+    strict: bool,
+
+    /// Config file with persistent options [default: ~/.config/macos-defaults/config.yaml].
+    #[arg(long, value_hint = ValueHint::FilePath)]
+    config: Option<Utf8PathBuf>,
+
+    /// Resolve domains under this directory instead of your home directory, e.g. another user's
+    /// home or an iOS Simulator's `data` directory.
+    #[arg(long, value_hint = ValueHint::DirPath)]
+    prefs_root: Option<Utf8PathBuf>,
+
+    /// Change another user's preferences, as root: resolve domains under their home directory,
+    /// give them ownership of the files written, and only restart their processes.
+    #[arg(long, value_name = "NAME", conflicts_with = "prefs_root")]
+    user: Option<String>,
+
+    /// Run even if this isn't a supported macOS version, or isn't macOS at all.
+    #[arg(long)]
+    force: bool,
+
+    /// Don't check at startup that this is a supported macOS version, as `version_check: false`
+    /// in the config file does.
+    #[arg(long)]
+    no_version_check: bool,
+
+    /// Pretend to be a Mac, e.g. to plan YAML files in CI on Linux: resolve domains under an empty
+    /// home directory (or `--prefs-root`) with a fake hardware UUID. Only `plan`, `diff` and
+    /// commands that don't use preferences can run.
+    #[arg(long, conflicts_with = "user")]
+    simulate: bool,
+
+    /// Resolve every path under this directory as if it were `/`, e.g. to run complete applies in CI
+    /// on any OS and check the plists written: your home directory, `/Library` and
+    /// `/Applications`. `ByHost` plists use a fake hardware UUID, and no processes are restarted.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath, conflicts_with_all = ["prefs_root", "user", "simulate"])]
+    target_root: Option<Utf8PathBuf>,
+
     #[clap(flatten)]
     verbose: clap_verbosity_flag::Verbosity,
 
+    /// Format of the log output on stderr.
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
     /// Clap subcommand to run.
     #[clap(subcommand)]
     command: Commands,
 }
 
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, including the file/document/domain/key spans.
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub(crate) enum Commands {
     /// Set macOS defaults in plist files.
-    Apply {
-        /// Sets the input file or path to use.
-        #[arg(required = true, value_hint = ValueHint::FilePath)]
-        path: Utf8PathBuf,
-
-        /// If changes were applied, exit with this return code.
-        #[clap(short, long, default_value = "0")]
-        exit_code: i32,
-    },
+    Apply(ApplyArgs),
 
     /// Generate shell completions to stdout.
     Completions {
@@ -74,28 +148,541 @@ pub(crate) enum Commands {
         shell: CompletionShell,
     },
 
+    /// List installed domains or configured YAML files, for the completion scripts.
+    #[command(hide = true)]
+    Complete {
+        #[arg(value_enum)]
+        kind: CompletionKind,
+    },
+
+    /// Generate Markdown documentation of the defaults in a YAML file or directory.
+    Docs {
+        /// YAML file or directory of YAML files to document.
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+
+        /// Write the Markdown to this file instead of stdout.
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<Utf8PathBuf>,
+    },
+
+    /// Convert YAML files into a configuration profile of managed preferences, to deploy the same
+    /// settings with MDM. `!once` keys are set once, the rest are enforced.
+    ExportProfile {
+        /// YAML file or directory of YAML files to convert.
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+
+        /// Write the profile to this file, e.g. `settings.mobileconfig`, instead of stdout.
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Option<Utf8PathBuf>,
+
+        /// Identifier of the profile, which its payloads' identifiers are derived from.
+        #[arg(long, default_value = "local.macos-defaults")]
+        identifier: String,
+
+        /// Name of the profile, as shown in System Settings.
+        #[arg(long, default_value = "macOS Defaults")]
+        name: String,
+
+        /// When the `!once` settings were set, e.g. `2024-06-01T00:00:00Z`. MDM sets them again
+        /// when it changes. Defaults to when the newest of the files was modified, so exporting
+        /// the same files again gives the same profile.
+        #[arg(long, value_parser = parse_date)]
+        set_once_timestamp: Option<plist::Date>,
+    },
+
+    /// Render how the files, documents, domains and kill targets of a YAML tree relate, and the
+    /// order documents are applied in.
+    Graph {
+        /// YAML file or directory of YAML files to render.
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        format: GraphFormat,
+    },
+
+    /// Check for common problems with preference files.
+    Doctor {
+        /// Change the owner of root-owned user preference plists back to the user (uses sudo).
+        #[arg(long)]
+        fix_ownership: bool,
+    },
+
     /// Dump existing defaults as YAML.
-    #[clap(group(
-    ArgGroup::new("dump")
-        .required(true)
-        .args(&["domain", "global_domain"]),
-    ))]
-    Dump {
-        /// Read from the current host.
+    Dump(DumpArgs),
+
+    /// Convert between plist, YAML and JSON files, by their extensions (`.plist`, `.yaml` or
+    /// `.yml`, and `.json`).
+    Convert {
+        /// File to convert.
+        #[arg(value_hint = ValueHint::FilePath)]
+        input: Utf8PathBuf,
+
+        /// File to write.
+        #[arg(short, long, value_hint = ValueHint::FilePath)]
+        output: Utf8PathBuf,
+
+        /// Format of a `.plist` output.
+        #[arg(long, value_enum, default_value_t = PlistFormat::Binary)]
+        plist_format: PlistFormat,
+    },
+
+    /// Describe well known defaults from the built-in catalog.
+    Explain {
+        /// Domain to describe, e.g. `com.apple.dock`.
+        domain: String,
+
+        /// Key to describe, or all known keys in the domain if not given.
+        key: Option<String>,
+    },
+
+    /// Find keys or string values containing a word across all domains.
+    Find {
+        /// Search the current host (`ByHost`) domains.
         #[arg(short, long)]
         current_host: bool,
 
-        /// Read from the global domain.
-        #[clap(short, long)]
-        global_domain: bool,
+        /// Case insensitive word to search for.
+        needle: String,
+    },
+
+    /// Record a hash of the plists YAML files set, and of the keys they set, for a quick
+    /// `status --fast` check of whether anything changed out-of-band since. Run it after `apply`.
+    Fingerprint {
+        /// YAML file or directory of YAML files to fingerprint.
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+    },
 
-        /// Domain to generate.
-        #[clap(short, long)]
-        domain: Option<String>,
+    /// Move `ByHost` plists from an old hardware UUID (e.g. before a logic board replacement or
+    /// migration) to this Mac's, merging into any plist it already has.
+    MigrateByhost {
+        /// The old hardware UUID in the `ByHost` plist file names.
+        #[arg(long)]
+        from: String,
 
-        /// Path to YAML file for dump output.
-        #[arg(value_hint = ValueHint::FilePath)]
+        /// Only print what would be renamed or merged.
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Create a directory of starter YAML files from a bundled preset, commented from the built-in catalog.
+    Init {
+        /// Which bundled preset to start from, see `preset list`.
+        #[arg(long, default_value = "minimal", value_parser = PossibleValuesParser::new(preset_names()))]
+        preset: String,
+
+        /// Overwrite existing files.
+        #[arg(long)]
+        force: bool,
+
+        /// Directory to create the YAML files in.
+        #[arg(default_value = "macos-defaults", value_hint = ValueHint::DirPath)]
+        path: Utf8PathBuf,
+    },
+
+    /// Install a launch agent that runs `apply` at login, to keep this machine converged.
+    InstallAgent {
+        /// Also run `apply` every this many seconds.
+        #[arg(long, value_name = "SECONDS")]
+        interval: Option<u64>,
+
+        /// Post a notification when each apply finishes, as `apply --notify` does.
+        #[arg(long)]
+        notify: bool,
+
+        /// YAML file or directory of YAML files to apply. Defaults to the `paths` in the config file.
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Option<Utf8PathBuf>,
+    },
+
+    /// Unload and remove the launch agent installed by `install-agent`.
+    UninstallAgent,
+
+    /// Print what `apply` would change, as YAML or JSON, a tree of the changes, or an Ansible
+    /// module result in check mode, without changing anything.
+    Plan {
+        /// YAML file or directory of YAML files, or `-` for stdin. Defaults to the `paths` in the config file.
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Option<Utf8PathBuf>,
+
+        /// Plan a bundled preset before the YAML files, as `apply --preset` does. May be repeated.
+        #[arg(long, value_parser = PossibleValuesParser::new(preset_names()))]
+        preset: Vec<String>,
+
+        /// Include writes to unsandboxed plists, as `apply --also-write-unsandboxed` does.
+        #[arg(long)]
+        also_write_unsandboxed: bool,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = PlanFormat::Yaml)]
+        format: PlanFormat,
+    },
+
+    /// Print each key `apply` would add, replace or remove, down to nested keys, as a list of JSON
+    /// Patch-like operations for other tools to consume.
+    Diff {
+        /// YAML file or directory of YAML files, or `-` for stdin. Defaults to the `paths` in the config file.
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Option<Utf8PathBuf>,
+
+        /// Diff a bundled preset before the YAML files, as `apply --preset` does. May be repeated.
+        #[arg(long, value_parser = PossibleValuesParser::new(preset_names()))]
+        preset: Vec<String>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = DiffFormat::Yaml)]
+        format: DiffFormat,
+    },
+
+    /// Report behavior changes since an earlier version of this tool that affect your YAML files.
+    UpgradeImpact {
+        /// The version you're upgrading from, e.g. `0.2.0`.
+        #[arg(long, value_name = "VERSION")]
+        from: String,
+
+        /// YAML file or directory of YAML files. Defaults to the `paths` in the config file.
+        #[arg(value_hint = ValueHint::AnyPath)]
         path: Option<Utf8PathBuf>,
+
+        /// Output format.
+        #[arg(long, value_enum, default_value_t = OutputFormat::Yaml)]
+        format: OutputFormat,
+    },
+
+    /// List or show the bundled presets that `apply --preset` can layer under your YAML files.
+    Preset {
+        #[clap(subcommand)]
+        command: PresetCommand,
+    },
+
+    /// Print a JSON Schema of the YAML files, for editors to complete and validate them. YAML tags
+    /// are listed in `x-yaml-custom-tags`, for the YAML language server's `yaml.customTags`.
+    Schema,
+
+    /// Restart processes so they pick up changed preferences, e.g. after editing a plist by hand.
+    ///
+    /// Matches processes by exact name, or by the bundle identifier of the app they run from.
+    Restart {
+        /// Process names (e.g. `Dock`, `cfprefsd`), bundle identifiers (e.g. `com.apple.finder`), or
+        /// `launchd` jobs to restart with `launchctl kickstart -k`, by process name or label (e.g.
+        /// `service:SystemUIServer`).
+        #[arg(required = true)]
+        targets: Vec<String>,
+    },
+
+    /// Show which YAML files are out of sync with the current defaults, without changing anything.
+    Status {
+        /// YAML file or directory of YAML files to check.
+        #[arg(required = true, value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+
+        /// Only check whether the keys the files set changed since `fingerprint` was run, without
+        /// reading the YAML files. Plists whose files haven't changed at all aren't even parsed.
+        #[arg(long)]
+        fast: bool,
+
+        /// How plists are read: from the plist file, or through the `CFPreferences` API, which
+        /// includes changes cfprefsd hasn't written to the file yet.
+        #[arg(long, value_enum, default_value_t = Backend::File)]
+        backend: Backend,
+    },
+
+    /// Revert everything a YAML file or directory ever set, from the keys `apply` has recorded:
+    /// keys get back the value they had before, or are removed if they didn't exist.
+    Teardown {
+        /// YAML file or directory of YAML files to revert. They don't have to exist anymore.
+        #[arg(value_hint = ValueHint::AnyPath)]
+        path: Utf8PathBuf,
+
+        /// Print what would be reverted, without changing anything.
+        #[arg(long)]
+        dry_run: bool,
+
+        /// How reverted keys are written, as for `apply`.
+        #[arg(long, value_enum, default_value_t = Backend::File)]
+        backend: Backend,
+    },
+}
+
+#[derive(Debug, Args)]
+#[clap(group(
+    ArgGroup::new("dump")
+        .required(true)
+        .multiple(true)
+        .args(&["domain", "global_domain", "all"]),
+))]
+#[allow(clippy::struct_excessive_bools)]
+pub(crate) struct DumpArgs {
+    /// Read from the current host.
+    #[arg(short, long)]
+    current_host: bool,
+
+    /// Read from the global domain.
+    #[clap(short, long)]
+    global_domain: bool,
+
+    /// Domain to generate. May be repeated, to dump a YAML document per domain.
+    #[clap(short, long)]
+    domain: Vec<String>,
+
+    /// Add a process to the `kill` list of the generated document. May be repeated.
+    #[arg(long = "with-kill", value_name = "PROCESS")]
+    kill: Vec<String>,
+
+    /// Description of the generated document, defaults to the domain. Only for a single domain.
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Print the resolved plist path for the domain instead of dumping it.
+    #[arg(long)]
+    show_path: bool,
+
+    /// Sort the array at this key path (e.g. `persistent-others`) into a stable order. May be repeated.
+    #[arg(long = "sort-array", value_name = "KEY_PATH")]
+    sort_arrays: Vec<String>,
+
+    /// Handle binary data values larger than this many bytes according to `--large-values`.
+    #[arg(long, value_name = "BYTES")]
+    max_value_size: Option<usize>,
+
+    /// What to do with values larger than `--max-value-size`.
+    #[arg(long, value_enum, default_value_t = LargeValues::Skip, requires = "max_value_size")]
+    large_values: LargeValues,
+
+    /// Only dump keys whose key path matches this glob, e.g. `autohide*` or
+    /// `DesktopViewSettings.IconViewSettings.*`. `*` also matches the `.` between nested keys. May be repeated.
+    #[arg(long = "keys", value_name = "GLOB")]
+    keys: Vec<String>,
+
+    /// Leave out keys whose key path matches this glob, e.g. `NSWindow Frame *` or `*Timestamp*`. May be repeated.
+    #[arg(long = "exclude-keys", value_name = "GLOB")]
+    exclude_keys: Vec<String>,
+
+    /// Dump as YAML, or copy the domain's plist as a binary or XML `.plist` file, which `apply`
+    /// accepts in place of YAML when a setting doesn't survive conversion, or as a Nix attribute
+    /// set for home-manager's `targets.darwin.defaults`.
+    #[arg(long, value_enum, default_value_t = DumpFormat::Yaml)]
+    format: DumpFormat,
+
+    /// Path to YAML file for dump output.
+    #[arg(value_hint = ValueHint::FilePath, conflicts_with = "output_dir")]
+    path: Option<Utf8PathBuf>,
+
+    /// Dump every installed domain. Domains that can't be dumped are skipped with a warning.
+    #[arg(long, conflicts_with_all = ["domain", "global_domain"])]
+    all: bool,
+
+    /// Write each domain to its own file in this directory, named by `--file-name`.
+    #[arg(long, value_name = "DIR", value_hint = ValueHint::DirPath)]
+    output_dir: Option<Utf8PathBuf>,
+
+    /// File name template for `--output-dir`: `{domain}` is replaced by the domain, and `{vendor}`
+    /// by `apple` or `third-party`, e.g. `{vendor}/{domain}.yaml` to group Apple's domains
+    /// [default: `{domain}.yaml`, or `{domain}.plist` for `--format plist` and `xml`].
+    #[arg(long, value_name = "TEMPLATE", requires = "output_dir")]
+    file_name: Option<String>,
+
+    /// Sort the keys of nested dictionaries too, not only the top level ones, so dumps are stable
+    /// and diff cleanly. Arrays keep their order.
+    #[arg(long)]
+    sorted_nested: bool,
+
+    /// Update the dumped domains in an existing YAML file (or the files in `--output-dir`), keeping
+    /// its comments, key order, quoting and other documents. Unchanged keys are left as written.
+    #[arg(long)]
+    merge: bool,
+}
+
+#[derive(Debug, Args)]
+#[allow(clippy::struct_excessive_bools)]
+pub(crate) struct ApplyArgs {
+    /// Sets the input file or path to use, or `-` to read YAML from stdin. Raw `.plist` files
+    /// replace the whole plist of the domain they're named after.
+    /// Defaults to the `phases`, or else the `paths`, in the config file.
+    #[arg(value_hint = ValueHint::FilePath)]
+    path: Option<Utf8PathBuf>,
+
+    /// Resume the config file's `phases` from the phase the last apply stopped before, after the
+    /// logout or restart it asked for.
+    #[arg(long = "continue", conflicts_with = "path")]
+    resume: bool,
+
+    /// Read the YAML document stream from stdin, e.g. a here-doc in a provisioning script, as the
+    /// path `-` does.
+    #[arg(long, conflicts_with_all = ["path", "resume"])]
+    stdin: bool,
+
+    /// If changes were applied, exit with this return code [default: 0].
+    ///
+    /// Other exit codes: 0 = nothing changed, 1 = failure, 3 = YAML parse error,
+    /// 4 = permission denied, 5 = partial success (some domains failed),
+    /// 6 = nothing changed with `--detailed-exit-codes`.
+    #[clap(short, long)]
+    exit_code: Option<i32>,
+
+    /// Exit with 6 if nothing changed, so it can be told apart from changes applied without `--exit-code`.
+    #[arg(long)]
+    detailed_exit_codes: bool,
+
+    /// Number of older valid plist backups to keep (`.prev.1`, `.prev.2`, ...) besides `.prev` [default: 1].
+    #[arg(long)]
+    backup_depth: Option<usize>,
+
+    /// Don't back up plists before changing them.
+    #[arg(long, conflicts_with = "backup_depth")]
+    no_backup: bool,
+
+    /// When a domain has both a container plist and one in ~/Library/Preferences, write to both.
+    #[arg(long)]
+    also_write_unsandboxed: bool,
+
+    /// Re-read each plist after writing it, and fail if the changed values didn't persist.
+    /// `--verify=defaults` also reads the values back through `defaults export`.
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_missing_value = "file")]
+    verify: Option<Verify>,
+
+    /// When changed values didn't persist after writing a plist, e.g. because cfprefsd rewrote it
+    /// from its cache, restart the user's cfprefsd and write it again, up to this many times,
+    /// waiting longer each time. Implies `--verify`.
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    write_retries: u32,
+
+    /// Indentation of rewritten XML plists: `tab`, or a number of spaces.
+    #[arg(long, default_value = "tab")]
+    xml_indent: XmlIndent,
+
+    /// End rewritten XML plists with a newline, as `plutil` does.
+    #[arg(long)]
+    xml_trailing_newline: bool,
+
+    /// Leave the `<!DOCTYPE ...>` declaration out of rewritten XML plists.
+    #[arg(long)]
+    xml_no_doctype: bool,
+
+    /// Format of plists created by the `file` backend: `auto` creates binary plists as macOS does,
+    /// or `xml` for plists kept in git [default: `auto`, or `write_format` in the config file].
+    #[arg(long, value_enum)]
+    write_format: Option<WriteFormat>,
+
+    /// Also convert existing plists to `--write-format` when they're written, rather than keeping
+    /// the format they're in.
+    #[arg(long)]
+    convert_format: bool,
+
+    /// What to do when a plist to change is a symlink, e.g. into a dotfiles repository.
+    #[arg(long, value_enum, default_value = "follow")]
+    symlinks: Symlinks,
+
+    /// Apply a bundled preset (see `preset list`) before the YAML files, which can override its values.
+    /// May be repeated.
+    #[arg(long, value_parser = PossibleValuesParser::new(preset_names()))]
+    preset: Vec<String>,
+
+    /// Don't show a progress bar. It's only shown when stdout is a terminal and logs are text.
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Print a machine-readable result on stdout instead of the console output, e.g. the JSON of
+    /// an Ansible module.
+    #[arg(long, value_enum)]
+    report: Option<ReportFormat>,
+
+    /// Before applying, plan the apply twice in memory, the second time against the result of the
+    /// first, and fail without changing anything if the second would still change keys.
+    /// Catches values like `!uuid` and `!now` that change on every apply.
+    #[arg(long)]
+    assert_idempotent: bool,
+
+    /// Fail on domains that don't have a plist yet, suggesting similarly named installed domains,
+    /// rather than creating one, e.g. for a typo like `com.apple.finderr`. Documents with
+    /// `create: true` can still create them.
+    #[arg(long)]
+    no_create: bool,
+
+    /// Leave out keys that a configuration profile manages, which writing has no visible effect
+    /// on, rather than only warning about them.
+    #[arg(long)]
+    skip_managed: bool,
+
+    /// For `current_host` documents, merge into the values macOS reads (the per-host plist's over
+    /// the any-host plist's), so keys already set for every host aren't copied per host.
+    #[arg(long)]
+    layered_read: bool,
+
+    /// Carry on applying the other domains and documents when one fails, e.g. on a corrupt plist,
+    /// and list every failure with what to do about it at the end.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Skip domains whose YAML and plists haven't changed since they were last applied, without
+    /// reading their plists, from a cache of their plists' modification times and sizes. Documents
+    /// with YAML tags like `!file` are always applied.
+    #[arg(long)]
+    lazy_read: bool,
+
+    /// Keep every plist as it was before the run's first write to it, and if a domain fails, put
+    /// them all back and restart the processes of the documents that changed anything, leaving
+    /// the preferences as they were before the run.
+    #[arg(long, conflicts_with = "keep_going")]
+    transactional: bool,
+
+    /// Move corrupt plists aside (as `<name>.corrupt-<unix time>`) and recreate them from the
+    /// YAML alone, rather than failing their domains.
+    #[arg(long)]
+    repair: bool,
+
+    /// Post a notification of how many settings changed, or of the error, once the apply
+    /// finishes, e.g. when the launch agent runs it in the background.
+    #[arg(long)]
+    notify: bool,
+
+    /// How changed keys are written: by rewriting the plist file, with `defaults write` per key, or
+    /// through the `CFPreferences` API.
+    #[arg(long, value_enum, default_value_t = Backend::File)]
+    backend: Backend,
+}
+
+impl Commands {
+    /// Whether the command reads or writes this machine's preferences, and so needs a supported
+    /// macOS version.
+    const fn uses_preferences(&self) -> bool {
+        !matches!(
+            self,
+            Self::Complete { .. }
+                | Self::Completions { .. }
+                | Self::Convert { .. }
+                | Self::Docs { .. }
+                | Self::Explain { .. }
+                | Self::ExportProfile { .. }
+                | Self::Graph { .. }
+                | Self::Init { .. }
+                | Self::Preset { .. }
+                | Self::Schema
+                | Self::UpgradeImpact { .. }
+        )
+    }
+
+    /// Whether the command can run with `--simulate`: it only reads preferences, to compare them
+    /// with YAML files, or doesn't use them at all.
+    const fn simulatable(&self) -> bool {
+        matches!(self, Self::Plan { .. } | Self::Diff { .. }) || !self.uses_preferences()
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum PresetCommand {
+    /// List the bundled presets with their versions.
+    List,
+
+    /// Print a bundled preset's YAML.
+    Show {
+        /// Name of the preset.
+        #[arg(value_parser = PossibleValuesParser::new(preset_names()))]
+        name: String,
     },
 }
 
@@ -104,34 +691,708 @@ fn main() -> Result<()> {
 
     let cli = CLI::parse();
 
-    env_logger::Builder::new().filter_level(cli.verbose.log_level_filter()).init();
+    let mut config = Config::load(cli.config.as_deref())?;
+
+    let log_level = log_level(config.log_level.map_or(TracingLevelFilter::ERROR, Into::into), cli.verbose.log_level());
+
+    init_logging(log_level, cli.log_format);
+
+    // Identifies this run in JSON logs and apply's summary, to correlate overlapping runs.
+    let run_id = Uuid::new_v4();
+    let _run_span = matches!(cli.log_format, LogFormat::Json).then(|| info_span!("run", id = %run_id).entered());
+
+    check_environment(&cli, &config, log_level)?;
+
+    if let Some(target_root) = &cli.target_root {
+        set_target_root(target_root)?;
 
-    match cli.command {
-        Commands::Apply { path, exit_code } => {
-            //
-            let mut changed = false;
+        // The config file is read before there's a target root, so its `~` is the real home.
+        config.backup_dir = config.backup_dir.as_deref().map(target_path);
+    } else if cli.simulate {
+        set_simulated(cli.prefs_root)?;
+    } else if let Some(prefs_root) = cli.prefs_root {
+        set_prefs_root(prefs_root)?;
+    }
 
-            for p in process_path(path)? {
-                fs::metadata(&p).map_err(|e| E::FileRead { path: p.clone(), source: e })?;
+    if let Some(user) = &cli.user {
+        set_user(user)?;
+    }
 
-                if apply_defaults(&p)? {
-                    changed = true;
-                }
-            }
+    add_containers(&config.containers, &config.group_containers);
 
-            std::process::exit(if changed { exit_code } else { 0 });
+    let result = match cli.command {
+        Commands::Apply(args) => {
+            // At `-v` and up; the config file's log level doesn't count.
+            let console = ConsoleObserver {
+                verbose: cli.verbose.log_level() > Some(Level::Error),
+            };
+
+            run_apply(
+                args,
+                cli.strict,
+                cli.dry_run,
+                matches!(cli.log_format, LogFormat::Text),
+                console,
+                config,
+                run_id,
+            )
         }
-        Commands::Completions { shell } => {
-            generate(shell, &mut CLI::command(), "macos-defaults", &mut io::stdout().lock());
+        Commands::Complete { kind } => {
+            complete(kind, config.paths);
             Ok(())
         }
-        Commands::Dump {
-            current_host,
+        Commands::Completions { shell } => completions(shell, &mut CLI::command()),
+        Commands::Docs { path, output } => docs(path, output),
+        Commands::Doctor { fix_ownership } => doctor(fix_ownership),
+        Commands::Dump(args) => run_dump(args, cli.strict),
+        Commands::Convert { input, output, plist_format } => convert(&input, &output, plist_format, cli.strict),
+        Commands::Explain { domain, key } => explain(&domain, key.as_deref()),
+        Commands::ExportProfile {
+            path,
+            output,
+            identifier,
+            name,
+            set_once_timestamp,
+        } => export_profile(path, output, &identifier, &name, set_once_timestamp),
+        Commands::Graph { path, format } => graph(path, format),
+        Commands::Find { current_host, needle } => find(&needle, current_host),
+        Commands::Fingerprint { path } => fingerprint(path),
+        Commands::MigrateByhost { from, dry_run } => migrate_byhost(&from, dry_run),
+        Commands::Restart { targets } => restart(&targets),
+        Commands::Schema => schema(),
+        Commands::Init { preset, force, path } => init(&path, &preset, force),
+        Commands::InstallAgent { interval, notify, path } => install_agent(path.as_deref(), cli.config.as_deref(), interval, notify),
+        Commands::UninstallAgent => uninstall_agent(),
+        Commands::Plan {
             path,
-            global_domain,
-            domain,
-        } => dump(current_host, path, global_domain, domain),
-    }?;
+            preset,
+            also_write_unsandboxed,
+            format,
+        } => run_plan(path, &preset, also_write_unsandboxed, format, config),
+        Commands::Diff { path, preset, format } => run_diff(path, &preset, format, config),
+        Commands::Preset { command: PresetCommand::List } => {
+            preset_list();
+            Ok(())
+        }
+        Commands::Preset {
+            command: PresetCommand::Show { name },
+        } => preset_show(&name),
+        Commands::UpgradeImpact { from, path, format } => {
+            let (_, paths) = apply_sources(path, &[], config.paths)?;
+            let files = paths.into_iter().map(process_path).flatten_ok().collect::<Result<Vec<_>>>()?;
+
+            upgrade_impact(&from, &files, format)
+        }
+        Commands::Status { path, fast, backend } => {
+            prefs_backend(backend).and_then(|backend| status(path, &DriftIgnore::new(config.drift_ignore), fast, backend))
+        }
+        Commands::Teardown { path, dry_run, backend } => prefs_backend(backend).and_then(|backend| teardown(&path, dry_run, backend)),
+    };
+
+    log_container_cache_stats();
+    remove_simulated_home();
+    result?;
 
     std::process::exit(0);
 }
+
+/// Check this is a supported macOS, or a `--simulate` run of a command that allows it, for commands
+/// using its preferences. At the info level, also log what decides whether writes behave as
+/// expected, for making sense of the rest of the output (`doctor` prints it anyway).
+fn check_environment(cli: &CLI, config: &Config, log_level: TracingLevelFilter) -> Result<()> {
+    if !cli.command.uses_preferences() {
+        return Ok(());
+    }
+
+    if cli.simulate {
+        return if cli.command.simulatable() { Ok(()) } else { Err(E::NotSimulatable.into()) };
+    }
+
+    if checks_version(cli, config) {
+        check_platform(cli.force)?;
+    }
+
+    let doctor = matches!(cli.command, Commands::Doctor { .. });
+
+    if log_level >= TracingLevelFilter::INFO && !doctor {
+        for line in Environment::gather().to_string().lines() {
+            info!("{line}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether to check this is a supported macOS version, unless turned off with `--no-version-check`
+/// or the config file's `version_check: false`. Preferences under `--prefs-root` or `--target-root`
+/// don't depend on the OS this is running on.
+fn checks_version(cli: &CLI, config: &Config) -> bool {
+    cli.prefs_root.is_none() && cli.target_root.is_none() && !cli.no_version_check && config.version_check != Some(false)
+}
+
+/// `apply` command, merging its arguments with the config file.
+fn run_apply(args: ApplyArgs, strict: bool, dry_run: bool, text_logs: bool, console: ConsoleObserver, config: Config, run_id: Uuid) -> Result<()> {
+    let ApplyArgs {
+        path,
+        stdin,
+        exit_code,
+        detailed_exit_codes,
+        backup_depth,
+        no_backup,
+        also_write_unsandboxed,
+        verify,
+        write_retries,
+        xml_indent,
+        xml_trailing_newline,
+        xml_no_doctype,
+        write_format,
+        convert_format,
+        symlinks,
+        preset,
+        no_progress,
+        report,
+        assert_idempotent: check_idempotent,
+        resume,
+        no_create,
+        skip_managed,
+        layered_read,
+        keep_going,
+        lazy_read,
+        transactional,
+        repair,
+        notify,
+        backend,
+    } = args;
+
+    let backend = prefs_backend(backend)?;
+    let dry_run_backend = dry_run.then(|| Rc::new(DryRunBackend::default()));
+    let state_cache = load_state_cache(lazy_read, dry_run)?;
+    let transaction = start_transaction(transactional, dry_run, run_id)?;
+
+    let options = ApplyOptions {
+        write: WriteOptions {
+            strict,
+            backup: !no_backup && config.backup != Some(false),
+            backup_depth: backup_depth.or(config.backup_depth).unwrap_or(1),
+            backup_dir: config.backup_dir,
+            also_write_unsandboxed,
+            verify,
+            write_retries,
+            xml_format: XmlFormat {
+                indent: xml_indent,
+                trailing_newline: xml_trailing_newline,
+                doctype: !xml_no_doctype,
+            },
+            write_format: write_format.or(config.write_format).unwrap_or_default(),
+            convert_format,
+            symlinks,
+            layered_read,
+            repair,
+            backend: match &dry_run_backend {
+                Some(dry_run_backend) => dry_run_backend.clone(),
+                None => backend,
+            },
+            transaction,
+        },
+        kill: config.kill,
+        drift_ignore: DriftIgnore::new(config.drift_ignore),
+        ignore_keys: config.ignore_keys,
+        no_create,
+        skip_managed,
+        manifest: load_manifest(dry_run)?,
+        keep_going,
+        dry_run,
+        state_cache,
+    };
+    let exit_code = exit_code.or(config.exit_code).unwrap_or(ExitCode::Success as i32);
+
+    // Otherwise the apply would wait for YAML typed in.
+    if stdin && io::stdin().is_terminal() {
+        return Err(E::StdinIsTerminal.into());
+    }
+
+    let path = if stdin { Some(Utf8PathBuf::from(STDIN_PATH)) } else { path };
+
+    let output = ApplyOutput::new(report, no_progress, text_logs, console);
+    let mut summary = ApplySummary {
+        run_id,
+        ..ApplySummary::default()
+    };
+
+    let result = if resume || (path.is_none() && !config.phases.is_empty()) {
+        let presets = preset.iter().map(|name| find_preset(name)).collect::<Result<Vec<_>, _>>()?;
+        apply_phases(&config.phases, resume, &presets, &options, check_idempotent, output, &mut summary)
+    } else {
+        let (presets, paths) = apply_sources(path, &preset, config.paths)?;
+        apply_paths(&presets, paths, &options, check_idempotent, output, &mut summary)
+    };
+
+    finish_apply(&options, dry_run_backend.as_deref());
+
+    if notify {
+        notify_apply(&summary, result.as_ref().err());
+    }
+
+    if let Err(report) = result {
+        // Ansible only reads stdout, which is empty if the apply failed before it started.
+        if output == ApplyOutput::Ansible && !summary.reported {
+            OutputFormat::Json.print(&AnsibleReport::failure(&report.to_string())?)?;
+        }
+
+        eprintln!("Error in run {run_id}: {report:?}");
+        std::process::exit(ExitCode::from_report(&report, summary.domains_applied > 0) as i32);
+    }
+
+    std::process::exit(success_exit_code(summary.changed, exit_code, detailed_exit_codes));
+}
+
+/// The exit code of an apply that didn't fail: `exit_code` if anything changed, otherwise
+/// [`ExitCode::Unchanged`] with `--detailed-exit-codes`, or [`ExitCode::Success`].
+const fn success_exit_code(changed: bool, exit_code: i32, detailed: bool) -> i32 {
+    match (changed, detailed) {
+        (true, _) => exit_code,
+        (false, true) => ExitCode::Unchanged as i32,
+        (false, false) => ExitCode::Success as i32,
+    }
+}
+
+/// An RFC 3339 date in UTC, as plists store them.
+fn parse_date(date: &str) -> Result<plist::Date, String> {
+    plist::Date::from_xml_format(date).map_err(|_| format!("expected a date like 2024-06-01T00:00:00Z, not {date:?}"))
+}
+
+/// The backend reading and writing plists for `--backend`. Under `--target-root`, only plist files
+/// can be written, as the other backends change the running system's preferences.
+fn prefs_backend(backend: Backend) -> Result<Rc<dyn PrefsBackend>> {
+    if target_root().is_some() && backend != Backend::File {
+        return Err(E::TargetRootBackend.into());
+    }
+
+    Ok(backend.prefs_backend())
+}
+
+/// Save what the apply recorded for the next ones, and with `--dry-run`, say what it would have
+/// written.
+fn finish_apply(options: &ApplyOptions, dry_run_backend: Option<&DryRunBackend>) {
+    log_container_cache_stats();
+    remove_simulated_home();
+
+    // Rolled back writes are no longer there for teardown to revert.
+    let rolled_back = options.write.transaction.as_ref().is_some_and(|transaction| transaction.rolled_back());
+
+    if let Some(manifest) = options.manifest.as_ref().filter(|_| !rolled_back) {
+        if let Err(e) = manifest.borrow().save() {
+            warn!("Couldn't save the managed-keys manifest, so teardown won't revert this run's changes: {e}");
+        }
+    }
+
+    if let Some(state_cache) = &options.state_cache {
+        save_state_cache(state_cache);
+    }
+
+    if let Some(dry_run_backend) = dry_run_backend {
+        let written = dry_run_backend.written();
+        info!(
+            "Dry run, so nothing was written. {} plists would have been: {}",
+            written.len(),
+            written.iter().join(", ")
+        );
+    }
+}
+
+/// The managed-keys manifest to record the apply's changes in, unless it's a dry run, or writes
+/// preferences under `--prefs-root` or `--target-root` rather than this Mac's (or `--user`'s).
+fn load_manifest(dry_run: bool) -> Result<Option<RefCell<Manifest>>> {
+    let sandboxed = prefs_root().is_some() && prefs_user().is_none();
+
+    (!dry_run && !sandboxed)
+        .then(Manifest::load)
+        .transpose()
+        .map(|manifest| manifest.map(RefCell::new))
+}
+
+/// With `--lazy-read`, the cache of the domains in sync as of the last applies. A dry run doesn't
+/// use it, as it would record domains it didn't write as in sync.
+fn load_state_cache(lazy_read: bool, dry_run: bool) -> Result<Option<Rc<StateCache>>> {
+    (lazy_read && !dry_run).then(StateCache::load).transpose().map(|cache| cache.map(Rc::new))
+}
+
+/// Save the state cache once the apply finishes. Failing to only costs the next apply reading
+/// every domain, so it's a warning.
+fn save_state_cache(state_cache: &StateCache) {
+    if let Err(e) = state_cache.save() {
+        warn!("Couldn't save the state cache: {e}");
+    }
+}
+
+/// With `--transactional`, start keeping the plists the run writes, to roll them back.
+fn start_transaction(transactional: bool, dry_run: bool, run_id: Uuid) -> Result<Option<Rc<Transaction>>> {
+    (transactional && !dry_run)
+        .then(|| Transaction::new(run_id))
+        .transpose()
+        .map(|transaction| transaction.map(Rc::new))
+}
+
+/// `plan` command, with the config file's paths and kill targets.
+fn run_plan(path: Option<Utf8PathBuf>, preset: &[String], also_write_unsandboxed: bool, format: PlanFormat, config: Config) -> Result<()> {
+    let (presets, paths) = apply_sources(path, preset, config.paths)?;
+    let files = paths.into_iter().map(process_path).flatten_ok().collect::<Result<Vec<_>>>()?;
+
+    let options = ApplyOptions {
+        write: WriteOptions {
+            also_write_unsandboxed,
+            ..WriteOptions::default()
+        },
+        kill: config.kill,
+        ignore_keys: config.ignore_keys,
+        ..ApplyOptions::default()
+    };
+
+    plan(&presets, &files, &options, format)
+}
+
+/// `dump` command.
+fn run_dump(args: DumpArgs, strict: bool) -> Result<()> {
+    let DumpArgs {
+        current_host,
+        global_domain,
+        domain,
+        kill,
+        description,
+        show_path,
+        sort_arrays,
+        max_value_size,
+        large_values,
+        keys,
+        exclude_keys,
+        format,
+        path,
+        sorted_nested,
+        merge,
+        all,
+        output_dir,
+        file_name,
+    } = args;
+
+    dump(
+        global_domain,
+        domain,
+        path.as_deref(),
+        &DumpOptions {
+            current_host,
+            strict,
+            kill,
+            description,
+            show_path,
+            sort_arrays,
+            sorted_nested,
+            max_value_size,
+            large_values,
+            format,
+            keys,
+            exclude_keys,
+            merge,
+            all,
+            output_dir,
+            file_name,
+        },
+    )
+}
+
+/// `diff` command, with the config file's paths, kill targets and ignored keys.
+fn run_diff(path: Option<Utf8PathBuf>, preset: &[String], format: DiffFormat, config: Config) -> Result<()> {
+    let (presets, paths) = apply_sources(path, preset, config.paths)?;
+    let files = paths.into_iter().map(process_path).flatten_ok().collect::<Result<Vec<_>>>()?;
+
+    let options = ApplyOptions {
+        kill: config.kill,
+        drift_ignore: DriftIgnore::new(config.drift_ignore),
+        ignore_keys: config.ignore_keys,
+        ..ApplyOptions::default()
+    };
+
+    diff(&presets, &files, &options, format)
+}
+
+/// Apply the presets and then the paths.
+fn apply_paths(
+    presets: &[&BundledPreset],
+    paths: Vec<Utf8PathBuf>,
+    options: &ApplyOptions,
+    check_idempotent: bool,
+    output: ApplyOutput,
+    summary: &mut ApplySummary,
+) -> Result<()> {
+    let files = paths.into_iter().map(process_path).flatten_ok().collect::<Result<Vec<_>>>();
+
+    let observer: Box<dyn ApplyObserver> = match (&files, output) {
+        (_, ApplyOutput::Ansible) => Box::new(AnsibleObserver::default()),
+        (_, ApplyOutput::Json) => Box::new(JsonObserver),
+        (Ok(files), ApplyOutput::Progress(console)) => Box::new(ProgressObserver::new(presets.len() + files.len(), console)),
+        (_, ApplyOutput::Console(console) | ApplyOutput::Progress(console)) => Box::new(console),
+    };
+
+    let result = files.and_then(|files| {
+        if check_idempotent {
+            assert_idempotent(presets, &files, options)?;
+        }
+
+        for preset in presets {
+            apply_preset(preset, options, observer.as_ref(), summary)?;
+        }
+
+        apply_files(&files, options, observer.as_ref(), summary)?;
+        Ok(files)
+    });
+
+    let result = match &options.write.transaction {
+        Some(transaction) => finish_transaction(transaction, result),
+        None => result,
+    };
+
+    if let Err(error) = &result {
+        observer.on_error(error);
+    }
+
+    restart_processes(observer.as_ref(), summary);
+    observer.on_finish(summary);
+    summary.reported = true;
+
+    let files = result?;
+
+    if let Some(failures) = summary.failures() {
+        return Err(failures.into());
+    }
+
+    if !options.dry_run {
+        refresh_fingerprints(&files);
+    }
+
+    Ok(())
+}
+
+/// With `--transactional`, roll back the plists written if the apply failed, before the processes
+/// that would pick up the changes are restarted, and otherwise drop the copies kept of them.
+fn finish_transaction<T>(transaction: &Transaction, result: Result<T>) -> Result<T> {
+    let e = match result {
+        Ok(value) => {
+            transaction.discard();
+            return Ok(value);
+        }
+        Err(e) => e,
+    };
+
+    match transaction.rollback() {
+        Ok(0) => Err(e),
+        Ok(restored) => {
+            // So cfprefsd doesn't write its cached values back over the restored plists.
+            kill_processes("cfprefsd");
+            Err(e.wrap_err(format!("Rolled back the {restored} plists this run wrote")))
+        }
+        Err(rollback) => Err(e.wrap_err(format!("Rolling back the plists this run wrote failed: {rollback}"))),
+    }
+}
+
+/// Where `apply` reports what it's doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ApplyOutput {
+    Console(ConsoleObserver),
+    /// The console output below a progress bar.
+    Progress(ConsoleObserver),
+    Ansible,
+    Json,
+}
+
+impl ApplyOutput {
+    /// The `--report` format, or else the console output, with a progress bar if stderr is a
+    /// terminal showing text logs.
+    fn new(report: Option<ReportFormat>, no_progress: bool, text_logs: bool, console: ConsoleObserver) -> Self {
+        match report {
+            Some(ReportFormat::Ansible) => Self::Ansible,
+            Some(ReportFormat::Json) => Self::Json,
+            None if !no_progress && text_logs && io::stderr().is_terminal() => Self::Progress(console),
+            None => Self::Console(console),
+        }
+    }
+}
+
+/// Apply the config file's `phases` that [`phases_to_apply`] picks, in one go, and if that moves
+/// the bootstrap on, say what to do before the next one. Presets are applied first, unless
+/// resuming.
+#[allow(clippy::print_stdout)]
+fn apply_phases(
+    phases: &Phases,
+    resume: bool,
+    presets: &[&BundledPreset],
+    options: &ApplyOptions,
+    check_idempotent: bool,
+    output: ApplyOutput,
+    summary: &mut ApplySummary,
+) -> Result<()> {
+    let run = phases_to_apply(phases, resume)?;
+    let presets = if resume { &[] } else { presets };
+
+    let console = !matches!(output, ApplyOutput::Ansible | ApplyOutput::Json);
+
+    if console {
+        println!("{} Phase {}", "▶".green(), run.phases.iter().join(", ").bold().white());
+    }
+
+    let paths = run.phases.iter().filter_map(|phase| phases.get(phase)).flatten().cloned().collect();
+    apply_paths(presets, paths, options, check_idempotent, output, summary)?;
+
+    let Some(&last) = run.phases.last().filter(|_| run.advances) else {
+        return Ok(());
+    };
+
+    if let NextPhase::Checkpoint(next, checkpoint) = finish_phase(phases, last)? {
+        let next = format!("{checkpoint}, then run `macos-defaults apply --continue` to apply phase {next}.");
+
+        // Only the report goes to stdout.
+        if console {
+            println!("{next}");
+        } else {
+            warn!("{next}");
+        }
+    }
+
+    Ok(())
+}
+
+/// The presets and paths to apply: the path argument, or else the config file's `paths`.
+fn apply_sources(path: Option<Utf8PathBuf>, presets: &[String], config_paths: Vec<Utf8PathBuf>) -> Result<(Vec<&'static BundledPreset>, Vec<Utf8PathBuf>)> {
+    let presets = presets.iter().map(|name| find_preset(name)).collect::<Result<Vec<_>, _>>()?;
+
+    let paths = match path {
+        Some(path) => vec![path],
+        None if !config_paths.is_empty() => config_paths,
+        None if !presets.is_empty() => Vec::new(),
+        None => return Err(E::NoPathsToApply.into()),
+    };
+
+    Ok((presets, paths))
+}
+
+/// The log level: `base` (the config file's, or errors only), made more verbose by each `-v` and
+/// quieter by each `-q`, which `flags` gives as the level they make the errors-only default.
+fn log_level(base: TracingLevelFilter, flags: Option<Level>) -> TracingLevelFilter {
+    const LEVELS: [TracingLevelFilter; 6] = [
+        TracingLevelFilter::OFF,
+        TracingLevelFilter::ERROR,
+        TracingLevelFilter::WARN,
+        TracingLevelFilter::INFO,
+        TracingLevelFilter::DEBUG,
+        TracingLevelFilter::TRACE,
+    ];
+
+    let index = |level| LEVELS.iter().position(|l| *l == level).unwrap_or(1);
+    let verbosity = flags.map_or(0, |level| index(level_filter(level)));
+
+    LEVELS[(index(base) + verbosity).saturating_sub(1).min(LEVELS.len() - 1)]
+}
+
+const fn level_filter(level: Level) -> TracingLevelFilter {
+    match level {
+        Level::Error => TracingLevelFilter::ERROR,
+        Level::Warn => TracingLevelFilter::WARN,
+        Level::Info => TracingLevelFilter::INFO,
+        Level::Debug => TracingLevelFilter::DEBUG,
+        Level::Trace => TracingLevelFilter::TRACE,
+    }
+}
+
+/// Send `tracing` output to stderr at the requested verbosity.
+fn init_logging(level: TracingLevelFilter, format: LogFormat) {
+    let subscriber = tracing_subscriber::fmt().with_max_level(level).with_writer(io::stderr);
+
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().with_current_span(true).with_span_list(true).init(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use testresult::TestResult;
+
+    use clap_verbosity_flag::Level;
+    use tracing_subscriber::filter::LevelFilter;
+
+    use super::{checks_version, log_level, success_exit_code, Commands, CLI};
+    use macos_defaults::config::Config;
+    use macos_defaults::errors::ExitCode;
+
+    #[test]
+    fn test_success_exit_code() {
+        assert_eq!(success_exit_code(true, 2, false), 2);
+        assert_eq!(success_exit_code(false, 2, false), ExitCode::Success as i32);
+
+        // Without `--exit-code`, nothing changed is only told apart from changes with `--detailed-exit-codes`.
+        assert_eq!(success_exit_code(true, ExitCode::Success as i32, true), 0);
+        assert_eq!(success_exit_code(false, ExitCode::Success as i32, true), ExitCode::Unchanged as i32);
+        assert_ne!(ExitCode::Unchanged as i32, ExitCode::Success as i32);
+    }
+
+    #[test]
+    fn test_checks_version() -> TestResult {
+        let checks = |args: &[&str], version_check| -> TestResult<bool> {
+            let config = Config {
+                version_check,
+                ..Config::default()
+            };
+            Ok(checks_version(&CLI::try_parse_from(args)?, &config))
+        };
+
+        assert!(checks(&["macos-defaults", "doctor"], None)?);
+        assert!(checks(&["macos-defaults", "doctor"], Some(true))?);
+        assert!(!checks(&["macos-defaults", "doctor"], Some(false))?);
+        assert!(!checks(&["macos-defaults", "--no-version-check", "doctor"], None)?);
+        assert!(!checks(&["macos-defaults", "--no-version-check", "doctor"], Some(true))?);
+        assert!(!checks(&["macos-defaults", "--prefs-root", "/tmp", "doctor"], None)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_log_level() {
+        // No flags.
+        assert_eq!(log_level(LevelFilter::ERROR, Some(Level::Error)), LevelFilter::ERROR);
+        assert_eq!(log_level(LevelFilter::INFO, Some(Level::Error)), LevelFilter::INFO);
+
+        // `-v` and `-q` are relative to the config file's level.
+        assert_eq!(log_level(LevelFilter::INFO, Some(Level::Warn)), LevelFilter::DEBUG);
+        assert_eq!(log_level(LevelFilter::INFO, None), LevelFilter::WARN);
+        assert_eq!(log_level(LevelFilter::ERROR, None), LevelFilter::OFF);
+        assert_eq!(log_level(LevelFilter::DEBUG, Some(Level::Trace)), LevelFilter::TRACE);
+    }
+
+    #[test]
+    fn test_simulatable() -> TestResult {
+        let simulatable = |args: &[&str]| CLI::try_parse_from(args).map(|cli| cli.simulate && cli.command.simulatable());
+
+        assert!(simulatable(&["macos-defaults", "--simulate", "plan", "defaults.yaml"])?);
+        assert!(simulatable(&["macos-defaults", "--simulate", "diff", "defaults.yaml"])?);
+        assert!(simulatable(&["macos-defaults", "--simulate", "schema"])?);
+        assert!(!simulatable(&["macos-defaults", "--simulate", "apply", "defaults.yaml"])?);
+
+        // Another user's preferences can't be simulated.
+        assert!(CLI::try_parse_from(["macos-defaults", "--simulate", "--user", "test", "plan"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_args() -> TestResult {
+        let parse = |args: &[&str]| CLI::try_parse_from(args).map(|cli| matches!(cli.command, Commands::Dump(_)));
+
+        assert!(parse(&["macos-defaults", "dump", "--all", "--output-dir", "dumps"])?);
+        assert!(parse(&["macos-defaults", "dump", "-d", "com.apple.dock", "-g"])?);
+
+        // One of the domains to dump is required, and `--all` can't be combined with others.
+        assert!(parse(&["macos-defaults", "dump"]).is_err());
+        assert!(parse(&["macos-defaults", "dump", "--all", "-d", "com.apple.dock"]).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,97 @@
+//! Preferences managed by configuration profiles (MDM), which macOS keeps in
+//! `/Library/Managed Preferences/`. A managed key's value takes precedence over the user's, so
+//! writing the key has no visible effect.
+
+use std::collections::BTreeSet;
+use std::env;
+
+use camino::Utf8Path;
+use plist::Value;
+use tracing::{trace, warn};
+
+use crate::defaults::{prefs_root, prefs_user, read_plist, target_path, target_root};
+use crate::domain::Domain;
+
+/// Where managed preferences are cached: for every user at the top level, and per user in a
+/// directory named after them.
+const MANAGED_PREFERENCES_DIR: &str = "/Library/Managed Preferences";
+
+/// The top level keys of a domain that configuration profiles manage for the user whose
+/// preferences are being written. Always empty under a `--prefs-root`, which may not be this
+/// machine's, but read from under a `--target-root`.
+pub fn managed_keys(domain: &Domain) -> BTreeSet<String> {
+    let user = match prefs_user() {
+        Some(user) => Some(user.to_owned()),
+        None if prefs_root().is_some() && target_root().is_none() => return BTreeSet::new(),
+        None => env::var("USER").ok(),
+    };
+
+    managed_keys_in(&target_path(Utf8Path::new(MANAGED_PREFERENCES_DIR)), user.as_deref(), domain)
+}
+
+fn managed_keys_in(dir: &Utf8Path, user: Option<&str>, domain: &Domain) -> BTreeSet<String> {
+    let name = match domain {
+        Domain::Global => ".GlobalPreferences",
+        Domain::Named(name) => name,
+        Domain::Path(_) => return BTreeSet::new(),
+    };
+
+    let filename = format!("{name}.plist");
+
+    [Some(dir.join(&filename)), user.map(|user| dir.join(user).join(&filename))]
+        .into_iter()
+        .flatten()
+        .filter(|path| path.exists())
+        .filter_map(|path| {
+            trace!("Reading managed preferences {path}");
+
+            match read_plist(&path) {
+                Ok(Value::Dictionary(dict)) => Some(dict),
+                Ok(_) => None,
+                Err(e) => {
+                    warn!("Unable to read managed preferences: {e}");
+                    None
+                }
+            }
+        })
+        .flat_map(|dict| dict.into_iter().map(|(key, _)| key))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::managed_keys_in;
+    use crate::domain::Domain;
+
+    #[test]
+    fn test_managed_keys() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        std::fs::create_dir_all(dir.join("test"))?;
+
+        Value::Dictionary(Dictionary::from_iter([("autohide", Value::Boolean(true))])).to_file_xml(dir.join("com.apple.dock.plist"))?;
+        Value::Dictionary(Dictionary::from_iter([("tilesize", Value::from(36))])).to_file_xml(dir.join("test/com.apple.dock.plist"))?;
+        Value::Dictionary(Dictionary::from_iter([("AppleInterfaceStyle", Value::from("Dark"))])).to_file_xml(dir.join(".GlobalPreferences.plist"))?;
+
+        let dock = Domain::Named("com.apple.dock".to_owned());
+
+        // Profiles for every user, and for this one.
+        assert_eq!(
+            managed_keys_in(&dir, Some("test"), &dock).into_iter().collect::<Vec<_>>(),
+            ["autohide", "tilesize"]
+        );
+        assert_eq!(managed_keys_in(&dir, Some("other"), &dock).into_iter().collect::<Vec<_>>(), ["autohide"]);
+
+        assert_eq!(
+            managed_keys_in(&dir, None, &Domain::Global).into_iter().collect::<Vec<_>>(),
+            ["AppleInterfaceStyle"]
+        );
+        assert!(managed_keys_in(&dir, None, &Domain::Named("com.apple.finder".to_owned())).is_empty());
+
+        Ok(())
+    }
+}
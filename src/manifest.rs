@@ -0,0 +1,308 @@
+//! The managed-keys manifest: every key `apply` has changed, with the value it had before the
+//! first change (or none if it didn't exist yet) and the YAML files that set it, so that
+//! `teardown` can revert everything a config ever set.
+//!
+//! Kept in `$XDG_STATE_HOME/macos-defaults/managed-keys.plist` (falling back to
+//! `~/.local/state/macos-defaults/managed-keys.plist`), as a dictionary of plist paths to their
+//! keys: `{path: {key: {original: value, sources: [file, ...]}}}`. Keys an apply removes, by
+//! replacing a domain with a raw `.plist` file or wiping it with `"!"`, are recorded too.
+//!
+//! An apply loads it once and saves it once it finishes, and not at all for `--dry-run`,
+//! `--prefs-root` or `--target-root` runs, whose preferences aren't this Mac's.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use plist::Value;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use crate::defaults::{home_dir, target_path, PlistWrite};
+use crate::errors::DefaultsError as E;
+
+/// Plist paths to their managed keys.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Manifest {
+    plists: BTreeMap<Utf8PathBuf, BTreeMap<String, Entry>>,
+}
+
+/// A key's value before `apply` first changed it, and the files that set it since.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Entry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    original: Option<Value>,
+    sources: Vec<Utf8PathBuf>,
+    /// Whether a secret value was written to the key, so `teardown` doesn't show its values.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    secret: bool,
+}
+
+/// A managed key, as `teardown` sees it.
+#[derive(Debug, PartialEq)]
+pub struct ManagedKey {
+    pub key: String,
+    /// `None` if the key didn't exist before `apply` first set it.
+    pub original: Option<Value>,
+    /// Other files that still set the key, which keep it from being reverted.
+    pub other_sources: Vec<Utf8PathBuf>,
+    /// Whether a secret value was written to the key.
+    pub secret: bool,
+}
+
+impl Manifest {
+    /// Read the manifest, or an empty one if nothing has been recorded yet.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&manifest_path()?)
+    }
+
+    fn load_from(path: &Utf8Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        Ok(plist::from_file(path).map_err(|e| E::PlistRead {
+            path: path.to_owned(),
+            source: e,
+        })?)
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&manifest_path()?)
+    }
+
+    fn save_to(&self, path: &Utf8Path) -> Result<()> {
+        debug!("Saving the managed-keys manifest to {path}");
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+                path: parent.to_owned(),
+                source: e,
+            })?;
+        }
+
+        // Written next to it and renamed over it, so an interrupted save doesn't lose it.
+        let partial = path.with_extension("plist.partial");
+
+        plist::to_file_xml(&partial, self).map_err(|e| E::PlistWrite {
+            path: partial.clone(),
+            source: e,
+        })?;
+
+        fs::rename(&partial, path).map_err(|e| E::FileRename {
+            from_path: partial,
+            to_path: path.to_owned(),
+            source: e,
+        })?;
+
+        Ok(())
+    }
+
+    /// Note the keys a write changed or removed, and that `source` set them. `before` is the plist
+    /// before the write, for the original values of removed keys. The original value is only kept
+    /// the first time a key is changed.
+    pub fn record(&mut self, source: &Utf8Path, write: &PlistWrite, before: Option<&Value>) {
+        let source = absolute(source);
+        let keys = self.plists.entry(write.path.clone()).or_default();
+
+        let changed = write.changes.iter().map(|change| (&change.key, change.old_value.as_ref(), change.redacted));
+
+        // Not just `removed`, as wiping a domain with `"!"` also removes keys.
+        let new_dict = write.value.as_dictionary();
+        let removed = before
+            .and_then(Value::as_dictionary)
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| new_dict.is_some_and(|dict| !dict.contains_key(key)))
+            .map(|(key, value)| (key, Some(value), false));
+
+        for (key, original, secret) in changed.chain(removed) {
+            trace!("Recording {} {key} in the managed-keys manifest", write.path);
+
+            let entry = keys.entry(key.clone()).or_insert_with(|| Entry {
+                original: original.cloned(),
+                sources: Vec::new(),
+                secret: false,
+            });
+
+            entry.secret |= secret;
+
+            if !entry.sources.contains(&source) {
+                entry.sources.push(source.clone());
+            }
+        }
+    }
+
+    /// Forget that files at or under `path` set anything, and return the keys they set by plist
+    /// path. Keys no other file sets are dropped from the manifest.
+    pub fn release(&mut self, path: &Utf8Path) -> Vec<(Utf8PathBuf, Vec<ManagedKey>)> {
+        let path = absolute(path);
+        let mut released = Vec::new();
+
+        for (plist_path, keys) in &mut self.plists {
+            let mut managed = Vec::new();
+
+            keys.retain(|key, entry| {
+                let before = entry.sources.len();
+                entry.sources.retain(|source| !source.starts_with(&path));
+
+                if entry.sources.len() < before {
+                    managed.push(ManagedKey {
+                        key: key.clone(),
+                        original: entry.original.clone(),
+                        other_sources: entry.sources.clone(),
+                        secret: entry.secret,
+                    });
+                }
+
+                !entry.sources.is_empty()
+            });
+
+            if !managed.is_empty() {
+                released.push((plist_path.clone(), managed));
+            }
+        }
+
+        self.plists.retain(|_, keys| !keys.is_empty());
+
+        released
+    }
+}
+
+/// A source file as an absolute path, so teardown finds it from any directory. `preset:<name>`
+/// and `-` (stdin) are kept as they are.
+pub(crate) fn absolute(source: &Utf8Path) -> Utf8PathBuf {
+    if source.is_absolute() || source == "-" || source.as_str().starts_with("preset:") {
+        return source.to_owned();
+    }
+
+    source
+        .canonicalize_utf8()
+        .or_else(|_| env::current_dir().map(|dir| Utf8PathBuf::try_from(dir).unwrap_or_default().join(source)))
+        .unwrap_or_else(|_| source.to_owned())
+}
+
+/// Where macos-defaults keeps its state: `$XDG_STATE_HOME/macos-defaults`, falling back to
+/// `~/.local/state/macos-defaults`, under the `--target-root` if one was given.
+pub(crate) fn state_dir() -> Result<Utf8PathBuf> {
+    let state_dir = match env::var("XDG_STATE_HOME") {
+        Ok(dir) if !dir.is_empty() => target_path(Utf8Path::new(&dir)),
+        _ => home_dir()?.join(".local/state"),
+    };
+
+    Ok(state_dir.join("macos-defaults"))
+}
+
+fn manifest_path() -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("managed-keys.plist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::{Utf8Path, Utf8PathBuf};
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{ManagedKey, Manifest};
+    use crate::defaults::{KeyChange, PlistWrite};
+
+    fn write(path: &str, changes: Vec<KeyChange>) -> TestResult<PlistWrite> {
+        Ok(PlistWrite {
+            domain: "com.example.app".parse()?,
+            path: Utf8PathBuf::from(path),
+            exists: true,
+            needs_sudo: false,
+            changes,
+            removed: Vec::new(),
+            value: Value::Boolean(false),
+        })
+    }
+
+    fn change(key: &str, old_value: Option<Value>, new_value: Value) -> KeyChange {
+        KeyChange::new(key.to_owned(), old_value, new_value)
+    }
+
+    #[test]
+    fn test_manifest() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let plist = "/prefs/com.example.app.plist";
+
+        let mut manifest = Manifest::default();
+        manifest.record(
+            Utf8Path::new("/config/app.yaml"),
+            &write(
+                plist,
+                vec![change("a", Some(Value::from(1)), Value::from(2)), change("b", None, Value::from(true))],
+            )?,
+            None,
+        );
+        // Only the first original is kept.
+        manifest.record(
+            Utf8Path::new("/config/app.yaml"),
+            &write(plist, vec![change("a", Some(Value::from(2)), Value::from(3))])?,
+            None,
+        );
+        manifest.record(
+            Utf8Path::new("/other/app.yaml"),
+            &write(plist, vec![change("b", Some(Value::from(true)), Value::from(false))])?,
+            None,
+        );
+
+        manifest.save_to(&dir.join("managed-keys.plist"))?;
+        let mut manifest = Manifest::load_from(&dir.join("managed-keys.plist"))?;
+
+        let released = manifest.release(Utf8Path::new("/config"));
+        assert_eq!(
+            released,
+            [(
+                Utf8PathBuf::from(plist),
+                vec![
+                    ManagedKey {
+                        key: "a".to_owned(),
+                        original: Some(Value::from(1)),
+                        other_sources: Vec::new(),
+                        secret: false,
+                    },
+                    ManagedKey {
+                        key: "b".to_owned(),
+                        original: None,
+                        other_sources: vec![Utf8PathBuf::from("/other/app.yaml")],
+                        secret: false,
+                    },
+                ]
+            )]
+        );
+
+        // Keys a write removes are recorded with the value they had before it.
+        let removal = PlistWrite {
+            value: Value::Dictionary(Dictionary::from_iter([("a", Value::from(3))])),
+            ..write(plist, Vec::new())?
+        };
+        let before = Value::Dictionary(Dictionary::from_iter([("a", Value::from(3)), ("c", Value::from("gone"))]));
+        manifest.record(Utf8Path::new("/removal/app.yaml"), &removal, Some(&before));
+
+        assert_eq!(
+            manifest.release(Utf8Path::new("/removal")),
+            [(
+                Utf8PathBuf::from(plist),
+                vec![ManagedKey {
+                    key: "c".to_owned(),
+                    original: Some(Value::from("gone")),
+                    other_sources: Vec::new(),
+                    secret: false,
+                }]
+            )]
+        );
+
+        // `b` is still set by the other file, and nothing is left once that's released too.
+        assert!(manifest.release(Utf8Path::new("/config")).is_empty());
+        assert_eq!(manifest.release(Utf8Path::new("/other/app.yaml")).len(), 1);
+        assert!(manifest.plists.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Plist values as Nix expressions, for `dump --format nix`: an attribute set of domains, as
+//! home-manager's `targets.darwin.defaults` (and nix-darwin's `system.defaults.CustomUserPreferences`)
+//! take.
+
+use std::fmt::Write as _;
+
+use plist::{Dictionary, Value};
+use tracing::warn;
+
+/// Nix keywords, which can't be attribute names without quotes.
+const KEYWORDS: &[&str] = &["assert", "else", "if", "in", "inherit", "let", "or", "rec", "then", "with"];
+
+/// Render domains and their preferences as a Nix attribute set. Keys whose values Nix can't
+/// express (data, dates, UIDs, non-finite reals), which home-manager couldn't write anyway, are
+/// left out with a comment.
+pub fn domains_to_nix(domains: &Dictionary) -> String {
+    let mut nix = String::new();
+    write_dictionary(&mut nix, domains, 0);
+    nix.push('\n');
+    nix
+}
+
+fn write_dictionary(nix: &mut String, dict: &Dictionary, indent: usize) {
+    if dict.is_empty() {
+        nix.push_str("{ }");
+        return;
+    }
+
+    nix.push_str("{\n");
+
+    for (key, value) in dict {
+        let _ = write!(nix, "{:width$}", "", width = indent + 2);
+
+        if let Some(value_type) = unsupported(value) {
+            warn!("Leaving out {key}, as {value_type} values have no Nix equivalent.");
+            let _ = writeln!(nix, "# {}: left out, {value_type} values have no Nix equivalent.", key.replace('\n', " "));
+            continue;
+        }
+
+        let _ = write!(nix, "{} = ", attribute_name(key));
+        write_value(nix, value, indent + 2);
+        nix.push_str(";\n");
+    }
+
+    let _ = write!(nix, "{:width$}}}", "", width = indent);
+}
+
+fn write_value(nix: &mut String, value: &Value, indent: usize) {
+    match value {
+        Value::Boolean(b) => nix.push_str(if *b { "true" } else { "false" }),
+        Value::Integer(i) => {
+            let _ = write!(nix, "{}", i.as_signed().unwrap_or_default());
+        }
+        Value::Real(f) => {
+            let _ = write!(nix, "{f:?}");
+        }
+        Value::String(s) => nix.push_str(&string_literal(s)),
+        Value::Array(array) if array.is_empty() => nix.push_str("[ ]"),
+        Value::Array(array) => {
+            nix.push_str("[\n");
+
+            for item in array {
+                let _ = write!(nix, "{:width$}", "", width = indent + 2);
+
+                // A negative number would be parsed as a subtraction from the previous item.
+                let negative =
+                    matches!(item, Value::Integer(i) if i.as_signed().is_some_and(i64::is_negative)) || matches!(item, Value::Real(f) if f.is_sign_negative());
+
+                if negative {
+                    nix.push('(');
+                    write_value(nix, item, indent + 2);
+                    nix.push(')');
+                } else {
+                    write_value(nix, item, indent + 2);
+                }
+
+                nix.push('\n');
+            }
+
+            let _ = write!(nix, "{:width$}]", "", width = indent);
+        }
+        Value::Dictionary(dict) => write_dictionary(nix, dict, indent),
+        // Left out by `write_dictionary`.
+        _ => nix.push_str("null"),
+    }
+}
+
+/// The type of a value (or of an array's item) that Nix can't express, if any. Dictionaries leave
+/// out their own keys.
+fn unsupported(value: &Value) -> Option<&'static str> {
+    match value {
+        Value::Data(_) => Some("data"),
+        Value::Date(_) => Some("date"),
+        Value::Uid(_) => Some("UID"),
+        Value::Integer(i) if i.as_signed().is_none() => Some("64-bit unsigned integer"),
+        Value::Real(f) if !f.is_finite() => Some("non-finite real"),
+        Value::Array(array) => array.iter().find_map(unsupported),
+        _ => None,
+    }
+}
+
+/// A key as an attribute name, quoted unless it's a valid identifier.
+fn attribute_name(key: &str) -> String {
+    let mut chars = key.chars();
+
+    let is_identifier = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '\'' | '-'))
+        && !KEYWORDS.contains(&key);
+
+    if is_identifier {
+        key.to_owned()
+    } else {
+        string_literal(key)
+    }
+}
+
+fn string_literal(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+
+    format!("\"{escaped}\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+
+    use super::{attribute_name, domains_to_nix};
+
+    #[test]
+    fn test_attribute_name() {
+        assert_eq!(attribute_name("autohide"), "autohide");
+        assert_eq!(attribute_name("show-recents"), "show-recents");
+        assert_eq!(attribute_name("com.apple.dock"), "\"com.apple.dock\"");
+        assert_eq!(attribute_name("NSWindow Frame Main"), "\"NSWindow Frame Main\"");
+        assert_eq!(attribute_name("with"), "\"with\"");
+        assert_eq!(attribute_name("1st"), "\"1st\"");
+    }
+
+    #[test]
+    fn test_domains_to_nix() {
+        let dock = Dictionary::from_iter([
+            ("autohide", Value::Boolean(true)),
+            ("tilesize", Value::from(36)),
+            ("magnification-scale", Value::Real(1.5)),
+            ("offsets", Value::Array(vec![Value::from(-1), Value::from(2)])),
+            ("wvous-tl-corner", Value::from("${HOME}\n\"quoted\"")),
+            ("empty", Value::Dictionary(Dictionary::new())),
+            ("icon", Value::Data(vec![0, 1])),
+        ]);
+
+        let domains = Dictionary::from_iter([("com.apple.dock", Value::Dictionary(dock))]);
+
+        assert_eq!(
+            domains_to_nix(&domains),
+            r#"{
+  "com.apple.dock" = {
+    autohide = true;
+    tilesize = 36;
+    magnification-scale = 1.5;
+    offsets = [
+      (-1)
+      2
+    ];
+    wvous-tl-corner = "\${HOME}\n\"quoted\"";
+    empty = { };
+    # icon: left out, data values have no Nix equivalent.
+  };
+}
+"#
+        );
+    }
+}
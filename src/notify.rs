@@ -0,0 +1,74 @@
+//! `apply --notify`: a user notification once an apply finishes, for applies nobody is watching,
+//! like those the launch agent runs in the background.
+
+use color_eyre::Report;
+use duct::cmd;
+use tracing::{debug, warn};
+
+use crate::cmd::ApplySummary;
+
+/// Title of the notifications.
+const TITLE: &str = "macos-defaults";
+
+/// Post a notification saying how many settings an apply changed, or why it failed.
+pub fn notify_apply(summary: &ApplySummary, error: Option<&Report>) {
+    let (message, sound) = apply_message(summary, error);
+    let script = notification_script(&message, sound);
+
+    debug!("Posting notification: {message}");
+
+    if let Err(e) = cmd!("osascript", "-e", script).stdout_null().stderr_capture().run() {
+        warn!("Unable to post a notification with osascript: {e}");
+    }
+}
+
+/// The notification's message, and whether it's a failure worth a sound.
+fn apply_message(summary: &ApplySummary, error: Option<&Report>) -> (String, bool) {
+    let changed = match summary.keys_changed {
+        0 => "No settings changed".to_owned(),
+        1 => "Changed 1 setting".to_owned(),
+        keys => format!("Changed {keys} settings"),
+    };
+
+    match error {
+        Some(error) if summary.keys_changed > 0 => (format!("{changed} before failing: {error}"), true),
+        Some(error) => (format!("Apply failed: {error}"), true),
+        None => (format!("{changed}."), false),
+    }
+}
+
+/// The `osascript` script posting a notification.
+fn notification_script(message: &str, sound: bool) -> String {
+    let quote = |s: &str| format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""));
+    let sound = if sound { " sound name \"Basso\"" } else { "" };
+
+    format!("display notification {} with title {}{sound}", quote(message), quote(TITLE))
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::{apply_message, notification_script};
+    use crate::cmd::ApplySummary;
+
+    #[test]
+    fn test_apply_message() {
+        let mut summary = ApplySummary::default();
+        assert_eq!(apply_message(&summary, None), ("No settings changed.".to_owned(), false));
+
+        summary.keys_changed = 3;
+        assert_eq!(apply_message(&summary, None), ("Changed 3 settings.".to_owned(), false));
+
+        let error = eyre!("Permission denied");
+        assert_eq!(
+            apply_message(&summary, Some(&error)),
+            ("Changed 3 settings before failing: Permission denied".to_owned(), true)
+        );
+
+        assert_eq!(
+            notification_script(r#"Invalid "dock.yaml""#, false),
+            r#"display notification "Invalid \"dock.yaml\"" with title "macos-defaults""#
+        );
+    }
+}
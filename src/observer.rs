@@ -0,0 +1,274 @@
+//! Events emitted while applying defaults, so reporting is decoupled from the engine.
+
+use std::cell::RefCell;
+use std::time::Duration;
+
+use camino::Utf8Path;
+use color_eyre::Report;
+use colored::Colorize;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use itertools::Itertools;
+use plist::Value;
+use serde::Serialize;
+use tracing::error;
+
+use crate::ansible::AnsibleReport;
+use crate::cmd::ApplySummary;
+
+/// Receives events as YAML documents are applied.
+///
+/// The CLI implements this for its console output; other front ends (GUIs, progress bars) can
+/// implement it to follow along. All methods default to doing nothing.
+pub trait ApplyObserver {
+    /// A YAML file is about to be applied.
+    fn on_file_start(&self, _path: &Utf8Path) {}
+
+    /// A YAML document is about to be applied.
+    fn on_document_start(&self, _description: Option<&str>) {}
+
+    /// A domain in the current document is about to be written.
+    fn on_domain_start(&self, _domain: &str) {}
+
+    /// A key in a domain was changed. `old_value` is `None` if the key didn't exist. Secret values
+    /// are already redacted.
+    fn on_key_changed(&self, _domain: &str, _key: &str, _old_value: Option<&Value>, _new_value: &Value) {}
+
+    /// Applying a domain failed.
+    fn on_domain_error(&self, _domain: &str, _error: &Report) {}
+
+    /// Applying stopped on an error that isn't about one domain, e.g. a YAML file that can't be
+    /// read. It's also returned, so there's no need to log it.
+    fn on_error(&self, _error: &Report) {}
+
+    /// An app is being quit before its preferences are written.
+    fn on_quit(&self, _app: &str) {}
+
+    /// A process is being restarted to pick up changes.
+    fn on_kill(&self, _process: &str) {}
+
+    /// All files have been applied, or applying stopped on an error.
+    fn on_finish(&self, _summary: &ApplySummary) {}
+}
+
+/// How many of the slowest domains the summary of an apply lists.
+const SLOWEST_DOMAINS: usize = 5;
+
+/// Domains taking longer than this are listed in the console summary of an apply, and any of the
+/// slowest ones at `-v` and up.
+const SLOW_DOMAIN: Duration = Duration::from_secs(1);
+
+/// Console output of the `apply` command.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleObserver {
+    /// Run at `-v` and up, so list the slowest domains even if none are slow.
+    pub verbose: bool,
+}
+
+impl ApplyObserver for ConsoleObserver {
+    #[allow(clippy::print_stdout)]
+    fn on_document_start(&self, description: Option<&str>) {
+        // TODO: Get global CLI verbosity values.
+        if let Some(description) = description {
+            println!("  {} {}", "▶".green(), description.bold().white());
+        }
+    }
+
+    fn on_domain_error(&self, domain: &str, error: &Report) {
+        error!("{domain}: {error:?}");
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn on_quit(&self, app: &str) {
+        println!("    {} Quitting: {}", "✖".blue(), app.white());
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn on_kill(&self, process: &str) {
+        println!("    {} Restarting: {}", "✖".blue(), process.white());
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn on_finish(&self, summary: &ApplySummary) {
+        let changed = if summary.changed { "changed" } else { "unchanged" };
+
+        println!(
+            "{}",
+            format!("Domains applied: {} ({changed}), run {}", summary.domains_applied, summary.run_id).dimmed()
+        );
+
+        let slowest = summary
+            .slowest_domains(SLOWEST_DOMAINS)
+            .into_iter()
+            .filter(|(_, time)| self.verbose || *time > SLOW_DOMAIN)
+            .map(|(domain, time)| format!("{domain} ({}ms)", time.as_millis()))
+            .join(", ");
+
+        if !slowest.is_empty() {
+            println!("{}", format!("Slowest domains: {slowest}").dimmed());
+        }
+    }
+}
+
+/// Console output of the `apply` command, below a progress bar of the files and domains applied.
+#[derive(Debug)]
+pub struct ProgressObserver {
+    bar: ProgressBar,
+    console: ConsoleObserver,
+}
+
+impl ProgressObserver {
+    /// A progress bar for applying `files` YAML files, above the `console` output.
+    pub fn new(files: usize, console: ConsoleObserver) -> Self {
+        let style =
+            ProgressStyle::with_template("{spinner:.green} [{pos}/{len}] {prefix:.bold} {wide_msg:.dim}").expect("The progress bar template should be valid.");
+
+        let bar = ProgressBar::with_draw_target(Some(files as u64), ProgressDrawTarget::stderr()).with_style(style);
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        Self { bar, console }
+    }
+}
+
+impl ApplyObserver for ProgressObserver {
+    fn on_file_start(&self, path: &Utf8Path) {
+        self.bar.inc(1);
+        self.bar.set_prefix(path.to_string());
+        self.bar.set_message("");
+    }
+
+    fn on_document_start(&self, description: Option<&str>) {
+        self.bar.suspend(|| self.console.on_document_start(description));
+    }
+
+    fn on_domain_start(&self, domain: &str) {
+        self.bar.set_message(domain.to_owned());
+    }
+
+    fn on_domain_error(&self, domain: &str, error: &Report) {
+        self.bar.suspend(|| self.console.on_domain_error(domain, error));
+    }
+
+    fn on_quit(&self, app: &str) {
+        self.bar.set_message(format!("quitting {app}"));
+        self.bar.suspend(|| self.console.on_quit(app));
+    }
+
+    fn on_kill(&self, process: &str) {
+        self.bar.set_message(format!("restarting {process}"));
+        self.bar.suspend(|| self.console.on_kill(process));
+    }
+
+    fn on_finish(&self, summary: &ApplySummary) {
+        self.bar.finish_and_clear();
+        self.console.on_finish(summary);
+    }
+}
+
+/// `apply --report ansible`: nothing but the Ansible module result on stdout once everything has
+/// been applied.
+#[derive(Debug, Default)]
+pub struct AnsibleObserver {
+    report: RefCell<AnsibleReport>,
+}
+
+impl ApplyObserver for AnsibleObserver {
+    fn on_key_changed(&self, domain: &str, key: &str, old_value: Option<&Value>, new_value: &Value) {
+        self.report.borrow_mut().add_change(domain, key, old_value, new_value);
+    }
+
+    fn on_domain_error(&self, domain: &str, error: &Report) {
+        error!("{domain}: {error:?}");
+        self.report.borrow_mut().add_error(domain, &error.to_string());
+    }
+
+    fn on_error(&self, error: &Report) {
+        self.report.borrow_mut().add_failure(&error.to_string());
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn on_finish(&self, summary: &ApplySummary) {
+        // Documents that failed as a whole with `keep_going`, which no domain error covers.
+        for failure in summary.failed.iter().filter(|failure| failure.domain.is_none()) {
+            self.report.borrow_mut().add_error(failure.source.as_str(), &failure.error.to_string());
+        }
+
+        match self.report.borrow().result().and_then(|result| Ok(serde_json::to_string(&result)?)) {
+            Ok(json) => println!("{json}"),
+            Err(e) => error!("Couldn't report the result: {e:?}"),
+        }
+    }
+}
+
+/// `apply --report json`: nothing but a summary of the run on stdout once everything has been
+/// applied.
+#[derive(Debug, Default)]
+pub struct JsonObserver;
+
+impl ApplyObserver for JsonObserver {
+    fn on_domain_error(&self, domain: &str, error: &Report) {
+        error!("{domain}: {error:?}");
+    }
+
+    #[allow(clippy::print_stdout)]
+    fn on_finish(&self, summary: &ApplySummary) {
+        match serde_json::to_string(&JsonReport::new(summary)) {
+            Ok(json) => println!("{json}"),
+            Err(e) => error!("Couldn't report the result: {e:?}"),
+        }
+    }
+}
+
+/// What `apply --report json` prints.
+#[derive(Debug, Serialize)]
+struct JsonReport<'a> {
+    run_id: String,
+    changed: bool,
+    domains_applied: usize,
+    keys_changed: usize,
+    /// Domains (or whole documents) that failed with `--keep-going`.
+    failed: Vec<JsonFailure<'a>>,
+    /// The domains that took longest to read, plan and write, slowest first.
+    slowest_domains: Vec<DomainTime<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonFailure<'a> {
+    source: &'a Utf8Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    domain: Option<&'a str>,
+    error: String,
+}
+
+#[derive(Debug, Serialize)]
+struct DomainTime<'a> {
+    domain: &'a str,
+    seconds: f64,
+}
+
+impl<'a> JsonReport<'a> {
+    fn new(summary: &'a ApplySummary) -> Self {
+        Self {
+            run_id: summary.run_id.to_string(),
+            changed: summary.changed,
+            domains_applied: summary.domains_applied,
+            keys_changed: summary.keys_changed,
+            failed: summary
+                .failed
+                .iter()
+                .map(|failure| JsonFailure {
+                    source: &failure.source,
+                    domain: failure.domain.as_deref(),
+                    error: failure.error.chain().join(": "),
+                })
+                .collect(),
+            slowest_domains: summary
+                .slowest_domains(SLOWEST_DOMAINS)
+                .into_iter()
+                .map(|(domain, time)| DomainTime {
+                    domain,
+                    seconds: time.as_secs_f64(),
+                })
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,323 @@
+//! Machine bootstrap phases: the config file's `phases` are applied in order, stopping where the
+//! next phase needs a logout or a restart, and resumed from there with `apply --continue`. Once
+//! the last phase has been applied, `apply` applies every phase, as the launch agent does.
+//!
+//! Progress is kept in `$XDG_STATE_HOME/macos-defaults/phases.yaml` (falling back to
+//! `~/.local/state/macos-defaults/phases.yaml`). Remove it to bootstrap from the first phase again.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use sysinfo::System;
+use tracing::{debug, warn};
+
+use crate::errors::DefaultsError as E;
+use crate::manifest::state_dir;
+
+/// A stage of setting up a machine, in the order they're applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// Applied right away.
+    PreLogin,
+    /// Applied after logging out and back in.
+    PostLogin,
+    /// Applied after restarting the machine.
+    PostReboot,
+}
+
+/// What has to happen between two phases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Checkpoint {
+    Logout,
+    Reboot,
+}
+
+impl Phase {
+    /// What has to happen after the previous phase before this one is applied.
+    const fn checkpoint(self) -> Option<Checkpoint> {
+        match self {
+            Self::PreLogin => None,
+            Self::PostLogin => Some(Checkpoint::Logout),
+            Self::PostReboot => Some(Checkpoint::Reboot),
+        }
+    }
+
+    const fn as_str(self) -> &'static str {
+        match self {
+            Self::PreLogin => "pre-login",
+            Self::PostLogin => "post-login",
+            Self::PostReboot => "post-reboot",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Logout => "Log out and back in",
+            Self::Reboot => "Restart the machine",
+        })
+    }
+}
+
+/// Files or directories to apply per phase, from the config file.
+pub type Phases = BTreeMap<Phase, Vec<Utf8PathBuf>>;
+
+/// What to do once a phase has been applied.
+#[derive(Debug, PartialEq, Eq)]
+pub enum NextPhase {
+    /// This phase is applied by `apply --continue` after the checkpoint.
+    Checkpoint(Phase, Checkpoint),
+    /// Every phase has been applied.
+    Done,
+}
+
+/// Persisted between runs once a bootstrap has started.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct Progress {
+    /// The phase `apply --continue` applies, or `None` once every phase has been applied.
+    next: Option<Phase>,
+    /// When the machine was booted at the checkpoint, to tell whether it has restarted since.
+    boot_time: u64,
+}
+
+/// The phases an `apply` applies, in order.
+#[derive(Debug, PartialEq, Eq)]
+pub struct PhaseRun {
+    pub phases: Vec<Phase>,
+    /// Whether applying them moves the bootstrap on, so [`finish_phase`] is called for the last.
+    pub advances: bool,
+}
+
+/// The phases to apply: with `resume`, the one the last run stopped before. Otherwise see
+/// [`phases_for_progress`].
+pub fn phases_to_apply(phases: &Phases, resume: bool) -> Result<PhaseRun> {
+    if phases.is_empty() {
+        return Err(E::NoPhases.into());
+    }
+
+    let progress = read_progress(&progress_path()?)?;
+
+    if !resume {
+        return Ok(phases_for_progress(phases, progress.as_ref()));
+    }
+
+    let progress = progress.ok_or(E::NoPhaseInProgress)?;
+    let next = progress.next.ok_or(E::NoPhaseInProgress)?;
+
+    check_checkpoint(&progress, System::boot_time())?;
+
+    if !phases.contains_key(&next) {
+        return Err(E::PhaseNotConfigured { phase: next }.into());
+    }
+
+    Ok(PhaseRun {
+        phases: vec![next],
+        advances: true,
+    })
+}
+
+/// The phases a plain `apply` applies: the first one if no bootstrap has started, the ones already
+/// applied while it's stopped at a checkpoint, and every one once it's done.
+fn phases_for_progress(phases: &Phases, progress: Option<&Progress>) -> PhaseRun {
+    let configured = phases.keys().copied();
+
+    match progress {
+        None => PhaseRun {
+            phases: configured.take(1).collect(),
+            advances: true,
+        },
+        Some(Progress { next: Some(next), .. }) => {
+            warn!("The bootstrap is stopped before phase {next}, use `apply --continue` to apply it.");
+
+            PhaseRun {
+                phases: configured.filter(|phase| phase < next).collect(),
+                advances: false,
+            }
+        }
+        Some(Progress { next: None, .. }) => PhaseRun {
+            phases: configured.collect(),
+            advances: false,
+        },
+    }
+}
+
+/// Record that `phase` was applied, and work out what comes next.
+pub fn finish_phase(phases: &Phases, phase: Phase) -> Result<NextPhase> {
+    let next = next_phase(phases, phase);
+
+    write_progress(
+        &progress_path()?,
+        &Progress {
+            next: match next {
+                NextPhase::Checkpoint(next, _) => Some(next),
+                NextPhase::Done => None,
+            },
+            boot_time: System::boot_time(),
+        },
+    )?;
+
+    Ok(next)
+}
+
+/// The configured phase after `phase`, with the checkpoint before it.
+fn next_phase(phases: &Phases, phase: Phase) -> NextPhase {
+    phases
+        .keys()
+        .copied()
+        .filter(|next| *next > phase)
+        .find_map(|next| next.checkpoint().map(|checkpoint| NextPhase::Checkpoint(next, checkpoint)))
+        .unwrap_or(NextPhase::Done)
+}
+
+/// Fail if the machine hasn't been restarted since stopping before a phase that needs it.
+/// Logging out can't be checked, so it's up to the user.
+fn check_checkpoint(progress: &Progress, boot_time: u64) -> Result<(), E> {
+    match progress.next {
+        Some(phase) if phase.checkpoint() == Some(Checkpoint::Reboot) && boot_time == progress.boot_time => Err(E::CheckpointNotReached {
+            phase,
+            checkpoint: Checkpoint::Reboot,
+        }),
+        _ => Ok(()),
+    }
+}
+
+fn progress_path() -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("phases.yaml"))
+}
+
+fn read_progress(path: &Utf8Path) -> Result<Option<Progress>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(path).map_err(|e| E::FileRead {
+        path: path.to_owned(),
+        source: e,
+    })?;
+
+    let progress = serde_yaml::from_str(&contents).map_err(|e| E::InvalidYaml {
+        path: path.to_owned(),
+        source: e,
+    })?;
+
+    Ok(Some(progress))
+}
+
+fn write_progress(path: &Utf8Path, progress: &Progress) -> Result<()> {
+    debug!("Saving bootstrap progress to {path}: {progress:?}");
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+            path: parent.to_owned(),
+            source: e,
+        })?;
+    }
+
+    fs::write(path, serde_yaml::to_string(progress)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{check_checkpoint, next_phase, phases_for_progress, read_progress, write_progress, Checkpoint, NextPhase, Phase, PhaseRun, Phases, Progress};
+
+    #[test]
+    fn test_next_phase() -> TestResult {
+        let phases: Phases = serde_yaml::from_str("post-reboot: [finder.yaml]\npre-login: [system]\npost-login: [apps]\n")?;
+
+        assert_eq!(
+            phases.keys().copied().collect::<Vec<_>>(),
+            [Phase::PreLogin, Phase::PostLogin, Phase::PostReboot]
+        );
+        assert_eq!(
+            next_phase(&phases, Phase::PreLogin),
+            NextPhase::Checkpoint(Phase::PostLogin, Checkpoint::Logout)
+        );
+        assert_eq!(
+            next_phase(&phases, Phase::PostLogin),
+            NextPhase::Checkpoint(Phase::PostReboot, Checkpoint::Reboot)
+        );
+        assert_eq!(next_phase(&phases, Phase::PostReboot), NextPhase::Done);
+
+        // Skipping post-login still needs a restart before post-reboot.
+        let phases: Phases = serde_yaml::from_str("pre-login: [system]\npost-reboot: [finder.yaml]\n")?;
+        assert_eq!(
+            next_phase(&phases, Phase::PreLogin),
+            NextPhase::Checkpoint(Phase::PostReboot, Checkpoint::Reboot)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_progress() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        let path = dir.join("macos-defaults/phases.yaml");
+
+        assert_eq!(read_progress(&path)?, None);
+
+        let progress = Progress {
+            next: Some(Phase::PostReboot),
+            boot_time: 1_700_000_000,
+        };
+        write_progress(&path, &progress)?;
+        assert_eq!(read_progress(&path)?, Some(progress));
+
+        let progress = read_progress(&path)?.ok_or("progress was written")?;
+        assert!(check_checkpoint(&progress, 1_700_000_000).is_err());
+        assert!(check_checkpoint(&progress, 1_700_000_500).is_ok());
+
+        // Files from before a bootstrap could finish with the progress kept.
+        std::fs::write(&path, "next: post-login\nboot_time: 1700000000\n")?;
+        assert_eq!(read_progress(&path)?.and_then(|progress| progress.next), Some(Phase::PostLogin));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_phases_for_progress() -> TestResult {
+        let phases: Phases = serde_yaml::from_str("pre-login: [system]\npost-login: [apps]\npost-reboot: [finder.yaml]\n")?;
+        let progress = |next| Progress { next, boot_time: 0 };
+
+        assert_eq!(
+            phases_for_progress(&phases, None),
+            PhaseRun {
+                phases: vec![Phase::PreLogin],
+                advances: true,
+            }
+        );
+        assert_eq!(
+            phases_for_progress(&phases, Some(&progress(Some(Phase::PostReboot)))),
+            PhaseRun {
+                phases: vec![Phase::PreLogin, Phase::PostLogin],
+                advances: false,
+            }
+        );
+        assert_eq!(
+            phases_for_progress(&phases, Some(&progress(None))),
+            PhaseRun {
+                phases: vec![Phase::PreLogin, Phase::PostLogin, Phase::PostReboot],
+                advances: false,
+            }
+        );
+
+        Ok(())
+    }
+}
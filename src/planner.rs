@@ -0,0 +1,322 @@
+//! Applying happens in two phases: a [`Plan`] of the plist writes and process restarts is
+//! computed without changing anything, and then executed.
+//!
+//! `apply` plans and executes one document at a time, `plan` only prints the plan, and anything
+//! else that needs to know what an apply would do (dry runs, diffs, reports) can share the planner.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use color_eyre::Report;
+use itertools::Itertools;
+use plist::Value;
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::cmd::apply::{domain_setting, DefaultsConfig};
+use crate::cmd::ApplyOptions;
+use crate::defaults::{
+    add_containers, did_you_mean, domain_read_paths, plan_domain_replace, plan_domain_writes, raw_plist_current_host, raw_plist_domain, read_plist,
+    MacOSDefaults, PlistWrite,
+};
+use crate::errors::DefaultsError as E;
+use crate::managed::managed_keys;
+use crate::state_cache::{document_hash, domain_key};
+use crate::tags::TagContext;
+
+/// Everything applying a set of YAML documents would do.
+#[derive(Debug, Default, Serialize)]
+pub struct Plan {
+    pub documents: Vec<DocumentPlan>,
+}
+
+/// What applying one YAML document would do.
+#[derive(Debug, Serialize)]
+pub struct DocumentPlan {
+    /// The YAML file (or `preset:<name>`, or raw `.plist` file) the document came from.
+    pub source: Utf8PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Whether the document asks for root-owned plists to be written with `sudo`.
+    pub sudo: bool,
+    pub domains: Vec<DomainPlan>,
+    /// Apps to quit before the writes. Empty if nothing would change.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quit: Vec<String>,
+    /// Whether to launch the quit apps that were running again after the writes.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub relaunch: bool,
+    /// Processes to restart after the writes. Empty if nothing would change.
+    pub kill: Vec<String>,
+}
+
+/// The writes for one domain of a document, or why they couldn't be planned.
+#[derive(Debug, Serialize)]
+pub struct DomainPlan {
+    /// The domain as given in the YAML document.
+    pub domain: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub writes: Vec<PlistWrite>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_error")]
+    pub error: Option<Report>,
+    /// The document's `drift_ignore` patterns for the domain, if it has any.
+    #[serde(skip)]
+    pub drift_ignore: Option<Vec<String>>,
+    /// How long reading the domain's plists and planning the writes took.
+    #[serde(skip)]
+    pub planning_time: Duration,
+    /// With `--lazy-read`, the domain's state cache key and the plists planning it reads.
+    #[serde(skip)]
+    pub state_cache: Option<(String, Vec<Utf8PathBuf>)>,
+}
+
+impl DocumentPlan {
+    /// Whether executing the plan would change any plist.
+    pub fn changes_anything(&self) -> bool {
+        self.domains.iter().any(|domain| !domain.writes.is_empty())
+    }
+}
+
+/// For `apply --no-create`: fail if a domain's plist doesn't exist and isn't created by an earlier
+/// document, suggesting installed domains with similar names in case it's a typo.
+fn check_no_create(writes: Vec<PlistWrite>, current_host: bool) -> Result<Vec<PlistWrite>> {
+    let Some(missing) = writes.iter().find(|write| !write.exists) else {
+        return Ok(writes);
+    };
+
+    let domain = missing.domain.as_str();
+
+    let hint = match did_you_mean(domain, current_host) {
+        Some(suggestion) => format!("{suggestion} Otherwise set `create: true` in the document if the domain is new."),
+        None => "Set `create: true` in the document if the domain is new.".to_owned(),
+    };
+
+    Err(E::NoCreate {
+        domain: domain.to_owned(),
+        hint,
+    }
+    .into())
+}
+
+/// Warn about changes to keys a configuration profile manages, as they'd have no visible effect,
+/// or with `skip`, leave them out.
+fn check_managed(mut writes: Vec<PlistWrite>, skip: bool) -> Vec<PlistWrite> {
+    for write in &mut writes {
+        let managed = managed_keys(&write.domain);
+
+        if managed.is_empty() {
+            continue;
+        }
+
+        let domain = write.domain.clone();
+
+        write.changes.retain(|change| {
+            if !managed.contains(&change.key) {
+                return true;
+            }
+
+            if !skip {
+                warn!(
+                    "{domain} {} is managed by a configuration profile, so changing it has no visible effect.",
+                    change.key
+                );
+                return true;
+            }
+
+            warn!("Skipping {domain} {}, which is managed by a configuration profile.", change.key);
+
+            if let Value::Dictionary(dict) = &mut write.value {
+                match &change.old_value {
+                    Some(old_value) => dict.insert(change.key.clone(), old_value.clone()),
+                    None => dict.remove(&change.key),
+                };
+            }
+
+            false
+        });
+    }
+
+    writes.retain(|write| !write.changes.is_empty() || !write.removed.is_empty());
+    writes
+}
+
+/// Plans documents, one after another.
+///
+/// Later documents are planned against the plist contents earlier documents' writes will produce,
+/// so a single planner gives an accurate plan for a whole run, as long as it's executed in order.
+#[derive(Debug, Default)]
+pub struct Planner {
+    /// Plist path -> contents after the writes planned so far.
+    planned: HashMap<Utf8PathBuf, Value>,
+}
+
+impl Planner {
+    /// Plan a YAML document read from `path`.
+    pub fn plan_document(&mut self, config: MacOSDefaults, path: &Utf8Path, options: &ApplyOptions) -> Result<DocumentPlan> {
+        //
+        let document_hash = options.state_cache.as_ref().and_then(|_| document_hash(&config, &planning_options(options)));
+        let data = config.data.ok_or_else(|| E::MissingData { path: path.to_owned() })?;
+        add_containers(&config.containers, &config.group_containers);
+
+        let defaults = DefaultsConfig::for_document(data, config.once, &TagContext::for_yaml_path(path))?.expand_domain_globs(config.current_host);
+
+        let domains: Vec<DomainPlan> = defaults
+            .0
+            .into_iter()
+            .sorted_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(domain, prefs)| {
+                let started = Instant::now();
+
+                let state_cache = document_hash.as_deref().and_then(|hash| {
+                    let plist_paths = domain_read_paths(&domain, config.current_host, &options.write).ok()?;
+                    Some((domain_key(hash, &domain), plist_paths))
+                });
+
+                if let (Some(cache), Some((key, _))) = (&options.state_cache, &state_cache) {
+                    if cache.is_fresh(path, key) {
+                        debug!("{domain} hasn't changed since it was last applied, skipping it");
+
+                        return DomainPlan {
+                            planning_time: started.elapsed(),
+                            state_cache,
+                            ..self.domain_plan(domain, Ok(Vec::new()))
+                        };
+                    }
+                }
+
+                let ignore_keys: Vec<String> = [&config.ignore_keys, &options.ignore_keys]
+                    .into_iter()
+                    .filter_map(|ignore_keys| domain_setting(ignore_keys, &domain))
+                    .flatten()
+                    .cloned()
+                    .collect();
+
+                let mut writes = plan_domain_writes(&domain, &prefs, &ignore_keys, config.current_host, &options.write, &self.planned);
+
+                if options.no_create && !config.create {
+                    writes = writes.and_then(|writes| check_no_create(writes, config.current_host));
+                }
+
+                writes = writes.map(|writes| check_managed(writes, options.skip_managed));
+
+                let drift_ignore = domain_setting(&config.drift_ignore, &domain).cloned();
+
+                DomainPlan {
+                    drift_ignore,
+                    planning_time: started.elapsed(),
+                    state_cache,
+                    ..self.domain_plan(domain, writes)
+                }
+            })
+            .collect();
+
+        let mut plan = DocumentPlan {
+            source: path.to_owned(),
+            description: config.description,
+            sudo: config.sudo,
+            domains,
+            quit: Vec::new(),
+            relaunch: config.relaunch,
+            kill: Vec::new(),
+        };
+
+        if plan.changes_anything() {
+            plan.quit = config.quit;
+            plan.kill = config.kill.iter().flatten().chain(&options.kill).unique().cloned().collect();
+        }
+
+        Ok(plan)
+    }
+
+    /// Plan a raw `.plist` file, which replaces the whole plist of the domain it's named after,
+    /// other than the keys the config file's `ignore_keys` has for it.
+    pub fn plan_plist_file(&mut self, path: &Utf8Path, options: &ApplyOptions) -> Result<DocumentPlan> {
+        //
+        let started = Instant::now();
+        let value = read_plist(path)?;
+
+        let domain = raw_plist_domain(path)?;
+        let ignore_keys = domain_setting(&options.ignore_keys, domain.as_str()).map(Vec::as_slice).unwrap_or_default();
+        let writes = plan_domain_replace(&domain, &value, ignore_keys, raw_plist_current_host(path), &options.write, &self.planned);
+
+        let domain_plan = DomainPlan {
+            planning_time: started.elapsed(),
+            ..self.domain_plan(domain.to_string(), writes)
+        };
+
+        let mut plan = DocumentPlan {
+            source: path.to_owned(),
+            description: None,
+            sudo: false,
+            domains: vec![domain_plan],
+            quit: Vec::new(),
+            relaunch: false,
+            kill: Vec::new(),
+        };
+
+        if plan.changes_anything() {
+            plan.kill.clone_from(&options.kill);
+        }
+
+        Ok(plan)
+    }
+
+    /// The contents of each plist after the writes planned so far.
+    pub fn planned(&self) -> &HashMap<Utf8PathBuf, Value> {
+        &self.planned
+    }
+
+    /// Record a domain's planned writes for later documents to be planned against.
+    fn domain_plan(&mut self, domain: String, writes: Result<Vec<PlistWrite>>) -> DomainPlan {
+        match writes {
+            Ok(writes) => {
+                for write in &writes {
+                    self.planned.insert(write.path.clone(), write.value.clone());
+                }
+
+                DomainPlan {
+                    domain,
+                    writes,
+                    error: None,
+                    drift_ignore: None,
+                    planning_time: Duration::ZERO,
+                    state_cache: None,
+                }
+            }
+            Err(error) => DomainPlan {
+                domain,
+                writes: Vec::new(),
+                error: Some(error),
+                drift_ignore: None,
+                planning_time: Duration::ZERO,
+                state_cache: None,
+            },
+        }
+    }
+}
+
+/// The apply options that change what's planned for a document, for its state cache key.
+fn planning_options(options: &ApplyOptions) -> String {
+    format!(
+        "backend={:?} symlinks={:?} also_write_unsandboxed={} layered_read={} repair={} no_create={} skip_managed={} ignore_keys={:?}",
+        options.write.backend,
+        options.write.symlinks,
+        options.write.also_write_unsandboxed,
+        options.write.layered_read,
+        options.write.repair,
+        options.no_create,
+        options.skip_managed,
+        options.ignore_keys.iter().sorted().collect::<Vec<_>>()
+    )
+}
+
+/// Serialize an error as its chain of messages.
+#[allow(clippy::ref_option)] // serde passes fields by reference.
+fn serialize_error<S: serde::Serializer>(error: &Option<Report>, serializer: S) -> Result<S::Ok, S::Error> {
+    match error {
+        Some(error) => serializer.serialize_str(&error.chain().join(": ")),
+        None => serializer.serialize_none(),
+    }
+}
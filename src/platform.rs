@@ -0,0 +1,83 @@
+//! Startup check that this is a macOS version whose preference layout we know: `ByHost`, sandbox
+//! containers and `cfprefsd` caching as they've been since macOS 11.
+
+use std::env;
+
+use sysinfo::System;
+use tracing::{debug, warn};
+
+use crate::errors::DefaultsError as E;
+
+/// The oldest supported macOS version, as (major, minor).
+pub const MINIMUM_MACOS: (u32, u32) = (11, 0);
+
+/// Fail if this isn't macOS, or is a macOS older than [`MINIMUM_MACOS`]. With `force`, only warn.
+pub fn check_platform(force: bool) -> Result<(), E> {
+    let result = check_version(env::consts::OS, System::os_version().as_deref());
+
+    match result {
+        Err(e) if force => {
+            warn!("{e} Continuing anyway because of --force.");
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+fn check_version(os: &str, version: Option<&str>) -> Result<(), E> {
+    if os != "macos" {
+        return Err(E::UnsupportedOs { os: os.to_owned() });
+    }
+
+    let Some(version) = version else {
+        warn!("Unable to determine the macOS version, assuming it's supported.");
+        return Ok(());
+    };
+
+    debug!("macOS version: {version}");
+
+    match parse_version(version) {
+        Some(parsed) if parsed >= MINIMUM_MACOS => Ok(()),
+        Some(_) => Err(E::UnsupportedMacosVersion {
+            version: version.to_owned(),
+            minimum: format!("{}.{}", MINIMUM_MACOS.0, MINIMUM_MACOS.1),
+        }),
+        None => {
+            warn!("Unable to parse the macOS version {version}, assuming it's supported.");
+            Ok(())
+        }
+    }
+}
+
+/// The major and minor numbers of a version like `14.2.1` or `10.15`.
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.trim().split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().map_or(Some(0), |minor| minor.parse().ok())?;
+
+    Some((major, minor))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_version, parse_version};
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse_version("14.2.1"), Some((14, 2)));
+        assert_eq!(parse_version("10.15"), Some((10, 15)));
+        assert_eq!(parse_version("15"), Some((15, 0)));
+        assert_eq!(parse_version("Sonoma"), None);
+    }
+
+    #[test]
+    fn test_check_version() {
+        assert!(check_version("macos", Some("14.2.1")).is_ok());
+        assert!(check_version("macos", Some("11.0")).is_ok());
+        assert!(check_version("macos", Some("10.15.7")).is_err());
+        assert!(check_version("linux", Some("6.8")).is_err());
+
+        // Unknown versions are given the benefit of the doubt.
+        assert!(check_version("macos", None).is_ok());
+    }
+}
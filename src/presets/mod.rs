@@ -0,0 +1,90 @@
+//! Bundled preset documents, which `apply --preset` layers under your own YAML files, and
+//! `init --preset` writes out as starter files.
+//!
+//! Bump a preset's version whenever its values change, so `preset list` shows which revision
+//! is being applied.
+
+use crate::errors::DefaultsError as E;
+
+/// A named, versioned YAML document stream compiled into the binary.
+#[derive(Debug)]
+pub struct BundledPreset {
+    /// Name used with `apply --preset`, `init --preset` and `preset show`.
+    pub name: &'static str,
+
+    /// Revision of the preset's values.
+    pub version: u32,
+
+    /// One line summary for `preset list`.
+    pub summary: &'static str,
+
+    /// The YAML documents, in the same format as any other YAML file.
+    pub yaml: &'static str,
+}
+
+/// All bundled presets, in alphabetical order.
+pub const PRESETS: &[BundledPreset] = &[
+    BundledPreset {
+        name: "developer-workstation",
+        version: 1,
+        summary: "Fast key repeat, no text substitutions, and a Finder that shows everything.",
+        yaml: include_str!("developer-workstation.yaml"),
+    },
+    BundledPreset {
+        name: "minimal",
+        version: 1,
+        summary: "Uncontroversial fixes most people want, e.g. showing file extensions.",
+        yaml: include_str!("minimal.yaml"),
+    },
+    BundledPreset {
+        name: "privacy-hardening",
+        version: 1,
+        summary: "Less data shared with Apple, iCloud and advertisers, and a locked screen when away.",
+        yaml: include_str!("privacy-hardening.yaml"),
+    },
+];
+
+/// Names of the bundled presets, for argument parsing and completions.
+pub fn preset_names() -> impl Iterator<Item = &'static str> {
+    PRESETS.iter().map(|preset| preset.name)
+}
+
+/// Look up a bundled preset by name.
+pub fn find_preset(name: &str) -> Result<&'static BundledPreset, E> {
+    PRESETS.iter().find(|preset| preset.name == name).ok_or_else(|| E::UnknownPreset {
+        name: name.to_owned(),
+        available: preset_names().collect::<Vec<_>>().join(", "),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{find_preset, PRESETS};
+    use crate::cmd::apply::{load_documents_from_reader, DefaultsConfig};
+    use crate::tags::TagContext;
+
+    #[test]
+    fn test_presets_parse() -> TestResult {
+        for preset in PRESETS {
+            let path = Utf8PathBuf::from(format!("preset:{}", preset.name));
+            let documents = load_documents_from_reader(preset.yaml.as_bytes(), &path)?;
+            assert!(!documents.is_empty(), "{} has no documents", preset.name);
+
+            for document in documents {
+                assert!(document.description.is_some(), "{} has a document without a description", preset.name);
+
+                let data = document.data.ok_or("preset documents have data")?;
+                DefaultsConfig::from_yaml(data, &TagContext::for_yaml_path(&path))?;
+            }
+        }
+
+        assert!(PRESETS.windows(2).all(|pair| pair[0].name < pair[1].name));
+        assert!(find_preset("privacy-hardening").is_ok());
+        assert!(find_preset("nope").is_err());
+
+        Ok(())
+    }
+}
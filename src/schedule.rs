@@ -0,0 +1,239 @@
+//! The order documents are applied in.
+//!
+//! By default that's the order of the files (sorted by name within a directory) and of the
+//! documents within each file. A document can move itself earlier or later with `priority`
+//! (higher first), or wait for other documents with `after`, naming their `id`.
+
+use std::collections::HashSet;
+
+use camino::Utf8PathBuf;
+use color_eyre::eyre::Result;
+use itertools::Itertools;
+use tracing::{debug, warn};
+
+use crate::cmd::apply::{is_plist, load_documents};
+use crate::defaults::MacOSDefaults;
+use crate::errors::DefaultsError as E;
+
+/// A document from a YAML file, or a raw `.plist` file.
+#[derive(Debug)]
+pub enum Document {
+    Yaml(Box<MacOSDefaults>),
+    Plist,
+}
+
+/// A document to apply, and the file it came from.
+#[derive(Debug)]
+pub struct SourceDocument {
+    pub path: Utf8PathBuf,
+    /// Position of the document in its file.
+    pub index: usize,
+    pub document: Document,
+}
+
+impl SourceDocument {
+    fn id(&self) -> Option<&str> {
+        match &self.document {
+            Document::Yaml(config) => config.id.as_deref(),
+            Document::Plist => None,
+        }
+    }
+
+    fn priority(&self) -> i32 {
+        match &self.document {
+            Document::Yaml(config) => config.priority,
+            Document::Plist => 0,
+        }
+    }
+
+    /// Why the document isn't applied, if it's disabled.
+    fn skip_reason(&self) -> Option<&str> {
+        match &self.document {
+            Document::Yaml(config) => config.skip_reason(),
+            Document::Plist => None,
+        }
+    }
+
+    fn after(&self) -> &[String] {
+        match &self.document {
+            Document::Yaml(config) => &config.after,
+            Document::Plist => &[],
+        }
+    }
+
+    /// How the document is named in errors.
+    fn name(&self) -> String {
+        match self.id() {
+            Some(id) => format!("{} ({id})", self.path),
+            None => format!("{} document {}", self.path, self.index + 1),
+        }
+    }
+}
+
+/// Read the documents of every file, in the order they're applied, leaving out disabled ones.
+///
+/// Disabled documents are still scheduled, so documents `after` them don't fail on an unknown id.
+pub fn load_scheduled(files: &[Utf8PathBuf]) -> Result<Vec<SourceDocument>> {
+    //
+    let mut documents = Vec::new();
+
+    for path in files {
+        if is_plist(path) {
+            documents.push(SourceDocument {
+                path: path.clone(),
+                index: 0,
+                document: Document::Plist,
+            });
+            continue;
+        }
+
+        for (index, config) in load_documents(path)?.into_iter().enumerate() {
+            documents.push(SourceDocument {
+                path: path.clone(),
+                index,
+                document: Document::Yaml(Box::new(config)),
+            });
+        }
+    }
+
+    let documents = schedule(documents)?
+        .into_iter()
+        .filter(|document| match document.skip_reason() {
+            Some(reason) => {
+                warn!("Skipping {}: {reason}", document.name());
+                false
+            }
+            None => true,
+        })
+        .collect();
+
+    Ok(documents)
+}
+
+/// Order documents so each comes after the documents its `after` names, picking the highest
+/// `priority` of those that are ready, and otherwise keeping the given order.
+///
+/// Several documents may share an `id`; `after` then waits for all of them.
+pub fn schedule(documents: Vec<SourceDocument>) -> Result<Vec<SourceDocument>, E> {
+    //
+    let ids: HashSet<&str> = documents.iter().filter_map(SourceDocument::id).collect();
+
+    if let Some((document, id)) = documents
+        .iter()
+        .flat_map(|document| document.after().iter().map(move |id| (document, id)))
+        .find(|(_, id)| !ids.contains(id.as_str()))
+    {
+        return Err(E::UnknownDocumentId {
+            document: document.name(),
+            id: id.clone(),
+        });
+    }
+
+    // For each document, the other documents it has to wait for.
+    let waits_for: Vec<Vec<usize>> = documents
+        .iter()
+        .enumerate()
+        .map(|(i, document)| {
+            documents
+                .iter()
+                .enumerate()
+                .filter(|(j, other)| *j != i && other.id().is_some_and(|id| document.after().iter().any(|after| after == id)))
+                .map(|(j, _)| j)
+                .collect()
+        })
+        .collect();
+
+    let mut done = vec![false; documents.len()];
+    let mut order = Vec::with_capacity(documents.len());
+
+    while order.len() < documents.len() {
+        let next = (0..documents.len())
+            .filter(|&i| !done[i] && waits_for[i].iter().all(|&j| done[j]))
+            // Highest priority first, then earliest.
+            .min_by_key(|&i| (-i64::from(documents[i].priority()), i));
+
+        let Some(next) = next else {
+            let waiting = (0..documents.len()).filter(|&i| !done[i]).map(|i| documents[i].name()).join(", ");
+            return Err(E::DocumentCycle { documents: waiting });
+        };
+
+        done[next] = true;
+        order.push(next);
+    }
+
+    debug!("Document order: {:?}", order);
+
+    let mut documents: Vec<Option<SourceDocument>> = documents.into_iter().map(Some).collect();
+
+    Ok(order.into_iter().filter_map(|i| documents[i].take()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use testresult::TestResult;
+
+    use super::{load_scheduled, schedule, Document, SourceDocument};
+    use crate::defaults::MacOSDefaults;
+
+    fn document(path: &str, yaml: &str) -> TestResult<SourceDocument> {
+        let config: MacOSDefaults = serde_yaml::from_str(yaml)?;
+
+        Ok(SourceDocument {
+            path: Utf8PathBuf::from(path),
+            index: 0,
+            document: Document::Yaml(Box::new(config)),
+        })
+    }
+
+    fn paths(documents: &[SourceDocument]) -> Vec<&str> {
+        documents.iter().map(|document| document.path.as_str()).collect()
+    }
+
+    #[test]
+    fn test_schedule() -> TestResult {
+        let documents = vec![
+            document("apps.yaml", "after: [global]\ndata: {}")?,
+            document("cfprefsd.yaml", "priority: -10\ndata: {}")?,
+            document("dock.yaml", "data: {}")?,
+            document("global.yaml", "id: global\ndata: {}")?,
+            document("keyboard.yaml", "id: global\npriority: 5\ndata: {}")?,
+        ];
+
+        let order = schedule(documents)?;
+        assert_eq!(paths(&order), ["keyboard.yaml", "dock.yaml", "global.yaml", "apps.yaml", "cfprefsd.yaml"]);
+
+        // Without priority or after, the order is unchanged.
+        let documents = vec![document("b.yaml", "data: {}")?, document("a.yaml", "data: {}")?];
+        assert_eq!(paths(&schedule(documents)?), ["b.yaml", "a.yaml"]);
+
+        let documents = vec![document("a.yaml", "after: [missing]\ndata: {}")?];
+        assert!(schedule(documents).is_err());
+
+        let documents = vec![
+            document("a.yaml", "id: a\nafter: [b]\ndata: {}")?,
+            document("b.yaml", "id: b\nafter: [a]\ndata: {}")?,
+        ];
+        assert!(schedule(documents).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_scheduled_skips_disabled() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let file = dir.join("apps.yaml");
+        std::fs::write(
+            &file,
+            "id: base\nenabled: false\ndata: {}\n---\nskip: Breaks the Dock\ndata: {}\n---\nafter: [base]\nenabled: true\ndata: {}\n",
+        )?;
+
+        // Documents after a disabled one are still applied.
+        let documents = load_scheduled(&[file])?;
+        assert_eq!(documents.iter().map(|document| document.index).collect::<Vec<_>>(), [2]);
+
+        Ok(())
+    }
+}
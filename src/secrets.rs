@@ -0,0 +1,245 @@
+//! Decrypting `!secret` values: [age](https://age-encryption.org) ciphertexts in ASCII armor, so
+//! tokens and license keys can be kept in dotfiles without being readable there.
+//!
+//! Encrypt a value with e.g. `echo -n "$TOKEN" | age -a -r <recipient>`. It's decrypted with the
+//! `age` command at apply time, with the first identity found in:
+//!
+//! 1. The file `$MACOS_DEFAULTS_AGE_IDENTITY` names.
+//! 2. `$XDG_CONFIG_HOME/macos-defaults/age.key` (or `~/.config/macos-defaults/age.key`).
+//! 3. The keychain's generic password with service `macos-defaults` and account `age-identity`.
+//!
+//! `!keychain <service>/<account>` values aren't in the YAML at all, they're read from the
+//! keychain's generic passwords with [`find_generic_password`].
+//!
+//! Resolved values are [remembered](remember), so anything that shows values, like the change log,
+//! `plan` or reports, shows [`REDACTED`] instead of them.
+
+use std::borrow::Cow;
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::Mutex;
+
+use camino::Utf8PathBuf;
+use duct::cmd;
+use plist::Value;
+use tracing::debug;
+
+use crate::config::config_dir;
+use crate::errors::DefaultsError as E;
+
+/// Environment variable naming an age identity file.
+const IDENTITY_ENV: &str = "MACOS_DEFAULTS_AGE_IDENTITY";
+
+/// Keychain service and account of the age identity.
+const KEYCHAIN_SERVICE: &str = "macos-defaults";
+const KEYCHAIN_ACCOUNT: &str = "age-identity";
+
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+const ARMOR_END: &str = "-----END AGE ENCRYPTED FILE-----";
+
+/// Shown in place of secret values.
+pub const REDACTED: &str = "<redacted>";
+
+/// The identity, once found, so the keychain is only asked once per run.
+static IDENTITY: Mutex<Option<String>> = Mutex::new(None);
+
+/// The secret values resolved so far.
+static RESOLVED: Mutex<Vec<Value>> = Mutex::new(Vec::new());
+
+/// Remember a resolved secret value, so it's never shown.
+pub(crate) fn remember(value: Value) -> Value {
+    // An empty secret would redact every empty string.
+    if !matches!(&value, Value::String(s) if s.is_empty()) {
+        RESOLVED.lock().unwrap_or_else(std::sync::PoisonError::into_inner).push(value.clone());
+    }
+
+    value
+}
+
+/// Whether a value is, or contains, a resolved secret value.
+pub fn contains_secret(value: &Value) -> bool {
+    let resolved = RESOLVED.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+
+    !resolved.is_empty() && contains_any(value, &resolved)
+}
+
+fn contains_any(value: &Value, secrets: &[Value]) -> bool {
+    secrets.contains(value)
+        || match value {
+            Value::Array(array) => array.iter().any(|value| contains_any(value, secrets)),
+            Value::Dictionary(dict) => dict.values().any(|value| contains_any(value, secrets)),
+            _ => false,
+        }
+}
+
+/// A value to show: [`REDACTED`] if it is, or contains, a secret value.
+pub fn redact(value: &Value) -> Cow<'_, Value> {
+    if contains_secret(value) {
+        Cow::Owned(Value::String(REDACTED.to_owned()))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Whether a string is an ASCII armored age ciphertext.
+pub fn is_armored(ciphertext: &str) -> bool {
+    let ciphertext = ciphertext.trim();
+    ciphertext.starts_with(ARMOR_BEGIN) && ciphertext.ends_with(ARMOR_END)
+}
+
+/// Decrypt an armored age ciphertext: a `String` if the plaintext is UTF-8, `Data` otherwise.
+pub fn decrypt(ciphertext: &str) -> Result<Value, E> {
+    let identity = identity()?;
+
+    // The identity is passed on stdin, so it's never written to disk; the ciphertext goes in a
+    // temporary file, as `age` only reads one of them from stdin. It's created with a random name,
+    // readable only by the current user, and removed when it's dropped.
+    let mut ciphertext_file = tempfile::Builder::new()
+        .prefix("macos-defaults-secret-")
+        .suffix(".age")
+        .permissions(fs::Permissions::from_mode(0o600))
+        .tempfile()
+        .map_err(|e| E::SecretDecrypt { source: e })?;
+
+    ciphertext_file
+        .write_all(ciphertext.trim().as_bytes())
+        .map_err(|e| E::SecretDecrypt { source: e })?;
+
+    let plaintext = cmd!("age", "--decrypt", "--identity", "-", ciphertext_file.path())
+        .stdin_bytes(identity)
+        .stdout_capture()
+        .stderr_capture()
+        .run()
+        .map_err(|e| E::SecretDecrypt { source: e })?
+        .stdout;
+
+    Ok(remember(match String::from_utf8(plaintext) {
+        Ok(plaintext) => Value::String(plaintext),
+        Err(e) => Value::Data(e.into_bytes()),
+    }))
+}
+
+fn identity() -> Result<String, E> {
+    let mut cached = IDENTITY.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+
+    if let Some(identity) = cached.as_ref() {
+        return Ok(identity.clone());
+    }
+
+    let default_path = config_dir().map_err(|e| E::EyreError { source: e })?.join("age.key");
+
+    let identity = identity_from_files(env::var(IDENTITY_ENV).ok().map(Utf8PathBuf::from), &default_path)?
+        .or_else(|| find_generic_password(KEYCHAIN_SERVICE, KEYCHAIN_ACCOUNT))
+        .ok_or(E::NoSecretIdentity { path: default_path })?;
+
+    *cached = Some(identity.clone());
+
+    Ok(identity)
+}
+
+/// The identity in the file named by the environment, which has to exist if it's set, or in the
+/// default file if there is one.
+fn identity_from_files(env_path: Option<Utf8PathBuf>, default_path: &Utf8PathBuf) -> Result<Option<String>, E> {
+    let path = match env_path {
+        Some(path) if !path.as_str().is_empty() => path,
+        _ if default_path.exists() => default_path.clone(),
+        _ => return Ok(None),
+    };
+
+    debug!("Reading age identity from {path}");
+
+    fs::read_to_string(&path).map(Some).map_err(|e| E::FileRead { path, source: e })
+}
+
+/// The password of a generic password item in the keychain, with `security find-generic-password`,
+/// if there is one.
+pub fn find_generic_password(service: &str, account: &str) -> Option<String> {
+    let output = cmd!("security", "find-generic-password", "-s", service, "-a", account, "-w")
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    debug!("Read keychain item {service}/{account}");
+
+    // `security` adds a newline.
+    String::from_utf8(output.stdout).ok().map(|password| password.trim_end_matches('\n').to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::{contains_secret, identity_from_files, is_armored, redact, remember, REDACTED};
+    use crate::defaults::KeyChange;
+
+    #[test]
+    fn test_is_armored() {
+        assert!(is_armored(
+            "-----BEGIN AGE ENCRYPTED FILE-----\nYWdlLWVuY3J5cHRpb24ub3JnL3Yx\n-----END AGE ENCRYPTED FILE-----\n"
+        ));
+        assert!(!is_armored("hunter2"));
+        assert!(!is_armored("-----BEGIN AGE ENCRYPTED FILE-----\nYWdlLWVuY3J5cHRpb24ub3JnL3Yx\n"));
+    }
+
+    #[test]
+    fn test_identity_from_files() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let default_path = dir.join("age.key");
+        assert_eq!(identity_from_files(None, &default_path)?, None);
+
+        std::fs::write(&default_path, "AGE-SECRET-KEY-DEFAULT\n")?;
+        assert_eq!(identity_from_files(None, &default_path)?.as_deref(), Some("AGE-SECRET-KEY-DEFAULT\n"));
+
+        // The environment takes precedence, and has to name a file that exists.
+        let env_path = dir.join("other.key");
+        assert!(identity_from_files(Some(env_path.clone()), &default_path).is_err());
+
+        std::fs::write(&env_path, "AGE-SECRET-KEY-OTHER\n")?;
+        assert_eq!(identity_from_files(Some(env_path), &default_path)?.as_deref(), Some("AGE-SECRET-KEY-OTHER\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_redact() -> TestResult {
+        let secret = remember(Value::from("test-redact-token"));
+        let nested = Value::Dictionary(Dictionary::from_iter([("token", Value::Array(vec![secret.clone()]))]));
+
+        assert!(contains_secret(&nested));
+        assert!(!contains_secret(&Value::from("test-redact-other")));
+        assert_eq!(*redact(&nested), Value::from(REDACTED));
+        assert_eq!(*redact(&Value::from(1)), Value::from(1));
+
+        // Empty secrets aren't remembered, or every empty string would be redacted.
+        remember(Value::from(""));
+        assert!(!contains_secret(&Value::from("")));
+
+        let change = KeyChange::new("token".to_owned(), Some(Value::from("old")), secret);
+        let (old_value, new_value) = change.shown_values();
+        assert!(change.redacted);
+        assert_eq!(old_value.as_deref(), Some(&Value::from(REDACTED)));
+        assert_eq!(*new_value, Value::from(REDACTED));
+        assert_eq!(
+            serde_json::to_string(&change)?,
+            r#"{"key":"token","old_value":"<redacted>","new_value":"<redacted>"}"#
+        );
+
+        let change = KeyChange::new("token".to_owned(), None, Value::from(1));
+        assert!(!change.redacted);
+        assert_eq!(change.shown_values().0, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,216 @@
+//! `apply --lazy-read`: a cache of the domains the last applies found (or left) in sync, so a
+//! domain whose YAML and plists haven't changed since isn't read and planned again.
+//!
+//! Kept in `$XDG_STATE_HOME/macos-defaults/state-cache.plist`, as a dictionary of YAML files to
+//! their domains: `{file: {key: {path: {modified, size}}}}`. The key is a hash of the domain's
+//! document, its name and the apply options that change what's planned, so editing the YAML
+//! misses the cache; a plist with another modification time or size misses it too.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+
+use crate::defaults::MacOSDefaults;
+use crate::errors::DefaultsError as E;
+use crate::fingerprints::file_stamp;
+use crate::manifest::{absolute, state_dir};
+
+/// The plists a domain read when it was last applied, with what they looked like then.
+type DomainStamps = BTreeMap<Utf8PathBuf, PlistStamp>;
+
+/// YAML files to the domains of theirs in sync, by key.
+type Files = BTreeMap<Utf8PathBuf, BTreeMap<String, DomainStamps>>;
+
+/// The domains in sync as of the last applies, and the ones used by this one.
+#[derive(Debug, Default)]
+pub struct StateCache {
+    files: RefCell<Files>,
+    /// The keys of each file checked or recorded this run. The file's other keys are dropped on
+    /// save, as its YAML has changed since.
+    used: RefCell<BTreeMap<Utf8PathBuf, BTreeSet<String>>>,
+}
+
+/// What a plist looked like when its domain was last applied.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct PlistStamp {
+    /// Nanoseconds since the epoch, zero if it didn't exist.
+    modified: u64,
+    size: u64,
+}
+
+impl PlistStamp {
+    /// The plist's current modification time and size.
+    fn take(path: &Utf8Path) -> Self {
+        let (modified, size) = file_stamp(path);
+        Self { modified, size }
+    }
+}
+
+impl StateCache {
+    /// Read the cache, or start an empty one if nothing has been applied with it yet.
+    pub fn load() -> Result<Self> {
+        Self::load_from(&cache_path()?)
+    }
+
+    fn load_from(path: &Utf8Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let files = plist::from_file(path).map_err(|e| E::PlistRead {
+            path: path.to_owned(),
+            source: e,
+        })?;
+
+        Ok(Self {
+            files: RefCell::new(files),
+            used: RefCell::default(),
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.save_to(&cache_path()?)
+    }
+
+    fn save_to(&self, path: &Utf8Path) -> Result<()> {
+        debug!("Saving the state cache to {path}");
+
+        let mut files = self.files.borrow_mut();
+
+        for (file, used) in &*self.used.borrow() {
+            if let Some(keys) = files.get_mut(file) {
+                keys.retain(|key, _| used.contains(key));
+            }
+        }
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| E::DirCreation {
+                path: parent.to_owned(),
+                source: e,
+            })?;
+        }
+
+        plist::to_file_binary(path, &*files).map_err(|e| E::PlistWrite {
+            path: path.to_owned(),
+            source: e,
+        })?;
+
+        Ok(())
+    }
+
+    /// Whether the domain `key` of `file` was in sync when it was last applied, and its plists
+    /// haven't changed since.
+    pub fn is_fresh(&self, file: &Utf8Path, key: &str) -> bool {
+        let file = absolute(file);
+        self.used.borrow_mut().entry(file.clone()).or_default().insert(key.to_owned());
+
+        self.files
+            .borrow()
+            .get(&file)
+            .and_then(|keys| keys.get(key))
+            .is_some_and(|plists| plists.iter().all(|(path, stamp)| PlistStamp::take(path) == *stamp))
+    }
+
+    /// Record that the domain `key` of `file` is in sync, as of its plists' current state.
+    pub fn record(&self, file: &Utf8Path, key: &str, plists: &[Utf8PathBuf]) {
+        let file = absolute(file);
+        trace!("Recording {key} of {file} as in sync");
+
+        let stamps = plists.iter().map(|path| (path.clone(), PlistStamp::take(path))).collect();
+
+        self.used.borrow_mut().entry(file.clone()).or_default().insert(key.to_owned());
+        self.files.borrow_mut().entry(file).or_default().insert(key.to_owned(), stamps);
+    }
+}
+
+/// Hash of a document, to key its domains with [`domain_key`], or `None` if its values have YAML
+/// tags like `!file` or `!now`, which can resolve to something else without the YAML changing.
+/// `options` are the apply options that change what's planned.
+pub fn document_hash(config: &MacOSDefaults, options: &str) -> Option<String> {
+    if config.data.as_ref().is_some_and(has_tags) {
+        return None;
+    }
+
+    // As JSON, whose maps are sorted, rather than YAML, whose maps are in `HashMap` order.
+    let json = serde_json::to_value(config).ok()?;
+    Some(hex::encode(Sha256::digest(format!("{options}\n{json}"))))
+}
+
+/// Cache key of a domain of the document with `document_hash`.
+pub fn domain_key(document_hash: &str, domain: &str) -> String {
+    hex::encode(Sha256::digest(format!("{document_hash}\n{domain}")))
+}
+
+fn has_tags(value: &serde_yaml::Value) -> bool {
+    match value {
+        serde_yaml::Value::Tagged(_) => true,
+        serde_yaml::Value::Sequence(sequence) => sequence.iter().any(has_tags),
+        serde_yaml::Value::Mapping(mapping) => mapping.iter().any(|(key, value)| has_tags(key) || has_tags(value)),
+        _ => false,
+    }
+}
+
+fn cache_path() -> Result<Utf8PathBuf> {
+    Ok(state_dir()?.join("state-cache.plist"))
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::{document_hash, StateCache};
+    use crate::defaults::MacOSDefaults;
+
+    #[test]
+    fn test_state_cache() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let plist = dir.join("com.example.app.plist");
+        let yaml = dir.join("app.yaml");
+        Value::from(1).to_file_binary(&plist)?;
+
+        let cache = StateCache::default();
+        assert!(!cache.is_fresh(&yaml, "a"));
+
+        cache.record(&yaml, "a", std::slice::from_ref(&plist));
+        cache.record(&yaml, "b", std::slice::from_ref(&plist));
+        assert!(cache.is_fresh(&yaml, "a"));
+
+        cache.save_to(&dir.join("state-cache.plist"))?;
+
+        let cache = StateCache::load_from(&dir.join("state-cache.plist"))?;
+        assert!(cache.is_fresh(&yaml, "a"));
+        cache.save_to(&dir.join("state-cache.plist"))?;
+
+        // Keys of the file not used in the run that saved the cache are dropped.
+        let cache = StateCache::load_from(&dir.join("state-cache.plist"))?;
+        assert!(!cache.is_fresh(&yaml, "b"));
+        assert!(cache.is_fresh(&yaml, "a"));
+
+        Value::from(1000).to_file_binary(&plist)?;
+        assert!(!cache.is_fresh(&yaml, "a"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_document_hash() -> TestResult {
+        let plain: MacOSDefaults = serde_yaml::from_str("data:\n  com.example.app:\n    a: 1\n")?;
+        let tagged: MacOSDefaults = serde_yaml::from_str("data:\n  com.example.app:\n    a: !uuid\n")?;
+
+        assert!(document_hash(&plain, "").is_some());
+        assert_ne!(document_hash(&plain, ""), document_hash(&plain, "layered_read"));
+        assert!(document_hash(&tagged, "").is_none());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,527 @@
+//! YAML tags that are resolved into plist values at apply time.
+//!
+//! - `!file <path>`: the contents of a file, relative to the YAML file, as `Data`.
+//!   `!file {path: <path>, sha256: <hex>}` also checks the file's SHA-256 digest.
+//! - `!uuid`: a new random UUID string, generated on every apply.
+//! - `!uuid-stable <seed>`: a UUID string derived from the seed, so it's the same on every apply.
+//! - `!now`, `!now+7d`, `!now-1h`: a `Date` relative to the time of the apply (units: `s`, `m`, `h`,
+//!   `d`, `w`). `!now+7d {if_unset: true}` only sets the key if it doesn't already have a value.
+//! - `!once <value>`: only set the key if it doesn't already have a value.
+//! - `!array-add [...]`: append to the existing array, like `defaults write -array-add`.
+//! - `!dict-add {...}`: add keys to the existing dictionary, like `defaults write -dict-add`.
+//! - `!uid <n>`: a keyed archive `Uid`, as written by `dump`.
+//! - `!secret <ciphertext>`: an ASCII armored age ciphertext, decrypted at apply time. See
+//!   [`secrets`](crate::secrets).
+//! - `!keychain <service>/<account>`: the password of a generic password item in the keychain.
+//!   `!keychain {service: <service>, account: <account>}` for services containing a `/`.
+//!
+//! Tags of the [`transformers`](crate::transformers) registry, like `!color`, are resolved after these.
+
+use std::fs;
+use std::time::{Duration, SystemTime};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use plist::Value;
+use serde::Deserialize;
+use serde_yaml::value::TaggedValue;
+use sha2::{Digest, Sha256};
+use tracing::trace;
+use uuid::Uuid;
+
+use crate::defaults::{if_unset, ARRAY_ADD, DICT_ADD, FILE_TAG, UID_TAG};
+use crate::errors::DefaultsError as E;
+use crate::secrets;
+use crate::transformers::transformers;
+
+/// YAML tag for a random UUID.
+const UUID_TAG: &str = "!uuid";
+/// YAML tag for a UUID derived from a seed.
+const UUID_STABLE_TAG: &str = "!uuid-stable";
+
+/// YAML tag for the current time, optionally followed by an offset like `+7d`.
+const NOW_TAG: &str = "!now";
+
+/// YAML tag for a value that's only set if the key doesn't already have one.
+const ONCE_TAG: &str = "!once";
+
+/// YAML tag for an encrypted value.
+const SECRET_TAG: &str = "!secret";
+
+/// YAML tag for a keychain password.
+const KEYCHAIN_TAG: &str = "!keychain";
+
+/// The tags resolved here, with the kinds of YAML node each takes (`scalar`, `sequence` or
+/// `mapping`), as editors like the YAML language server want them declared.
+pub const VALUE_TAGS: &[(&str, &[&str])] = &[
+    (FILE_TAG, &["scalar", "mapping"]),
+    (UUID_TAG, &["scalar"]),
+    (UUID_STABLE_TAG, &["scalar"]),
+    (NOW_TAG, &["scalar", "mapping"]),
+    (ONCE_TAG, &["scalar", "sequence", "mapping"]),
+    (ARRAY_ADD, &["sequence"]),
+    (DICT_ADD, &["mapping"]),
+    (UID_TAG, &["scalar"]),
+    (SECRET_TAG, &["scalar"]),
+    (KEYCHAIN_TAG, &["scalar", "mapping"]),
+];
+
+/// Namespace for `!uuid-stable` UUIDs, so the same seed used by other tools gives a different UUID.
+const UUID_STABLE_NAMESPACE: Uuid = Uuid::from_u128(0x6d61_636f_732d_4465_6661_756c_7473_2d31);
+
+/// Where tagged values are being resolved from.
+#[derive(Debug)]
+pub struct TagContext {
+    /// Directory that relative paths in tags are resolved against.
+    pub base_dir: Utf8PathBuf,
+}
+
+impl TagContext {
+    /// Context for a YAML file, or stdin if the path is `-`.
+    pub fn for_yaml_path(path: &Utf8Path) -> Self {
+        let base_dir = match path.parent() {
+            Some(parent) if path != "-" => parent.to_path_buf(),
+            _ => Utf8PathBuf::from("."),
+        };
+
+        Self { base_dir }
+    }
+}
+
+/// Convert a YAML value to a plist value, resolving any tags within it.
+pub fn yaml_to_plist(value: serde_yaml::Value, context: &TagContext) -> Result<Value, E> {
+    match value {
+        serde_yaml::Value::Tagged(tagged) => resolve_tag(*tagged, context),
+        serde_yaml::Value::Sequence(sequence) => Ok(Value::Array(sequence.into_iter().map(|v| yaml_to_plist(v, context)).collect::<Result<_, _>>()?)),
+        serde_yaml::Value::Mapping(mapping) => Ok(Value::Dictionary(
+            mapping
+                .into_iter()
+                .map(|(k, v)| {
+                    let key: String = serde_yaml::from_value(k).map_err(|e| E::DeserializationFailed { source: e })?;
+                    Ok((key, yaml_to_plist(v, context)?))
+                })
+                .collect::<Result<_, E>>()?,
+        )),
+        value => serde_yaml::from_value(value).map_err(|e| E::DeserializationFailed { source: e }),
+    }
+}
+
+fn resolve_tag(tagged: TaggedValue, context: &TagContext) -> Result<Value, E> {
+    let TaggedValue { tag, value } = tagged;
+
+    trace!("Resolving tag {tag}: {value:?}");
+
+    if tag == FILE_TAG {
+        let FileTag { path, sha256 } = match value {
+            serde_yaml::Value::String(path) => FileTag { path, sha256: None },
+            value => serde_yaml::from_value(value).map_err(|_| E::InvalidTagValue {
+                tag: FILE_TAG,
+                expected: "a path, or a mapping with `path` and an optional `sha256`",
+            })?,
+        };
+        let path = context.base_dir.join(path);
+
+        let bytes = fs::read(&path).map_err(|e| E::FileRead { path: path.clone(), source: e })?;
+
+        if let Some(expected) = sha256 {
+            verify_sha256(&path, &bytes, &expected)?;
+        }
+
+        return Ok(Value::Data(bytes));
+    }
+
+    if tag == UUID_TAG {
+        if !matches!(value, serde_yaml::Value::Null) && value.as_str() != Some("") {
+            return Err(E::InvalidTagValue {
+                tag: UUID_TAG,
+                expected: "no value",
+            });
+        }
+
+        return Ok(Value::String(format_uuid(Uuid::new_v4())));
+    }
+
+    if tag == UUID_STABLE_TAG {
+        let seed = match value {
+            serde_yaml::Value::String(seed) if !seed.is_empty() => seed,
+            serde_yaml::Value::Number(seed) => seed.to_string(),
+            _ => {
+                return Err(E::InvalidTagValue {
+                    tag: UUID_STABLE_TAG,
+                    expected: "a seed string",
+                })
+            }
+        };
+
+        return Ok(Value::String(format_uuid(Uuid::new_v5(&UUID_STABLE_NAMESPACE, seed.as_bytes()))));
+    }
+
+    if tag == UID_TAG {
+        return value.as_u64().map(|uid| Value::Uid(plist::Uid::new(uid))).ok_or(E::InvalidTagValue {
+            tag: UID_TAG,
+            expected: "an unsigned integer",
+        });
+    }
+
+    if tag == SECRET_TAG {
+        return resolve_secret(&value);
+    }
+
+    if tag == KEYCHAIN_TAG {
+        return resolve_keychain(value);
+    }
+
+    if tag == ONCE_TAG {
+        return Ok(if_unset(yaml_to_plist(value, context)?));
+    }
+
+    if tag == ARRAY_ADD || tag == DICT_ADD {
+        let (operator, value) = match value {
+            serde_yaml::Value::Sequence(_) if tag == ARRAY_ADD => (ARRAY_ADD, value),
+            serde_yaml::Value::Mapping(_) if tag == DICT_ADD => (DICT_ADD, value),
+            _ if tag == ARRAY_ADD => {
+                return Err(E::InvalidTagValue {
+                    tag: ARRAY_ADD,
+                    expected: "a sequence",
+                })
+            }
+            _ => {
+                return Err(E::InvalidTagValue {
+                    tag: DICT_ADD,
+                    expected: "a mapping",
+                })
+            }
+        };
+
+        return Ok(Value::Dictionary(plist::Dictionary::from_iter([(operator, yaml_to_plist(value, context)?)])));
+    }
+
+    if let Some(transformer) = transformers().get(&tag) {
+        return transformer.expand(value);
+    }
+
+    let tag = tag.to_string();
+
+    if let Some(offset) = tag.strip_prefix(NOW_TAG) {
+        let NowTag { if_unset: once } = match value {
+            serde_yaml::Value::Null => NowTag::default(),
+            serde_yaml::Value::String(s) if s.is_empty() => NowTag::default(),
+            value => serde_yaml::from_value(value).map_err(|_| E::InvalidTagValue {
+                tag: NOW_TAG,
+                expected: "no value, or a mapping with `if_unset`",
+            })?,
+        };
+
+        let date = Value::Date(now_with_offset(offset).ok_or(E::UnknownTag { tag: tag.clone() })?.into());
+
+        return Ok(if once { if_unset(date) } else { date });
+    }
+
+    Err(E::UnknownTag { tag })
+}
+
+/// Decrypt the armored age ciphertext of a `!secret` tag.
+fn resolve_secret(value: &serde_yaml::Value) -> Result<Value, E> {
+    match value.as_str() {
+        Some(ciphertext) if secrets::is_armored(ciphertext) => secrets::decrypt(ciphertext),
+        _ => Err(E::InvalidTagValue {
+            tag: SECRET_TAG,
+            expected: "an ASCII armored age ciphertext",
+        }),
+    }
+}
+
+/// Read the keychain item of a `!keychain` tag.
+fn resolve_keychain(value: serde_yaml::Value) -> Result<Value, E> {
+    let KeychainTag { service, account } = match value {
+        serde_yaml::Value::String(item) => item
+            .rsplit_once('/')
+            .filter(|(service, account)| !service.is_empty() && !account.is_empty())
+            .map(|(service, account)| KeychainTag {
+                service: service.to_owned(),
+                account: account.to_owned(),
+            }),
+        value => serde_yaml::from_value(value).ok(),
+    }
+    .ok_or(E::InvalidTagValue {
+        tag: KEYCHAIN_TAG,
+        expected: "`<service>/<account>`, or a mapping with `service` and `account`",
+    })?;
+
+    secrets::find_generic_password(&service, &account)
+        .map(|password| secrets::remember(Value::String(password)))
+        .ok_or(E::KeychainItemNotFound { service, account })
+}
+
+/// The current time, moved by an offset like `+7d` or `-1h`, or an empty string for no offset.
+fn now_with_offset(offset: &str) -> Option<SystemTime> {
+    let now = SystemTime::now();
+
+    if offset.is_empty() {
+        return Some(now);
+    }
+
+    // Split after the sign as a character, as it may not be ASCII in a malformed tag.
+    let sign = offset.chars().next()?;
+    let amount = &offset[sign.len_utf8()..];
+    let (number, unit) = amount.split_at(amount.char_indices().find(|(_, c)| !c.is_ascii_digit())?.0);
+
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+
+    let seconds = number.parse::<u64>().ok()?.checked_mul(unit_seconds)?;
+
+    match sign {
+        '+' => now.checked_add(Duration::from_secs(seconds)),
+        '-' => now.checked_sub(Duration::from_secs(seconds)),
+        _ => None,
+    }
+}
+
+/// The long form of a `!now` tag.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NowTag {
+    /// Keep the existing value of the key, if it has one.
+    #[serde(default)]
+    if_unset: bool,
+}
+
+/// UUIDs are written in uppercase, as macOS apps (via `NSUUID`) write them.
+fn format_uuid(uuid: Uuid) -> String {
+    uuid.hyphenated().to_string().to_uppercase()
+}
+
+/// The long form of a `!file` tag.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileTag {
+    path: String,
+    sha256: Option<String>,
+}
+
+/// The long form of a `!keychain` tag.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeychainTag {
+    service: String,
+    account: String,
+}
+
+/// Fail if the contents of an asset don't match its pinned SHA-256 digest.
+fn verify_sha256(path: &Utf8Path, bytes: &[u8], expected: &str) -> Result<(), E> {
+    let actual = hex::encode(Sha256::digest(bytes));
+
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(E::ChecksumMismatch {
+            path: path.to_path_buf(),
+            expected: expected.to_owned(),
+            actual,
+        });
+    }
+
+    trace!("SHA-256 of {path} matches: {actual}");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::{yaml_to_plist, TagContext};
+    use crate::errors::DefaultsError as E;
+
+    #[test]
+    fn test_file_tag() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let base_dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+        std::fs::create_dir_all(base_dir.join("blobs"))?;
+        std::fs::write(base_dir.join("blobs/toolbar.bin"), b"\x00\x01binary")?;
+
+        let yaml: serde_yaml::Value = serde_yaml::from_str("toolbar: !file ./blobs/toolbar.bin\nsize: 36\n")?;
+        let value = yaml_to_plist(yaml, &TagContext { base_dir: base_dir.clone() })?;
+
+        let dict = value.as_dictionary().ok_or("expected a dictionary")?;
+        assert_eq!(dict.get("toolbar"), Some(&Value::Data(b"\x00\x01binary".to_vec())));
+        assert_eq!(dict.get("size"), Some(&Value::from(36)));
+
+        let sha256 = hex::encode(<sha2::Sha256 as sha2::Digest>::digest(b"\x00\x01binary"));
+        let pinned: serde_yaml::Value = serde_yaml::from_str(&format!("!file {{path: blobs/toolbar.bin, sha256: {sha256}}}"))?;
+        assert_eq!(
+            yaml_to_plist(pinned, &TagContext { base_dir: base_dir.clone() })?,
+            Value::Data(b"\x00\x01binary".to_vec())
+        );
+
+        let mismatch: serde_yaml::Value = serde_yaml::from_str("!file {path: blobs/toolbar.bin, sha256: deadbeef}")?;
+        assert!(yaml_to_plist(mismatch, &TagContext { base_dir: base_dir.clone() }).is_err());
+
+        let unknown: serde_yaml::Value = serde_yaml::from_str("key: !nope value")?;
+        assert!(yaml_to_plist(unknown, &TagContext { base_dir: base_dir.clone() }).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uuid_tags() -> TestResult {
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+        let resolve = |yaml: &str| -> TestResult<Value> { Ok(yaml_to_plist(serde_yaml::from_str(yaml)?, &context)?) };
+
+        let random = resolve("!uuid")?;
+        let random = random.as_string().ok_or("expected a string")?;
+        assert_eq!(random.len(), 36);
+        assert_eq!(random, random.to_uppercase());
+        assert_ne!(resolve("!uuid")?, resolve("!uuid")?);
+
+        assert_eq!(resolve("!uuid-stable dock-tile-1")?, resolve("!uuid-stable dock-tile-1")?);
+        assert_ne!(resolve("!uuid-stable dock-tile-1")?, resolve("!uuid-stable dock-tile-2")?);
+
+        assert!(yaml_to_plist(serde_yaml::from_str("!uuid-stable")?, &context).is_err());
+        assert!(yaml_to_plist(serde_yaml::from_str("!uuid value")?, &context).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_now_tags() -> TestResult {
+        use std::time::{Duration, SystemTime};
+
+        use super::now_with_offset;
+
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        let now = SystemTime::now();
+        let in_a_week = now_with_offset("+7d").ok_or("expected a time")?;
+        assert!(in_a_week.duration_since(now)? >= Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(now_with_offset("-1h").ok_or("expected a time")? < now);
+        assert!(now_with_offset("+7x").is_none());
+        assert!(now_with_offset("7d").is_none());
+        assert!(now_with_offset("é7d").is_none());
+        assert!(now_with_offset("+7é").is_none());
+        assert!(now_with_offset(&format!("+{}w", u64::MAX / 2)).is_none());
+
+        assert!(matches!(yaml_to_plist(serde_yaml::from_str("!now")?, &context)?, Value::Date(_)));
+        assert!(matches!(yaml_to_plist(serde_yaml::from_str("!now+30m")?, &context)?, Value::Date(_)));
+
+        let if_unset = yaml_to_plist(serde_yaml::from_str("!now+7d {if_unset: true}")?, &context)?;
+        let dict = if_unset.as_dictionary().ok_or("expected a dictionary")?;
+        assert!(matches!(dict.get(crate::defaults::IF_UNSET), Some(Value::Date(_))));
+
+        assert!(yaml_to_plist(serde_yaml::from_str("!now+2y")?, &context).is_err());
+        assert!(yaml_to_plist(serde_yaml::from_str("!now {typo: true}")?, &context).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_tags() -> TestResult {
+        use crate::defaults::{ARRAY_ADD, DICT_ADD};
+
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        let array_add = yaml_to_plist(serde_yaml::from_str("!array-add [a, 1]")?, &context)?;
+        assert_eq!(
+            array_add.as_dictionary().and_then(|dict| dict.get(ARRAY_ADD)),
+            Some(&Value::Array(vec!["a".into(), 1.into()]))
+        );
+
+        let dict_add = yaml_to_plist(serde_yaml::from_str("!dict-add {a: 1}")?, &context)?;
+        assert!(dict_add
+            .as_dictionary()
+            .and_then(|dict| dict.get(DICT_ADD))
+            .is_some_and(|v| v.as_dictionary().is_some()));
+
+        assert_eq!(
+            yaml_to_plist(serde_yaml::from_str("!once [a]")?, &context)?,
+            crate::defaults::if_unset(Value::Array(vec!["a".into()]))
+        );
+
+        assert!(yaml_to_plist(serde_yaml::from_str("!array-add {a: 1}")?, &context).is_err());
+        assert!(yaml_to_plist(serde_yaml::from_str("!dict-add [a]")?, &context).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uid_tag() -> TestResult {
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        assert_eq!(yaml_to_plist(serde_yaml::from_str("!uid 7")?, &context)?, Value::Uid(plist::Uid::new(7)));
+
+        assert!(yaml_to_plist(serde_yaml::from_str("!uid -1")?, &context).is_err());
+        assert!(yaml_to_plist(serde_yaml::from_str("!uid seven")?, &context).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_tags() -> TestResult {
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        // Plaintext is refused rather than written as is.
+        assert!(yaml_to_plist(serde_yaml::from_str("!secret hunter2")?, &context).is_err());
+        assert!(yaml_to_plist(serde_yaml::from_str("!secret {ciphertext: x}")?, &context).is_err());
+
+        assert!(matches!(
+            yaml_to_plist(serde_yaml::from_str("!keychain license")?, &context),
+            Err(E::InvalidTagValue { .. })
+        ));
+
+        Ok(())
+    }
+
+    // On macOS this would look the items up in the real keychain.
+    #[test]
+    #[cfg(not(target_os = "macos"))]
+    fn test_keychain_tags() -> TestResult {
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        // There's no keychain here, but the item is parsed first.
+        assert!(matches!(
+            yaml_to_plist(serde_yaml::from_str("!keychain com.example.app/license")?, &context),
+            Err(E::KeychainItemNotFound { service, account }) if service == "com.example.app" && account == "license"
+        ));
+        assert!(matches!(
+            yaml_to_plist(serde_yaml::from_str("!keychain {service: a/b, account: c}")?, &context),
+            Err(E::KeychainItemNotFound { service, .. }) if service == "a/b"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transformer_tag() -> TestResult {
+        let context = TagContext {
+            base_dir: Utf8PathBuf::from("."),
+        };
+
+        let yaml: serde_yaml::Value = serde_yaml::from_str("AppleHighlightColor: !color \"#BF5AF2\"\n")?;
+        let value = yaml_to_plist(yaml, &context)?;
+
+        assert_eq!(
+            value.as_dictionary().and_then(|dict| dict.get("AppleHighlightColor")),
+            Some(&Value::from("0.749020 0.352941 0.949020"))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,221 @@
+//! `apply --transactional`: copies of the plists an apply writes, as they were before its first
+//! write to each, to restore them all if a later domain fails.
+//!
+//! The copies are kept in `$XDG_STATE_HOME/macos-defaults/transactions/{run id}/`, mirroring the
+//! plists' absolute paths, and removed once the apply finishes. An apply that's interrupted
+//! leaves them there to restore by hand.
+
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::Result;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use crate::defaults::{clone_file, set_prefs_owner};
+use crate::errors::DefaultsError as E;
+use crate::manifest::state_dir;
+
+/// The plists an apply has written so far, and where their original contents are kept.
+#[derive(Debug)]
+pub struct Transaction {
+    dir: Utf8PathBuf,
+    /// Each plist written, with its copy, or `None` if the apply created it. Symlinked plists are
+    /// recorded as the file they point to.
+    originals: RefCell<BTreeMap<Utf8PathBuf, Option<Utf8PathBuf>>>,
+    rolled_back: Cell<bool>,
+}
+
+impl Transaction {
+    /// Start keeping the plists of the run `run_id`.
+    pub fn new(run_id: Uuid) -> Result<Self> {
+        Ok(Self::in_dir(state_dir()?.join("transactions").join(run_id.to_string())))
+    }
+
+    fn in_dir(dir: Utf8PathBuf) -> Self {
+        Self {
+            dir,
+            originals: RefCell::new(BTreeMap::new()),
+            rolled_back: Cell::new(false),
+        }
+    }
+
+    /// Keep a copy of a plist about to be written, unless it already has been this run.
+    pub fn record(&self, plist_path: &Utf8Path) -> Result<(), E> {
+        if self.originals.borrow().contains_key(plist_path) {
+            return Ok(());
+        }
+
+        let copy = if plist_path.exists() {
+            let copy = self.dir.join(plist_path.as_str().trim_start_matches('/'));
+            let copy_dir = copy.parent().ok_or(E::UnexpectedNone)?;
+
+            fs::create_dir_all(copy_dir).map_err(|e| E::DirCreation {
+                path: copy_dir.to_owned(),
+                source: e,
+            })?;
+
+            debug!("Keeping {plist_path} as {copy} until the apply finishes");
+
+            clone_file(plist_path, &copy).map_err(|e| E::FileCopy {
+                from_path: plist_path.to_owned(),
+                to_path: copy.clone(),
+                source: e,
+            })?;
+
+            Some(copy)
+        } else {
+            None
+        };
+
+        self.originals.borrow_mut().insert(plist_path.to_owned(), copy);
+        Ok(())
+    }
+
+    /// Put every plist written back as it was, removing the ones that were created. Returns how
+    /// many were restored. Copies that couldn't be restored are kept, and the first error returned.
+    pub fn rollback(&self) -> Result<usize> {
+        let originals = self.originals.take();
+        let mut restored = 0;
+        let mut first_error = None;
+
+        for (plist_path, copy) in &originals {
+            let result = match copy {
+                Some(copy) => clone_file(copy, plist_path)
+                    .map_err(|e| E::FileCopy {
+                        from_path: copy.clone(),
+                        to_path: plist_path.clone(),
+                        source: e,
+                    })
+                    .and_then(|()| set_prefs_owner(plist_path)),
+                None => fs::remove_file(plist_path).map_err(|e| E::FileRemove {
+                    path: plist_path.clone(),
+                    source: e,
+                }),
+            };
+
+            match result {
+                Ok(()) => {
+                    debug!("Rolled back {plist_path}");
+                    restored += 1;
+                }
+                Err(e) => {
+                    warn!("Couldn't roll back {plist_path}: {e}");
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        self.rolled_back.set(true);
+
+        if let Some(e) = first_error {
+            warn!("Keeping the original plists in {} to restore by hand.", self.dir);
+            return Err(e.into());
+        }
+
+        self.discard();
+        Ok(restored)
+    }
+
+    /// Whether the plists written have been rolled back, so nothing the run recorded about its
+    /// writes (e.g. in the managed-keys manifest) should be kept.
+    pub fn rolled_back(&self) -> bool {
+        self.rolled_back.get()
+    }
+
+    /// Remove the copies, once the apply has finished.
+    pub fn discard(&self) {
+        self.originals.borrow_mut().clear();
+
+        if self.dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&self.dir) {
+                warn!("Couldn't remove {}: {e}", self.dir);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use camino::Utf8PathBuf;
+    use plist::{Dictionary, Value};
+    use testresult::TestResult;
+
+    use super::Transaction;
+    use crate::defaults::{execute_plist_write, KeyChange, PlistWrite, Symlinks, WriteOptions};
+    use crate::observer::ConsoleObserver;
+
+    #[test]
+    fn test_rollback() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let existing = dir.join("com.example.existing.plist");
+        let created = dir.join("com.example.created.plist");
+        Value::from(1).to_file_binary(&existing)?;
+
+        let transaction = Transaction::in_dir(dir.join("transaction"));
+
+        transaction.record(&existing)?;
+        Value::from(2).to_file_binary(&existing)?;
+
+        // Only the contents before the first write are kept.
+        transaction.record(&existing)?;
+        Value::from(3).to_file_binary(&existing)?;
+
+        transaction.record(&created)?;
+        Value::from(4).to_file_binary(&created)?;
+
+        assert_eq!(transaction.rollback()?, 2);
+        assert_eq!(Value::from_file(&existing)?, Value::from(1));
+        assert!(!created.exists());
+        assert!(!dir.join("transaction").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback_symlink() -> TestResult {
+        let tmp = tempfile::tempdir()?;
+        let dir = Utf8PathBuf::try_from(tmp.path().to_path_buf())?;
+
+        let target = dir.join("dotfiles.plist");
+        let plist_path = dir.join("com.example.symlink.plist");
+        let old_value = Value::from(Dictionary::from_iter([("key", Value::from(1))]));
+        old_value.to_file_xml(&target)?;
+        std::os::unix::fs::symlink("dotfiles.plist", &plist_path)?;
+
+        let write = PlistWrite {
+            domain: "com.example.symlink".parse()?,
+            path: plist_path.clone(),
+            exists: true,
+            needs_sudo: false,
+            changes: vec![KeyChange::new("key".to_owned(), Some(Value::from(1)), Value::from(2))],
+            removed: Vec::new(),
+            value: Value::from(Dictionary::from_iter([("key", Value::from(2))])),
+        };
+
+        let transaction = Rc::new(Transaction::in_dir(dir.join("transaction")));
+        let options = WriteOptions {
+            backup: false,
+            symlinks: Symlinks::Follow,
+            transaction: Some(transaction.clone()),
+            ..WriteOptions::default()
+        };
+
+        assert!(execute_plist_write(&write, &options, &ConsoleObserver::default())?);
+        assert_eq!(Value::from_file(&target)?, write.value);
+
+        // The file the symlink points to is restored, and the symlink kept.
+        assert_eq!(transaction.rollback()?, 1);
+        assert!(transaction.rolled_back());
+        assert!(plist_path.symlink_metadata()?.is_symlink());
+        assert_eq!(Value::from_file(&target)?, old_value);
+
+        Ok(())
+    }
+}
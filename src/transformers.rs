@@ -0,0 +1,214 @@
+//! Value transformers: YAML tags that expand a readable value into the plist value macOS
+//! expects, and that `dump` writes back for values they recognize.
+//!
+//! - `!color "#BF5AF2"`: a color as the space separated RGB floats of e.g. `AppleHighlightColor`.
+//!
+//! Tools embedding this crate add their own by registering them in a [`Transformers`], e.g. one
+//! starting from [`Transformers::builtin`], and installing it with [`set_transformers`] before
+//! applying or dumping.
+
+use std::sync::OnceLock;
+
+use plist::Value;
+use serde_yaml::value::Tag;
+
+use crate::errors::DefaultsError as E;
+
+/// YAML tag for a hex color.
+pub const COLOR_TAG: &str = "!color";
+
+/// Keys whose values are colors as space separated RGB floats.
+const COLOR_KEYS: &[&str] = &["AppleHighlightColor"];
+
+static TRANSFORMERS: OnceLock<Transformers> = OnceLock::new();
+
+/// A YAML tag with a conversion to a plist value, and optionally back.
+pub trait Transformer: Send + Sync {
+    /// The YAML tag, e.g. `!color`.
+    fn tag(&self) -> &'static str;
+
+    /// Expand the tagged YAML value into a plist value.
+    fn expand(&self, value: serde_yaml::Value) -> Result<Value, E>;
+
+    /// The tagged string for a plist value at a key, if this transformer recognizes it and expanding
+    /// it gives the same value back.
+    fn collapse(&self, key: &str, value: &Value) -> Option<String>;
+}
+
+/// Registry of transformers, looked up by tag.
+#[derive(Default)]
+pub struct Transformers {
+    transformers: Vec<Box<dyn Transformer>>,
+}
+
+impl Transformers {
+    /// The transformers built into this tool.
+    pub fn builtin() -> Self {
+        let mut transformers = Self::default();
+        transformers.register(ColorTransformer);
+        transformers
+    }
+
+    /// Add a transformer. A later transformer with the same tag replaces an earlier one.
+    pub fn register(&mut self, transformer: impl Transformer + 'static) -> &mut Self {
+        self.transformers.retain(|existing| existing.tag() != transformer.tag());
+        self.transformers.push(Box::new(transformer));
+        self
+    }
+
+    /// The YAML tags of the transformers.
+    pub fn tags(&self) -> impl Iterator<Item = &'static str> + '_ {
+        self.transformers.iter().map(|transformer| transformer.tag())
+    }
+
+    /// The transformer for a YAML tag.
+    pub fn get(&self, tag: &Tag) -> Option<&dyn Transformer> {
+        self.transformers.iter().find(|transformer| tag == transformer.tag()).map(AsRef::as_ref)
+    }
+
+    /// The first transformer's tagged YAML for a plist value at a key, e.g. `!color '#BF5AF2'`.
+    pub fn collapse(&self, key: &str, value: &Value) -> Option<String> {
+        self.transformers.iter().find_map(|transformer| {
+            let collapsed = transformer.collapse(key, value)?;
+            Some(format!("{} '{}'", transformer.tag(), collapsed.replace('\'', "''")))
+        })
+    }
+}
+
+/// Install the transformer registry used by `apply` and `dump`. Gives the registry back if one is
+/// already installed, or the built-in one was already used.
+pub fn set_transformers(transformers: Transformers) -> Result<(), Transformers> {
+    TRANSFORMERS.set(transformers)
+}
+
+/// The installed transformer registry, or the built-in one.
+pub fn transformers() -> &'static Transformers {
+    TRANSFORMERS.get_or_init(Transformers::builtin)
+}
+
+/// `!color "#RRGGBB"` to e.g. `"0.749020 0.352941 0.949020"`.
+struct ColorTransformer;
+
+impl ColorTransformer {
+    fn parse_hex(hex: &str) -> Option<[u8; 3]> {
+        let hex = hex.strip_prefix('#').unwrap_or(hex);
+
+        if hex.len() != 6 || !hex.is_ascii() {
+            return None;
+        }
+
+        let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+
+        Some([channel(0)?, channel(2)?, channel(4)?])
+    }
+
+    fn format_floats(rgb: [u8; 3]) -> String {
+        rgb.map(|c| format!("{:.6}", f64::from(c) / 255.0)).join(" ")
+    }
+}
+
+impl Transformer for ColorTransformer {
+    fn tag(&self) -> &'static str {
+        COLOR_TAG
+    }
+
+    fn expand(&self, value: serde_yaml::Value) -> Result<Value, E> {
+        value
+            .as_str()
+            .and_then(Self::parse_hex)
+            .map(|rgb| Value::String(Self::format_floats(rgb)))
+            .ok_or(E::InvalidTagValue {
+                tag: COLOR_TAG,
+                expected: "a hex color like \"#BF5AF2\"",
+            })
+    }
+
+    fn collapse(&self, key: &str, value: &Value) -> Option<String> {
+        if !COLOR_KEYS.contains(&key) {
+            return None;
+        }
+
+        let floats = value.as_string()?;
+        let channels: Vec<f64> = floats.split(' ').map(str::parse).collect::<Result<_, _>>().ok()?;
+
+        let [r, g, b] = channels[..] else {
+            return None;
+        };
+
+        let rgb = [r, g, b].map(|c| (c * 255.0).round());
+
+        if rgb.iter().any(|c| !(0.0..=255.0).contains(c)) {
+            return None;
+        }
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let rgb = rgb.map(|c| c as u8);
+
+        // Only lossless, so applying the dump doesn't change the value.
+        (Self::format_floats(rgb) == floats).then(|| format!("#{}", hex::encode_upper(rgb)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::{set_transformers, transformers, Transformer, Transformers, COLOR_TAG};
+    use crate::errors::DefaultsError as E;
+
+    #[test]
+    fn test_color_transformer() -> TestResult {
+        let transformers = Transformers::builtin();
+        let color = transformers.get(&serde_yaml::value::Tag::new(COLOR_TAG)).ok_or("color is built in")?;
+
+        assert_eq!(color.expand("#BF5AF2".into())?, Value::from("0.749020 0.352941 0.949020"));
+        assert_eq!(color.expand("bf5af2".into())?, Value::from("0.749020 0.352941 0.949020"));
+        assert!(color.expand("#BF5AF".into()).is_err());
+        assert!(color.expand(7.into()).is_err());
+
+        let floats = Value::from("0.749020 0.352941 0.949020");
+        assert_eq!(transformers.collapse("AppleHighlightColor", &floats).as_deref(), Some("!color '#BF5AF2'"));
+        assert_eq!(transformers.collapse("OtherKey", &floats), None);
+
+        // Named colors, and floats that wouldn't round-trip, are left alone.
+        assert_eq!(transformers.collapse("AppleHighlightColor", &"0.968627 0.831373 1.000000 Purple".into()), None);
+        assert_eq!(transformers.collapse("AppleHighlightColor", &"0.7647 0.9765 0.5686".into()), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_register_transformer() -> TestResult {
+        struct Upper;
+
+        impl Transformer for Upper {
+            fn tag(&self) -> &'static str {
+                "!upper"
+            }
+
+            fn expand(&self, value: serde_yaml::Value) -> Result<Value, E> {
+                Ok(Value::String(value.as_str().unwrap_or_default().to_uppercase()))
+            }
+
+            fn collapse(&self, _key: &str, _value: &Value) -> Option<String> {
+                None
+            }
+        }
+
+        let mut transformers = Transformers::builtin();
+        transformers.register(Upper);
+
+        let upper = transformers.get(&serde_yaml::value::Tag::new("!upper")).ok_or("registered")?;
+        assert_eq!(upper.expand("dark".into())?, Value::from("DARK"));
+        assert!(transformers.get(&serde_yaml::value::Tag::new(COLOR_TAG)).is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_transformers_after_use() {
+        assert!(transformers().tags().any(|tag| tag == COLOR_TAG));
+        assert!(set_transformers(Transformers::default()).is_err());
+    }
+}
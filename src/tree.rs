@@ -0,0 +1,174 @@
+//! Changes to plist values as a colored tree, for people rather than tools: `+` added, `-`
+//! removed and `~` changed keys, with nested dictionaries and arrays expanded to what changed.
+//! Long strings and data are truncated, and long lists of changes elided.
+
+use std::fmt::Write as _;
+
+use colored::Colorize;
+use plist::{Dictionary, Value};
+
+/// Strings longer than this many characters are truncated.
+const MAX_STRING: usize = 60;
+
+/// Bytes of data shown before truncating.
+const MAX_DATA: usize = 16;
+
+/// Changes shown per dictionary or array before eliding the rest.
+const MAX_CHANGES: usize = 20;
+
+/// Render a key changing from `old_value` (`None` if it didn't exist) to `new_value`, indented by
+/// `indent` spaces.
+pub fn render_change(key: &str, old_value: Option<&Value>, new_value: &Value, indent: usize) -> String {
+    let mut tree = String::new();
+    write_change(&mut tree, key, old_value, Some(new_value), indent);
+    tree
+}
+
+/// Render a key being removed, whose old value isn't known, indented by `indent` spaces.
+pub fn render_removal(key: &str, indent: usize) -> String {
+    format!("{:indent$}{} {}\n", "", "-".red(), key.red())
+}
+
+fn write_change(tree: &mut String, key: &str, old_value: Option<&Value>, new_value: Option<&Value>, indent: usize) {
+    let _ = match (old_value, new_value) {
+        (None, Some(new_value)) => writeln!(tree, "{:indent$}{} {}: {}", "", "+".green(), key, summary(new_value).green()),
+        (Some(old_value), None) => writeln!(tree, "{:indent$}{} {}: {}", "", "-".red(), key, summary(old_value).red()),
+        (Some(Value::Dictionary(old)), Some(Value::Dictionary(new))) => {
+            let _ = writeln!(tree, "{:indent$}{} {}", "", "~".yellow(), key);
+            write_dictionary_changes(tree, old, new, indent + 4);
+            Ok(())
+        }
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            let _ = writeln!(tree, "{:indent$}{} {}", "", "~".yellow(), key);
+            write_array_changes(tree, old, new, indent + 4);
+            Ok(())
+        }
+        (Some(old_value), Some(new_value)) => writeln!(
+            tree,
+            "{:indent$}{} {}: {} → {}",
+            "",
+            "~".yellow(),
+            key,
+            summary(old_value).red(),
+            summary(new_value).green()
+        ),
+        (None, None) => Ok(()),
+    };
+}
+
+fn write_dictionary_changes(tree: &mut String, old: &Dictionary, new: &Dictionary, indent: usize) {
+    let updated = new
+        .iter()
+        .filter(|(key, value)| old.get(key) != Some(value))
+        .map(|(key, value)| (key, old.get(key), Some(value)));
+
+    let removed = old
+        .iter()
+        .filter(|(key, _)| !new.contains_key(key))
+        .map(|(key, value)| (key, Some(value), None));
+
+    let changes: Vec<_> = updated.chain(removed).collect();
+
+    for (key, old_value, new_value) in changes.iter().take(MAX_CHANGES) {
+        write_change(tree, key, *old_value, *new_value, indent);
+    }
+
+    write_elided(tree, changes.len(), indent);
+}
+
+/// Items are compared by index, so an insertion shows as every later item changing.
+fn write_array_changes(tree: &mut String, old: &[Value], new: &[Value], indent: usize) {
+    let changes: Vec<_> = (0..old.len().max(new.len()))
+        .filter(|&index| old.get(index) != new.get(index))
+        .map(|index| (format!("[{index}]"), old.get(index), new.get(index)))
+        .collect();
+
+    for (key, old_value, new_value) in changes.iter().take(MAX_CHANGES) {
+        write_change(tree, key, *old_value, *new_value, indent);
+    }
+
+    write_elided(tree, changes.len(), indent);
+}
+
+fn write_elided(tree: &mut String, changes: usize, indent: usize) {
+    if changes > MAX_CHANGES {
+        let _ = writeln!(tree, "{:indent$}{}", "", format!("… {} more changes", changes - MAX_CHANGES).dimmed());
+    }
+}
+
+/// A value on one line: scalars as they are (long strings and data truncated), dictionaries and
+/// arrays by their size.
+pub fn summary(value: &Value) -> String {
+    match value {
+        Value::Array(array) => match array.len() {
+            1 => "[1 item]".to_owned(),
+            len => format!("[{len} items]"),
+        },
+        Value::Dictionary(dict) => match dict.len() {
+            1 => "{1 key}".to_owned(),
+            len => format!("{{{len} keys}}"),
+        },
+        Value::Boolean(b) => b.to_string(),
+        Value::Data(data) => {
+            let hex = data.iter().take(MAX_DATA).fold(String::new(), |mut hex, byte| {
+                let _ = write!(hex, "{byte:02x}");
+                hex
+            });
+            let more = if data.len() > MAX_DATA { "…" } else { "" };
+            format!("<{} bytes: {hex}{more}>", data.len())
+        }
+        Value::Date(date) => date.to_xml_format(),
+        Value::Real(f) => f.to_string(),
+        Value::Integer(i) => i.to_string(),
+        Value::String(s) if s.chars().count() > MAX_STRING => format!("{:?}…", s.chars().take(MAX_STRING).collect::<String>()),
+        Value::String(s) => format!("{s:?}"),
+        Value::Uid(uid) => format!("uid({})", uid.get()),
+        _ => "?".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use plist::{Dictionary, Value};
+
+    use super::{render_change, render_removal, summary, MAX_CHANGES};
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(summary(&Value::from("a".repeat(70))), format!("{:?}…", "a".repeat(60)));
+        assert_eq!(summary(&Value::Data(vec![0xab; 20])), format!("<20 bytes: {}…>", "ab".repeat(16)));
+        assert_eq!(summary(&Value::Array(vec![Value::from(1)])), "[1 item]");
+        assert_eq!(summary(&Value::Dictionary(Dictionary::new())), "{0 keys}");
+    }
+
+    #[test]
+    fn test_render_change() {
+        colored::control::set_override(false);
+
+        let old = Value::Dictionary(Dictionary::from_iter([
+            ("same", Value::from(1)),
+            ("changed", Value::from(1)),
+            ("gone", Value::from(true)),
+            ("list", Value::Array(vec![Value::from(1), Value::from(2)])),
+        ]));
+        let new = Value::Dictionary(Dictionary::from_iter([
+            ("same", Value::from(1)),
+            ("changed", Value::from(2)),
+            ("list", Value::Array(vec![Value::from(1), Value::from(3), Value::from(4)])),
+            ("added", Value::from("x")),
+        ]));
+
+        assert_eq!(
+            render_change("prefs", Some(&old), &new, 2),
+            "  ~ prefs\n      ~ changed: 1 → 2\n      ~ list\n          ~ [1]: 2 → 3\n          + [2]: 4\n      + added: \"x\"\n      - gone: true\n"
+        );
+
+        assert_eq!(render_change("new", None, &Value::from(1), 0), "+ new: 1\n");
+        assert_eq!(render_removal("old", 0), "- old\n");
+
+        let long = Value::Array((0..30).map(Value::from).collect());
+        let tree = render_change("long", Some(&Value::Array(Vec::new())), &long, 0);
+        assert_eq!(tree.lines().count(), 1 + MAX_CHANGES + 1);
+        assert!(tree.ends_with("    … 10 more changes\n"));
+    }
+}
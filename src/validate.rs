@@ -0,0 +1,142 @@
+//! Constraints on the values of keys in YAML, so obviously wrong values (a tile size of 1000, a
+//! misspelled orientation) fail before they're written into a plist, where they'd only cause
+//! subtle misbehavior:
+//!
+//! ```yaml
+//! data:
+//!   com.apple.dock:
+//!     tilesize: {value: 36, valid: {min: 16, max: 128}}
+//!     orientation: {value: left, valid: {enum: [left, bottom, right]}}
+//! ```
+//!
+//! A key's value that's a mapping of exactly `value` and `valid` is always read as a constrained
+//! value, never as a dictionary with those two keys.
+
+use plist::Value;
+use serde::Deserialize;
+
+use crate::defaults::IF_UNSET;
+use crate::errors::DefaultsError as E;
+use crate::secrets;
+use crate::tags::{yaml_to_plist, TagContext};
+
+const VALUE_KEY: &str = "value";
+const VALID_KEY: &str = "valid";
+
+/// What a key's value must be. Every constraint given must hold.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Valid {
+    /// Smallest number allowed, inclusive.
+    min: Option<f64>,
+    /// Largest number allowed, inclusive.
+    max: Option<f64>,
+    /// The values allowed.
+    #[serde(rename = "enum")]
+    one_of: Option<Vec<serde_yaml::Value>>,
+}
+
+/// Split a key's YAML value into the value and its constraints, if it has any.
+pub fn split_constraints(value: serde_yaml::Value) -> Result<(serde_yaml::Value, Option<serde_yaml::Value>), E> {
+    match value {
+        serde_yaml::Value::Mapping(mut mapping) if mapping.len() == 2 && mapping.contains_key(VALUE_KEY) && mapping.contains_key(VALID_KEY) => {
+            let value = mapping.remove(VALUE_KEY).ok_or(E::UnexpectedNone)?;
+            let valid = mapping.remove(VALID_KEY).ok_or(E::UnexpectedNone)?;
+            Ok((value, Some(valid)))
+        }
+        value => Ok((value, None)),
+    }
+}
+
+/// Check a key's value against the YAML of its `valid` constraints, failing with why it's invalid.
+#[allow(clippy::cast_precision_loss)] // Integers are only compared with `min` and `max`, which are f64 anyway.
+pub fn check(domain: &str, key: &str, value: &Value, valid: serde_yaml::Value, context: &TagContext) -> Result<(), E> {
+    let invalid = |reason: String| E::InvalidValue {
+        domain: domain.to_owned(),
+        key: key.to_owned(),
+        reason,
+    };
+
+    let valid: Valid = serde_yaml::from_value(valid).map_err(|e| invalid(format!("its `valid` constraints aren't understood: {e}")))?;
+
+    // `!once` values are checked as what they'd set.
+    let value = match value.as_dictionary() {
+        Some(dict) if dict.len() == 1 => dict.get(IF_UNSET).unwrap_or(value),
+        _ => value,
+    };
+
+    if valid.min.is_some() || valid.max.is_some() {
+        let number = match value {
+            Value::Integer(i) => i.as_signed().map(|i| i as f64).or_else(|| i.as_unsigned().map(|i| i as f64)),
+            Value::Real(f) => Some(*f),
+            _ => None,
+        }
+        .ok_or_else(|| invalid(format!("{:?} isn't a number, but it has a `min` or `max`", secrets::redact(value))))?;
+
+        if let Some(min) = valid.min.filter(|&min| number < min) {
+            return Err(invalid(format!("{number} is less than the minimum of {min}")));
+        }
+
+        if let Some(max) = valid.max.filter(|&max| number > max) {
+            return Err(invalid(format!("{number} is more than the maximum of {max}")));
+        }
+    }
+
+    if let Some(one_of) = valid.one_of {
+        let allowed = one_of
+            .into_iter()
+            .map(|allowed| yaml_to_plist(allowed, context))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if !allowed.contains(value) {
+            return Err(invalid(format!("{:?} isn't one of {allowed:?}", secrets::redact(value))));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8Path;
+    use plist::Value;
+    use testresult::TestResult;
+
+    use super::{check, split_constraints};
+    use crate::tags::TagContext;
+
+    #[test]
+    fn test_check() {
+        let context = TagContext::for_yaml_path(Utf8Path::new("dock.yaml"));
+        let check = |value: Value, valid: &str| {
+            let valid = serde_yaml::from_str(valid).map_err(|e| e.to_string())?;
+            check("com.apple.dock", "key", &value, valid, &context).map_err(|e| e.to_string())
+        };
+
+        assert!(check(Value::from(36), "{min: 16, max: 128}").is_ok());
+        assert!(check(Value::Real(16.0), "{min: 16}").is_ok());
+        assert_eq!(
+            check(Value::from(1000), "{min: 16, max: 128}"),
+            Err("Invalid value for com.apple.dock key: 1000 is more than the maximum of 128.".to_owned())
+        );
+        assert!(check(Value::from(8), "{min: 16}").is_err());
+        assert!(check(Value::from("36"), "{max: 128}").is_err());
+
+        assert!(check(Value::from("left"), "{enum: [left, bottom, right]}").is_ok());
+        assert!(check(Value::from("top"), "{enum: [left, bottom, right]}").is_err());
+        assert!(check(Value::from("left"), "{oneof: [left]}").is_err());
+    }
+
+    #[test]
+    fn test_split_constraints() -> TestResult {
+        let (value, valid) = split_constraints(serde_yaml::from_str("{value: 36, valid: {min: 16}}")?)?;
+        assert_eq!(value, serde_yaml::Value::from(36));
+        assert!(valid.is_some());
+
+        // Other dictionaries are left as they are.
+        let dictionary: serde_yaml::Value = serde_yaml::from_str("{value: 36, other: 1}")?;
+        assert_eq!(split_constraints(dictionary.clone())?, (dictionary, None));
+
+        Ok(())
+    }
+}
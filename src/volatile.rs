@@ -0,0 +1,106 @@
+//! Heuristics for keys that are machine-generated noise rather than user preferences: counters,
+//! timestamps, window positions and the like, which the OS or app rewrite as they run.
+
+use plist::Value;
+
+use crate::glob::glob_matches;
+
+/// Patterns of key names that are usually machine-generated, matched case insensitively. `*` matches
+/// any characters and `?` any one character, as for `dump --keys`.
+pub const VOLATILE_KEY_PATTERNS: &[&str] = &[
+    "NSWindow Frame *",
+    "NSTableView *",
+    "NSSplitView *",
+    "NSNavPanel*",
+    "NSToolbar Configuration *",
+    "*Preferred Position*",
+    "*Analytics*",
+    "*LastCheck*",
+    "*LastLaunch*",
+    "*LastRun*",
+    "*LastUpdate*",
+    "*LastUsed*",
+    "*LaunchCount*",
+    "*Timestamp*",
+    "mod-count",
+];
+
+/// Which top level keys are left out when comparing the current defaults with a YAML document.
+///
+/// By default, these are the volatile keys no document sets. Patterns given in the config file
+/// replace them, and a document's `drift_ignore` patterns for a domain replace them for that
+/// domain; either applies to every key, whether a document sets it or not.
+#[derive(Debug, Default, Clone)]
+pub struct DriftIgnore {
+    patterns: Option<Vec<String>>,
+}
+
+impl DriftIgnore {
+    /// The given patterns, or the built-in volatile keys.
+    pub const fn new(patterns: Option<Vec<String>>) -> Self {
+        Self { patterns }
+    }
+
+    /// Whether a key is left out of comparisons, given the document's patterns for its domain if
+    /// any, and whether a document sets the key.
+    pub fn is_ignored(&self, key: &str, domain_patterns: Option<&[String]>, managed: bool) -> bool {
+        match domain_patterns.or(self.patterns.as_deref()) {
+            Some(patterns) => patterns.iter().any(|pattern| glob_matches(pattern, key, false)),
+            None => !managed && is_volatile_key(key),
+        }
+    }
+}
+
+/// Whether a key (with its value) looks like machine-generated noise.
+pub fn is_volatile(key: &str, value: &Value) -> bool {
+    matches!(value, Value::Date(_)) || is_volatile_key(key)
+}
+
+/// Whether a key's name alone looks like machine-generated noise.
+pub fn is_volatile_key(key: &str) -> bool {
+    let key = key.to_lowercase();
+
+    VOLATILE_KEY_PATTERNS.iter().any(|pattern| glob_matches(&pattern.to_lowercase(), &key, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use plist::Value;
+
+    use super::{is_volatile, DriftIgnore};
+
+    #[test]
+    fn test_is_volatile() {
+        assert!(is_volatile("mod-count", &Value::from(3)));
+        assert!(is_volatile("NSWindow Frame Main", &Value::from("0 0 100 100")));
+        assert!(is_volatile("SULastCheckTime", &Value::from("whenever")));
+        assert!(is_volatile("FirstRun", &Value::Date(SystemTime::now().into())));
+
+        assert!(!is_volatile("autohide", &Value::from(true)));
+        assert!(!is_volatile("tilesize", &Value::from(36)));
+    }
+
+    #[test]
+    fn test_drift_ignore() {
+        let builtin = DriftIgnore::default();
+
+        assert!(builtin.is_ignored("NSWindow Frame Main", None, false));
+        assert!(builtin.is_ignored("SUUpdater_LastUsedDate", None, false));
+        assert!(builtin.is_ignored("mod-count", None, false));
+        assert!(!builtin.is_ignored("autohide", None, false));
+
+        // The built-in volatile keys are still compared when a document sets them.
+        assert!(!builtin.is_ignored("NSNavPanelExpandedStateForSaveMode", None, true));
+
+        // A domain's own patterns replace the built-in ones, and apply to keys documents set too.
+        let dock = ["autohide*".to_owned()];
+        assert!(builtin.is_ignored("autohide-delay", Some(&dock), true));
+        assert!(!builtin.is_ignored("mod-count", Some(&dock), false));
+        assert!(!builtin.is_ignored("mod-count", Some(&[]), false));
+
+        assert!(!DriftIgnore::new(Some(Vec::new())).is_ignored("mod-count", None, false));
+        assert!(DriftIgnore::new(Some(vec!["mod-count".to_owned()])).is_ignored("mod-count", None, true));
+    }
+}
@@ -0,0 +1,344 @@
+//! `dump --merge`: update the domains of an existing YAML file with a fresh dump, line by line, so
+//! everything about the file that a YAML parser throws away survives re-dumping: comments, key
+//! order, quoting and indentation.
+//!
+//! Within a dumped domain, keys whose values haven't changed are kept exactly as written, changed
+//! keys get the dumped value but keep their comments, keys that are gone are removed, and new keys
+//! are added at the end. Everything else in the file is left alone, and domains the file doesn't
+//! have yet are added as new documents.
+
+use tracing::debug;
+
+/// A key of a domain and its value: the key line and every line of its value, with the comment
+/// and blank lines before it.
+#[derive(Debug)]
+struct KeyBlock<'a> {
+    leading: Vec<&'a str>,
+    key: String,
+    lines: Vec<&'a str>,
+}
+
+/// Where a domain's keys are in a document's lines.
+#[derive(Debug)]
+struct DomainSection<'a> {
+    /// Index of the first line after the domain's own line.
+    start: usize,
+    /// Index after the domain's last key line, before any comments following it.
+    end: usize,
+    /// Indentation of the domain's keys.
+    indent: usize,
+    blocks: Vec<KeyBlock<'a>>,
+}
+
+/// Merge dumped documents, each with the domain it's for, into an existing YAML file.
+pub fn merge_dump(existing: &str, dumped: &[(String, String)]) -> String {
+    let mut documents: Vec<Vec<String>> = split_documents(existing)
+        .into_iter()
+        .map(|lines| lines.into_iter().map(str::to_owned).collect())
+        .collect();
+    let mut appended: Vec<Vec<String>> = Vec::new();
+
+    for (domain, yaml) in dumped {
+        let new_lines: Vec<&str> = yaml.lines().collect();
+
+        let Some(new_range) = domain_range(&new_lines, domain) else {
+            debug!("The dump of {domain} doesn't have it under `data:`, adding it as a new document");
+            appended.push(new_lines.iter().map(|line| (*line).to_owned()).collect());
+            continue;
+        };
+
+        let new_section = find_domain(&new_lines, domain);
+
+        let existing_document = documents.iter_mut().find_map(|lines| {
+            let view: Vec<&str> = lines.iter().map(String::as_str).collect();
+            let old_range = domain_range(&view, domain)?;
+
+            let merged = match (find_domain(&view, domain), &new_section) {
+                (Some(old_section), Some(new_section)) => merge_section(&view, &old_section, new_section),
+                // An empty or flow style domain can't be merged into key by key, so the dumped
+                // domain replaces it, and only it.
+                _ => replace_domain(&view, old_range, &new_lines, new_range),
+            };
+
+            Some((lines, merged))
+        });
+
+        match existing_document {
+            Some((lines, merged)) => *lines = merged,
+            None => appended.push(new_lines.iter().map(|line| (*line).to_owned()).collect()),
+        }
+    }
+
+    let mut merged: Vec<String> = documents.into_iter().chain(appended).flatten().collect();
+    merged.push(String::new());
+
+    merged.join("\n")
+}
+
+/// Split a YAML stream into its documents' lines, each starting at its `---`, if it has one.
+fn split_documents(yaml: &str) -> Vec<Vec<&str>> {
+    let mut documents: Vec<Vec<&str>> = vec![Vec::new()];
+
+    for line in yaml.lines() {
+        if is_document_start(line) && documents.last().is_some_and(|document| document.iter().any(|line| is_content(line))) {
+            documents.push(Vec::new());
+        }
+
+        if let Some(document) = documents.last_mut() {
+            document.push(line);
+        }
+    }
+
+    documents
+}
+
+fn is_document_start(line: &str) -> bool {
+    line == "---" || line.starts_with("--- ") || line.starts_with("---\t")
+}
+
+/// Whether a line has YAML on it, rather than being blank or a comment.
+fn is_content(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    !trimmed.is_empty() && !trimmed.starts_with('#') && !is_document_start(line)
+}
+
+fn indent(line: &str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+/// The key of a `key: value` or `key:` line, unquoted.
+fn line_key(line: &str) -> Option<String> {
+    let line = line.trim_start();
+
+    let (raw, rest) = if let Some(quote @ ('"' | '\'')) = line.chars().next() {
+        let mut escaped = false;
+        let end = line
+            .char_indices()
+            .skip(1)
+            .find(|&(_, c)| {
+                let closes = c == quote && !escaped;
+                escaped = quote == '"' && c == '\\' && !escaped;
+                closes
+            })?
+            .0;
+        line.split_at(end + 1)
+    } else {
+        let end = line.find(": ").or_else(|| line.strip_suffix(':').map(str::len))?;
+        line.split_at(end)
+    };
+
+    if !rest.starts_with(':') {
+        return None;
+    }
+
+    Some(serde_yaml::from_str::<String>(raw).unwrap_or_else(|_| raw.to_owned()))
+}
+
+/// The `# comment` at the end of a line, if it has one outside quotes.
+fn inline_comment(line: &str) -> Option<&str> {
+    let mut quote = None;
+    let mut previous = ' ';
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q && previous != '\\' => quote = None,
+            (None, '#') if previous.is_whitespace() && !line[..i].trim().is_empty() => return Some(&line[i..]),
+            _ => {}
+        }
+        previous = c;
+    }
+
+    None
+}
+
+/// Index of a domain's line under a document's `data:`, if the document has the domain.
+fn domain_line(lines: &[&str], domain: &str) -> Option<usize> {
+    let data = lines.iter().position(|line| indent(line) == 0 && line_key(line).as_deref() == Some("data"))?;
+
+    (data + 1..lines.len())
+        .take_while(|&i| !is_content(lines[i]) || indent(lines[i]) > 0)
+        .find(|&i| is_content(lines[i]) && line_key(lines[i]).as_deref() == Some(domain))
+}
+
+/// The lines of a domain under a document's `data:`, from its own line to its last value line, as
+/// `(start, end)`.
+fn domain_range(lines: &[&str], domain: &str) -> Option<(usize, usize)> {
+    let start = domain_line(lines, domain)?;
+    let domain_indent = indent(lines[start]);
+
+    let end = (start + 1..lines.len())
+        .take_while(|&i| !is_content(lines[i]) || indent(lines[i]) > domain_indent)
+        .filter(|&i| is_content(lines[i]))
+        .last()
+        .unwrap_or(start)
+        + 1;
+
+    Some((start, end))
+}
+
+/// Find a domain under a document's `data:`, split into its keys. `None` if the document doesn't
+/// have the domain, or it's empty or written in flow style (`domain: {a: 1}`).
+fn find_domain<'a>(lines: &[&'a str], domain: &str) -> Option<DomainSection<'a>> {
+    let domain_line = domain_line(lines, domain)?;
+
+    let domain_indent = indent(lines[domain_line]);
+    let after_key = lines[domain_line].trim_end().trim_end_matches(|c| c != ':');
+
+    // Anything after the colon other than a comment is a flow style value.
+    if after_key.len() < lines[domain_line].len() && inline_comment(lines[domain_line]).is_none() && !lines[domain_line].trim_end().ends_with(':') {
+        return None;
+    }
+
+    let start = domain_line + 1;
+    let end = (start..lines.len())
+        .take_while(|&i| !is_content(lines[i]) || indent(lines[i]) > domain_indent)
+        .filter(|&i| is_content(lines[i]))
+        .last()?
+        + 1;
+
+    let indent = indent(lines[(start..end).find(|&i| is_content(lines[i]))?]);
+    let mut blocks: Vec<KeyBlock> = Vec::new();
+    let mut pending = Vec::new();
+
+    for &line in &lines[start..end] {
+        if !is_content(line) {
+            pending.push(line);
+            continue;
+        }
+
+        let starts_key = self::indent(line) == indent && !line.trim_start().starts_with("- ") && line.trim_start() != "-";
+
+        match (starts_key, blocks.last_mut()) {
+            (true, _) | (false, None) => blocks.push(KeyBlock {
+                leading: std::mem::take(&mut pending),
+                key: line_key(line).unwrap_or_else(|| line.trim().to_owned()),
+                lines: vec![line],
+            }),
+            (false, Some(block)) => {
+                block.lines.append(&mut pending);
+                block.lines.push(line);
+            }
+        }
+    }
+
+    Some(DomainSection { start, end, indent, blocks })
+}
+
+/// The value of a key block, parsed, to tell whether it changed however it's formatted.
+fn block_value(block: &KeyBlock, indent: usize) -> Option<serde_yaml::Value> {
+    let yaml: Vec<&str> = block.lines.iter().map(|line| line.get(indent..).unwrap_or(line.trim_start())).collect();
+    serde_yaml::from_str(&yaml.join("\n")).ok()
+}
+
+/// Lines indented by `from` or more, indented by `to` instead.
+fn shift(lines: &[&str], from: usize, to: usize) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| match line.get(from..) {
+            Some(rest) if indent(line) >= from => format!("{}{rest}", " ".repeat(to)),
+            _ => (*line).to_owned(),
+        })
+        .collect()
+}
+
+/// A dumped key block's lines at another indentation, keeping the comment on the old key line.
+fn reindent(block: &KeyBlock, from: usize, to: usize, old_key_line: Option<&str>) -> Vec<String> {
+    let mut lines = shift(&block.lines, from, to);
+
+    if let (Some(comment), Some(first)) = (old_key_line.and_then(inline_comment), lines.first_mut()) {
+        if inline_comment(first).is_none() {
+            first.push(' ');
+            first.push_str(comment);
+        }
+    }
+
+    lines
+}
+
+/// Merge a dumped domain's keys into a document's lines, returning the document's new lines.
+fn merge_section(lines: &[&str], old: &DomainSection, new: &DomainSection) -> Vec<String> {
+    let mut merged: Vec<String> = lines[..old.start].iter().map(|line| (*line).to_owned()).collect();
+
+    for old_block in &old.blocks {
+        let Some(new_block) = new.blocks.iter().find(|block| block.key == old_block.key) else {
+            debug!("Removing {} from the merged dump", old_block.key);
+            continue;
+        };
+
+        merged.extend(old_block.leading.iter().map(|line| (*line).to_owned()));
+
+        let old_value = block_value(old_block, old.indent);
+
+        if old_value.is_some() && old_value == block_value(new_block, new.indent) {
+            merged.extend(old_block.lines.iter().map(|line| (*line).to_owned()));
+        } else {
+            merged.extend(reindent(new_block, new.indent, old.indent, old_block.lines.first().copied()));
+        }
+    }
+
+    for new_block in new.blocks.iter().filter(|block| !old.blocks.iter().any(|old| old.key == block.key)) {
+        merged.extend(reindent(new_block, new.indent, old.indent, None));
+    }
+
+    merged.extend(lines[old.end..].iter().map(|line| (*line).to_owned()));
+
+    merged
+}
+
+/// Replace a domain's lines in a document with the dumped domain's, leaving the rest of the
+/// document (its other domains, fields and comments) as it is.
+fn replace_domain(lines: &[&str], (start, end): (usize, usize), new_lines: &[&str], (new_start, new_end): (usize, usize)) -> Vec<String> {
+    let mut merged: Vec<String> = lines[..start].iter().map(|line| (*line).to_owned()).collect();
+
+    merged.extend(shift(&new_lines[new_start..new_end], indent(new_lines[new_start]), indent(lines[start])));
+    merged.extend(lines[end..].iter().map(|line| (*line).to_owned()));
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::merge_dump;
+
+    #[test]
+    fn test_merge_dump() {
+        let existing = "# My dock settings.\n---\ndescription: Dock\nkill: [Dock]\ndata:\n  com.apple.dock:\n    # Hide it.\n    autohide: true # Keep it hidden\n    tilesize: 36\n    \"mru-spaces\": false\n    persistent-others:\n      - a\n      - b\n";
+        let dumped = [
+            (
+                "com.apple.dock".to_owned(),
+                "---\ndescription: com.apple.dock\ndata:\n  com.apple.dock:\n    autohide: true\n    orientation: left\n    persistent-others:\n      - a\n    tilesize: 48\n".to_owned(),
+            ),
+            ("com.apple.finder".to_owned(), "---\ndata:\n  com.apple.finder:\n    ShowPathbar: true\n".to_owned()),
+        ];
+
+        assert_eq!(
+            merge_dump(existing, &dumped),
+            "# My dock settings.\n---\ndescription: Dock\nkill: [Dock]\ndata:\n  com.apple.dock:\n    # Hide it.\n    autohide: true # Keep it hidden\n    tilesize: 48\n    persistent-others:\n      - a\n    orientation: left\n---\ndata:\n  com.apple.finder:\n    ShowPathbar: true\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_dump_flow_style() {
+        let existing =
+            "---\ndescription: Dock\nkill: [Dock]\ndata:\n  # The dock.\n  com.apple.dock: {autohide: true}\n  com.apple.finder:\n    ShowPathbar: true\n";
+        let dumped = [("com.apple.dock".to_owned(), "---\ndata:\n  com.apple.dock:\n    autohide: false\n".to_owned())];
+
+        // Only the flow style domain is replaced.
+        assert_eq!(
+            merge_dump(existing, &dumped),
+            "---\ndescription: Dock\nkill: [Dock]\ndata:\n  # The dock.\n  com.apple.dock:\n    autohide: false\n  com.apple.finder:\n    ShowPathbar: true\n"
+        );
+    }
+
+    #[test]
+    fn test_merge_dump_empty_domain() {
+        let existing = "---\ndescription: Dock\ndata:\n  com.apple.dock:\n    autohide: true # Hidden\n  com.apple.finder:\n    ShowPathbar: true\n";
+        let dumped = [("com.apple.dock".to_owned(), "---\ndata:\n  com.apple.dock: {}\n".to_owned())];
+
+        assert_eq!(
+            merge_dump(existing, &dumped),
+            "---\ndescription: Dock\ndata:\n  com.apple.dock: {}\n  com.apple.finder:\n    ShowPathbar: true\n"
+        );
+    }
+}